@@ -0,0 +1,126 @@
+#ifndef TERMINUS_H
+#define TERMINUS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* all functions return 0 on success and -1 on failure, terminus_last_error() describes the failure.
+   internal panics of the simulator are reported as failures */
+
+#define TERMINUS_MAX_WRITES 4
+#define TERMINUS_MAX_TRAPS 4
+
+/* bit positions in mip */
+#define TERMINUS_IRQ_MSIP 3
+#define TERMINUS_IRQ_MTIP 7
+#define TERMINUS_IRQ_SEIP 9
+#define TERMINUS_IRQ_MEIP 11
+
+#define TERMINUS_PRIV_U 0
+#define TERMINUS_PRIV_S 1
+#define TERMINUS_PRIV_M 3
+
+typedef struct Terminus terminus_t;
+
+typedef struct {
+    const char *name;
+    /* NULL: no elf, load images by terminus_load_raw or terminus_write_mem and reset with entry */
+    const char *elf;
+    uint32_t num_harts;
+    /* 32 or 64 */
+    uint32_t xlen;
+    /* isa string without "rv32"/"rv64", e.g. "imafdcsu", "gcsu_zicsr_zifencei" */
+    const char *extensions;
+    uint64_t freq;
+    uint64_t timer_freq;
+    uint64_t mem_base;
+    uint64_t mem_size;
+    /* 0: no clint */
+    uint64_t clint_base;
+    /* 0: no boot rom, boot rom jumps to elf entry */
+    uint64_t boot_rom_base;
+} terminus_cfg_t;
+
+typedef struct {
+    uint32_t id;
+    uint64_t value;
+    /* upper 64 bits of 128 bits fregs */
+    uint64_t value_hi;
+} terminus_reg_write_t;
+
+typedef struct {
+    /* virtual address */
+    uint64_t addr;
+    uint64_t pa;
+    uint32_t len;
+    uint64_t data;
+} terminus_mem_write_t;
+
+typedef struct {
+    uint8_t interrupt;
+    /* privilege the trap is taken to */
+    uint8_t privilege;
+    uint64_t code;
+    uint64_t tval;
+    uint64_t epc;
+} terminus_trap_t;
+
+/* *_num is the actual number of records, only the first TERMINUS_MAX_* ones are filled */
+typedef struct {
+    uint64_t pc;
+    /* 0 if the instruction is not executed */
+    uint32_t insn;
+    /* the instruction was executed, it may still raise an exception after partial execution */
+    uint8_t retired;
+    /* privilege after the step */
+    uint8_t privilege;
+    uint32_t xreg_num;
+    terminus_reg_write_t xreg[TERMINUS_MAX_WRITES];
+    uint32_t freg_num;
+    terminus_reg_write_t freg[TERMINUS_MAX_WRITES];
+    uint32_t mem_num;
+    terminus_mem_write_t mem[TERMINUS_MAX_WRITES];
+    uint32_t trap_num;
+    terminus_trap_t trap[TERMINUS_MAX_TRAPS];
+} terminus_commit_t;
+
+const char *terminus_last_error(void);
+
+/* return NULL on failure */
+terminus_t *terminus_new(const terminus_cfg_t *cfg);
+void terminus_free(terminus_t *t);
+uint32_t terminus_num_harts(terminus_t *t);
+
+int terminus_load_elf(terminus_t *t);
+int terminus_load_raw(terminus_t *t, const char *file, uint64_t addr);
+/* entry == UINT64_MAX: start from boot rom if exists, otherwise from elf entry */
+int terminus_reset(terminus_t *t, uint64_t entry);
+
+/* step one instruction of hart, commit can be NULL.
+   mtime follows the slowest hart, so step all harts to keep timer interrupts going */
+int terminus_step(terminus_t *t, uint32_t hart, terminus_commit_t *commit);
+/* return 1 if the program requested to exit, e.g. by htif */
+int terminus_exited(terminus_t *t);
+
+/* pc of the next instruction */
+int terminus_get_pc(terminus_t *t, uint32_t hart, uint64_t *pc);
+int terminus_get_xreg(terminus_t *t, uint32_t hart, uint32_t id, uint64_t *value);
+int terminus_set_xreg(terminus_t *t, uint32_t hart, uint32_t id, uint64_t value);
+/* csr access without privilege check */
+int terminus_get_csr(terminus_t *t, uint32_t hart, uint32_t id, uint64_t *value);
+int terminus_set_csr(terminus_t *t, uint32_t hart, uint32_t id, uint64_t value);
+/* physical memory access */
+int terminus_read_mem(terminus_t *t, uint64_t addr, void *buf, size_t len);
+int terminus_write_mem(terminus_t *t, uint64_t addr, const void *buf, size_t len);
+/* irq is one of TERMINUS_IRQ_* */
+int terminus_set_irq(terminus_t *t, uint32_t hart, uint32_t irq, int level);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,421 @@
+//C API of terminus, see include/terminus.h.
+//All functions return 0 on success and -1 on failure, terminus_last_error() describes the failure.
+use terminus::processor::ProcessorCfg;
+use terminus::processor::commit::Commit;
+use terminus::system::{System, SystemBuilder};
+use terminus::devices::clint::Clint;
+use terminus_spaceport::memory::region::GHEAP;
+use terminus_spaceport::EXIT_CTRL;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ffi::{CStr, CString};
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+
+pub const TERMINUS_MAX_WRITES: usize = 4;
+pub const TERMINUS_MAX_TRAPS: usize = 4;
+
+#[repr(C)]
+pub struct TerminusCfg {
+    name: *const c_char,
+    elf: *const c_char,
+    num_harts: u32,
+    xlen: u32,
+    extensions: *const c_char,
+    freq: u64,
+    timer_freq: u64,
+    mem_base: u64,
+    mem_size: u64,
+    clint_base: u64,
+    boot_rom_base: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct TerminusRegWrite {
+    id: u32,
+    value: u64,
+    value_hi: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct TerminusMemWrite {
+    addr: u64,
+    pa: u64,
+    len: u32,
+    data: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct TerminusTrap {
+    interrupt: u8,
+    privilege: u8,
+    code: u64,
+    tval: u64,
+    epc: u64,
+}
+
+#[repr(C)]
+pub struct TerminusCommit {
+    pc: u64,
+    insn: u32,
+    retired: u8,
+    privilege: u8,
+    xreg_num: u32,
+    xreg: [TerminusRegWrite; TERMINUS_MAX_WRITES],
+    freg_num: u32,
+    freg: [TerminusRegWrite; TERMINUS_MAX_WRITES],
+    mem_num: u32,
+    mem: [TerminusMemWrite; TERMINUS_MAX_WRITES],
+    trap_num: u32,
+    trap: [TerminusTrap; TERMINUS_MAX_TRAPS],
+}
+
+impl TerminusCommit {
+    fn fill(&mut self, commit: &Commit, privilege: u8) {
+        self.pc = commit.pc;
+        self.insn = commit.ir;
+        self.retired = commit.retired as u8;
+        self.privilege = privilege;
+        self.xreg_num = commit.xreg_writes.len() as u32;
+        for (i, (id, value)) in commit.xreg_writes.iter().take(TERMINUS_MAX_WRITES).enumerate() {
+            self.xreg[i] = TerminusRegWrite { id: *id, value: *value, value_hi: 0 }
+        }
+        self.freg_num = commit.freg_writes.len() as u32;
+        for (i, (id, value)) in commit.freg_writes.iter().take(TERMINUS_MAX_WRITES).enumerate() {
+            self.freg[i] = TerminusRegWrite { id: *id, value: *value as u64, value_hi: (*value >> 64) as u64 }
+        }
+        self.mem_num = commit.mem_writes.len() as u32;
+        for (i, w) in commit.mem_writes.iter().take(TERMINUS_MAX_WRITES).enumerate() {
+            self.mem[i] = TerminusMemWrite { addr: w.addr, pa: w.pa, len: w.len as u32, data: w.data }
+        }
+        self.trap_num = commit.traps.len() as u32;
+        for (i, t) in commit.traps.iter().take(TERMINUS_MAX_TRAPS).enumerate() {
+            self.trap[i] = TerminusTrap {
+                interrupt: t.interrupt as u8,
+                privilege: t.privilege.into(),
+                code: t.code,
+                tval: t.tval,
+                epc: t.epc,
+            }
+        }
+    }
+}
+
+pub struct Terminus {
+    sys: System,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(msg: String) -> c_int {
+    LAST_ERROR.with(|e| { *e.borrow_mut() = CString::new(msg).unwrap_or_default() });
+    -1
+}
+
+fn to_ret<T>(r: Result<T, String>) -> c_int {
+    match r {
+        Ok(_) => 0,
+        Err(e) => set_error(e)
+    }
+}
+
+//a panic must not unwind into c, it is reported as a failure instead
+fn guard<T, F: FnOnce() -> T>(failed: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|e| {
+        let msg = e.downcast_ref::<&str>().map(|s| { s.to_string() })
+            .or_else(|| { e.downcast_ref::<String>().cloned() })
+            .unwrap_or_else(|| { "unknown".to_string() });
+        set_error(format!("panic: {}", msg));
+        failed
+    })
+}
+
+unsafe fn to_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        Err(format!("{} is NULL!", what))
+    } else {
+        CStr::from_ptr(s).to_str().map_err(|e| { format!("{}: {}", what, e) })
+    }
+}
+
+fn build(cfg: &TerminusCfg) -> Result<System, String> {
+    let name = unsafe { to_str(cfg.name, "name") }?;
+    let extensions = unsafe { to_str(cfg.extensions, "extensions") }?;
+    if cfg.xlen != 32 && cfg.xlen != 64 {
+        return Err(format!("invalid xlen {}!", cfg.xlen));
+    }
+    if cfg.num_harts == 0 {
+        return Err("num_harts must be greater than 0!".to_string());
+    }
+    let config = ProcessorCfg::from_isa(&format!("rv{}{}", cfg.xlen, extensions), cfg.freq as usize)?;
+    let configs = vec![config; cfg.num_harts as usize];
+    let mut builder = SystemBuilder::new(name).timer_freq(cfg.timer_freq as usize).processors(configs);
+    if !cfg.elf.is_null() {
+        builder = builder.elf_file(unsafe { to_str(cfg.elf, "elf") }?)
+    }
+    let mut sys = builder.build().map_err(|e| { format!("{:?}", e) })?;
+    let mem = GHEAP.alloc(cfg.mem_size, 1).map_err(|e| { format!("main_memory alloc fail! {:?}", e) })?;
+    sys.register_memory("main_memory", cfg.mem_base, &mem).map_err(|e| { format!("{:?}", e) })?;
+    if cfg.clint_base != 0 {
+        sys.register_device("clint", cfg.clint_base, 0x000c0000, Clint::new(sys.timer())).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if cfg.boot_rom_base != 0 {
+        sys.make_boot_rom(cfg.boot_rom_base, None).map_err(|e| { format!("{:?}", e) })?;
+    }
+    Ok(sys)
+}
+
+unsafe fn terminus<'a>(t: *mut Terminus) -> Result<&'a mut Terminus, String> {
+    t.as_mut().ok_or("terminus_t is NULL!".to_string())
+}
+
+#[no_mangle]
+pub extern "C" fn terminus_last_error() -> *const c_char {
+    guard(std::ptr::null(), || {
+        LAST_ERROR.with(|e| { e.borrow().as_ptr() })
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_new(cfg: *const TerminusCfg) -> *mut Terminus {
+    guard(std::ptr::null_mut(), || {
+        let cfg = if let Some(cfg) = cfg.as_ref() {
+            cfg
+        } else {
+            set_error("cfg is NULL!".to_string());
+            return std::ptr::null_mut();
+        };
+        EXIT_CTRL.reset();
+        match build(cfg) {
+            Ok(sys) => Box::into_raw(Box::new(Terminus { sys })),
+            Err(e) => {
+                set_error(e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_free(t: *mut Terminus) {
+    guard((), || {
+        if !t.is_null() {
+            drop(Box::from_raw(t))
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_load_elf(t: *mut Terminus) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| { t.sys.load_elf().map_err(|e| { format!("{:?}", e) }) }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_load_raw(t: *mut Terminus, file: *const c_char, addr: u64) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let file = to_str(file, "file")?;
+            t.sys.load_raw(file, addr).map_err(|e| { format!("{:?}", e) })
+        }))
+    })
+}
+
+//entry == UINT64_MAX: start from boot rom if exists, otherwise from elf entry
+#[no_mangle]
+pub unsafe extern "C" fn terminus_reset(t: *mut Terminus, entry: u64) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let num = t.sys.processors().len();
+            let entry = if entry == u64::MAX { None } else { Some(entry) };
+            t.sys.reset(vec![entry; num]).map_err(|e| { format!("{:?}", e) })
+        }))
+    })
+}
+
+//step one instruction of hart, commit can be NULL.
+//mtime follows the slowest hart, so step all harts to keep timer interrupts going
+#[no_mangle]
+pub unsafe extern "C" fn terminus_step(t: *mut Terminus, hart: u32, commit: *mut TerminusCommit) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let p = t.sys.processor(hart as usize).ok_or(format!("invalid hart {}!", hart))?;
+            let c = p.step_commit();
+            if let Some(commit) = commit.as_mut() {
+                commit.fill(&c, (*p.state().privilege()).into())
+            }
+            //timer, htif proxy and reboot requests
+            t.sys.sync();
+            Ok(())
+        }))
+    })
+}
+
+//return 1 if the program requested to exit, e.g. by htif
+#[no_mangle]
+pub unsafe extern "C" fn terminus_exited(_: *mut Terminus) -> c_int {
+    guard(-1, || {
+        EXIT_CTRL.poll().is_ok() as c_int
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_get_pc(t: *mut Terminus, hart: u32, pc: *mut u64) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let p = t.sys.processor(hart as usize).ok_or(format!("invalid hart {}!", hart))?;
+            *pc.as_mut().ok_or("pc is NULL!".to_string())? = *p.state().next_pc();
+            Ok(())
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_get_xreg(t: *mut Terminus, hart: u32, id: u32, value: *mut u64) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let p = t.sys.processor(hart as usize).ok_or(format!("invalid hart {}!", hart))?;
+            *value.as_mut().ok_or("value is NULL!".to_string())? = *p.state().xreg(id);
+            Ok(())
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_set_xreg(t: *mut Terminus, hart: u32, id: u32, value: u64) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let p = t.sys.processor(hart as usize).ok_or(format!("invalid hart {}!", hart))?;
+            p.state_mut().set_xreg(id, value);
+            Ok(())
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_get_csr(t: *mut Terminus, hart: u32, id: u32, value: *mut u64) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let p = t.sys.processor(hart as usize).ok_or(format!("invalid hart {}!", hart))?;
+            let v = p.state().csr_backdoor(id).ok_or(format!("csr {:#x} does not exist!", id))?;
+            *value.as_mut().ok_or("value is NULL!".to_string())? = v;
+            Ok(())
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_set_csr(t: *mut Terminus, hart: u32, id: u32, value: u64) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let p = t.sys.processor(hart as usize).ok_or(format!("invalid hart {}!", hart))?;
+            p.state().set_csr_backdoor(id, value).ok_or(format!("csr {:#x} does not exist!", id))
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_read_mem(t: *mut Terminus, addr: u64, buf: *mut c_void, len: usize) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            if buf.is_null() {
+                return Err("buf is NULL!".to_string());
+            }
+            let data = std::slice::from_raw_parts_mut(buf as *mut u8, len);
+            t.sys.read_mem(addr, data).map_err(|e| { format!("{:?}", e) })
+        }))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn terminus_write_mem(t: *mut Terminus, addr: u64, buf: *const c_void, len: usize) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            if buf.is_null() {
+                return Err("buf is NULL!".to_string());
+            }
+            let data = std::slice::from_raw_parts(buf as *const u8, len);
+            //flush stale decoded instructions of modified memory
+            for p in t.sys.processors().iter() {
+                p.fetcher().flush_icache()
+            }
+            t.sys.write_mem(addr, data).map_err(|e| { format!("{:?}", e) })
+        }))
+    })
+}
+
+//irq is the bit position in mip: 3(msip), 7(mtip), 9(seip), 11(meip)
+#[no_mangle]
+pub unsafe extern "C" fn terminus_set_irq(t: *mut Terminus, hart: u32, irq: u32, level: c_int) -> c_int {
+    guard(-1, || {
+        to_ret(terminus(t).and_then(|t| {
+            let p = t.sys.processor(hart as usize).ok_or(format!("invalid hart {}!", hart))?;
+            p.state().set_irq(irq as usize, level != 0)
+        }))
+    })
+}
+
+//number of harts, 0 if t is NULL
+#[no_mangle]
+pub unsafe extern "C" fn terminus_num_harts(t: *mut Terminus) -> u32 {
+    guard(0, || {
+        terminus(t).map_or(0, |t| { t.sys.processors().len() as u32 })
+    })
+}
+
+#[test]
+fn capi_timer_test() {
+    const MSTATUS: u32 = 0x300;
+    const MIE: u32 = 0x304;
+    const MTVEC: u32 = 0x305;
+    let name = CString::new("capi_timer_test").unwrap();
+    let extensions = CString::new("imac").unwrap();
+    let cfg = TerminusCfg {
+        name: name.as_ptr(),
+        elf: std::ptr::null(),
+        num_harts: 1,
+        xlen: 64,
+        extensions: extensions.as_ptr(),
+        freq: 1000000000,
+        timer_freq: 10000000,
+        mem_base: 0x80000000,
+        mem_size: 0x1000,
+        clint_base: 0x02000000,
+        boot_rom_base: 0,
+    };
+    unsafe {
+        let t = terminus_new(&cfg);
+        assert!(!t.is_null());
+        //"j ." at entry and trap handler
+        let j = 0x6fu32.to_le_bytes();
+        assert_eq!(terminus_write_mem(t, 0x80000000, j.as_ptr() as *const c_void, 4), 0);
+        assert_eq!(terminus_write_mem(t, 0x80000100, j.as_ptr() as *const c_void, 4), 0);
+        assert_eq!(terminus_reset(t, 0x80000000), 0);
+        assert_eq!(terminus_set_csr(t, 0, MTVEC, 0x80000100), 0);
+        assert_eq!(terminus_set_csr(t, 0, MIE, 1 << 7), 0);
+        assert_eq!(terminus_set_csr(t, 0, MSTATUS, 1 << 3), 0);
+        //10 ticks are 1000 cycles
+        (*t).sys.timer().set_mtimecmp(0, 10);
+        let mut commit: TerminusCommit = std::mem::zeroed();
+        let mut steps = 0;
+        while commit.trap_num == 0 && steps < 2000 {
+            assert_eq!(terminus_step(t, 0, &mut commit), 0);
+            steps += 1;
+        }
+        assert!(steps >= 1000 && steps < 2000);
+        assert_eq!((commit.trap[0].interrupt, commit.trap[0].code), (1, 7));
+        assert_eq!((*t).sys.timer().time(), 10);
+        let mut pc = 0;
+        assert_eq!(terminus_get_pc(t, 0, &mut pc), 0);
+        assert_eq!(pc, 0x80000100);
+        terminus_free(t)
+    }
+    assert_eq!(guard(-1, || -> c_int { panic!("boom") }), -1);
+    let msg = unsafe { CStr::from_ptr(terminus_last_error()) };
+    assert_eq!(msg.to_str().unwrap(), "panic: boom");
+}
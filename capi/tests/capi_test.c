@@ -0,0 +1,112 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "terminus.h"
+
+#define CHECK(x) do { \
+    if ((x) != 0) { \
+        fprintf(stderr, "%s:%d: %s fail: %s\n", __FILE__, __LINE__, #x, terminus_last_error()); \
+        exit(1); \
+    } \
+} while (0)
+
+#define ASSERT(x) do { \
+    if (!(x)) { \
+        fprintf(stderr, "%s:%d: assert %s fail\n", __FILE__, __LINE__, #x); \
+        exit(1); \
+    } \
+} while (0)
+
+#define MSCRATCH 0x340
+#define MIP 0x344
+
+int main(int argc, char **argv) {
+    const char *elf = argc > 1 ? argv[1] : "../top_tests/elf/rv64ui-p-sd";
+    terminus_cfg_t cfg = {
+        .name = "capi_test",
+        .elf = elf,
+        .num_harts = 1,
+        .xlen = 64,
+        .extensions = "imafdcsu",
+        .freq = 1000000000,
+        .timer_freq = 10000000,
+        .mem_base = 0x80000000,
+        .mem_size = 0x10000000,
+        .clint_base = 0x02000000,
+        .boot_rom_base = 0,
+    };
+    terminus_t *t = terminus_new(&cfg);
+    if (!t) {
+        fprintf(stderr, "terminus_new fail: %s\n", terminus_last_error());
+        return 1;
+    }
+    ASSERT(terminus_num_harts(t) == 1);
+    ASSERT(terminus_step(t, 1, NULL) != 0);
+    CHECK(terminus_load_elf(t));
+    CHECK(terminus_reset(t, UINT64_MAX));
+
+    /* csr and memory overrides */
+    uint64_t v;
+    CHECK(terminus_set_csr(t, 0, MSCRATCH, 0x5a5a));
+    CHECK(terminus_get_csr(t, 0, MSCRATCH, &v));
+    ASSERT(v == 0x5a5a);
+    uint32_t word = 0xdeadbeef, read = 0;
+    CHECK(terminus_read_mem(t, 0x8ffffff0, &v, sizeof(v)));
+    CHECK(terminus_write_mem(t, 0x8ffffff0, &word, sizeof(word)));
+    CHECK(terminus_read_mem(t, 0x8ffffff0, &read, sizeof(read)));
+    ASSERT(read == word);
+    ASSERT(terminus_read_mem(t, 0x10, &read, sizeof(read)) != 0);
+
+    /* interrupt injection */
+    CHECK(terminus_set_irq(t, 0, TERMINUS_IRQ_MEIP, 1));
+    CHECK(terminus_get_csr(t, 0, MIP, &v));
+    ASSERT((v >> TERMINUS_IRQ_MEIP) & 1);
+    CHECK(terminus_set_irq(t, 0, TERMINUS_IRQ_MEIP, 0));
+    CHECK(terminus_get_csr(t, 0, MIP, &v));
+    ASSERT(!((v >> TERMINUS_IRQ_MEIP) & 1));
+    ASSERT(terminus_set_irq(t, 0, 1, 1) != 0);
+
+    uint64_t insns = 0, xreg_writes = 0, mem_writes = 0, ecalls = 0;
+    int exited = 0;
+    terminus_commit_t commit;
+    while (insns < 100000) {
+        if (terminus_exited(t)) {
+            exited = 1;
+            break;
+        }
+        uint64_t pc;
+        CHECK(terminus_get_pc(t, 0, &pc));
+        CHECK(terminus_step(t, 0, &commit));
+        insns++;
+        ASSERT(commit.pc == pc);
+        for (uint32_t i = 0; i < commit.xreg_num && i < TERMINUS_MAX_WRITES; i++) {
+            CHECK(terminus_get_xreg(t, 0, commit.xreg[i].id, &v));
+            ASSERT(commit.xreg[i].id != 0);
+            xreg_writes++;
+        }
+        for (uint32_t i = 0; i < commit.mem_num && i < TERMINUS_MAX_WRITES; i++) {
+            ASSERT(commit.mem[i].len == 1 || commit.mem[i].len == 2 || commit.mem[i].len == 4 || commit.mem[i].len == 8);
+            mem_writes++;
+        }
+        for (uint32_t i = 0; i < commit.trap_num && i < TERMINUS_MAX_TRAPS; i++) {
+            /* ecall from m-mode */
+            if (!commit.trap[i].interrupt && commit.trap[i].code == 11) {
+                ASSERT(commit.trap[i].epc == commit.pc);
+                ASSERT(commit.trap[i].privilege == TERMINUS_PRIV_M);
+                ecalls++;
+            }
+        }
+    }
+    ASSERT(exited);
+    /* riscv-tests set gp to 1 before ecall when pass */
+    CHECK(terminus_get_xreg(t, 0, 3, &v));
+    printf("%s: insns = %lu; xreg_writes = %lu; mem_writes = %lu; ecalls = %lu; gp = %lu\n",
+           elf, (unsigned long) insns, (unsigned long) xreg_writes, (unsigned long) mem_writes, (unsigned long) ecalls, (unsigned long) v);
+    ASSERT(v == 1);
+    ASSERT(xreg_writes > 0);
+    ASSERT(mem_writes > 0);
+    ASSERT(ecalls > 0);
+    terminus_free(t);
+    printf("capi_test pass!\n");
+    return 0;
+}
@@ -1,46 +1,28 @@
 use terminus::processor::ProcessorCfg;
-use terminus::system::System;
+use terminus::system::SystemBuilder;
 use std::path::Path;
 use terminus::devices::clint::Clint;
 use terminus_spaceport::devices::term_exit;
 use terminus_spaceport::EXIT_CTRL;
 use terminus_spaceport::memory::region::GHEAP;
-use terminus_global::XLen;
 use std::ops::Deref;
 
 fn main() {
     let num_cores = 1;
-    let configs = vec![ProcessorCfg {
-        xlen: XLen::X64,
-        enable_dirty: true,
-        extensions: vec!['m', 'f', 'd', 's', 'u', 'c', 'a'].into_boxed_slice(),
-        freq: 1000000000,
-    }; num_cores];
-    let mut sys = System::new("sys", Path::new("examples/linux/image/br-base-bin-nodisk").to_str().expect("image not found!"), configs, 10000000);
+    let configs = vec![ProcessorCfg::from_isa("rv64imafdcsu", 1000000000).unwrap(); num_cores];
+    let mut sys = SystemBuilder::new("sys").processors(configs).elf_file(Path::new("examples/linux/image/br-base-bin-nodisk").to_str().expect("image not found!")).build().unwrap();
     sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x80000000, 1).expect("main_memory alloc fail!")).unwrap();
     sys.register_device("clint", 0x02000000, 0x000c0000, Clint::new(sys.timer())).unwrap();
-    sys.make_boot_rom(0x20000000, -1i64 as u64).unwrap();
+    sys.make_boot_rom(0x20000000, None).unwrap();
     sys.load_elf().unwrap();
-    sys.reset(vec![-1i64 as u64; num_cores]).unwrap();
-    let interval: u64 = 100;
-    //let mut interval_cnt: u64 = 0;
-    'outer:loop {
+    sys.reset(vec![None; num_cores]).unwrap();
+    let interval: usize = 100;
+    loop {
         if let Ok(msg) = EXIT_CTRL.poll() {
             eprintln!("{}", msg);
             break;
         }
-        for p in sys.processors() {
-            p.step(interval as usize);
-            //eprintln!("{}", p.state().trace());
-            // if p.state().pc() == 0xffffffe0015a8cb4{
-            //     break 'outer;
-            // }
-        }
-        sys.timer().tick(1)
-        // interval_cnt += 1;
-        // if interval_cnt % interval == interval - 1 {
-        //     sys.timer().tick(interval)
-        // }
+        sys.step(interval);
     }
     eprintln!("{}", sys.processor(0).unwrap().state().to_string());
     term_exit();
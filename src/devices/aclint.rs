@@ -0,0 +1,299 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_spaceport::irq::IrqVec;
+use terminus_macros::*;
+use std::sync::Arc;
+use crate::devices::clint::Timer;
+use crate::devices::device::{Device, StateReader};
+use crate::system::fdt::{FdtNode, FdtProp};
+
+//RISC-V ACLINT devices, registers of hart i are at i * 4 (msip, setssip) or i * 8 (mtimecmp).
+//msip, mtimecmp and mtime are kept in Timer as Clint does, so mswi at base and mtimer at base + MSWI_SIZE
+//have the same layout as a clint at base.
+pub const MSWI_SIZE: u64 = 0x4000;
+pub const MTIMER_SIZE: u64 = 0x8000;
+pub const SSWI_SIZE: u64 = 0x4000;
+const MTIME: u64 = 0x7ff8;
+
+fn bytes_to_u32(data: &[u8]) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(data);
+    u32::from_le_bytes(bytes)
+}
+
+fn bytes_to_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(data);
+    u64::from_le_bytes(bytes)
+}
+
+fn node(name: &str, compatible: &str, reg: Vec<u64>, harts: &[u32], mip: u32) -> FdtNode {
+    let mut node = FdtNode::new(name);
+    node.add_prop(FdtProp::str_prop("compatible", vec![compatible]));
+    node.add_prop(FdtProp::u64_prop("reg", reg));
+    node.add_prop(FdtProp::u32_prop("interrupts-extended", harts.iter().flat_map(|h| { vec![*h, mip] }).collect()));
+    node
+}
+
+//machine-level software interrupt device, msip of hart i at i * 4
+#[derive_io(Bytes, U32)]
+pub struct Mswi(Arc<Timer>);
+
+impl Mswi {
+    pub fn new(timer: &Arc<Timer>) -> Mswi {
+        Mswi(timer.clone())
+    }
+
+    fn hart(&self, addr: &u64) -> Option<usize> {
+        let hartid = (*addr >> 2) as usize;
+        if *addr & 0x3 == 0 && hartid < self.0.num_harts() {
+            Some(hartid)
+        } else {
+            None
+        }
+    }
+}
+
+impl Clone for Mswi {
+    fn clone(&self) -> Mswi {
+        Mswi(self.0.clone())
+    }
+}
+
+impl Device for Mswi {
+    fn reset(&self) {
+        for hartid in 0..self.0.num_harts() {
+            self.0.set_msip(hartid, false)
+        }
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, harts: &[u32]) -> Option<FdtNode> {
+        Some(node(&format!("mswi@{:x}", base), "riscv,aclint-mswi", vec![base, size], harts, 3))
+    }
+
+    fn save(&self) -> Vec<u8> {
+        (0..self.0.num_harts()).flat_map(|i| { (self.0.msip(i) as u32).to_le_bytes().to_vec() }).collect()
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data);
+        for hartid in 0..self.0.num_harts() {
+            self.0.set_msip(hartid, reader.u32()? != 0)
+        }
+        Ok(())
+    }
+}
+
+impl BytesAccess for Mswi {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            U32Access::write(self, addr, bytes_to_u32(data))
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        if data.len() == 4 {
+            data.copy_from_slice(&U32Access::read(self, addr).to_le_bytes())
+        }
+    }
+}
+
+impl U32Access for Mswi {
+    fn write(&self, addr: &u64, data: u32) {
+        if let Some(hartid) = self.hart(addr) {
+            self.0.set_msip(hartid, data & 1 == 1)
+        }
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        self.hart(addr).map_or(0, |hartid| { self.0.msip(hartid) as u32 })
+    }
+}
+
+//machine-level timer device, mtimecmp of hart i at i * 8 and mtime at 0x7ff8
+#[derive_io(Bytes, U32, U64)]
+pub struct Mtimer(Arc<Timer>);
+
+impl Mtimer {
+    pub fn new(timer: &Arc<Timer>) -> Mtimer {
+        Mtimer(timer.clone())
+    }
+
+    fn hart(&self, addr: &u64) -> Option<usize> {
+        let hartid = (*addr >> 3) as usize;
+        if *addr < MTIME && hartid < self.0.num_harts() {
+            Some(hartid)
+        } else {
+            None
+        }
+    }
+}
+
+impl Clone for Mtimer {
+    fn clone(&self) -> Mtimer {
+        Mtimer(self.0.clone())
+    }
+}
+
+impl Device for Mtimer {
+    //"reg" is mtime followed by mtimecmps
+    fn fdt_node(&self, base: u64, _size: u64, harts: &[u32]) -> Option<FdtNode> {
+        Some(node(&format!("mtimer@{:x}", base), "riscv,aclint-mtimer", vec![base + MTIME, 8, base, MTIME], harts, 7))
+    }
+
+    //mtime, then mtimecmps
+    fn save(&self) -> Vec<u8> {
+        let mut values = vec![self.0.time()];
+        values.extend((0..self.0.num_harts()).map(|i| { self.0.mtimecmp(i) }));
+        values.iter().flat_map(|v| { v.to_le_bytes().to_vec() }).collect()
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data);
+        self.0.set_time(bytes_to_u64(reader.bytes(8)?));
+        for hartid in 0..self.0.num_harts() {
+            self.0.set_mtimecmp(hartid, bytes_to_u64(reader.bytes(8)?))
+        }
+        Ok(())
+    }
+}
+
+impl BytesAccess for Mtimer {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            U32Access::write(self, addr, bytes_to_u32(data))
+        } else if data.len() == 8 {
+            U64Access::write(self, addr, bytes_to_u64(data))
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        if data.len() == 4 {
+            data.copy_from_slice(&U32Access::read(self, addr).to_le_bytes())
+        } else if data.len() == 8 {
+            data.copy_from_slice(&U64Access::read(self, addr).to_le_bytes())
+        }
+    }
+}
+
+//32 bits access to either half of the 64 bits registers
+impl U32Access for Mtimer {
+    fn write(&self, addr: &u64, data: u32) {
+        if *addr & 0x3 != 0 {
+            return;
+        }
+        let aligned = *addr & !0x7;
+        let old = U64Access::read(self, &aligned);
+        let value = if *addr & 0x4 == 0 {
+            (old & !0xffff_ffff) | data as u64
+        } else {
+            (old & 0xffff_ffff) | ((data as u64) << 32)
+        };
+        U64Access::write(self, &aligned, value)
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        if *addr & 0x3 != 0 {
+            return 0;
+        }
+        (U64Access::read(self, &(*addr & !0x7)) >> ((*addr & 0x4) << 3)) as u32
+    }
+}
+
+impl U64Access for Mtimer {
+    fn write(&self, addr: &u64, data: u64) {
+        if *addr == MTIME {
+            self.0.set_time(data)
+        } else if let Some(hartid) = self.hart(addr) {
+            self.0.set_mtimecmp(hartid, data)
+        }
+    }
+
+    fn read(&self, addr: &u64) -> u64 {
+        if *addr == MTIME {
+            self.0.time()
+        } else {
+            self.hart(addr).map_or(0, |hartid| { self.0.mtimecmp(hartid) })
+        }
+    }
+}
+
+//supervisor-level software interrupt device, writing 1 to setssip of hart i at i * 4 sets its mip.SSIP,
+//which is cleared by software through sip. setssip reads 0
+#[derive_io(Bytes, U32)]
+pub struct Sswi(Arc<Vec<Arc<IrqVec>>>);
+
+impl Sswi {
+    //eirqs of harts indexed by hartid
+    pub fn new(eirqs: Vec<Arc<IrqVec>>) -> Sswi {
+        Sswi(Arc::new(eirqs))
+    }
+}
+
+impl Clone for Sswi {
+    fn clone(&self) -> Sswi {
+        Sswi(self.0.clone())
+    }
+}
+
+impl Device for Sswi {
+    fn fdt_node(&self, base: u64, size: u64, harts: &[u32]) -> Option<FdtNode> {
+        Some(node(&format!("sswi@{:x}", base), "riscv,aclint-sswi", vec![base, size], harts, 1))
+    }
+}
+
+impl BytesAccess for Sswi {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            U32Access::write(self, addr, bytes_to_u32(data))
+        }
+    }
+
+    fn read(&self, _: &u64, data: &mut [u8]) {
+        data.iter_mut().for_each(|v| { *v = 0 })
+    }
+}
+
+impl U32Access for Sswi {
+    fn write(&self, addr: &u64, data: u32) {
+        if *addr & 0x3 != 0 || data & 1 == 0 {
+            return;
+        }
+        //eirq line 3 is latched into mip.SSIP by the hart
+        if let Some(eirq) = self.0.get((*addr >> 2) as usize) {
+            eirq.set_pending(3).unwrap()
+        }
+    }
+
+    fn read(&self, _: &u64) -> u32 {
+        0
+    }
+}
+
+#[test]
+fn aclint_test() {
+    let timer = Arc::new(Timer::new(100));
+    let irq_vecs = vec![timer.alloc_irq(), timer.alloc_irq()];
+    let mswi = Mswi::new(&timer);
+    U32Access::write(&mswi, &4, 1);
+    assert!(!irq_vecs[0].pending(0).unwrap());
+    assert!(irq_vecs[1].pending(0).unwrap());
+    mswi.reset();
+    assert_eq!(U32Access::read(&mswi, &4), 0);
+
+    let mtimer = Mtimer::new(&timer);
+    U64Access::write(&mtimer, &8, 10);
+    assert!(irq_vecs[0].pending(1).unwrap());
+    assert!(!irq_vecs[1].pending(1).unwrap());
+    timer.tick(10);
+    assert!(irq_vecs[1].pending(1).unwrap());
+    U32Access::write(&mtimer, &0xc, 1);
+    assert_eq!(U64Access::read(&mtimer, &8), 0x1_0000_000a);
+    assert_eq!(U32Access::read(&mtimer, &MTIME), 10);
+
+    let eirq = Arc::new(IrqVec::new(4));
+    eirq.set_enable(3).unwrap();
+    let sswi = Sswi::new(vec![eirq.clone()]);
+    U32Access::write(&sswi, &0, 1);
+    assert!(eirq.pending(3).unwrap());
+    assert_eq!(U32Access::read(&sswi, &0), 0);
+}
@@ -0,0 +1,507 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_spaceport::irq::IrqVec;
+use terminus_macros::*;
+use std::sync::{Arc, Mutex};
+use crate::processor::Privilege;
+use crate::devices::device::{Device, IrqSink, StateReader};
+use crate::devices::imsic::Imsic;
+use crate::system::fdt::{FdtNode, FdtProp};
+
+const DOMAINCFG: u64 = 0x0000;
+const SOURCECFG: u64 = 0x0004;
+const MSIADDRCFG: u64 = 0x1bc0;
+const MSIADDRCFG_END: u64 = 0x1bd0;
+const SETIP: u64 = 0x1c00;
+const SETIPNUM: u64 = 0x1cdc;
+const IN_CLRIP: u64 = 0x1d00;
+const CLRIPNUM: u64 = 0x1ddc;
+const SETIE: u64 = 0x1e00;
+const SETIENUM: u64 = 0x1edc;
+const CLRIE: u64 = 0x1f00;
+const CLRIENUM: u64 = 0x1fdc;
+const SETIPNUM_LE: u64 = 0x2000;
+const SETIPNUM_BE: u64 = 0x2004;
+const GENMSI: u64 = 0x3000;
+const TARGET: u64 = 0x3004;
+const IDC: u64 = 0x4000;
+const IDC_SIZE: u64 = 0x20;
+const IDELIVERY: u64 = 0x00;
+const IFORCE: u64 = 0x04;
+const ITHRESHOLD: u64 = 0x08;
+const TOPI: u64 = 0x18;
+const CLAIMI: u64 = 0x1c;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+//bit 31 reads 1
+const DOMAINCFG_FIXED: u32 = 0x8000_0000;
+
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE1: u32 = 4;
+const SM_EDGE0: u32 = 5;
+const SM_LEVEL1: u32 = 6;
+const SM_LEVEL0: u32 = 7;
+
+//"interrupts" cell of level high sources
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+#[derive(Default, Clone)]
+struct Source {
+    mode: u32,
+    target: u32,
+    input: bool,
+    pending: bool,
+    enabled: bool,
+}
+
+impl Source {
+    fn active(&self) -> bool {
+        self.mode != SM_INACTIVE
+    }
+
+    fn level(&self) -> bool {
+        self.mode == SM_LEVEL1 || self.mode == SM_LEVEL0
+    }
+
+    fn rectified(&self) -> bool {
+        match self.mode {
+            SM_EDGE1 | SM_LEVEL1 => self.input,
+            SM_EDGE0 | SM_LEVEL0 => !self.input,
+            _ => false
+        }
+    }
+
+    fn hart(&self) -> usize {
+        (self.target >> 18) as usize
+    }
+
+    fn prio(&self) -> u32 {
+        self.target & 0xff
+    }
+
+    fn eiid(&self) -> u32 {
+        self.target & 0x7ff
+    }
+}
+
+#[derive(Default, Clone)]
+struct Idc {
+    idelivery: bool,
+    iforce: bool,
+    ithreshold: u32,
+}
+
+struct AplicInner {
+    domaincfg: u32,
+    //accepted but not used, msis are delivered to interrupt files of Imsic directly
+    msiaddrcfg: [u32; 4],
+    genmsi: u32,
+    //source 0 does not exist
+    sources: Vec<Source>,
+    idcs: Vec<Idc>,
+    eirqs: Vec<Arc<IrqVec>>,
+    line: usize,
+    msi: Option<Imsic>,
+}
+
+impl AplicInner {
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+
+    fn set_pending(&mut self, id: usize, pending: bool) {
+        let msi_mode = self.msi_mode();
+        if let Some(s) = self.sources.get_mut(id) {
+            //level sources follow the input in direct mode, and can only be set while asserted in msi mode
+            match s.mode {
+                SM_DETACHED | SM_EDGE1 | SM_EDGE0 => s.pending = pending,
+                SM_LEVEL1 | SM_LEVEL0 if msi_mode => s.pending = pending && s.rectified(),
+                _ => {}
+            }
+        }
+    }
+
+    fn set_input(&mut self, id: usize, level: bool) {
+        let msi_mode = self.msi_mode();
+        if let Some(s) = self.sources.get_mut(id) {
+            let old = s.rectified();
+            s.input = level;
+            let new = s.rectified();
+            match s.mode {
+                SM_EDGE1 | SM_EDGE0 => s.pending |= !old && new,
+                SM_LEVEL1 | SM_LEVEL0 if msi_mode => s.pending = new && (s.pending || !old),
+                SM_LEVEL1 | SM_LEVEL0 => s.pending = new,
+                _ => {}
+            }
+        }
+    }
+
+    fn set_sourcecfg(&mut self, id: usize, data: u32) {
+        let msi_mode = self.msi_mode();
+        if let Some(s) = self.sources.get_mut(id) {
+            //delegation is not supported, reserved modes are inactive
+            s.mode = match data & 0x7 {
+                SM_DETACHED | SM_EDGE1 | SM_EDGE0 | SM_LEVEL1 | SM_LEVEL0 if data & 0x400 == 0 => data & 0x7,
+                _ => SM_INACTIVE
+            };
+            if !s.active() {
+                *s = Source { input: s.input, ..Source::default() }
+            } else if s.level() && !msi_mode {
+                s.pending = s.rectified()
+            }
+        }
+    }
+
+    fn set_target(&mut self, id: usize, data: u32) {
+        let msi_mode = self.msi_mode();
+        if let Some(s) = self.sources.get_mut(id).filter(|s| { s.active() }) {
+            s.target = if msi_mode {
+                //guest index is not supported
+                data & 0xfffc_07ff
+            } else if data & 0xff == 0 {
+                (data & 0xfffc_0000) | 1
+            } else {
+                data & 0xfffc_00ff
+            }
+        }
+    }
+
+    //highest priority pending and enabled source of hart in direct mode, (id, priority)
+    fn top(&self, hart: usize) -> Option<(usize, u32)> {
+        if self.domaincfg & DOMAINCFG_IE == 0 || self.msi_mode() {
+            return None;
+        }
+        let threshold = self.idcs[hart].ithreshold;
+        self.sources.iter().enumerate()
+            .filter(|(_, s)| { s.active() && s.pending && s.enabled && s.hart() == hart })
+            .filter(|(_, s)| { threshold == 0 || s.prio() < threshold })
+            .min_by_key(|(id, s)| { (s.prio(), *id) })
+            .map(|(id, s)| { (id, s.prio()) })
+    }
+
+    fn topi(&self, hart: usize) -> u32 {
+        self.top(hart).map_or(0, |(id, prio)| { (id as u32) << 16 | prio })
+    }
+
+    fn claim(&mut self, hart: usize) -> u32 {
+        let topi = self.topi(hart);
+        if let Some((id, _)) = self.top(hart) {
+            let s = &mut self.sources[id];
+            if !s.level() {
+                s.pending = false
+            }
+        } else {
+            self.idcs[hart].iforce = false
+        }
+        topi
+    }
+
+    fn send_msi(&self, hart: usize, eiid: u32) {
+        if let Some(file) = self.msi.as_ref().and_then(|imsic| { imsic.files().get(hart) }) {
+            file.send(eiid)
+        }
+    }
+
+    fn update(&mut self) {
+        if self.msi_mode() {
+            if self.domaincfg & DOMAINCFG_IE != 0 {
+                for id in 1..self.sources.len() {
+                    let s = &self.sources[id];
+                    if s.active() && s.pending && s.enabled {
+                        self.send_msi(s.hart(), s.eiid());
+                        self.sources[id].pending = false
+                    }
+                }
+            }
+            return;
+        }
+        for hart in 0..self.idcs.len() {
+            let idc = &self.idcs[hart];
+            if idc.idelivery && (idc.iforce || self.top(hart).is_some()) {
+                self.eirqs[hart].set_pending(self.line).unwrap()
+            } else {
+                self.eirqs[hart].clr_pending(self.line).unwrap()
+            }
+        }
+    }
+
+    //bit i of word k is source k * 32 + i
+    fn word<F: Fn(&Source) -> bool>(&self, k: usize, f: F) -> u32 {
+        (0..32).filter(|i| { matches!(self.sources.get(k * 32 + i), Some(s) if f(s)) }).fold(0, |w, i| { w | 1 << i })
+    }
+
+    fn read(&mut self, addr: u64) -> u32 {
+        let k = ((addr & 0x7f) >> 2) as usize;
+        match addr {
+            DOMAINCFG => self.domaincfg | DOMAINCFG_FIXED,
+            SOURCECFG..=0xffc => self.sources.get(((addr - SOURCECFG) >> 2) as usize + 1).map_or(0, |s| { s.mode }),
+            MSIADDRCFG..=MSIADDRCFG_END if addr < MSIADDRCFG_END => self.msiaddrcfg[((addr - MSIADDRCFG) >> 2) as usize],
+            SETIP..=0x1c7c => self.word(k, |s| { s.pending }),
+            IN_CLRIP..=0x1d7c => self.word(k, |s| { s.rectified() }),
+            SETIE..=0x1e7c => self.word(k, |s| { s.enabled }),
+            GENMSI => self.genmsi,
+            TARGET..=0x3ffc => self.sources.get(((addr - TARGET) >> 2) as usize + 1).map_or(0, |s| { s.target }),
+            _ if addr >= IDC && ((addr - IDC) / IDC_SIZE) < self.idcs.len() as u64 => {
+                let hart = ((addr - IDC) / IDC_SIZE) as usize;
+                match (addr - IDC) % IDC_SIZE {
+                    IDELIVERY => self.idcs[hart].idelivery as u32,
+                    IFORCE => self.idcs[hart].iforce as u32,
+                    ITHRESHOLD => self.idcs[hart].ithreshold,
+                    TOPI => self.topi(hart),
+                    CLAIMI => {
+                        let topi = self.claim(hart);
+                        self.update();
+                        topi
+                    }
+                    _ => 0
+                }
+            }
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, addr: u64, data: u32) {
+        let k = ((addr & 0x7f) >> 2) as usize;
+        let bits = |data: u32| { (0..32).filter(move |i| { (data >> i) & 1 == 1 }).map(move |i| { k * 32 + i }) };
+        match addr {
+            DOMAINCFG => self.domaincfg = data & (DOMAINCFG_IE | DOMAINCFG_DM),
+            SOURCECFG..=0xffc => self.set_sourcecfg(((addr - SOURCECFG) >> 2) as usize + 1, data),
+            MSIADDRCFG..=MSIADDRCFG_END if addr < MSIADDRCFG_END => self.msiaddrcfg[((addr - MSIADDRCFG) >> 2) as usize] = data,
+            SETIP..=0x1c7c => bits(data).for_each(|id| { self.set_pending(id, true) }),
+            SETIPNUM | SETIPNUM_LE => self.set_pending(data as usize, true),
+            SETIPNUM_BE => self.set_pending(data.swap_bytes() as usize, true),
+            IN_CLRIP..=0x1d7c => bits(data).for_each(|id| { self.set_pending(id, false) }),
+            CLRIPNUM => self.set_pending(data as usize, false),
+            SETIE..=0x1e7c => bits(data).for_each(|id| { self.set_enable(id, true) }),
+            SETIENUM => self.set_enable(data as usize, true),
+            CLRIE..=0x1f7c => bits(data).for_each(|id| { self.set_enable(id, false) }),
+            CLRIENUM => self.set_enable(data as usize, false),
+            GENMSI => {
+                //delivered at once, so busy is never set
+                self.genmsi = data & 0xfffc_07ff;
+                if self.msi_mode() {
+                    self.send_msi((data >> 18) as usize, data & 0x7ff)
+                }
+            }
+            TARGET..=0x3ffc => self.set_target(((addr - TARGET) >> 2) as usize + 1, data),
+            _ if addr >= IDC && ((addr - IDC) / IDC_SIZE) < self.idcs.len() as u64 => {
+                let idc = &mut self.idcs[((addr - IDC) / IDC_SIZE) as usize];
+                match (addr - IDC) % IDC_SIZE {
+                    IDELIVERY => idc.idelivery = data & 1 == 1,
+                    IFORCE => idc.iforce = data & 1 == 1,
+                    ITHRESHOLD => idc.ithreshold = data & 0xff,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        self.update()
+    }
+
+    fn set_enable(&mut self, id: usize, enabled: bool) {
+        if let Some(s) = self.sources.get_mut(id).filter(|s| { s.active() }) {
+            s.enabled = enabled
+        }
+    }
+}
+
+//single interrupt domain of the advanced platform-level interrupt controller, without delegation to child domains.
+//in direct mode sources are delivered to harts by interrupt delivery controllers, the idc of hart i is at 0x4000 + i * 0x20.
+//in msi mode sources are sent to interrupt files of msi, mmsiaddrcfg and smsiaddrcfg are ignored.
+#[derive_io(Bytes, U32)]
+pub struct Aplic {
+    inner: Arc<Mutex<AplicInner>>,
+    level: Privilege,
+    msi_parent: Option<String>,
+}
+
+impl Aplic {
+    //sources are 1..=num_sources, eirqs of harts are indexed by hartid and hart index is hartid.
+    //msi is the name and device of the imsic at the same privilege level
+    pub fn new(num_sources: usize, level: Privilege, eirqs: Vec<Arc<IrqVec>>, msi: Option<(&str, &Imsic)>) -> Aplic {
+        Aplic {
+            inner: Arc::new(Mutex::new(AplicInner {
+                domaincfg: 0,
+                msiaddrcfg: [0; 4],
+                genmsi: 0,
+                sources: vec![Source::default(); num_sources + 1],
+                idcs: vec![Idc::default(); eirqs.len()],
+                eirqs,
+                line: if level == Privilege::M { 0 } else { 1 },
+                msi: msi.map(|(_, imsic)| { imsic.clone() }),
+            })),
+            level,
+            msi_parent: msi.map(|(name, _)| { name.to_string() }),
+        }
+    }
+
+    //idcs follow the 16KB of domain registers
+    pub fn size(&self) -> u64 {
+        let size = IDC + self.inner.lock().unwrap().idcs.len() as u64 * IDC_SIZE;
+        (size + 0xfff) & !0xfff
+    }
+}
+
+impl Clone for Aplic {
+    fn clone(&self) -> Aplic {
+        Aplic {
+            inner: self.inner.clone(),
+            level: self.level,
+            msi_parent: self.msi_parent.clone(),
+        }
+    }
+}
+
+impl IrqSink for Aplic {
+    fn set_irq(&self, id: usize, level: bool) {
+        let mut aplic = self.inner.lock().unwrap();
+        aplic.set_input(id, level);
+        aplic.update()
+    }
+
+    fn irq_cells(&self, id: usize) -> Vec<u32> {
+        vec![id as u32, IRQ_TYPE_LEVEL_HIGH]
+    }
+}
+
+impl Device for Aplic {
+    fn reset(&self) {
+        let mut aplic = self.inner.lock().unwrap();
+        aplic.domaincfg = 0;
+        aplic.msiaddrcfg = [0; 4];
+        aplic.genmsi = 0;
+        aplic.sources.iter_mut().for_each(|s| { *s = Source { input: s.input, ..Source::default() } });
+        aplic.idcs.iter_mut().for_each(|idc| { *idc = Idc::default() });
+        aplic.update()
+    }
+
+    //direct mode is described by interrupts-extended, msi mode by msi-parent
+    fn fdt_node(&self, base: u64, size: u64, harts: &[u32]) -> Option<FdtNode> {
+        let aplic = self.inner.lock().unwrap();
+        let mut node = FdtNode::new(&format!("aplic@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["riscv,aplic"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        node.add_prop(FdtProp::null_prop("interrupt-controller"));
+        node.add_prop(FdtProp::u32_prop("#interrupt-cells", vec![2]));
+        node.add_prop(FdtProp::u32_prop("riscv,num-sources", vec![aplic.sources.len() as u32 - 1]));
+        if self.msi_parent.is_none() {
+            let mip = if self.level == Privilege::M { 11 } else { 9 };
+            node.add_prop(FdtProp::u32_prop("interrupts-extended", harts.iter().flat_map(|h| { vec![*h, mip] }).collect()));
+        }
+        Some(node)
+    }
+
+    fn fdt_refs(&self) -> Vec<(String, String)> {
+        self.msi_parent.iter().map(|imsic| { ("msi-parent".to_string(), imsic.clone()) }).collect()
+    }
+
+    fn irq_sink(&self) -> Option<Arc<dyn IrqSink>> {
+        Some(Arc::new(self.clone()))
+    }
+
+    //domaincfg, msiaddrcfg, genmsi, (mode, target, input, pending, enabled) of sources, then idcs
+    fn save(&self) -> Vec<u8> {
+        let aplic = self.inner.lock().unwrap();
+        let mut words = vec![aplic.domaincfg];
+        words.extend_from_slice(&aplic.msiaddrcfg);
+        words.push(aplic.genmsi);
+        for s in aplic.sources.iter() {
+            words.extend_from_slice(&[s.mode, s.target, s.input as u32, s.pending as u32, s.enabled as u32])
+        }
+        for idc in aplic.idcs.iter() {
+            words.extend_from_slice(&[idc.idelivery as u32, idc.iforce as u32, idc.ithreshold])
+        }
+        words.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect()
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut aplic = self.inner.lock().unwrap();
+        let mut reader = StateReader::new(data);
+        aplic.domaincfg = reader.u32()?;
+        for i in 0..4 {
+            aplic.msiaddrcfg[i] = reader.u32()?
+        }
+        aplic.genmsi = reader.u32()?;
+        for s in aplic.sources.iter_mut() {
+            s.mode = reader.u32()?;
+            s.target = reader.u32()?;
+            s.input = reader.u32()? != 0;
+            s.pending = reader.u32()? != 0;
+            s.enabled = reader.u32()? != 0;
+        }
+        for idc in aplic.idcs.iter_mut() {
+            idc.idelivery = reader.u32()? != 0;
+            idc.iforce = reader.u32()? != 0;
+            idc.ithreshold = reader.u32()?;
+        }
+        aplic.update();
+        Ok(())
+    }
+}
+
+impl BytesAccess for Aplic {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(data);
+            U32Access::write(self, addr, u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        if data.len() == 4 {
+            data.copy_from_slice(&U32Access::read(self, addr).to_le_bytes())
+        }
+    }
+}
+
+impl U32Access for Aplic {
+    fn write(&self, addr: &u64, data: u32) {
+        if *addr & 0x3 == 0 {
+            self.inner.lock().unwrap().write(*addr, data)
+        }
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        if *addr & 0x3 == 0 {
+            self.inner.lock().unwrap().read(*addr)
+        } else {
+            0
+        }
+    }
+}
+
+#[test]
+fn aplic_test() {
+    use crate::devices::imsic::eirq_line;
+    let eirq = Arc::new(IrqVec::new(6));
+    (0..6).for_each(|i| { eirq.set_enable(i).unwrap() });
+    let aplic = Aplic::new(31, Privilege::S, vec![eirq.clone()], None);
+    //direct mode, source 3 level high to hart 0 priority 2
+    U32Access::write(&aplic, &(SOURCECFG + 4 * 2), SM_LEVEL1);
+    U32Access::write(&aplic, &(TARGET + 4 * 2), 2);
+    U32Access::write(&aplic, &SETIENUM, 3);
+    U32Access::write(&aplic, &IDC, 1);
+    U32Access::write(&aplic, &DOMAINCFG, DOMAINCFG_IE);
+    aplic.set_irq(3, true);
+    assert!(eirq.pending(1).unwrap());
+    assert_eq!(U32Access::read(&aplic, &(IDC + TOPI)), 3 << 16 | 2);
+    assert_eq!(U32Access::read(&aplic, &(IDC + CLAIMI)), 3 << 16 | 2);
+    assert!(eirq.pending(1).unwrap());
+    aplic.set_irq(3, false);
+    assert!(!eirq.pending(1).unwrap());
+
+    //msi mode, source 5 rising edge to eiid 9 of hart 0
+    let imsic = Imsic::new(Privilege::S, 63, &[eirq.clone()]);
+    let aplic = Aplic::new(31, Privilege::S, vec![eirq.clone()], Some(("imsic", &imsic)));
+    U32Access::write(&aplic, &DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+    U32Access::write(&aplic, &(SOURCECFG + 4 * 4), SM_EDGE1);
+    U32Access::write(&aplic, &(TARGET + 4 * 4), 9);
+    U32Access::write(&aplic, &SETIENUM, 5);
+    aplic.set_irq(5, true);
+    assert_eq!(U32Access::read(&aplic, &SETIP), 0);
+    assert!(!eirq.pending(eirq_line(Privilege::S)).unwrap());
+    let file = &imsic.files()[0];
+    assert_eq!(file.read_ireg(0x80, terminus_global::XLen::X64), Some(1 << 9));
+}
@@ -0,0 +1,289 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_macros::*;
+use std::sync::{Arc, Mutex};
+use crate::devices::device::{Device, IrqSink, StateReader};
+use crate::system::fdt::{FdtNode, FdtProp};
+
+const CLICCFG: u64 = 0x0;
+const CLICINFO: u64 = 0x4;
+const CLICINTTRIG: u64 = 0x40;
+const CLICINTTRIG_END: u64 = 0xc0;
+const CLICINT: u64 = 0x1000;
+
+//all bits of clicintctl are implemented
+pub const CLICINTCTLBITS: u32 = 8;
+const VERSION: u32 = 0x09;
+pub const MAX_INTS: usize = 4096;
+
+//clicintattr: shv, trig and mode, only machine mode is supported
+const ATTR_SHV: u8 = 1;
+const ATTR_EDGE: u8 = 1 << 1;
+const ATTR_NEG: u8 = 1 << 2;
+const ATTR_MODE_M: u8 = 3 << 6;
+
+#[derive(Default, Clone)]
+struct ClicInt {
+    ip: bool,
+    ie: bool,
+    attr: u8,
+    ctl: u8,
+    input: bool,
+}
+
+impl ClicInt {
+    fn rectified(&self) -> bool {
+        self.input ^ (self.attr & ATTR_NEG != 0)
+    }
+
+    fn edge(&self) -> bool {
+        self.attr & ATTR_EDGE != 0
+    }
+}
+
+struct ClicInner {
+    //mnlbits, nmbits is 0
+    nlbits: u8,
+    inttrig: [u32; 32],
+    ints: Vec<ClicInt>,
+}
+
+impl ClicInner {
+    fn set_input(&mut self, id: usize, level: bool) {
+        if let Some(int) = self.ints.get_mut(id) {
+            let old = int.rectified();
+            int.input = level;
+            if !int.edge() {
+                int.ip = int.rectified()
+            } else if !old && int.rectified() {
+                int.ip = true
+            }
+        }
+    }
+
+    //upper nlbits of clicintctl, lower bits are filled with 1
+    fn level(&self, ctl: u8) -> u8 {
+        ctl | 0xffu8.checked_shr(self.nlbits as u32).unwrap_or(0)
+    }
+
+    fn read_byte(&self, addr: u64) -> u8 {
+        match addr {
+            CLICCFG => self.nlbits,
+            CLICINFO..=0x7 => {
+                let info = self.ints.len() as u32 | (VERSION << 13) | (CLICINTCTLBITS << 21);
+                (info >> ((addr - CLICINFO) << 3)) as u8
+            }
+            CLICINTTRIG..=CLICINTTRIG_END if addr < CLICINTTRIG_END => {
+                (self.inttrig[((addr - CLICINTTRIG) >> 2) as usize] >> ((addr & 0x3) << 3)) as u8
+            }
+            _ if addr >= CLICINT => {
+                match self.ints.get(((addr - CLICINT) >> 2) as usize) {
+                    Some(int) => match addr & 0x3 {
+                        0 => int.ip as u8,
+                        1 => int.ie as u8,
+                        2 => int.attr | ATTR_MODE_M,
+                        _ => int.ctl
+                    }
+                    None => 0
+                }
+            }
+            _ => 0
+        }
+    }
+
+    fn write_byte(&mut self, addr: u64, data: u8) {
+        match addr {
+            CLICCFG => self.nlbits = data & 0xf,
+            CLICINTTRIG..=CLICINTTRIG_END if addr < CLICINTTRIG_END => {
+                let trig = &mut self.inttrig[((addr - CLICINTTRIG) >> 2) as usize];
+                let shift = (addr & 0x3) << 3;
+                *trig = (*trig & !(0xff << shift)) | ((data as u32) << shift)
+            }
+            _ if addr >= CLICINT => {
+                if let Some(int) = self.ints.get_mut(((addr - CLICINT) >> 2) as usize) {
+                    match addr & 0x3 {
+                        //pending bits of level triggered interrupts follow inputs
+                        0 => if int.edge() {
+                            int.ip = data & 1 == 1
+                        }
+                        1 => int.ie = data & 1 == 1,
+                        2 => {
+                            int.attr = data & (ATTR_SHV | ATTR_EDGE | ATTR_NEG);
+                            if !int.edge() {
+                                int.ip = int.rectified()
+                            }
+                        }
+                        _ => int.ctl = data
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+//core-local interrupt controller of one hart, interrupt i has clicintip, clicintie, clicintattr and clicintctl at
+//0x1000 + i * 4. inputs 3, 7 and 11 are msip, mtip and meip of the hart, which are sampled by the hart in clic mode.
+//only machine mode interrupts are supported, and clicinttrig are not connected
+#[derive_io(Bytes, U8, U32)]
+pub struct Clic {
+    inner: Arc<Mutex<ClicInner>>,
+}
+
+impl Clic {
+    pub fn new(num_ints: usize) -> Clic {
+        Clic {
+            inner: Arc::new(Mutex::new(ClicInner {
+                nlbits: 0,
+                inttrig: [0; 32],
+                ints: vec![ClicInt::default(); num_ints.min(MAX_INTS)],
+            }))
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        let size = CLICINT + self.inner.lock().unwrap().ints.len() as u64 * 4;
+        (size + 0xfff) & !0xfff
+    }
+
+    //highest ranked pending and enabled interrupt, (id, level, shv).
+    //level is compared first, then priority, then id
+    pub fn top(&self) -> Option<(usize, u8, bool)> {
+        let clic = self.inner.lock().unwrap();
+        clic.ints.iter().enumerate()
+            .filter(|(_, int)| { int.ip && int.ie })
+            .max_by_key(|(id, int)| { (clic.level(int.ctl), int.ctl, *id) })
+            .map(|(id, int)| { (id, clic.level(int.ctl), int.attr & ATTR_SHV != 0) })
+    }
+
+    //pending bits of edge triggered interrupts are cleared when they are taken with hardware vectoring or by mnxti
+    pub fn claim(&self, id: usize) {
+        if let Some(int) = self.inner.lock().unwrap().ints.get_mut(id) {
+            if int.edge() {
+                int.ip = false
+            }
+        }
+    }
+}
+
+impl Clone for Clic {
+    fn clone(&self) -> Clic {
+        Clic {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl IrqSink for Clic {
+    fn set_irq(&self, id: usize, level: bool) {
+        self.inner.lock().unwrap().set_input(id, level)
+    }
+}
+
+impl Device for Clic {
+    fn reset(&self) {
+        let mut clic = self.inner.lock().unwrap();
+        clic.nlbits = 0;
+        clic.inttrig = [0; 32];
+        clic.ints.iter_mut().for_each(|int| { *int = ClicInt { input: int.input, ..ClicInt::default() } })
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, _harts: &[u32]) -> Option<FdtNode> {
+        let mut node = FdtNode::new(&format!("clic@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["riscv,clic0"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        node.add_prop(FdtProp::null_prop("interrupt-controller"));
+        node.add_prop(FdtProp::u32_prop("#interrupt-cells", vec![1]));
+        node.add_prop(FdtProp::u32_prop("riscv,num-sources", vec![self.inner.lock().unwrap().ints.len() as u32]));
+        Some(node)
+    }
+
+    fn irq_sink(&self) -> Option<Arc<dyn IrqSink>> {
+        Some(Arc::new(self.clone()))
+    }
+
+    //cliccfg, clicinttrig, then ip, ie, attr, ctl and input of interrupts
+    fn save(&self) -> Vec<u8> {
+        let clic = self.inner.lock().unwrap();
+        let mut words = vec![clic.nlbits as u32];
+        words.extend_from_slice(&clic.inttrig);
+        for int in clic.ints.iter() {
+            words.push(u32::from_le_bytes([int.ip as u8, int.ie as u8, int.attr, int.ctl]));
+            words.push(int.input as u32);
+        }
+        words.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect()
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut clic = self.inner.lock().unwrap();
+        let mut reader = StateReader::new(data);
+        clic.nlbits = reader.u32()? as u8;
+        for i in 0..32 {
+            clic.inttrig[i] = reader.u32()?
+        }
+        for int in clic.ints.iter_mut() {
+            let [ip, ie, attr, ctl] = reader.u32()?.to_le_bytes();
+            *int = ClicInt { ip: ip != 0, ie: ie != 0, attr, ctl, input: reader.u32()? != 0 }
+        }
+        Ok(())
+    }
+}
+
+impl BytesAccess for Clic {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        let mut clic = self.inner.lock().unwrap();
+        for (i, v) in data.iter().enumerate() {
+            clic.write_byte(*addr + i as u64, *v)
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        let clic = self.inner.lock().unwrap();
+        for (i, v) in data.iter_mut().enumerate() {
+            *v = clic.read_byte(*addr + i as u64)
+        }
+    }
+}
+
+impl U8Access for Clic {
+    fn write(&self, addr: &u64, data: u8) {
+        self.inner.lock().unwrap().write_byte(*addr, data)
+    }
+
+    fn read(&self, addr: &u64) -> u8 {
+        self.inner.lock().unwrap().read_byte(*addr)
+    }
+}
+
+impl U32Access for Clic {
+    fn write(&self, addr: &u64, data: u32) {
+        BytesAccess::write(self, addr, &data.to_le_bytes())
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        let mut data = [0; 4];
+        BytesAccess::read(self, addr, &mut data);
+        u32::from_le_bytes(data)
+    }
+}
+
+#[test]
+fn clic_test() {
+    let clic = Clic::new(64);
+    //nlbits 2, levels are 0x3f, 0x7f, 0xbf and 0xff
+    U8Access::write(&clic, &CLICCFG, 2);
+    U32Access::write(&clic, &(CLICINT + 4 * 20), u32::from_le_bytes([0, 1, ATTR_EDGE, 0x40]));
+    U32Access::write(&clic, &(CLICINT + 4 * 30), u32::from_le_bytes([0, 1, ATTR_SHV, 0x80]));
+    assert_eq!(U32Access::read(&clic, &CLICINFO) & 0x1fff, 64);
+    assert_eq!(U8Access::read(&clic, &(CLICINT + 4 * 20 + 2)), ATTR_EDGE | ATTR_MODE_M);
+    clic.set_irq(20, true);
+    clic.set_irq(20, false);
+    assert_eq!(clic.top(), Some((20, 0x7f, false)));
+    clic.set_irq(30, true);
+    assert_eq!(clic.top(), Some((30, 0xbf, true)));
+    //level triggered pending bits can not be cleared by software
+    U8Access::write(&clic, &(CLICINT + 4 * 30), 0);
+    assert_eq!(clic.top(), Some((30, 0xbf, true)));
+    clic.set_irq(30, false);
+    clic.claim(20);
+    assert_eq!(clic.top(), None);
+}
@@ -3,12 +3,31 @@ use terminus_spaceport::memory::prelude::*;
 use std::sync::{Mutex, Arc, MutexGuard, LockResult};
 use terminus_spaceport::irq::IrqVec;
 use terminus_macros::*;
+use std::collections::BTreeMap;
+use crate::devices::device::{Device, StateReader};
+use crate::system::fdt::{FdtNode, FdtProp};
+
+//events are ordered by (mtime, sequence), so events scheduled at the same time fire in scheduling order
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct EventId(u64, u64);
+
+impl EventId {
+    pub fn time(&self) -> u64 {
+        self.0
+    }
+}
+
+pub type EventCallback = Box<dyn FnOnce(u64) + Send>;
 
 struct TimerInner {
     freq: usize,
     cnt: u64,
+    elapsed: u64,
     irq_vecs: Vec<Arc<IrqVec>>,
     mtimecmps: Vec<u64>,
+    mtimecmp_events: Vec<Option<EventId>>,
+    events: BTreeMap<EventId, EventCallback>,
+    seq: u64,
 }
 
 impl TimerInner {
@@ -16,8 +35,12 @@ impl TimerInner {
         TimerInner {
             freq,
             cnt: 0,
+            elapsed: 0,
             irq_vecs: vec![],
             mtimecmps: vec![],
+            mtimecmp_events: vec![],
+            events: BTreeMap::new(),
+            seq: 0,
         }
     }
 
@@ -32,20 +55,60 @@ impl TimerInner {
         irq_vec.set_enable(1).unwrap();
         self.irq_vecs.push(irq_vec.clone());
         self.mtimecmps.push(0);
+        self.mtimecmp_events.push(None);
+        self.arm(self.irq_vecs.len() - 1);
         irq_vec
     }
 
-    fn tick(&mut self, n: u64) {
-        self.cnt_tick(n);
-        for (irq_vec, mtimecmp) in self.irq_vecs.iter().zip(self.mtimecmps.iter()) {
-            irq_vec.clr_pending(1).unwrap();
-            if self.cnt >= *mtimecmp {
+    fn schedule(&mut self, time: u64, cb: EventCallback) -> EventId {
+        let id = EventId(time, self.seq);
+        self.seq += 1;
+        self.events.insert(id, cb);
+        id
+    }
+
+    fn cancel(&mut self, id: &EventId) -> bool {
+        self.events.remove(id).is_some()
+    }
+
+    fn next_event(&self) -> Option<EventId> {
+        self.events.keys().next().cloned()
+    }
+
+    //earliest expired event scheduled before sequence seq
+    fn pop_expired(&mut self, seq: u64) -> Option<(EventId, EventCallback)> {
+        let cnt = self.cnt;
+        let id = self.events.keys().take_while(|id| { id.time() <= cnt }).find(|id| { id.1 < seq }).cloned()?;
+        self.events.remove(&id).map(|cb| { (id, cb) })
+    }
+
+    //re-evaluate mtip of hart i against current mtime, and schedule the crossing if it is in the future
+    fn arm(&mut self, i: usize) {
+        if let Some(id) = self.mtimecmp_events[i].take() {
+            self.cancel(&id);
+        }
+        let irq_vec = self.irq_vecs[i].clone();
+        irq_vec.clr_pending(1).unwrap();
+        if self.cnt >= self.mtimecmps[i] {
+            irq_vec.sender(1).unwrap().send().unwrap()
+        } else {
+            let id = self.schedule(self.mtimecmps[i], Box::new(move |_| {
                 irq_vec.sender(1).unwrap().send().unwrap()
-            }
+            }));
+            self.mtimecmp_events[i] = Some(id)
+        }
+    }
+
+    fn arm_all(&mut self) {
+        for i in 0..self.irq_vecs.len() {
+            self.arm(i)
         }
     }
 }
 
+//Timer is a simulated-time event queue, time unit is mtime tick(1/freq second).
+//mtime is advanced by System::step according to executed cycles of harts, or by tick() manually.
+//Timer interrupts and device events are fired when mtime crosses their scheduled time.
 pub struct Timer(Mutex<TimerInner>);
 
 impl Timer {
@@ -57,14 +120,118 @@ impl Timer {
         self.0.lock().unwrap().alloc_irq()
     }
 
+    //advance mtime n ticks, and fire all expired events
     pub fn tick(&self, n: u64) {
-        self.0.lock().unwrap().tick(n)
+        self.0.lock().unwrap().cnt_tick(n);
+        self.fire_expired()
+    }
+
+    //elapsed is the simulated time derived from executed cycles since reset,
+    //mtime is advanced by the delta since last sync, so mtime written by software is kept as offset
+    pub fn sync(&self, elapsed: u64) {
+        let delta = {
+            let mut timer = self.0.lock().unwrap();
+            let delta = elapsed.saturating_sub(timer.elapsed);
+            timer.elapsed += delta;
+            delta
+        };
+        self.tick(delta)
+    }
+
+    pub fn elapsed(&self) -> u64 {
+        self.0.lock().unwrap().elapsed
+    }
+
+    pub fn time(&self) -> u64 {
+        self.0.lock().unwrap().cnt
+    }
+
+    //schedule cb at absolute mtime, cb receives the scheduled mtime when it fires.
+    //an event scheduled in the past fires at next tick() or sync().
+    pub fn schedule<F: FnOnce(u64) + Send + 'static>(&self, time: u64, cb: F) -> EventId {
+        self.0.lock().unwrap().schedule(time, Box::new(cb))
+    }
+
+    pub fn schedule_after<F: FnOnce(u64) + Send + 'static>(&self, delay: u64, cb: F) -> EventId {
+        let mut timer = self.0.lock().unwrap();
+        let time = timer.cnt.saturating_add(delay);
+        timer.schedule(time, Box::new(cb))
+    }
+
+    pub fn cancel(&self, id: &EventId) -> bool {
+        self.0.lock().unwrap().cancel(id)
+    }
+
+    //mtime of the earliest pending event
+    pub fn next_event(&self) -> Option<u64> {
+        self.0.lock().unwrap().next_event().map(|id| { id.time() })
     }
 
     pub fn freq(&self) -> usize {
         self.0.lock().unwrap().freq
     }
 
+    //mtime written by software, timer interrupts of all harts are re-evaluated
+    pub fn set_time(&self, time: u64) {
+        let mut timer = self.0.lock().unwrap();
+        timer.cnt = time;
+        timer.arm_all()
+    }
+
+    //number of harts allocated by alloc_irq
+    pub fn num_harts(&self) -> usize {
+        self.0.lock().unwrap().irq_vecs.len()
+    }
+
+    pub fn msip(&self, hartid: usize) -> bool {
+        self.0.lock().unwrap().irq_vecs[hartid].pending(0).unwrap()
+    }
+
+    pub fn set_msip(&self, hartid: usize, level: bool) {
+        let timer = self.0.lock().unwrap();
+        if level {
+            timer.irq_vecs[hartid].set_pending(0).unwrap()
+        } else {
+            timer.irq_vecs[hartid].clr_pending(0).unwrap()
+        }
+    }
+
+    pub fn mtimecmp(&self, hartid: usize) -> u64 {
+        self.0.lock().unwrap().mtimecmps[hartid]
+    }
+
+    pub fn set_mtimecmp(&self, hartid: usize, value: u64) {
+        let mut timer = self.0.lock().unwrap();
+        timer.mtimecmps[hartid] = value;
+        timer.arm(hartid)
+    }
+
+    //convert cycles of a hart running at cpu_freq to mtime ticks
+    pub fn cycles_to_ticks(&self, cycles: u64, cpu_freq: usize) -> u64 {
+        (cycles as u128 * self.freq() as u128 / cpu_freq as u128) as u64
+    }
+
+    //minimum cycles of a hart running at cpu_freq to cover ticks
+    pub fn ticks_to_cycles(&self, ticks: u64, cpu_freq: usize) -> u64 {
+        let freq = self.freq() as u128;
+        ((ticks as u128 * cpu_freq as u128 + freq - 1) / freq) as u64
+    }
+
+    //events scheduled by the callbacks fire at next tick() or sync() even if they are expired already,
+    //so an event rescheduling itself at current mtime can not hang the simulation
+    fn fire_expired(&self) {
+        let seq = self.0.lock().unwrap().seq;
+        loop {
+            //callback is called without lock, so that it can schedule following events
+            let expired = self.0.lock().unwrap().pop_expired(seq);
+            if let Some((id, cb)) = expired {
+                cb(id.time())
+            } else {
+                break;
+            }
+        }
+    }
+
     fn lock(&self) -> LockResult<MutexGuard<'_, TimerInner>> {
         self.0.lock()
     }
@@ -87,6 +254,51 @@ impl Clint {
     }
 }
 
+impl Clone for Clint {
+    fn clone(&self) -> Clint {
+        Clint(self.0.clone())
+    }
+}
+
+//mtime and mtimecmps are kept in Timer, only msip is reset
+impl Device for Clint {
+    fn reset(&self) {
+        let timer = self.0.lock().unwrap();
+        for irq_vec in timer.irq_vecs.iter() {
+            irq_vec.clr_pending(0).unwrap()
+        }
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, harts: &[u32]) -> Option<FdtNode> {
+        let mut node = FdtNode::new(&format!("clint@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["sifive,clint0", "riscv,clint0"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        //msip, mtip
+        node.add_prop(FdtProp::u32_prop("interrupts-extended", harts.iter().flat_map(|h| { vec![*h, 3, *h, 7] }).collect()));
+        Some(node)
+    }
+
+    //msips, mtime, then mtimecmps
+    fn save(&self) -> Vec<u8> {
+        let mut data = (0..self.0.num_harts()).flat_map(|i| { (self.0.msip(i) as u32).to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+        data.extend_from_slice(&self.0.time().to_le_bytes());
+        data.extend((0..self.0.num_harts()).flat_map(|i| { self.0.mtimecmp(i).to_le_bytes().to_vec() }));
+        data
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data);
+        for hartid in 0..self.0.num_harts() {
+            self.0.set_msip(hartid, reader.u32()? != 0)
+        }
+        self.0.set_time(reader.u64()?);
+        for hartid in 0..self.0.num_harts() {
+            self.0.set_mtimecmp(hartid, reader.u64()?)
+        }
+        Ok(())
+    }
+}
+
 impl BytesAccess for Clint {
     fn write(&self, addr: &u64, data: &[u8]) {
         if data.len() == 4 {
@@ -127,14 +339,16 @@ impl U32Access for Clint {
             } else {
                 timer.mtimecmps[offset].set_bit_range(31, 0, data)
             };
-            timer.tick(0);
+            timer.arm(offset);
             return;
         } else if *addr >= MTIME_BASE && *addr + 4 <= MTIME_BASE + MTIME_SIZE {
-            return if (*addr).trailing_zeros() == 2 {
+            if (*addr).trailing_zeros() == 2 {
                 timer.cnt.set_bit_range(63, 32, data)
             } else {
                 timer.cnt.set_bit_range(31, 0, data)
             };
+            timer.arm_all();
+            return;
         }
 
         panic!("clint:U32Access Invalid addr!".to_string());
@@ -185,10 +399,12 @@ impl U64Access for Clint {
         } else if *addr >= MTIMECMP_BASE && *addr + 8 <= MTIMECMP_BASE + timer.mtimecmps.len() as u64 * MTMIECMP_SIZE {
             let offset = ((*addr - MTIMECMP_BASE) >> 3) as usize;
             timer.mtimecmps[offset] = data;
-            timer.tick(0);
+            timer.arm(offset);
             return;
         } else if *addr >= MTIME_BASE && *addr + 8 <= MTIME_BASE + MTIME_SIZE {
-            return timer.cnt = data;
+            timer.cnt = data;
+            timer.arm_all();
+            return;
         }
 
         panic!("clint:U64Access Invalid addr!".to_string());
@@ -249,4 +465,64 @@ fn timer_test() {
     );
 
     p0.join().unwrap();
-}
\ No newline at end of file
+}
+#[test]
+fn timer_event_test() {
+    let timer = Timer::new(100);
+    let fired = Arc::new(Mutex::new(vec![]));
+    let record = |id: u64| {
+        let fired = fired.clone();
+        move |time: u64| { fired.lock().unwrap().push((id, time)) }
+    };
+    timer.schedule(10, record(0));
+    let canceled = timer.schedule(5, record(1));
+    timer.schedule(10, record(2));
+    timer.schedule_after(3, record(3));
+    assert!(timer.cancel(&canceled));
+    assert_eq!(timer.next_event(), Some(3));
+    timer.tick(2);
+    assert!(fired.lock().unwrap().is_empty());
+    timer.sync(20);
+    assert_eq!(timer.time(), 22);
+    assert_eq!(*fired.lock().unwrap(), vec![(3, 3), (0, 10), (2, 10)]);
+    assert_eq!(timer.next_event(), None);
+
+    //an event rescheduling itself without delay fires once a tick
+    fn again(timer: &Arc<Timer>, count: Arc<Mutex<u64>>) {
+        let weak = Arc::downgrade(timer);
+        timer.schedule_after(0, move |_| {
+            *count.lock().unwrap() += 1;
+            if let Some(timer) = weak.upgrade() {
+                again(&timer, count)
+            }
+        });
+    }
+    let timer = Arc::new(timer);
+    let count = Arc::new(Mutex::new(0));
+    again(&timer, count.clone());
+    timer.tick(1);
+    assert_eq!(*count.lock().unwrap(), 1);
+    timer.tick(0);
+    assert_eq!(*count.lock().unwrap(), 2);
+}
+
+#[test]
+fn clint_save_test() {
+    let timer = Arc::new(Timer::new(100));
+    let irq_vecs = vec![timer.alloc_irq(), timer.alloc_irq()];
+    let clint = Clint::new(&timer);
+    U32Access::write(&clint, &(MSIP_BASE + 4), 1);
+    U64Access::write(&clint, &(MTIMECMP_BASE + 8), 20);
+    timer.tick(10);
+    let state = clint.save();
+    clint.reset();
+    U64Access::write(&clint, &(MTIMECMP_BASE + 8), 0);
+    timer.tick(20);
+    assert!(!irq_vecs[1].pending(0).unwrap());
+    assert!(clint.restore(&state[..4]).is_err());
+    clint.restore(&state).unwrap();
+    assert!(irq_vecs[1].pending(0).unwrap());
+    assert!(!irq_vecs[1].pending(1).unwrap());
+    assert_eq!(U64Access::read(&clint, &MTIME_BASE), 10);
+    assert_eq!(U64Access::read(&clint, &(MTIMECMP_BASE + 8)), 20);
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use crate::system::fdt::FdtNode;
+
+//receiver of interrupt lines, e.g. plic
+pub trait IrqSink: Send + Sync {
+    fn set_irq(&self, id: usize, level: bool);
+
+    //"interrupts" cells of source id in the device tree
+    fn irq_cells(&self, id: usize) -> Vec<u32> {
+        vec![id as u32]
+    }
+}
+
+//level triggered interrupt line of a device, id is the source id on the controller
+#[derive(Clone)]
+pub struct IrqLine {
+    controller: String,
+    sink: Arc<dyn IrqSink>,
+    id: usize,
+}
+
+impl IrqLine {
+    pub fn new(controller: &str, sink: Arc<dyn IrqSink>, id: usize) -> IrqLine {
+        IrqLine {
+            controller: controller.to_string(),
+            sink,
+            id,
+        }
+    }
+
+    //name of the controller device
+    pub fn controller(&self) -> &str {
+        &self.controller
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn cells(&self) -> Vec<u32> {
+        self.sink.irq_cells(self.id)
+    }
+
+    pub fn set(&self, level: bool) {
+        self.sink.set_irq(self.id, level)
+    }
+}
+
+//devices registered to System, the region is backed by a clone of the device, so devices are handles of shared state.
+//fdt_node describes the device at base for the generated device tree, harts are phandles of cpu interrupt controllers
+//indexed by hartid. phandle, interrupts and interrupt-parent are added by System.
+//save returns the state restored by restore for snapshots.
+pub trait Device: Send + Sync {
+    fn reset(&self) {}
+
+    fn fdt_node(&self, _base: u64, _size: u64, _harts: &[u32]) -> Option<FdtNode> {
+        None
+    }
+
+    fn irq_lines(&self) -> Vec<IrqLine> {
+        vec![]
+    }
+
+    //phandle properties of the node, (property, name of the referenced device), resolved by System
+    fn fdt_refs(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    //interrupt controllers hand out lines
+    fn irq_sink(&self) -> Option<Arc<dyn IrqSink>> {
+        None
+    }
+
+    //devices keeping host states, like open files and sockets, can not be saved
+    fn can_save(&self) -> bool {
+        true
+    }
+
+    fn save(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn restore(&self, _data: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+//little endian words of saved states
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        if self.data.len() < 4 {
+            return Err("state is truncated!".to_string());
+        }
+        let (head, tail) = self.data.split_at(4);
+        self.data = tail;
+        Ok(u32::from_le_bytes([head[0], head[1], head[2], head[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("state is truncated!".to_string());
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+}
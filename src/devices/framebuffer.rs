@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use std::fs;
+use std::path::Path;
+use crate::devices::clint::Timer;
+use crate::devices::virtio::GuestMemory;
+use crate::system::fdt::{FdtNode, FdtProp};
+
+//pixel formats of simple-framebuffer, pixels are little endian words
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FbFormat {
+    R5G6B5,
+    R8G8B8,
+    A8R8G8B8,
+    X8R8G8B8,
+    A8B8G8R8,
+    X8B8G8R8,
+}
+
+const FORMATS: &[(&str, FbFormat)] = &[("r5g6b5", FbFormat::R5G6B5), ("r8g8b8", FbFormat::R8G8B8), ("a8r8g8b8", FbFormat::A8R8G8B8),
+    ("x8r8g8b8", FbFormat::X8R8G8B8), ("a8b8g8r8", FbFormat::A8B8G8R8), ("x8b8g8r8", FbFormat::X8B8G8R8)];
+
+impl FbFormat {
+    pub fn parse(s: &str) -> Result<FbFormat, String> {
+        FORMATS.iter().find(|(name, _)| { *name == s }).map(|(_, f)| { *f })
+            .ok_or(format!("unknown framebuffer format {}, supported formats are {:?}!", s, FORMATS.iter().map(|(name, _)| { *name }).collect::<Vec<_>>()))
+    }
+
+    pub fn name(&self) -> &'static str {
+        FORMATS.iter().find(|(_, f)| { f == self }).unwrap().0
+    }
+
+    pub fn bytes_per_pixel(&self) -> u32 {
+        match self {
+            FbFormat::R5G6B5 => 2,
+            FbFormat::R8G8B8 => 3,
+            _ => 4
+        }
+    }
+
+    fn rgb(&self, v: u32) -> [u8; 3] {
+        match self {
+            FbFormat::R5G6B5 => {
+                let (r, g, b) = ((v >> 11) & 0x1f, (v >> 5) & 0x3f, v & 0x1f);
+                [((r << 3) | (r >> 2)) as u8, ((g << 2) | (g >> 4)) as u8, ((b << 3) | (b >> 2)) as u8]
+            }
+            FbFormat::A8B8G8R8 | FbFormat::X8B8G8R8 => [v as u8, (v >> 8) as u8, (v >> 16) as u8],
+            _ => [(v >> 16) as u8, (v >> 8) as u8, v as u8]
+        }
+    }
+}
+
+//memory region scanned out as a screen, described in /chosen for firmware and linux simplefb.
+//contents are dumped to ppm, or png if the file ends with .png
+pub struct Framebuffer {
+    mem: GuestMemory,
+    base: u64,
+    width: u32,
+    height: u32,
+    stride: u32,
+    format: FbFormat,
+}
+
+impl Framebuffer {
+    pub fn new(mem: GuestMemory, base: u64, width: u32, height: u32, stride: u32, format: FbFormat) -> Result<Framebuffer, String> {
+        if width == 0 || height == 0 {
+            return Err(format!("invalid framebuffer size {}x{}!", width, height));
+        }
+        let line = width.checked_mul(format.bytes_per_pixel()).ok_or(format!("framebuffer width {} is too large!", width))?;
+        if stride < line {
+            return Err(format!("framebuffer stride {} is less than a line of {} {} pixels!", stride, width, format.name()));
+        }
+        Ok(Framebuffer {
+            mem,
+            base,
+            width,
+            height,
+            stride,
+            format,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.stride as u64 * self.height as u64
+    }
+
+    pub fn fdt_node(&self) -> FdtNode {
+        let mut node = FdtNode::new(&format!("framebuffer@{:x}", self.base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["simple-framebuffer"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![self.base, self.size()]));
+        node.add_prop(FdtProp::u32_prop("width", vec![self.width]));
+        node.add_prop(FdtProp::u32_prop("height", vec![self.height]));
+        node.add_prop(FdtProp::u32_prop("stride", vec![self.stride]));
+        node.add_prop(FdtProp::str_prop("format", vec![self.format.name()]));
+        node.add_prop(FdtProp::str_prop("status", vec!["okay"]));
+        node
+    }
+
+    //rows of r, g, b bytes
+    pub fn rgb(&self) -> Result<Vec<u8>, String> {
+        let mut data = vec![0; self.size() as usize];
+        self.mem.read(self.base, &mut data).map_err(|addr| { format!("framebuffer is not in memory at 0x{:x}!", addr) })?;
+        let bpp = self.format.bytes_per_pixel() as usize;
+        let mut rgb = Vec::with_capacity(self.width as usize * self.height as usize * 3);
+        for line in data.chunks(self.stride as usize) {
+            for pixel in line[..self.width as usize * bpp].chunks(bpp) {
+                let v = pixel.iter().rev().fold(0, |v, b| { (v << 8) | *b as u32 });
+                rgb.extend_from_slice(&self.format.rgb(v))
+            }
+        }
+        Ok(rgb)
+    }
+
+    pub fn dump(&self, file: &str) -> Result<(), String> {
+        let rgb = self.rgb()?;
+        let image = if file.ends_with(".png") {
+            png(self.width, self.height, &rgb)
+        } else {
+            ppm(self.width, self.height, &rgb)
+        };
+        fs::write(file, image).map_err(|e| { format!("{}: {}", file, e) })
+    }
+
+    //dump every period ticks of timer, the simulated time in ms is added to file, e.g. fb-100.png
+    pub fn dump_every(fb: &Arc<Framebuffer>, timer: &Arc<Timer>, period: u64, file: &str) {
+        fn schedule(fb: Arc<Framebuffer>, timer: &Arc<Timer>, period: u64, file: String) {
+            let weak = Arc::downgrade(timer);
+            timer.schedule_after(period, move |_| {
+                if let Some(timer) = weak.upgrade() {
+                    let ms = (timer.elapsed() as u128 * 1000 / timer.freq() as u128) as u64;
+                    if let Err(e) = fb.dump(&timed_file(&file, ms)) {
+                        eprintln!("{}", e)
+                    }
+                    schedule(fb, &timer, period, file)
+                }
+            });
+        }
+        schedule(fb.clone(), timer, period, file.to_string())
+    }
+}
+
+fn timed_file(file: &str, ms: u64) -> String {
+    let path = Path::new(file);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path.with_file_name(format!("{}-{}.{}", stem.to_string_lossy(), ms, ext.to_string_lossy())).to_string_lossy().into_owned(),
+        _ => format!("{}-{}", file, ms)
+    }
+}
+
+fn ppm(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    image.extend_from_slice(rgb);
+    image
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| { if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 } })
+    })
+}
+
+fn png_chunk(image: &mut Vec<u8>, ty: &[u8], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = image.len();
+    image.extend_from_slice(ty);
+    image.extend_from_slice(data);
+    let crc = crc32(&image[start..]);
+    image.extend_from_slice(&crc.to_be_bytes())
+}
+
+//8-bit rgb, the zlib stream is made of stored deflate blocks, so no compression library is needed
+fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for line in rgb.chunks(width as usize * 3) {
+        raw.push(0);
+        raw.extend_from_slice(line)
+    }
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(0xffff).collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i + 1 == blocks.len()) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block)
+    }
+    let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), v| {
+        let a = (a + *v as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    let mut ihdr = vec![];
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    let mut image = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    png_chunk(&mut image, b"IHDR", &ihdr);
+    png_chunk(&mut image, b"IDAT", &zlib);
+    png_chunk(&mut image, b"IEND", &[]);
+    image
+}
+
+#[test]
+fn framebuffer_test() {
+    use terminus_spaceport::memory::region::{Region, GHEAP};
+    let mem = GuestMemory::new(vec![Region::remap(0x40000000, &GHEAP.alloc(0x1000, 1).unwrap())]);
+    assert!(Framebuffer::new(mem.clone(), 0x40000000, 2, 2, 2, FbFormat::R5G6B5).is_err());
+    assert!(Framebuffer::new(mem.clone(), 0x40000000, 0x80000000, 1, u32::MAX, FbFormat::R5G6B5).is_err());
+    //red, green / blue, white with a padding pixel in each line
+    let fb = Framebuffer::new(mem.clone(), 0x40000000, 2, 2, 6, FbFormat::R5G6B5).unwrap();
+    mem.write(0x40000000, &[0x00, 0xf8, 0xe0, 0x07, 0xff, 0xff, 0x1f, 0x00, 0xff, 0xff, 0xff, 0xff]).unwrap();
+    assert_eq!(fb.rgb().unwrap(), vec![0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(&ppm(2, 2, &fb.rgb().unwrap())[..11], b"P6\n2 2\n255\n");
+    let image = png(2, 2, &fb.rgb().unwrap());
+    assert_eq!(&image[..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+    assert_eq!(&image[image.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+    let node = fb.fdt_node();
+    assert_eq!(node.prop("format").unwrap().strs(), vec!["r5g6b5"]);
+    assert_eq!(timed_file("out/fb.png", 100), "out/fb-100.png");
+    assert_eq!(timed_file("fb", 100), "fb-100");
+}
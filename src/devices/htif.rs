@@ -1,8 +1,8 @@
 use terminus_spaceport::memory::prelude::*;
 use terminus_spaceport::EXIT_CTRL;
 use terminus_spaceport::devices::TERM;
-use std::sync::Mutex;
-use std::io::{Write, ErrorKind, Read};
+use std::sync::{Arc, Mutex};
+use std::io::{Write, Read};
 use terminus_macros::*;
 use std::borrow::{BorrowMut, Borrow};
 
@@ -10,6 +10,8 @@ use std::borrow::{BorrowMut, Borrow};
 struct HTIFDesp {
     tohost: u64,
     fromhost: u64,
+    //address of magic_mem of the pending syscall
+    syscall: Option<u64>,
 }
 
 impl HTIFDesp {
@@ -23,7 +25,7 @@ impl HTIFDesp {
 
 #[derive_io(Bytes, U32, U64)]
 pub struct HTIF {
-    desc: Mutex<HTIFDesp>,
+    desc: Arc<Mutex<HTIFDesp>>,
     tohost_off: u64,
     fromhost_off: Option<u64>,
 }
@@ -31,28 +33,38 @@ pub struct HTIF {
 impl HTIF {
     pub fn new(tohost_off: u64, fromhost_off: Option<u64>) -> HTIF {
         HTIF {
-            desc: Mutex::new(HTIFDesp { tohost: 0, fromhost: 0 }),
+            desc: Arc::new(Mutex::new(HTIFDesp { tohost: 0, fromhost: 0, syscall: None })),
             tohost_off,
             fromhost_off,
         }
     }
 
+    pub fn syscall_port(&self) -> HTIFSyscallPort {
+        HTIFSyscallPort(self.desc.clone())
+    }
+
     fn handle_cmd(desp: &mut HTIFDesp) {
         if desp.tohost & 0x1 == 1 && desp.tohost_device() == 0 && desp.tohost_cmd() == 0 {
             EXIT_CTRL.exit("htif shutdown!").unwrap();
+        } else if desp.tohost != 0 && desp.tohost_device() == 0 && desp.tohost_cmd() == 0 {
+            desp.syscall = Some(desp.tohost & 0xffff_ffff_ffff);
         } else if desp.tohost_device() == 1 && desp.tohost_cmd() == 1 {
             let mut data = [0u8; 1];
             data[0] = desp.tohost as u8;
             let stdout = TERM.stdout();
             let mut handle = stdout.lock();
-            handle.write(&data).unwrap();
-            handle.flush().unwrap();
-            desp.tohost = 0;
+            if let Err(e) = handle.write_all(&data).and_then(|_| { handle.flush() }) {
+                eprintln!("htif: {}", e)
+            }
             desp.fromhost = desp.tohost_device() << 56 | desp.tohost_cmd() << 48;
+            desp.tohost = 0;
         } else if desp.tohost_device() == 1 && desp.tohost_cmd() == 0 {
             desp.tohost = 0;
         } else {
-            panic!(format!("HTIF:unsupportd tohost={:#x}", desp.tohost))
+            //ack unsupported commands, the guest should not hang on them
+            eprintln!("htif: unsupported tohost={:#x}", desp.tohost);
+            desp.fromhost = desp.tohost_device() << 56 | desp.tohost_cmd() << 48;
+            desp.tohost = 0;
         }
     }
 
@@ -62,13 +74,32 @@ impl HTIF {
             let mut data = [0u8; 1];
             match TERM.stdin().lock().read_exact(&mut data) {
                 Ok(_) => desp.borrow_mut().fromhost.set_bit_range(7, 0, data[0]),
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
-                Err(e) => panic!("{:?}", e)
+                //no input yet, or eof and broken stdin
+                Err(_) => {}
             }
         }
     }
 }
 
+//syscalls of riscv-pk are served outside of the device, because arguments are in memory
+#[derive(Clone)]
+pub struct HTIFSyscallPort(Arc<Mutex<HTIFDesp>>);
+
+impl HTIFSyscallPort {
+    //address of magic_mem of the pending syscall
+    pub fn request(&self) -> Option<u64> {
+        self.0.lock().unwrap().syscall
+    }
+
+    //return value has been written to magic_mem
+    pub fn respond(&self) {
+        let mut desp = self.0.lock().unwrap();
+        desp.syscall = None;
+        desp.tohost = 0;
+        desp.fromhost = 1;
+    }
+}
+
 impl BytesAccess for HTIF {
     fn write(&self, addr: &u64, data: &[u8]) {
         if data.len() == 4 {
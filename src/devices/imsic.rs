@@ -0,0 +1,321 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_spaceport::irq::IrqVec;
+use terminus_macros::*;
+use terminus_global::XLen;
+use std::sync::{Arc, Mutex};
+use crate::processor::Privilege;
+use crate::devices::device::{Device, StateReader};
+use crate::system::fdt::{FdtNode, FdtProp};
+
+pub const IMSIC_PAGE_SIZE: u64 = 0x1000;
+const SETEIPNUM_LE: u64 = 0x0;
+const SETEIPNUM_BE: u64 = 0x4;
+
+//indirect registers of interrupt files, selected by miselect/siselect
+const EIDELIVERY: u64 = 0x70;
+const EITHRESHOLD: u64 = 0x72;
+const EIP0: u64 = 0x80;
+const EIE0: u64 = 0xc0;
+const EIE63: u64 = 0xff;
+
+//eirq lines of harts driven by interrupt files, or-ed into meip and seip
+pub fn eirq_line(level: Privilege) -> usize {
+    if level == Privilege::M { 4 } else { 5 }
+}
+
+struct ImsicFileInner {
+    eidelivery: bool,
+    eithreshold: u32,
+    //bit i of word j is identity j * 32 + i, identity 0 is never pending
+    eip: Vec<u32>,
+    eie: Vec<u32>,
+}
+
+impl ImsicFileInner {
+    //lowest identity is the highest priority, identities not below a nonzero threshold are masked
+    fn top(&self) -> u32 {
+        let top = self.eip.iter().zip(self.eie.iter()).enumerate().find_map(|(i, (p, e))| {
+            let bits = p & e;
+            if bits != 0 {
+                Some(i as u32 * 32 + bits.trailing_zeros())
+            } else {
+                None
+            }
+        }).unwrap_or(0);
+        if self.eithreshold != 0 && top >= self.eithreshold {
+            0
+        } else {
+            top
+        }
+    }
+}
+
+//interrupt file of a hart at one privilege level, software accesses it through aia csrs of the hart
+//and devices send msis to it through Imsic
+pub struct ImsicFile {
+    inner: Mutex<ImsicFileInner>,
+    num_ids: u32,
+    irq: Arc<IrqVec>,
+    line: usize,
+}
+
+impl ImsicFile {
+    //identities are 1..=num_ids, num_ids is 64 * n - 1
+    pub fn new(num_ids: u32, eirq: &Arc<IrqVec>, level: Privilege) -> ImsicFile {
+        let words = (num_ids as usize + 1) / 32;
+        ImsicFile {
+            inner: Mutex::new(ImsicFileInner {
+                eidelivery: false,
+                eithreshold: 0,
+                eip: vec![0; words],
+                eie: vec![0; words],
+            }),
+            num_ids,
+            irq: eirq.clone(),
+            line: eirq_line(level),
+        }
+    }
+
+    pub fn num_ids(&self) -> u32 {
+        self.num_ids
+    }
+
+    fn update(&self, file: &ImsicFileInner) {
+        if file.eidelivery && file.top() != 0 {
+            self.irq.set_pending(self.line).unwrap()
+        } else {
+            self.irq.clr_pending(self.line).unwrap()
+        }
+    }
+
+    //msi with identity id, invalid identities are ignored
+    pub fn send(&self, id: u32) {
+        if id == 0 || id > self.num_ids {
+            return;
+        }
+        let mut file = self.inner.lock().unwrap();
+        file.eip[(id >> 5) as usize] |= 1 << (id & 0x1f);
+        self.update(&file)
+    }
+
+    //highest priority pending and enabled identity, 0 if none
+    pub fn top(&self) -> u32 {
+        self.inner.lock().unwrap().top()
+    }
+
+    //clear and return the top identity, for writes to mtopei and stopei
+    pub fn claim(&self) -> u32 {
+        let mut file = self.inner.lock().unwrap();
+        let top = file.top();
+        if top != 0 {
+            file.eip[(top >> 5) as usize] &= !(1 << (top & 0x1f));
+            self.update(&file)
+        }
+        top
+    }
+
+    //eipk and eiek are 32 bits each for rv32, for rv64 only even k exist and cover 64 identities.
+    //none if sel is not an interrupt file register
+    pub fn read_ireg(&self, sel: u64, xlen: XLen) -> Option<u64> {
+        let file = self.inner.lock().unwrap();
+        match sel {
+            EIDELIVERY => Some(file.eidelivery as u64),
+            EITHRESHOLD => Some(file.eithreshold as u64),
+            EIP0..=EIE63 => {
+                let (array, k) = if sel < EIE0 { (&file.eip, sel - EIP0) } else { (&file.eie, sel - EIE0) };
+                let word = |i: u64| { array.get(i as usize).cloned().unwrap_or(0) as u64 };
+                match xlen {
+                    XLen::X64 if k & 1 == 1 => None,
+                    XLen::X64 => Some(word(k) | (word(k + 1) << 32)),
+                    XLen::X32 => Some(word(k))
+                }
+            }
+            _ => None
+        }
+    }
+
+    pub fn write_ireg(&self, sel: u64, xlen: XLen, value: u64) -> Option<()> {
+        let mut file = self.inner.lock().unwrap();
+        match sel {
+            EIDELIVERY => file.eidelivery = value & 1 == 1,
+            EITHRESHOLD => {
+                if value <= self.num_ids as u64 {
+                    file.eithreshold = value as u32
+                }
+            }
+            EIP0..=EIE63 => {
+                let (array, k) = if sel < EIE0 { (&mut file.eip, sel - EIP0) } else { (&mut file.eie, sel - EIE0) };
+                let words = match xlen {
+                    XLen::X64 if k & 1 == 1 => return None,
+                    XLen::X64 => vec![(k, value as u32), (k + 1, (value >> 32) as u32)],
+                    XLen::X32 => vec![(k, value as u32)]
+                };
+                for (i, w) in words {
+                    if let Some(v) = array.get_mut(i as usize) {
+                        *v = w
+                    }
+                }
+                file.eip[0] &= !1;
+                file.eie[0] &= !1;
+            }
+            _ => return None
+        }
+        self.update(&file);
+        Some(())
+    }
+
+    fn reset(&self) {
+        let mut file = self.inner.lock().unwrap();
+        file.eidelivery = false;
+        file.eithreshold = 0;
+        file.eip.iter_mut().for_each(|w| { *w = 0 });
+        file.eie.iter_mut().for_each(|w| { *w = 0 });
+        self.update(&file)
+    }
+
+    //eidelivery, eithreshold, then eip and eie words
+    fn save(&self) -> Vec<u32> {
+        let file = self.inner.lock().unwrap();
+        let mut words = vec![file.eidelivery as u32, file.eithreshold];
+        words.extend_from_slice(&file.eip);
+        words.extend_from_slice(&file.eie);
+        words
+    }
+
+    fn restore(&self, reader: &mut StateReader) -> Result<(), String> {
+        let mut file = self.inner.lock().unwrap();
+        file.eidelivery = reader.u32()? != 0;
+        file.eithreshold = reader.u32()?;
+        for i in 0..file.eip.len() {
+            file.eip[i] = reader.u32()?
+        }
+        for i in 0..file.eie.len() {
+            file.eie[i] = reader.u32()?
+        }
+        self.update(&file);
+        Ok(())
+    }
+}
+
+//interrupt files of one privilege level for all harts, the file of hart i is at page i.
+//a device writes an identity to seteipnum_le of a page to send an msi
+#[derive_io(Bytes, U32)]
+pub struct Imsic {
+    level: Privilege,
+    files: Arc<Vec<Arc<ImsicFile>>>,
+}
+
+impl Imsic {
+    //eirqs of harts are indexed by hartid
+    pub fn new(level: Privilege, num_ids: u32, eirqs: &[Arc<IrqVec>]) -> Imsic {
+        Imsic {
+            level,
+            files: Arc::new(eirqs.iter().map(|eirq| { Arc::new(ImsicFile::new(num_ids, eirq, level)) }).collect()),
+        }
+    }
+
+    pub fn level(&self) -> Privilege {
+        self.level
+    }
+
+    pub fn files(&self) -> &[Arc<ImsicFile>] {
+        &self.files
+    }
+
+    pub fn size(&self) -> u64 {
+        self.files.len() as u64 * IMSIC_PAGE_SIZE
+    }
+}
+
+impl Clone for Imsic {
+    fn clone(&self) -> Imsic {
+        Imsic {
+            level: self.level,
+            files: self.files.clone(),
+        }
+    }
+}
+
+impl Device for Imsic {
+    fn reset(&self) {
+        self.files.iter().for_each(|f| { f.reset() })
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, harts: &[u32]) -> Option<FdtNode> {
+        let mip = if self.level == Privilege::M { 11 } else { 9 };
+        let mut node = FdtNode::new(&format!("imsics@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["riscv,imsics"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        node.add_prop(FdtProp::null_prop("interrupt-controller"));
+        node.add_prop(FdtProp::u32_prop("#interrupt-cells", vec![0]));
+        node.add_prop(FdtProp::null_prop("msi-controller"));
+        node.add_prop(FdtProp::u32_prop("riscv,num-ids", vec![self.files.first().map_or(0, |f| { f.num_ids() })]));
+        node.add_prop(FdtProp::u32_prop("interrupts-extended", harts.iter().flat_map(|h| { vec![*h, mip] }).collect()));
+        Some(node)
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.files.iter().flat_map(|f| { f.save() }).flat_map(|w| { w.to_le_bytes().to_vec() }).collect()
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data);
+        for f in self.files.iter() {
+            f.restore(&mut reader)?
+        }
+        Ok(())
+    }
+}
+
+impl BytesAccess for Imsic {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(data);
+            U32Access::write(self, addr, u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn read(&self, _: &u64, data: &mut [u8]) {
+        data.iter_mut().for_each(|v| { *v = 0 })
+    }
+}
+
+impl U32Access for Imsic {
+    fn write(&self, addr: &u64, data: u32) {
+        if let Some(file) = self.files.get((*addr / IMSIC_PAGE_SIZE) as usize) {
+            match *addr % IMSIC_PAGE_SIZE {
+                SETEIPNUM_LE => file.send(data),
+                SETEIPNUM_BE => file.send(data.swap_bytes()),
+                _ => {}
+            }
+        }
+    }
+
+    fn read(&self, _: &u64) -> u32 {
+        0
+    }
+}
+
+#[test]
+fn imsic_test() {
+    let eirq = Arc::new(IrqVec::new(6));
+    eirq.set_enable(5).unwrap();
+    let imsic = Imsic::new(Privilege::S, 63, &[eirq.clone()]);
+    let file = imsic.files()[0].clone();
+    U32Access::write(&imsic, &SETEIPNUM_LE, 40);
+    U32Access::write(&imsic, &SETEIPNUM_LE, 5);
+    assert_eq!(file.read_ireg(EIP0, XLen::X64), Some((1 << 40) | (1 << 5)));
+    assert_eq!(file.read_ireg(EIP0 + 1, XLen::X64), None);
+    assert_eq!(file.top(), 0);
+    file.write_ireg(EIE0, XLen::X64, (1 << 40) | (1 << 5)).unwrap();
+    file.write_ireg(EIDELIVERY, XLen::X64, 1).unwrap();
+    assert_eq!(file.top(), 5);
+    assert!(eirq.pending(5).unwrap());
+    file.write_ireg(EITHRESHOLD, XLen::X64, 5).unwrap();
+    assert!(!eirq.pending(5).unwrap());
+    file.write_ireg(EITHRESHOLD, XLen::X64, 0).unwrap();
+    assert_eq!(file.claim(), 5);
+    assert_eq!(file.claim(), 40);
+    assert!(!eirq.pending(5).unwrap());
+}
@@ -1,3 +1,20 @@
 pub mod bus;
+pub mod device;
 pub mod htif;
-pub mod clint;
\ No newline at end of file
+pub mod clint;
+pub mod aclint;
+pub mod plic;
+pub mod imsic;
+pub mod aplic;
+pub mod clic;
+pub mod uart;
+pub mod virtio;
+pub mod virtio_mmio;
+pub mod net;
+pub mod virtio_net;
+pub mod virtio_9p;
+pub mod virtio_console;
+pub mod virtio_rng;
+pub mod syscon;
+pub mod rtc;
+pub mod framebuffer;
@@ -0,0 +1,227 @@
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::fs::FileTypeExt;
+use crate::devices::clint::Timer;
+
+//ethernet frames exchanged by a nic with the world outside of the system
+pub trait NetBackend: Send {
+    fn send(&mut self, frame: &[u8]);
+
+    //next received frame, none if nothing arrived
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NS: u32 = 0xa1b23c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: u32 = 65535;
+
+fn sim_ns(timer: &Timer) -> u64 {
+    (timer.elapsed() as u128 * 1_000_000_000 / timer.freq() as u128) as u64
+}
+
+//remove the socket left at path by a previous run, other files are kept
+pub fn remove_stale_socket(path: &str) -> Result<(), String> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path).map_err(|e| { format!("{}: {}", path, e) }),
+        Ok(_) => Err(format!("{}: file exists and is not a socket!", path)),
+        Err(_) => Ok(())
+    }
+}
+
+//sent frames are written to a pcap file, and frames of an input pcap file are received at their timestamps
+//in simulated time, so the traffic of a run can be captured and replayed
+pub struct PcapBackend {
+    timer: Arc<Timer>,
+    output: Option<File>,
+    //(ns, frame)
+    input: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl PcapBackend {
+    pub fn new(timer: &Arc<Timer>, output: Option<&str>, input: Option<&str>) -> Result<PcapBackend, String> {
+        let output = if let Some(file) = output {
+            let mut f = File::create(file).map_err(|e| { format!("{}: {}", file, e) })?;
+            let header = [PCAP_MAGIC, 0x0004_0002, 0, 0, SNAPLEN, LINKTYPE_ETHERNET];
+            f.write_all(&header.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect::<Vec<u8>>()).map_err(|e| { format!("{}: {}", file, e) })?;
+            Some(f)
+        } else {
+            None
+        };
+        let input = if let Some(file) = input {
+            let content = fs::read(file).map_err(|e| { format!("{}: {}", file, e) })?;
+            PcapBackend::parse(&content).map_err(|e| { format!("{}: {}", file, e) })?
+        } else {
+            VecDeque::new()
+        };
+        Ok(PcapBackend {
+            timer: timer.clone(),
+            output,
+            input,
+        })
+    }
+
+    //little and big endian files, with timestamps in us or ns.
+    //timestamps are rebased on the earliest record, so captures of the host clock are replayed from the start
+    fn parse(content: &[u8]) -> Result<VecDeque<(u64, Vec<u8>)>, String> {
+        let word = |offset: usize, be: bool| -> Result<u32, String> {
+            let bytes = content.get(offset..offset + 4).ok_or("pcap file is truncated!".to_string())?;
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            Ok(if be { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+        };
+        let magic = word(0, false)?;
+        let (be, scale) = match magic {
+            PCAP_MAGIC => (false, 1000),
+            PCAP_MAGIC_NS => (false, 1),
+            _ if magic.swap_bytes() == PCAP_MAGIC => (true, 1000),
+            _ if magic.swap_bytes() == PCAP_MAGIC_NS => (true, 1),
+            _ => return Err("not a pcap file!".to_string())
+        };
+        if word(20, be)? != LINKTYPE_ETHERNET {
+            return Err("link type is not ethernet!".to_string());
+        }
+        let mut frames = VecDeque::new();
+        let mut offset = 24;
+        while offset < content.len() {
+            let ns = word(offset, be)? as u64 * 1_000_000_000 + word(offset + 4, be)? as u64 * scale;
+            let len = word(offset + 8, be)? as usize;
+            let frame = content.get(offset + 16..offset + 16 + len).ok_or("pcap file is truncated!".to_string())?;
+            frames.push_back((ns, frame.to_vec()));
+            offset += 16 + len;
+        }
+        let start = frames.iter().map(|(ns, _)| { *ns }).min().unwrap_or(0);
+        Ok(frames.into_iter().map(|(ns, frame)| { (ns - start, frame) }).collect())
+    }
+}
+
+impl NetBackend for PcapBackend {
+    fn send(&mut self, frame: &[u8]) {
+        if let Some(ref mut f) = self.output {
+            let ns = sim_ns(&self.timer);
+            let len = frame.len().min(SNAPLEN as usize);
+            let header = [(ns / 1_000_000_000) as u32, (ns % 1_000_000_000 / 1000) as u32, len as u32, frame.len() as u32];
+            let record = header.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).chain(frame[..len].iter().cloned()).collect::<Vec<u8>>();
+            if let Err(e) = f.write_all(&record) {
+                eprintln!("pcap: {}", e);
+                self.output = None
+            }
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        match self.input.front() {
+            Some((ns, _)) if *ns <= sim_ns(&self.timer) => self.input.pop_front().map(|(_, frame)| { frame }),
+            _ => None
+        }
+    }
+}
+
+//one end of a cable between two nics in the same process, e.g. of two Systems
+pub struct Loopback {
+    tx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    rx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let a = Arc::new(Mutex::new(VecDeque::new()));
+        let b = Arc::new(Mutex::new(VecDeque::new()));
+        (Loopback { tx: a.clone(), rx: b.clone() }, Loopback { tx: b, rx: a })
+    }
+}
+
+impl NetBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.tx.lock().unwrap().push_back(frame.to_vec())
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.rx.lock().unwrap().pop_front()
+    }
+}
+
+//frames are datagrams between a socket bound at path and the socket at peer, e.g. another terminus.
+//frames are dropped while the peer is absent, like an unplugged cable
+pub struct UnixDgramBackend {
+    socket: UnixDatagram,
+    peer: String,
+}
+
+impl UnixDgramBackend {
+    pub fn new(path: &str, peer: &str) -> Result<UnixDgramBackend, String> {
+        remove_stale_socket(path)?;
+        let socket = UnixDatagram::bind(path).map_err(|e| { format!("{}: {}", path, e) })?;
+        socket.set_nonblocking(true).map_err(|e| { format!("{}: {}", path, e) })?;
+        Ok(UnixDgramBackend {
+            socket,
+            peer: peer.to_string(),
+        })
+    }
+}
+
+impl NetBackend for UnixDgramBackend {
+    fn send(&mut self, frame: &[u8]) {
+        match self.socket.send_to(frame, &self.peer) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::ConnectionRefused || e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => eprintln!("{}: {}", self.peer, e)
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let mut buf = vec![0; SNAPLEN as usize];
+        let len = self.socket.recv(&mut buf).ok()?;
+        buf.truncate(len);
+        Some(buf)
+    }
+}
+
+#[test]
+fn net_backend_test() {
+    let dir = std::env::temp_dir();
+    let pcap = dir.join(format!("terminus_net_test_{}.pcap", std::process::id()));
+    let pcap = pcap.to_str().unwrap();
+    //1 tick is 1us
+    let timer = Arc::new(Timer::new(1_000_000));
+    let mut capture = PcapBackend::new(&timer, Some(pcap), None).unwrap();
+    timer.sync(1_000_000);
+    capture.send(&[1, 2, 3]);
+    timer.sync(1_000_500);
+    capture.send(&[4, 5]);
+    drop(capture);
+    let timer = Arc::new(Timer::new(1_000_000));
+    let mut replay = PcapBackend::new(&timer, None, Some(pcap)).unwrap();
+    assert_eq!(replay.recv(), Some(vec![1, 2, 3]));
+    assert_eq!(replay.recv(), None);
+    timer.sync(499);
+    assert_eq!(replay.recv(), None);
+    timer.sync(500);
+    assert_eq!(replay.recv(), Some(vec![4, 5]));
+    assert_eq!(replay.recv(), None);
+
+    let a = dir.join(format!("terminus_net_test_{}_a", std::process::id()));
+    let b = dir.join(format!("terminus_net_test_{}_b", std::process::id()));
+    let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+    //frames are dropped while the peer is absent
+    let mut backend_a = UnixDgramBackend::new(a, b).unwrap();
+    backend_a.send(&[1]);
+    let mut backend_b = UnixDgramBackend::new(b, a).unwrap();
+    assert_eq!(backend_b.recv(), None);
+    backend_a.send(&[1, 2]);
+    assert_eq!(backend_b.recv(), Some(vec![1, 2]));
+    backend_b.send(&[3]);
+    assert_eq!(backend_a.recv(), Some(vec![3]));
+    //stale sockets are replaced, other files are kept
+    drop(backend_a);
+    let backend_a = UnixDgramBackend::new(a, b).unwrap();
+    drop(backend_a);
+    assert!(UnixDgramBackend::new(pcap, b).is_err());
+    assert!(fs::metadata(pcap).is_ok());
+
+    for file in [pcap, a, b].iter() {
+        fs::remove_file(file).unwrap();
+    }
+}
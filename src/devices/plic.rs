@@ -0,0 +1,302 @@
+use terminus_spaceport::memory::prelude::*;
+use std::sync::{Mutex, Arc};
+use terminus_spaceport::irq::IrqVec;
+use terminus_macros::*;
+use crate::devices::device::{Device, IrqSink, StateReader};
+use crate::system::fdt::{FdtNode, FdtProp};
+
+const PRIORITY_BASE: u64 = 0;
+const PENDING_BASE: u64 = 0x1000;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x200000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const PRIORITY_MASK: u32 = 0x7;
+
+struct PlicInner {
+    //source 0 is reserved
+    priority: Vec<u32>,
+    levels: Vec<bool>,
+    pending: Vec<bool>,
+    claimed: Vec<bool>,
+    enables: Vec<Vec<u32>>,
+    thresholds: Vec<u32>,
+    //(hartid, eirq of the hart, 11 for meip or 9 for seip) of each context, none if the context is not connected
+    contexts: Vec<Option<(usize, Arc<IrqVec>, usize)>>,
+}
+
+impl PlicInner {
+    fn enabled(&self, ctx: usize, id: usize) -> bool {
+        self.enables[ctx][id >> 5] & (1 << (id & 0x1f)) as u32 != 0
+    }
+
+    //pending and enabled source of the highest priority above threshold, lower id wins a tie
+    fn best(&self, ctx: usize) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for id in 1..self.priority.len() {
+            if self.pending[id] && self.enabled(ctx, id) && self.priority[id] > self.thresholds[ctx] && best.iter().all(|b| { self.priority[id] > self.priority[*b] }) {
+                best = Some(id)
+            }
+        }
+        best
+    }
+
+    fn update(&self) {
+        for (ctx, c) in self.contexts.iter().enumerate() {
+            if let Some((_, irq_vec, id)) = c {
+                //eirq lines, 0:meip, 1:seip
+                let line = if *id == 11 { 0 } else { 1 };
+                if self.best(ctx).is_some() {
+                    irq_vec.set_pending(line).unwrap()
+                } else {
+                    irq_vec.clr_pending(line).unwrap()
+                }
+            }
+        }
+    }
+
+    fn claim(&mut self, ctx: usize) -> u32 {
+        if let Some(id) = self.best(ctx) {
+            self.pending[id] = false;
+            self.claimed[id] = true;
+            self.update();
+            id as u32
+        } else {
+            0
+        }
+    }
+
+    //level triggered, the source pends again if it is still asserted
+    fn complete(&mut self, ctx: usize, id: usize) {
+        if id < self.priority.len() && self.enabled(ctx, id) && self.claimed[id] {
+            self.claimed[id] = false;
+            self.pending[id] = self.levels[id];
+            self.update()
+        }
+    }
+}
+
+//SiFive PLIC with level triggered sources 1..=num_sources.
+//contexts are usually (hart0 meip, hart0 seip, hart1 meip...), refer to "interrupts-extended" in device tree
+#[derive_io(Bytes, U32)]
+pub struct Plic(Arc<Mutex<PlicInner>>);
+
+impl Plic {
+    pub fn new(num_sources: usize, contexts: Vec<Option<(usize, Arc<IrqVec>, usize)>>) -> Plic {
+        let words = (num_sources + 1 + 31) >> 5;
+        Plic(Arc::new(Mutex::new(PlicInner {
+            priority: vec![0; num_sources + 1],
+            levels: vec![false; num_sources + 1],
+            pending: vec![false; num_sources + 1],
+            claimed: vec![false; num_sources + 1],
+            enables: vec![vec![0; words]; contexts.len()],
+            thresholds: vec![0; contexts.len()],
+            contexts,
+        })))
+    }
+
+    pub fn num_sources(&self) -> usize {
+        self.0.lock().unwrap().priority.len() - 1
+    }
+
+    //drive source id, out of range ids are ignored
+    pub fn set_irq(&self, id: usize, level: bool) {
+        let mut plic = self.0.lock().unwrap();
+        if id == 0 || id >= plic.priority.len() {
+            return;
+        }
+        plic.levels[id] = level;
+        if !plic.claimed[id] {
+            plic.pending[id] = level;
+        }
+        plic.update()
+    }
+}
+
+impl Clone for Plic {
+    fn clone(&self) -> Plic {
+        Plic(self.0.clone())
+    }
+}
+
+impl IrqSink for Plic {
+    fn set_irq(&self, id: usize, level: bool) {
+        Plic::set_irq(self, id, level)
+    }
+}
+
+impl Device for Plic {
+    //asserted sources are pending again
+    fn reset(&self) {
+        let mut plic = self.0.lock().unwrap();
+        let plic = &mut *plic;
+        plic.priority.iter_mut().for_each(|p| { *p = 0 });
+        plic.claimed.iter_mut().for_each(|c| { *c = false });
+        plic.pending.copy_from_slice(&plic.levels);
+        plic.enables.iter_mut().for_each(|e| { e.iter_mut().for_each(|w| { *w = 0 }) });
+        plic.thresholds.iter_mut().for_each(|t| { *t = 0 });
+        plic.update()
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, harts: &[u32]) -> Option<FdtNode> {
+        let plic = self.0.lock().unwrap();
+        let mut node = FdtNode::new(&format!("plic@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["sifive,plic-1.0.0", "riscv,plic0"]));
+        node.add_prop(FdtProp::u32_prop("#address-cells", vec![0]));
+        node.add_prop(FdtProp::u32_prop("#interrupt-cells", vec![1]));
+        node.add_prop(FdtProp::null_prop("interrupt-controller"));
+        node.add_prop(FdtProp::u32_prop("riscv,ndev", vec![plic.priority.len() as u32 - 1]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        //unconnected contexts are skipped by invalid phandles
+        let interrupts_extended = plic.contexts.iter().flat_map(|c| {
+            match c {
+                Some((hartid, _, id)) => vec![harts[*hartid], *id as u32],
+                None => vec![u32::MAX, u32::MAX]
+            }
+        }).collect();
+        node.add_prop(FdtProp::u32_prop("interrupts-extended", interrupts_extended));
+        Some(node)
+    }
+
+    fn irq_sink(&self) -> Option<Arc<dyn IrqSink>> {
+        Some(Arc::new(self.clone()))
+    }
+
+    //priority, level, pending and claimed of sources, then threshold and enables of contexts
+    fn save(&self) -> Vec<u8> {
+        let plic = self.0.lock().unwrap();
+        let mut words = vec![];
+        for id in 0..plic.priority.len() {
+            words.extend_from_slice(&[plic.priority[id], plic.levels[id] as u32, plic.pending[id] as u32, plic.claimed[id] as u32])
+        }
+        for ctx in 0..plic.contexts.len() {
+            words.push(plic.thresholds[ctx]);
+            words.extend_from_slice(&plic.enables[ctx])
+        }
+        words.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect()
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut plic = self.0.lock().unwrap();
+        let mut reader = StateReader::new(data);
+        for id in 0..plic.priority.len() {
+            plic.priority[id] = reader.u32()?;
+            plic.levels[id] = reader.u32()? != 0;
+            plic.pending[id] = reader.u32()? != 0;
+            plic.claimed[id] = reader.u32()? != 0;
+        }
+        for ctx in 0..plic.contexts.len() {
+            plic.thresholds[ctx] = reader.u32()?;
+            for w in 0..plic.enables[ctx].len() {
+                plic.enables[ctx][w] = reader.u32()?;
+            }
+        }
+        plic.update();
+        Ok(())
+    }
+}
+
+impl BytesAccess for Plic {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(data);
+            U32Access::write(self, addr, u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        if data.len() == 4 {
+            data.copy_from_slice(&U32Access::read(self, addr).to_le_bytes())
+        }
+    }
+}
+
+impl U32Access for Plic {
+    fn write(&self, addr: &u64, data: u32) {
+        let mut plic = self.0.lock().unwrap();
+        let sources = plic.priority.len() as u64;
+        let contexts = plic.contexts.len() as u64;
+        if *addr < PENDING_BASE {
+            let id = ((*addr - PRIORITY_BASE) >> 2) as usize;
+            if id != 0 && (id as u64) < sources {
+                plic.priority[id] = data & PRIORITY_MASK;
+            }
+        } else if *addr >= ENABLE_BASE && *addr < ENABLE_BASE + ENABLE_STRIDE * contexts {
+            let ctx = ((*addr - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let word = (((*addr - ENABLE_BASE) % ENABLE_STRIDE) >> 2) as usize;
+            if word < plic.enables[ctx].len() {
+                //source 0 does not exist
+                plic.enables[ctx][word] = if word == 0 { data & !1 } else { data };
+            }
+        } else if *addr >= CONTEXT_BASE && *addr < CONTEXT_BASE + CONTEXT_STRIDE * contexts {
+            let ctx = ((*addr - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+            match (*addr - CONTEXT_BASE) % CONTEXT_STRIDE {
+                0 => plic.thresholds[ctx] = data & PRIORITY_MASK,
+                4 => plic.complete(ctx, data as usize),
+                _ => return
+            }
+        } else {
+            return;
+        }
+        plic.update()
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        let mut plic = self.0.lock().unwrap();
+        let sources = plic.priority.len() as u64;
+        let contexts = plic.contexts.len() as u64;
+        if *addr < PENDING_BASE {
+            let id = ((*addr - PRIORITY_BASE) >> 2) as usize;
+            if (id as u64) < sources { plic.priority[id] } else { 0 }
+        } else if *addr < ENABLE_BASE {
+            let word = ((*addr - PENDING_BASE) >> 2) as usize;
+            (0..32).map(|i| { word * 32 + i }).filter(|id| { *id < plic.pending.len() && plic.pending[*id] }).fold(0, |v, id| { v | 1 << (id & 0x1f) as u32 })
+        } else if *addr < ENABLE_BASE + ENABLE_STRIDE * contexts {
+            let ctx = ((*addr - ENABLE_BASE) / ENABLE_STRIDE) as usize;
+            let word = (((*addr - ENABLE_BASE) % ENABLE_STRIDE) >> 2) as usize;
+            plic.enables[ctx].get(word).cloned().unwrap_or(0)
+        } else if *addr >= CONTEXT_BASE && *addr < CONTEXT_BASE + CONTEXT_STRIDE * contexts {
+            let ctx = ((*addr - CONTEXT_BASE) / CONTEXT_STRIDE) as usize;
+            match (*addr - CONTEXT_BASE) % CONTEXT_STRIDE {
+                0 => plic.thresholds[ctx],
+                4 => plic.claim(ctx),
+                _ => 0
+            }
+        } else {
+            0
+        }
+    }
+}
+
+#[test]
+fn plic_test() {
+    let irq_vec = Arc::new(IrqVec::new(2));
+    irq_vec.set_enable(0).unwrap();
+    irq_vec.set_enable(1).unwrap();
+    let plic = Plic::new(40, vec![Some((0, irq_vec.clone(), 11)), Some((0, irq_vec.clone(), 9))]);
+    U32Access::write(&plic, &(PRIORITY_BASE + 4 * 33), 2);
+    U32Access::write(&plic, &(PRIORITY_BASE + 4 * 3), 1);
+    U32Access::write(&plic, &(ENABLE_BASE + 4), 1 << 1);
+    U32Access::write(&plic, &ENABLE_BASE, 1 << 3);
+    plic.set_irq(3, true);
+    plic.set_irq(33, true);
+    assert_eq!(U32Access::read(&plic, &(PENDING_BASE + 4)), 1 << 1);
+    assert!(irq_vec.pending(0).unwrap());
+    assert!(!irq_vec.pending(1).unwrap());
+    assert_eq!(U32Access::read(&plic, &(CONTEXT_BASE + 4)), 33);
+    assert_eq!(U32Access::read(&plic, &(CONTEXT_BASE + 4)), 3);
+    assert!(!irq_vec.pending(0).unwrap());
+    plic.set_irq(33, false);
+    U32Access::write(&plic, &(CONTEXT_BASE + 4), 33);
+    U32Access::write(&plic, &(CONTEXT_BASE + 4), 3);
+    //source 3 is still asserted
+    assert!(irq_vec.pending(0).unwrap());
+    U32Access::write(&plic, &CONTEXT_BASE, 1);
+    assert!(!irq_vec.pending(0).unwrap());
+    let state = plic.save();
+    plic.reset();
+    assert_eq!(U32Access::read(&plic, &(PRIORITY_BASE + 4 * 3)), 0);
+    plic.restore(&state).unwrap();
+    assert_eq!(U32Access::read(&plic, &CONTEXT_BASE), 1);
+}
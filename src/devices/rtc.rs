@@ -0,0 +1,270 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_macros::*;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::devices::clint::{Timer, EventId};
+use crate::devices::device::{Device, IrqLine, StateReader};
+use crate::system::fdt::{FdtNode, FdtProp};
+
+const TIME_LOW: u64 = 0x00;
+const TIME_HIGH: u64 = 0x04;
+const ALARM_LOW: u64 = 0x08;
+const ALARM_HIGH: u64 = 0x0c;
+const IRQ_ENABLED: u64 = 0x10;
+const CLEAR_ALARM: u64 = 0x14;
+const ALARM_STATUS: u64 = 0x18;
+const CLEAR_INTERRUPT: u64 = 0x1c;
+
+pub const RTC_SIZE: u64 = 0x1000;
+
+const NS_PER_SEC: u128 = 1_000_000_000;
+
+//source of the wall clock in ns since unix epoch
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RtcClock {
+    Host,
+    //starts from the epoch and advances with the simulated time, so runs are deterministic
+    Fixed(u64),
+}
+
+struct RtcInner {
+    timer: Arc<Timer>,
+    clock: RtcClock,
+    //set by writing time, wrapping
+    offset: u64,
+    //latched by reading TIME_LOW
+    time_high: u32,
+    alarm: u64,
+    alarm_event: Option<EventId>,
+    irq_enabled: bool,
+    irq_pending: bool,
+    irq: Option<IrqLine>,
+}
+
+impl RtcInner {
+    fn clock(&self) -> u64 {
+        match self.clock {
+            RtcClock::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| { d.as_nanos() as u64 }),
+            RtcClock::Fixed(epoch) => epoch.wrapping_add((self.timer.elapsed() as u128 * NS_PER_SEC / self.timer.freq() as u128) as u64)
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock().wrapping_add(self.offset)
+    }
+
+    fn set_now(&mut self, time: u64) {
+        self.offset = time.wrapping_sub(self.clock())
+    }
+
+    fn update_irq(&self) {
+        if let Some(ref irq) = self.irq {
+            irq.set(self.irq_enabled && self.irq_pending)
+        }
+    }
+
+    fn clear_alarm(&mut self) {
+        if let Some(id) = self.alarm_event.take() {
+            self.timer.cancel(&id);
+        }
+    }
+
+    //fire the alarm if it is due, otherwise check it again when it should be due in simulated time.
+    //the host clock may run slower than the simulated one, so the alarm is checked until it fires
+    fn arm(&mut self, this: Weak<Mutex<RtcInner>>) {
+        self.clear_alarm();
+        let now = self.now();
+        if now >= self.alarm {
+            self.irq_pending = true;
+            self.update_irq();
+            return;
+        }
+        let delay = ((self.alarm - now) as u128 * self.timer.freq() as u128).div_ceil(NS_PER_SEC);
+        let id = self.timer.schedule_after(delay.min(u64::MAX as u128) as u64, move |_| {
+            if let Some(inner) = this.upgrade() {
+                let mut rtc = inner.lock().unwrap();
+                rtc.alarm_event = None;
+                rtc.arm(Arc::downgrade(&inner))
+            }
+        });
+        self.alarm_event = Some(id)
+    }
+
+    fn reset(&mut self) {
+        self.clear_alarm();
+        self.time_high = 0;
+        self.alarm = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.update_irq()
+    }
+}
+
+//goldfish rtc, time and alarm are in ns since unix epoch. the time written by software is kept as an offset
+//to the clock, so it survives resets
+#[derive_io(Bytes, U32)]
+pub struct GoldfishRtc {
+    inner: Arc<Mutex<RtcInner>>,
+}
+
+impl GoldfishRtc {
+    pub fn new(timer: &Arc<Timer>, clock: RtcClock, irq: Option<IrqLine>) -> GoldfishRtc {
+        GoldfishRtc {
+            inner: Arc::new(Mutex::new(RtcInner {
+                timer: timer.clone(),
+                clock,
+                offset: 0,
+                time_high: 0,
+                alarm: 0,
+                alarm_event: None,
+                irq_enabled: false,
+                irq_pending: false,
+                irq,
+            }))
+        }
+    }
+}
+
+impl Clone for GoldfishRtc {
+    fn clone(&self) -> GoldfishRtc {
+        GoldfishRtc {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Device for GoldfishRtc {
+    fn reset(&self) {
+        self.inner.lock().unwrap().reset()
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, _harts: &[u32]) -> Option<FdtNode> {
+        let mut node = FdtNode::new(&format!("rtc@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["google,goldfish-rtc"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        Some(node)
+    }
+
+    fn irq_lines(&self) -> Vec<IrqLine> {
+        self.inner.lock().unwrap().irq.iter().cloned().collect()
+    }
+
+    //time, alarm, time_high, then whether the alarm is running, irq_enabled and irq_pending
+    fn save(&self) -> Vec<u8> {
+        let rtc = self.inner.lock().unwrap();
+        let now = rtc.now();
+        let words = [now as u32, (now >> 32) as u32, rtc.alarm as u32, (rtc.alarm >> 32) as u32, rtc.time_high,
+            rtc.alarm_event.is_some() as u32, rtc.irq_enabled as u32, rtc.irq_pending as u32];
+        words.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect()
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut rtc = self.inner.lock().unwrap();
+        let mut reader = StateReader::new(data);
+        let now = reader.u32()? as u64 | ((reader.u32()? as u64) << 32);
+        rtc.set_now(now);
+        rtc.alarm = reader.u32()? as u64 | ((reader.u32()? as u64) << 32);
+        rtc.time_high = reader.u32()?;
+        let running = reader.u32()? != 0;
+        rtc.irq_enabled = reader.u32()? != 0;
+        rtc.irq_pending = reader.u32()? != 0;
+        rtc.clear_alarm();
+        if running {
+            rtc.arm(Arc::downgrade(&self.inner))
+        }
+        rtc.update_irq();
+        Ok(())
+    }
+}
+
+impl BytesAccess for GoldfishRtc {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(data);
+            U32Access::write(self, addr, u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        if data.len() == 4 {
+            data.copy_from_slice(&U32Access::read(self, addr).to_le_bytes())
+        }
+    }
+}
+
+impl U32Access for GoldfishRtc {
+    fn write(&self, addr: &u64, data: u32) {
+        let mut rtc = self.inner.lock().unwrap();
+        match *addr {
+            TIME_LOW => {
+                let now = rtc.now();
+                rtc.set_now((now & !0xffff_ffff) | data as u64)
+            }
+            TIME_HIGH => {
+                let now = rtc.now();
+                rtc.set_now((now & 0xffff_ffff) | ((data as u64) << 32))
+            }
+            ALARM_LOW => {
+                rtc.alarm = (rtc.alarm & !0xffff_ffff) | data as u64;
+                rtc.arm(Arc::downgrade(&self.inner))
+            }
+            ALARM_HIGH => rtc.alarm = (rtc.alarm & 0xffff_ffff) | ((data as u64) << 32),
+            IRQ_ENABLED => {
+                rtc.irq_enabled = data & 1 == 1;
+                rtc.update_irq()
+            }
+            CLEAR_ALARM => rtc.clear_alarm(),
+            CLEAR_INTERRUPT => {
+                rtc.irq_pending = false;
+                rtc.update_irq()
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        let mut rtc = self.inner.lock().unwrap();
+        match *addr {
+            TIME_LOW => {
+                let now = rtc.now();
+                rtc.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => rtc.time_high,
+            ALARM_LOW => rtc.alarm as u32,
+            ALARM_HIGH => (rtc.alarm >> 32) as u32,
+            IRQ_ENABLED => rtc.irq_enabled as u32,
+            ALARM_STATUS => rtc.alarm_event.is_some() as u32,
+            _ => 0
+        }
+    }
+}
+
+#[test]
+fn rtc_test() {
+    let timer = Arc::new(Timer::new(1000));
+    let rtc = GoldfishRtc::new(&timer, RtcClock::Fixed(5_000_000_000), None);
+    let time = |rtc: &GoldfishRtc| { U32Access::read(rtc, &TIME_LOW) as u64 | ((U32Access::read(rtc, &TIME_HIGH) as u64) << 32) };
+    assert_eq!(time(&rtc), 5_000_000_000);
+    timer.sync(1500);
+    assert_eq!(time(&rtc), 6_500_000_000);
+    U32Access::write(&rtc, &TIME_HIGH, 0);
+    U32Access::write(&rtc, &TIME_LOW, 1_000_000_000);
+    assert_eq!(time(&rtc), 1_000_000_000);
+    //alarm 2ms later
+    U32Access::write(&rtc, &ALARM_HIGH, 0);
+    U32Access::write(&rtc, &ALARM_LOW, 1_002_000_000);
+    U32Access::write(&rtc, &IRQ_ENABLED, 1);
+    assert_eq!(U32Access::read(&rtc, &ALARM_STATUS), 1);
+    timer.sync(1501);
+    assert_eq!(U32Access::read(&rtc, &ALARM_STATUS), 1);
+    timer.sync(1502);
+    assert_eq!(U32Access::read(&rtc, &ALARM_STATUS), 0);
+    assert!(rtc.inner.lock().unwrap().irq_pending);
+    U32Access::write(&rtc, &CLEAR_INTERRUPT, 1);
+    assert!(!rtc.inner.lock().unwrap().irq_pending);
+    //time is kept by reset
+    rtc.reset();
+    assert_eq!(time(&rtc), 1_002_000_000);
+}
@@ -0,0 +1,94 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_spaceport::EXIT_CTRL;
+use terminus_macros::*;
+use crate::processor::env::{ExitCode, ResetRequest};
+use crate::devices::device::Device;
+use crate::system::fdt::{FdtNode, FdtProp};
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+//sifive,test0 finisher behind syscon-poweroff/syscon-reboot, the status of fail is in the high 16 bits.
+//poweroff stops the simulation with the status, reset requests a reboot of the system
+#[derive_io(Bytes, U32)]
+pub struct SifiveTest {
+    exit_code: ExitCode,
+    reset: ResetRequest,
+}
+
+impl SifiveTest {
+    pub fn new(exit_code: &ExitCode, reset: &ResetRequest) -> SifiveTest {
+        SifiveTest {
+            exit_code: exit_code.clone(),
+            reset: reset.clone(),
+        }
+    }
+}
+
+impl Clone for SifiveTest {
+    fn clone(&self) -> SifiveTest {
+        SifiveTest {
+            exit_code: self.exit_code.clone(),
+            reset: self.reset.clone(),
+        }
+    }
+}
+
+//syscon-poweroff and syscon-reboot children, they use the regmap of the parent
+fn finisher_node(name: &str, value: u32) -> FdtNode {
+    let mut node = FdtNode::new(name);
+    node.add_prop(FdtProp::str_prop("compatible", vec![&format!("syscon-{}", name)]));
+    node.add_prop(FdtProp::u32_prop("offset", vec![0]));
+    node.add_prop(FdtProp::u32_prop("value", vec![value]));
+    node
+}
+
+impl Device for SifiveTest {
+    fn fdt_node(&self, base: u64, size: u64, _harts: &[u32]) -> Option<FdtNode> {
+        let mut node = FdtNode::new(&format!("test@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["sifive,test1", "sifive,test0", "syscon", "simple-mfd"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        node.add_node(finisher_node("poweroff", FINISHER_PASS));
+        node.add_node(finisher_node("reboot", FINISHER_RESET));
+        Some(node)
+    }
+}
+
+impl BytesAccess for SifiveTest {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if data.len() == 4 {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(data);
+            U32Access::write(self, addr, u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn read(&self, _: &u64, data: &mut [u8]) {
+        data.iter_mut().for_each(|v| { *v = 0 })
+    }
+}
+
+impl U32Access for SifiveTest {
+    fn write(&self, addr: &u64, data: u32) {
+        if *addr != 0 {
+            return;
+        }
+        match data & 0xffff {
+            FINISHER_PASS => {
+                self.exit_code.set(0);
+                EXIT_CTRL.exit("poweroff!").unwrap()
+            }
+            FINISHER_FAIL => {
+                self.exit_code.set((data >> 16) as u64);
+                EXIT_CTRL.exit(&format!("poweroff with status {}!", data >> 16)).unwrap()
+            }
+            FINISHER_RESET => self.reset.request(),
+            _ => {}
+        }
+    }
+
+    fn read(&self, _: &u64) -> u32 {
+        0
+    }
+}
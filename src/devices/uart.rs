@@ -0,0 +1,320 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_spaceport::devices::TERM;
+use std::sync::{Mutex, Arc};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use terminus_macros::*;
+use crate::devices::device::{Device, IrqLine, StateReader};
+use crate::devices::clint::Timer;
+use crate::system::fdt::{FdtNode, FdtProp};
+
+const RBR_THR: u64 = 0;
+const IER: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDI: u8 = 0x1;
+const IER_THRI: u8 = 0x2;
+const IIR_NO_INT: u8 = 0x1;
+const IIR_THRI: u8 = 0x2;
+const IIR_RDI: u8 = 0x4;
+const IIR_FIFO: u8 = 0xc0;
+const FCR_ENABLE: u8 = 0x1;
+const FCR_CLEAR_RCVR: u8 = 0x2;
+const LCR_DLAB: u8 = 0x80;
+const MCR_LOOP: u8 = 0x10;
+const LSR_DR: u8 = 0x1;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+const FIFO_SIZE: usize = 16;
+
+struct UartInner {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    fcr: u8,
+    rx: VecDeque<u8>,
+    //transmitter is always empty, the interrupt is cleared by reading iir or writing thr
+    thr_int: bool,
+    irq: Option<IrqLine>,
+}
+
+impl UartInner {
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & FCR_ENABLE != 0 { IIR_FIFO } else { 0 };
+        fifo | if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thr_int {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    fn update_irq(&self) {
+        if let Some(ref irq) = self.irq {
+            irq.set(self.iir() & IIR_NO_INT == 0)
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.mcr & MCR_LOOP != 0 {
+            return;
+        }
+        while self.rx.len() < FIFO_SIZE {
+            let mut data = [0u8; 1];
+            match TERM.stdin().lock().read_exact(&mut data) {
+                Ok(_) => self.rx.push_back(data[0]),
+                //no input, or input is closed
+                Err(_) => break
+            }
+        }
+        self.update_irq()
+    }
+
+    fn read(&mut self, offset: u64) -> u8 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.dll,
+            RBR_THR => {
+                self.poll();
+                let data = self.rx.pop_front().unwrap_or(0);
+                self.update_irq();
+                data
+            }
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR_FCR => {
+                let iir = self.iir();
+                if iir & 0xf == IIR_THRI {
+                    self.thr_int = false;
+                    self.update_irq()
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll();
+                LSR_THRE | LSR_TEMT | if self.rx.is_empty() { 0 } else { LSR_DR }
+            }
+            //loopback: cts = rts, dsr = dtr, ri = out1, dcd = out2. otherwise cts, dsr and dcd are asserted
+            MSR if self.mcr & MCR_LOOP != 0 => (self.mcr & 0x2) << 3 | (self.mcr & 0x1) << 5 | (self.mcr & 0xc) << 4,
+            MSR => 0xb0,
+            SCR => self.scr,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: u8) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => self.dll = data,
+            RBR_THR => {
+                if self.mcr & MCR_LOOP != 0 {
+                    if self.rx.len() < FIFO_SIZE {
+                        self.rx.push_back(data)
+                    }
+                } else {
+                    let stdout = TERM.stdout();
+                    let mut handle = stdout.lock();
+                    handle.write_all(&[data]).unwrap();
+                    handle.flush().unwrap();
+                }
+                self.thr_int = true
+            }
+            IER if dlab => self.dlm = data,
+            IER => {
+                //enabling thr interrupt with empty transmitter raises it
+                if data & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thr_int = true
+                }
+                self.ier = data & 0xf
+            }
+            IIR_FCR => {
+                if data & FCR_CLEAR_RCVR != 0 {
+                    self.rx.clear()
+                }
+                self.fcr = data & FCR_ENABLE
+            }
+            LCR => self.lcr = data,
+            MCR => self.mcr = data & 0x1f,
+            SCR => self.scr = data,
+            _ => {}
+        }
+        self.update_irq()
+    }
+}
+
+//ns16550a on the terminal, registers are placed at (index << reg_shift), only the low byte of wider access is used.
+//input is polled when software reads rbr or lsr, and by poll_input() for receive interrupts
+#[derive_io(Bytes, U8, U32)]
+pub struct Ns16550a {
+    inner: Arc<Mutex<UartInner>>,
+    reg_shift: u32,
+}
+
+impl Ns16550a {
+    pub fn new(reg_shift: u32, irq: Option<IrqLine>) -> Ns16550a {
+        Ns16550a {
+            inner: Arc::new(Mutex::new(UartInner {
+                ier: 0,
+                lcr: 0,
+                mcr: 0,
+                scr: 0,
+                dll: 0,
+                dlm: 0,
+                fcr: 0,
+                rx: VecDeque::new(),
+                thr_int: false,
+                irq,
+            })),
+            reg_shift,
+        }
+    }
+
+    //poll the terminal every period ticks of timer
+    pub fn poll_input(&self, timer: &Arc<Timer>, period: u64) {
+        fn schedule(inner: Arc<Mutex<UartInner>>, timer: &Arc<Timer>, period: u64) {
+            let weak = Arc::downgrade(timer);
+            timer.schedule_after(period, move |_| {
+                inner.lock().unwrap().poll();
+                if let Some(timer) = weak.upgrade() {
+                    schedule(inner, &timer, period)
+                }
+            });
+        }
+        schedule(self.inner.clone(), timer, period)
+    }
+
+    fn offset(&self, addr: &u64) -> u64 {
+        *addr >> self.reg_shift
+    }
+}
+
+impl Clone for Ns16550a {
+    fn clone(&self) -> Ns16550a {
+        Ns16550a {
+            inner: self.inner.clone(),
+            reg_shift: self.reg_shift,
+        }
+    }
+}
+
+impl Device for Ns16550a {
+    fn reset(&self) {
+        let mut uart = self.inner.lock().unwrap();
+        uart.ier = 0;
+        uart.lcr = 0;
+        uart.mcr = 0;
+        uart.scr = 0;
+        uart.dll = 0;
+        uart.dlm = 0;
+        uart.fcr = 0;
+        uart.rx.clear();
+        uart.thr_int = false;
+        uart.update_irq()
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, _harts: &[u32]) -> Option<FdtNode> {
+        let mut node = FdtNode::new(&format!("uart@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["ns16550a"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        node.add_prop(FdtProp::u32_prop("clock-frequency", vec![3686400]));
+        if self.reg_shift != 0 {
+            node.add_prop(FdtProp::u32_prop("reg-shift", vec![self.reg_shift]));
+        }
+        Some(node)
+    }
+
+    fn irq_lines(&self) -> Vec<IrqLine> {
+        self.inner.lock().unwrap().irq.iter().cloned().collect()
+    }
+
+    //registers, thr interrupt, then received bytes
+    fn save(&self) -> Vec<u8> {
+        let uart = self.inner.lock().unwrap();
+        let mut state = vec![uart.ier, uart.lcr, uart.mcr, uart.scr, uart.dll, uart.dlm, uart.fcr, uart.thr_int as u8];
+        state.extend(uart.rx.iter());
+        state
+    }
+
+    fn restore(&self, data: &[u8]) -> Result<(), String> {
+        let mut uart = self.inner.lock().unwrap();
+        let mut reader = StateReader::new(data);
+        let regs = reader.bytes(8)?;
+        let rx = reader.bytes(data.len() - 8)?;
+        if rx.len() > FIFO_SIZE {
+            return Err("uart fifo overflow!".to_string());
+        }
+        uart.ier = regs[0];
+        uart.lcr = regs[1];
+        uart.mcr = regs[2];
+        uart.scr = regs[3];
+        uart.dll = regs[4];
+        uart.dlm = regs[5];
+        uart.fcr = regs[6];
+        uart.thr_int = regs[7] != 0;
+        uart.rx = rx.iter().cloned().collect();
+        uart.update_irq();
+        Ok(())
+    }
+}
+
+impl BytesAccess for Ns16550a {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if let Some(v) = data.first() {
+            U8Access::write(self, addr, *v)
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        for (i, v) in data.iter_mut().enumerate() {
+            *v = if i == 0 { U8Access::read(self, addr) } else { 0 }
+        }
+    }
+}
+
+impl U8Access for Ns16550a {
+    fn write(&self, addr: &u64, data: u8) {
+        self.inner.lock().unwrap().write(self.offset(addr), data)
+    }
+
+    fn read(&self, addr: &u64) -> u8 {
+        self.inner.lock().unwrap().read(self.offset(addr))
+    }
+}
+
+impl U32Access for Ns16550a {
+    fn write(&self, addr: &u64, data: u32) {
+        U8Access::write(self, addr, data as u8)
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        U8Access::read(self, addr) as u32
+    }
+}
+
+#[test]
+fn ns16550a_test() {
+    let uart = Ns16550a::new(0, None);
+    U8Access::write(&uart, &MCR, MCR_LOOP);
+    U8Access::write(&uart, &IER, IER_RDI | IER_THRI);
+    assert_eq!(U8Access::read(&uart, &IIR_FCR), IIR_THRI);
+    assert_eq!(U8Access::read(&uart, &IIR_FCR), IIR_NO_INT);
+    U8Access::write(&uart, &RBR_THR, b'a');
+    assert_eq!(U8Access::read(&uart, &LSR), LSR_THRE | LSR_TEMT | LSR_DR);
+    assert_eq!(U8Access::read(&uart, &IIR_FCR), IIR_RDI);
+    assert_eq!(U8Access::read(&uart, &RBR_THR), b'a');
+    assert_eq!(U8Access::read(&uart, &IIR_FCR), IIR_THRI);
+    U8Access::write(&uart, &LCR, LCR_DLAB);
+    U8Access::write(&uart, &RBR_THR, 0x12);
+    assert_eq!(U8Access::read(&uart, &RBR_THR), 0x12);
+}
@@ -0,0 +1,235 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_spaceport::memory::region::Region;
+use std::sync::Arc;
+
+//virtio 1.1 split virtqueues, devices behind the virtio-mmio transport implement VirtioDevice
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+//guest physical memory seen by dma of devices, made of the memories registered to System
+#[derive(Clone, Default)]
+pub struct GuestMemory(Vec<Arc<Region>>);
+
+impl GuestMemory {
+    pub fn new(regions: Vec<Arc<Region>>) -> GuestMemory {
+        GuestMemory(regions)
+    }
+
+    fn region(&self, addr: u64, len: usize) -> Result<&Arc<Region>, u64> {
+        self.0.iter().find(|r| {
+            addr >= r.info.base && matches!(addr.checked_add(len as u64), Some(end) if end <= r.info.base + r.info.size)
+        }).ok_or(addr)
+    }
+
+    //accesses crossing memories fail with the start address
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> Result<(), u64> {
+        BytesAccess::read(self.region(addr, data.len())?.as_ref(), &addr, data);
+        Ok(())
+    }
+
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<(), u64> {
+        BytesAccess::write(self.region(addr, data.len())?.as_ref(), &addr, data);
+        Ok(())
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Result<u16, u64> {
+        let mut data = [0; 2];
+        self.read(addr, &mut data)?;
+        Ok(u16::from_le_bytes(data))
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32, u64> {
+        let mut data = [0; 4];
+        self.read(addr, &mut data)?;
+        Ok(u32::from_le_bytes(data))
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Result<u64, u64> {
+        let mut data = [0; 8];
+        self.read(addr, &mut data)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    pub fn write_u16(&self, addr: u64, value: u16) -> Result<(), u64> {
+        self.write(addr, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&self, addr: u64, value: u32) -> Result<(), u64> {
+        self.write(addr, &value.to_le_bytes())
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Desc {
+    pub addr: u64,
+    pub len: u32,
+    pub write: bool,
+}
+
+//buffers of one request, device-readable ones are followed by device-writable ones
+pub struct DescChain {
+    head: u16,
+    pub descs: Vec<Desc>,
+}
+
+impl DescChain {
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    pub fn readable_len(&self) -> usize {
+        self.descs.iter().filter(|d| { !d.write }).map(|d| { d.len as usize }).sum()
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.descs.iter().filter(|d| { d.write }).map(|d| { d.len as usize }).sum()
+    }
+
+    //all device-readable bytes
+    pub fn read_all(&self, mem: &GuestMemory) -> Result<Vec<u8>, u64> {
+        let mut data = vec![];
+        for d in self.descs.iter().filter(|d| { !d.write }) {
+            //a bogus length fails before it is allocated
+            mem.region(d.addr, d.len as usize)?;
+            let mut buf = vec![0; d.len as usize];
+            mem.read(d.addr, &mut buf)?;
+            data.extend_from_slice(&buf);
+        }
+        Ok(data)
+    }
+
+    //scatter data to device-writable buffers, return bytes written, data beyond the buffers is dropped
+    pub fn write_all(&self, mem: &GuestMemory, data: &[u8]) -> Result<usize, u64> {
+        let mut written = 0;
+        for d in self.descs.iter().filter(|d| { d.write }) {
+            if written == data.len() {
+                break;
+            }
+            let len = (d.len as usize).min(data.len() - written);
+            mem.write(d.addr, &data[written..written + len])?;
+            written += len;
+        }
+        Ok(written)
+    }
+}
+
+//split virtqueue configured by the driver through the transport
+#[derive(Default)]
+pub struct Queue {
+    pub num: u16,
+    pub ready: bool,
+    pub desc: u64,
+    pub driver: u64,
+    pub device: u64,
+    last_avail: u16,
+    used_idx: u16,
+}
+
+impl Queue {
+    pub fn reset(&mut self) {
+        *self = Queue::default()
+    }
+
+    //there are requests not popped yet
+    pub fn has_avail(&self, mem: &GuestMemory) -> bool {
+        self.ready && self.num != 0 && matches!(mem.read_u16(self.driver.wrapping_add(2)), Ok(idx) if idx != self.last_avail)
+    }
+
+    //next request of the driver, none if the queue is empty or broken.
+    //addresses written by the driver wrap around instead of overflowing
+    pub fn pop(&mut self, mem: &GuestMemory) -> Option<DescChain> {
+        if !self.has_avail(mem) {
+            return None;
+        }
+        let head = mem.read_u16(self.driver.wrapping_add(4 + 2 * (self.last_avail % self.num) as u64)).ok()?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        let mut descs = vec![];
+        let mut i = head;
+        //a chain can not be longer than the queue, loops are broken
+        for _ in 0..self.num {
+            if i >= self.num {
+                return None;
+            }
+            let entry = self.desc.wrapping_add(16 * i as u64);
+            let flags = mem.read_u16(entry.wrapping_add(12)).ok()?;
+            descs.push(Desc {
+                addr: mem.read_u64(entry).ok()?,
+                len: mem.read_u32(entry.wrapping_add(8)).ok()?,
+                write: flags & DESC_F_WRITE != 0,
+            });
+            if flags & DESC_F_NEXT == 0 {
+                return Some(DescChain { head, descs });
+            }
+            i = mem.read_u16(entry.wrapping_add(14)).ok()?;
+        }
+        None
+    }
+
+    //return the request to the driver with the bytes written to it
+    pub fn push(&mut self, mem: &GuestMemory, head: u16, len: u32) -> Result<(), u64> {
+        let entry = self.device.wrapping_add(4 + 8 * (self.used_idx % self.num) as u64);
+        mem.write_u32(entry, head as u32)?;
+        mem.write_u32(entry.wrapping_add(4), len)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        mem.write_u16(self.device.wrapping_add(2), self.used_idx)
+    }
+}
+
+//device type behind the transport, queues are owned by the transport and handed to the device
+pub trait VirtioDevice: Send {
+    fn device_id(&self) -> u32;
+
+    //VIRTIO_F_VERSION_1 is added by the transport
+    fn features(&self) -> u64;
+
+    fn num_queues(&self) -> usize;
+
+    fn queue_max(&self) -> u16 {
+        256
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    //driver is ok with the features
+    fn activate(&mut self, _features: u64) {}
+
+    fn reset(&mut self) {}
+
+    //the driver notified queue, return true if used buffers were returned
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], mem: &GuestMemory) -> bool;
+
+    //called periodically for input from the host
+    fn poll(&mut self, _queues: &mut [Queue], _mem: &GuestMemory) -> bool {
+        false
+    }
+}
+
+//copy a config struct to an access of config space
+pub fn read_config_bytes(config: &[u8], offset: u64, data: &mut [u8]) {
+    for (i, v) in data.iter_mut().enumerate() {
+        *v = config.get(offset as usize + i).cloned().unwrap_or(0)
+    }
+}
+
+//a queue of 8 entries at base with one request, whose only buffer is at base + 0x800
+#[cfg(test)]
+pub fn test_queue(mem: &GuestMemory, base: u64, len: u32, write: bool) -> Queue {
+    mem.write(base, &(base + 0x800).to_le_bytes()).unwrap();
+    mem.write_u32(base + 8, len).unwrap();
+    mem.write_u16(base + 12, if write { 2 } else { 0 }).unwrap();
+    mem.write_u16(base + 0x200 + 4, 0).unwrap();
+    mem.write_u16(base + 0x200 + 2, 1).unwrap();
+    Queue {
+        num: 8,
+        ready: true,
+        desc: base,
+        driver: base + 0x200,
+        device: base + 0x400,
+        ..Queue::default()
+    }
+}
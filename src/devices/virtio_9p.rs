@@ -0,0 +1,759 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions, DirBuilder, FileTimes};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{self as unix_fs, FileExt, MetadataExt, DirBuilderExt, PermissionsExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::devices::virtio::{VirtioDevice, GuestMemory, Queue, read_config_bytes};
+use crate::system::hostfs::{self, errno, EBADF, ENOTDIR, EINVAL, EROFS, ELOOP, EPROTO, EOPNOTSUPP, O_RDWR, O_WRONLY, O_CREAT, O_EXCL, O_TRUNC, O_APPEND, O_DIRECTORY, O_NOFOLLOW, AT_REMOVEDIR};
+
+const VIRTIO_ID_9P: u32 = 9;
+const VIRTIO_9P_MOUNT_TAG: u64 = 1;
+
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const VERSION: &[u8] = b"9P2000.L";
+const MAX_MSIZE: u32 = 512 * 1024;
+//size[4] type[1] tag[2] count[4] of rread and rreaddir
+const IO_HEADER: u32 = 11;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+
+const GETATTR_BASIC: u64 = 0x7ff;
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+
+const V9FS_MAGIC: u64 = 0x01021997;
+const LOCK_SUCCESS: u64 = 0;
+const F_UNLCK: u64 = 2;
+
+//fields of a request, strings are raw bytes
+struct Msg<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Msg<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], i64> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or(EPROTO)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, i64> {
+        Ok(self.bytes(len)?.iter().rev().fold(0, |v, b| { (v << 8) | *b as u64 }))
+    }
+
+    fn u8(&mut self) -> Result<u8, i64> {
+        Ok(self.uint(1)? as u8)
+    }
+
+    fn u16(&mut self) -> Result<u16, i64> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, i64> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64, i64> {
+        self.uint(8)
+    }
+
+    fn str(&mut self) -> Result<&'a [u8], i64> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
+
+fn put(r: &mut Vec<u8>, value: u64, len: usize) {
+    r.extend_from_slice(&value.to_le_bytes()[..len])
+}
+
+fn put_str(r: &mut Vec<u8>, s: &[u8]) {
+    put(r, s.len() as u64, 2);
+    r.extend_from_slice(s)
+}
+
+//type, version and path, the inode is the path
+fn qid(meta: &fs::Metadata) -> [u8; 13] {
+    let mut qid = [0; 13];
+    qid[0] = if meta.is_dir() {
+        QTDIR
+    } else if meta.file_type().is_symlink() {
+        QTSYMLINK
+    } else {
+        0
+    };
+    qid[5..].copy_from_slice(&meta.ino().to_le_bytes());
+    qid
+}
+
+//d_type of readdir
+fn dirent_type(meta: &fs::Metadata) -> u8 {
+    let ty = meta.file_type();
+    if ty.is_dir() {
+        4
+    } else if ty.is_symlink() {
+        10
+    } else if ty.is_file() {
+        8
+    } else {
+        0
+    }
+}
+
+struct Fid {
+    //handle of the file itself, opened without following links
+    node: File,
+    //relative to root, to remove, rename and link the file
+    path: PathBuf,
+    file: Option<File>,
+    //(qid, type, name) snapshotted by readdir from offset 0
+    entries: Vec<([u8; 13], u8, Vec<u8>)>,
+}
+
+impl Fid {
+    fn new(node: File, path: PathBuf) -> Fid {
+        Fid {
+            node,
+            path,
+            file: None,
+            entries: vec![],
+        }
+    }
+}
+
+//host path of an open handle, names joined to it are resolved relative to the handle
+fn fd_path(file: &File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+//handle of path, a symbolic link is opened as the link itself
+fn open_node(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_NOFOLLOW).open(path)
+}
+
+//files are opened and modified through handles, which must not be symbolic links
+fn no_symlink(node: &File) -> Result<fs::Metadata, i64> {
+    let meta = node.metadata().map_err(errno)?;
+    if meta.file_type().is_symlink() {
+        Err(ELOOP)
+    } else {
+        Ok(meta)
+    }
+}
+
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+//9P2000.L file server of a host directory. fids hold handles, every name is opened relative to the handle of its
+//directory without following symbolic links and ".." stops at root, links are resolved by the guest with readlink
+struct P9Server {
+    root: File,
+    readonly: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl P9Server {
+    fn fid(&mut self, fid: u32) -> Result<&mut Fid, i64> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn writable(&self) -> Result<(), i64> {
+        if self.readonly {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    //name in the directory
+    fn lookup(dir: &File, name: &[u8]) -> Result<PathBuf, i64> {
+        if !dir.metadata().map_err(errno)?.is_dir() {
+            return Err(ENOTDIR);
+        }
+        if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+            return Err(EINVAL);
+        }
+        Ok(fd_path(dir).join(OsStr::from_bytes(name)))
+    }
+
+    //name in the directory of fid, the directory handle must be kept while the name is used
+    fn child(&mut self, fid: u32, name: &[u8]) -> Result<(File, PathBuf), i64> {
+        let dir = self.fid(fid)?.node.try_clone().map_err(errno)?;
+        let path = Self::lookup(&dir, name)?;
+        Ok((dir, path))
+    }
+
+    //parent directory of dir, root is its own parent
+    fn parent(&self, dir: &File) -> Result<File, i64> {
+        if same_file(&dir.metadata().map_err(errno)?, &self.root.metadata().map_err(errno)?) {
+            self.root.try_clone().map_err(errno)
+        } else {
+            open_node(&fd_path(dir).join("..")).map_err(errno)
+        }
+    }
+
+    //path relative to root walked from the root handle, every directory on the way must not be a link
+    fn resolve(&self, path: &Path) -> Result<(File, PathBuf), i64> {
+        let name = path.file_name().ok_or(EINVAL)?;
+        let mut dir = self.root.try_clone().map_err(errno)?;
+        for c in path.parent().into_iter().flat_map(|p| { p.iter() }) {
+            dir = open_node(&Self::lookup(&dir, c.as_bytes())?).map_err(errno)?;
+        }
+        let path = Self::lookup(&dir, name.as_bytes())?;
+        Ok((dir, path))
+    }
+
+    fn reset(&mut self) {
+        self.fids.clear();
+        self.msize = MAX_MSIZE
+    }
+
+    //reply to request, an empty one if the request has no header
+    fn handle(&mut self, req: &[u8]) -> Vec<u8> {
+        if req.len() < 7 {
+            return vec![];
+        }
+        let mut m = Msg { data: req, pos: 4 };
+        let ty = m.u8().unwrap();
+        let tag = m.u16().unwrap();
+        let mut r = vec![0; 7];
+        let rty = match self.call(ty, &mut m, &mut r) {
+            Ok(_) => ty.wrapping_add(1),
+            Err(e) => {
+                r.truncate(7);
+                put(&mut r, e as u64, 4);
+                RLERROR
+            }
+        };
+        let size = r.len() as u32;
+        r[..4].copy_from_slice(&size.to_le_bytes());
+        r[4] = rty;
+        r[5..7].copy_from_slice(&tag.to_le_bytes());
+        r
+    }
+
+    fn call(&mut self, ty: u8, m: &mut Msg, r: &mut Vec<u8>) -> Result<(), i64> {
+        match ty {
+            TVERSION => {
+                let msize = m.u32()?;
+                let version = m.str()?;
+                if msize < 4096 {
+                    return Err(EINVAL);
+                }
+                self.reset();
+                self.msize = msize.min(MAX_MSIZE);
+                put(r, self.msize as u64, 4);
+                put_str(r, if version == VERSION { VERSION } else { b"unknown" })
+            }
+            TATTACH => {
+                let fid = m.u32()?;
+                let node = self.root.try_clone().map_err(errno)?;
+                let meta = node.metadata().map_err(errno)?;
+                self.fids.insert(fid, Fid::new(node, PathBuf::new()));
+                r.extend_from_slice(&qid(&meta))
+            }
+            TFLUSH => {}
+            TWALK => {
+                let fid = m.u32()?;
+                let newfid = m.u32()?;
+                let nwname = m.u16()?;
+                let names = (0..nwname).map(|_| { m.str() }).collect::<Result<Vec<_>, i64>>()?;
+                let f = self.fid(fid)?;
+                let mut path = f.path.clone();
+                let mut node = f.node.try_clone().map_err(errno)?;
+                let mut qids = vec![];
+                for name in names.iter() {
+                    let step = if *name == b".." {
+                        self.parent(&node)
+                    } else {
+                        Self::lookup(&node, name).and_then(|next| { open_node(&next).map_err(errno) })
+                    }.and_then(|next| { next.metadata().map(|meta| { (next, meta) }).map_err(errno) });
+                    match step {
+                        Ok((next, meta)) => {
+                            qids.push(qid(&meta));
+                            node = next;
+                            if *name == b".." {
+                                path.pop();
+                            } else {
+                                path.push(OsStr::from_bytes(name))
+                            }
+                        }
+                        Err(e) if qids.is_empty() => return Err(e),
+                        Err(_) => break
+                    }
+                }
+                if qids.len() == names.len() {
+                    self.fids.insert(newfid, Fid::new(node, path));
+                }
+                put(r, qids.len() as u64, 2);
+                qids.iter().for_each(|q| { r.extend_from_slice(q) })
+            }
+            TCLUNK => {
+                let fid = m.u32()?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            TREMOVE => {
+                let fid = m.u32()?;
+                let path = self.fids.remove(&fid).ok_or(EBADF)?.path;
+                self.writable()?;
+                let (_dir, host) = self.resolve(&path)?;
+                if fs::symlink_metadata(&host).map_err(errno)?.is_dir() {
+                    fs::remove_dir(&host).map_err(errno)?
+                } else {
+                    fs::remove_file(&host).map_err(errno)?
+                }
+            }
+            TSTATFS => {
+                self.fid(m.u32()?)?;
+                put(r, V9FS_MAGIC, 4);
+                put(r, 4096, 4);
+                //sizes of the host file system are unknown
+                for _ in 0..6 {
+                    put(r, 0, 8);
+                }
+                put(r, 255, 4)
+            }
+            TGETATTR => {
+                let fid = m.u32()?;
+                let f = self.fid(fid)?;
+                let meta = f.file.as_ref().unwrap_or(&f.node).metadata().map_err(errno)?;
+                put(r, GETATTR_BASIC, 8);
+                r.extend_from_slice(&qid(&meta));
+                put(r, meta.mode() as u64, 4);
+                put(r, meta.uid() as u64, 4);
+                put(r, meta.gid() as u64, 4);
+                for v in [meta.nlink(), meta.rdev(), meta.size(), meta.blksize(), meta.blocks(),
+                    meta.atime() as u64, meta.atime_nsec() as u64, meta.mtime() as u64, meta.mtime_nsec() as u64,
+                    meta.ctime() as u64, meta.ctime_nsec() as u64, 0, 0, 0, 0].iter() {
+                    put(r, *v, 8)
+                }
+            }
+            TSETATTR => {
+                let node = self.fid(m.u32()?)?.node.try_clone().map_err(errno)?;
+                let valid = m.u32()?;
+                let mode = m.u32()?;
+                let uid = m.u32()?;
+                let gid = m.u32()?;
+                let size = m.u64()?;
+                let atime = Duration::new(m.u64()?, (m.u64()? % 1_000_000_000) as u32);
+                let mtime = Duration::new(m.u64()?, (m.u64()? % 1_000_000_000) as u32);
+                self.writable()?;
+                no_symlink(&node)?;
+                let host = fd_path(&node);
+                if valid & SETATTR_MODE != 0 {
+                    fs::set_permissions(&host, fs::Permissions::from_mode(mode & 0o7777)).map_err(errno)?
+                }
+                if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+                    let uid = if valid & SETATTR_UID != 0 { Some(uid) } else { None };
+                    let gid = if valid & SETATTR_GID != 0 { Some(gid) } else { None };
+                    unix_fs::chown(&host, uid, gid).map_err(errno)?
+                }
+                if valid & SETATTR_SIZE != 0 {
+                    OpenOptions::new().write(true).open(&host).and_then(|f| { f.set_len(size) }).map_err(errno)?
+                }
+                if valid & (SETATTR_ATIME | SETATTR_MTIME) != 0 {
+                    let now = SystemTime::now();
+                    let mut times = FileTimes::new();
+                    if valid & SETATTR_ATIME != 0 {
+                        times = times.set_accessed(if valid & SETATTR_ATIME_SET != 0 { UNIX_EPOCH.checked_add(atime).ok_or(EINVAL)? } else { now })
+                    }
+                    if valid & SETATTR_MTIME != 0 {
+                        times = times.set_modified(if valid & SETATTR_MTIME_SET != 0 { UNIX_EPOCH.checked_add(mtime).ok_or(EINVAL)? } else { now })
+                    }
+                    File::open(&host).and_then(|f| { f.set_times(times) }).map_err(errno)?
+                }
+            }
+            TLOPEN => {
+                let fid = m.u32()?;
+                let flags = m.u32()? as u64;
+                let node = self.fid(fid)?.node.try_clone().map_err(errno)?;
+                let meta = no_symlink(&node)?;
+                let access = flags & 0x3;
+                if access == O_WRONLY || access == O_RDWR || flags & O_TRUNC != 0 {
+                    self.writable()?
+                }
+                let file = hostfs::open(&fd_path(&node), flags & (0x3 | O_TRUNC | O_APPEND | O_DIRECTORY), 0)?;
+                let f = self.fid(fid)?;
+                f.file = Some(file);
+                f.entries.clear();
+                r.extend_from_slice(&qid(&meta));
+                put(r, 0, 4)
+            }
+            TLCREATE => {
+                let fid = m.u32()?;
+                let name = m.str()?;
+                let flags = m.u32()? as u64;
+                let mode = m.u32()? as u64;
+                self.writable()?;
+                let (_dir, host) = self.child(fid, name)?;
+                let file = hostfs::open(&host, (flags & (0x3 | O_TRUNC | O_APPEND | O_EXCL)) | O_CREAT | O_NOFOLLOW, mode)?;
+                let meta = file.metadata().map_err(errno)?;
+                let node = open_node(&host).map_err(errno)?;
+                let f = self.fid(fid)?;
+                f.node = node;
+                f.path.push(OsStr::from_bytes(name));
+                f.file = Some(file);
+                r.extend_from_slice(&qid(&meta));
+                put(r, 0, 4)
+            }
+            TREAD => {
+                let fid = m.u32()?;
+                let offset = m.u64()?;
+                let count = m.u32()?.min(self.msize - IO_HEADER);
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let mut data = vec![0; count as usize];
+                let len = file.read_at(&mut data, offset).map_err(errno)?;
+                put(r, len as u64, 4);
+                r.extend_from_slice(&data[..len])
+            }
+            TWRITE => {
+                let fid = m.u32()?;
+                let offset = m.u64()?;
+                let count = m.u32()?;
+                let data = m.bytes(count as usize)?;
+                self.writable()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let len = file.write_at(data, offset).map_err(errno)?;
+                put(r, len as u64, 4)
+            }
+            TFSYNC => {
+                if let Some(ref file) = self.fid(m.u32()?)?.file {
+                    file.sync_all().map_err(errno)?
+                }
+            }
+            TREADDIR => {
+                let fid = m.u32()?;
+                let offset = m.u64()?;
+                let count = m.u32()?.min(self.msize - IO_HEADER) as usize;
+                let node = self.fid(fid)?.node.try_clone().map_err(errno)?;
+                if offset == 0 {
+                    let meta = node.metadata().map_err(errno)?;
+                    let parent = self.parent(&node)?.metadata().map_err(errno)?;
+                    let mut entries = vec![(qid(&meta), 4, b".".to_vec()), (qid(&parent), 4, b"..".to_vec())];
+                    for e in fs::read_dir(fd_path(&node)).map_err(errno)? {
+                        let e = e.map_err(errno)?;
+                        if let Ok(meta) = e.path().symlink_metadata() {
+                            entries.push((qid(&meta), dirent_type(&meta), e.file_name().as_bytes().to_vec()))
+                        }
+                    }
+                    self.fid(fid)?.entries = entries
+                }
+                let mut data = vec![];
+                for (i, (qid, ty, name)) in self.fid(fid)?.entries.iter().enumerate().skip(offset as usize) {
+                    if data.len() + 13 + 8 + 1 + 2 + name.len() > count {
+                        break;
+                    }
+                    data.extend_from_slice(qid);
+                    put(&mut data, i as u64 + 1, 8);
+                    put(&mut data, *ty as u64, 1);
+                    put_str(&mut data, name)
+                }
+                put(r, data.len() as u64, 4);
+                r.extend_from_slice(&data)
+            }
+            TMKDIR => {
+                let fid = m.u32()?;
+                let name = m.str()?;
+                let mode = m.u32()?;
+                self.writable()?;
+                let (_dir, host) = self.child(fid, name)?;
+                DirBuilder::new().mode(mode & 0o7777).create(&host).map_err(errno)?;
+                r.extend_from_slice(&qid(&fs::symlink_metadata(&host).map_err(errno)?))
+            }
+            TSYMLINK => {
+                let fid = m.u32()?;
+                let name = m.str()?;
+                let target = m.str()?;
+                self.writable()?;
+                let (_dir, host) = self.child(fid, name)?;
+                unix_fs::symlink(OsStr::from_bytes(target), &host).map_err(errno)?;
+                r.extend_from_slice(&qid(&fs::symlink_metadata(&host).map_err(errno)?))
+            }
+            TREADLINK => {
+                let path = self.fid(m.u32()?)?.path.clone();
+                let (_dir, host) = self.resolve(&path)?;
+                let target = fs::read_link(&host).map_err(errno)?;
+                put_str(r, target.as_os_str().as_bytes())
+            }
+            TLINK => {
+                let dfid = m.u32()?;
+                let fid = m.u32()?;
+                let name = m.str()?;
+                self.writable()?;
+                let target = self.fid(fid)?.path.clone();
+                let (_from_dir, from) = self.resolve(&target)?;
+                let (_to_dir, to) = self.child(dfid, name)?;
+                fs::hard_link(&from, &to).map_err(errno)?
+            }
+            TRENAME => {
+                let fid = m.u32()?;
+                let dfid = m.u32()?;
+                let name = m.str()?;
+                self.writable()?;
+                let from = self.fid(fid)?.path.clone();
+                let (_from_dir, from) = self.resolve(&from)?;
+                let (_to_dir, to) = self.child(dfid, name)?;
+                fs::rename(&from, &to).map_err(errno)?;
+                let path = self.fid(dfid)?.path.join(OsStr::from_bytes(name));
+                self.fid(fid)?.path = path
+            }
+            TRENAMEAT => {
+                let olddir = m.u32()?;
+                let oldname = m.str()?;
+                let newdir = m.u32()?;
+                let newname = m.str()?;
+                self.writable()?;
+                let (_from_dir, from) = self.child(olddir, oldname)?;
+                let (_to_dir, to) = self.child(newdir, newname)?;
+                fs::rename(&from, &to).map_err(errno)?
+            }
+            TUNLINKAT => {
+                let dir = m.u32()?;
+                let name = m.str()?;
+                let flags = m.u32()? as u64;
+                self.writable()?;
+                let (_dir, host) = self.child(dir, name)?;
+                if flags & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(&host).map_err(errno)?
+                } else {
+                    fs::remove_file(&host).map_err(errno)?
+                }
+            }
+            //locks are granted, the guest is the only user of the files
+            TLOCK => {
+                self.fid(m.u32()?)?;
+                put(r, LOCK_SUCCESS, 1)
+            }
+            TGETLOCK => {
+                self.fid(m.u32()?)?;
+                m.u8()?;
+                let start = m.u64()?;
+                let length = m.u64()?;
+                let proc_id = m.u32()?;
+                let client_id = m.str()?;
+                put(r, F_UNLCK, 1);
+                put(r, start, 8);
+                put(r, length, 8);
+                put(r, proc_id as u64, 4);
+                put_str(r, client_id)
+            }
+            //no xattrs, special files or authentication
+            _ => return Err(EOPNOTSUPP)
+        }
+        Ok(())
+    }
+}
+
+//virtio-9p device exporting root by the mount tag, e.g. mount -t 9p -o trans=virtio,version=9p2000.L tag /mnt
+pub struct Virtio9p {
+    tag: String,
+    server: P9Server,
+}
+
+impl Virtio9p {
+    pub fn new(tag: &str, root: &str, readonly: bool) -> Result<Virtio9p, String> {
+        if tag.is_empty() || tag.len() > u16::MAX as usize {
+            return Err(format!("invalid 9p mount tag \"{}\"!", tag));
+        }
+        let root = fs::canonicalize(root).map_err(|e| { format!("{}: {}", root, e) })?;
+        if !root.is_dir() {
+            return Err(format!("{}: not a directory!", root.display()));
+        }
+        let dir = open_node(&root).map_err(|e| { format!("{}: {}", root.display(), e) })?;
+        if !fd_path(&dir).is_dir() {
+            return Err("9p requires /proc/self/fd!".to_string());
+        }
+        Ok(Virtio9p {
+            tag: tag.to_string(),
+            server: P9Server {
+                root: dir,
+                readonly,
+                msize: MAX_MSIZE,
+                fids: HashMap::new(),
+            },
+        })
+    }
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    //tag_len and tag
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        read_config_bytes(&config, offset, data)
+    }
+
+    fn reset(&mut self) {
+        self.server.reset()
+    }
+
+    //requests are served in order, a reply is dropped if the driver gives no space for it
+    fn notify(&mut self, _queue: usize, queues: &mut [Queue], mem: &GuestMemory) -> bool {
+        let q = &mut queues[0];
+        let mut used = false;
+        while let Some(chain) = q.pop(mem) {
+            let reply = chain.read_all(mem).map(|req| { self.server.handle(&req) }).unwrap_or_default();
+            let len = if reply.len() <= chain.writable_len() {
+                chain.write_all(mem, &reply).unwrap_or(0)
+            } else {
+                0
+            };
+            used |= q.push(mem, chain.head(), len as u32).is_ok()
+        }
+        used
+    }
+}
+
+#[test]
+fn p9_test() {
+    let root = std::env::temp_dir().join(format!("terminus_9p_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("dir")).unwrap();
+    fs::write(root.join("dir/hello"), b"hello 9p").unwrap();
+    unix_fs::symlink("/", root.join("escape")).unwrap();
+    let msg = |ty: u8, body: &[u8]| -> Vec<u8> {
+        let mut m = vec![0; 7];
+        m.extend_from_slice(body);
+        let size = m.len() as u32;
+        m[..4].copy_from_slice(&size.to_le_bytes());
+        m[4] = ty;
+        m
+    };
+    let walk = |fid: u32, newfid: u32, names: &[&[u8]]| -> Vec<u8> {
+        let mut body = vec![];
+        put(&mut body, fid as u64, 4);
+        put(&mut body, newfid as u64, 4);
+        put(&mut body, names.len() as u64, 2);
+        names.iter().for_each(|n| { put_str(&mut body, n) });
+        msg(TWALK, &body)
+    };
+    let mut dev = Virtio9p::new("share", root.to_str().unwrap(), true).unwrap();
+    let s = &mut dev.server;
+    let mut version = vec![0, 0, 1, 0];
+    put_str(&mut version, VERSION);
+    let r = s.handle(&msg(TVERSION, &version));
+    assert_eq!(r[4], TVERSION + 1);
+    assert_eq!(&r[7..11], &0x10000u32.to_le_bytes());
+    let mut attach = vec![0; 8];
+    put_str(&mut attach, b"root");
+    put_str(&mut attach, b"");
+    put(&mut attach, 0, 4);
+    assert_eq!(s.handle(&msg(TATTACH, &attach))[7], QTDIR);
+
+    //read a file
+    let r = s.handle(&walk(0, 1, &[b"dir", b"hello"]));
+    assert_eq!(&r[7..9], &[2, 0]);
+    assert_eq!(s.handle(&msg(TLOPEN, &[1, 0, 0, 0, 0, 0, 0, 0]))[4], TLOPEN + 1);
+    let mut read = vec![1, 0, 0, 0];
+    put(&mut read, 6, 8);
+    put(&mut read, 100, 4);
+    let r = s.handle(&msg(TREAD, &read));
+    assert_eq!(&r[7..], &[2, 0, 0, 0, b'9', b'p']);
+
+    //".." stops at root, links are not followed
+    let r = s.handle(&walk(0, 2, &[b"..", b"..", b"dir"]));
+    assert_eq!(&r[7..9], &[3, 0]);
+    let r = s.handle(&walk(0, 3, &[b"escape", b"etc"]));
+    assert_eq!(&r[7..9], &[1, 0]);
+    assert_eq!(s.handle(&msg(TCLUNK, &[3, 0, 0, 0]))[4], RLERROR);
+    assert_eq!(s.handle(&walk(0, 3, &[b"dir/hello"]))[4], RLERROR);
+    s.handle(&walk(0, 3, &[b"escape"]));
+    assert_eq!(&s.handle(&msg(TLOPEN, &[3, 0, 0, 0, 0, 0, 0, 0]))[4..], &[RLERROR, 0, 0, ELOOP as u8, 0, 0, 0]);
+
+    //read only
+    let mut mkdir = vec![0; 4];
+    put_str(&mut mkdir, b"new");
+    put(&mut mkdir, 0o755, 4);
+    put(&mut mkdir, 0, 4);
+    assert_eq!(&s.handle(&msg(TMKDIR, &mkdir))[4..], &[RLERROR, 0, 0, EROFS as u8, 0, 0, 0]);
+    s.readonly = false;
+    assert_eq!(s.handle(&msg(TMKDIR, &mkdir))[7], QTDIR);
+    assert!(root.join("new").is_dir());
+
+    //create does not follow links
+    let outside = std::env::temp_dir().join(format!("terminus_9p_test_{}_outside", std::process::id()));
+    let _ = fs::remove_file(&outside);
+    unix_fs::symlink(&outside, root.join("new/link")).unwrap();
+    s.handle(&walk(0, 4, &[b"new"]));
+    let mut create = vec![4, 0, 0, 0];
+    put_str(&mut create, b"link");
+    put(&mut create, O_WRONLY, 4);
+    put(&mut create, 0o644, 4);
+    put(&mut create, 0, 4);
+    assert_eq!(&s.handle(&msg(TLCREATE, &create))[4..], &[RLERROR, 0, 0, ELOOP as u8, 0, 0, 0]);
+    assert!(fs::symlink_metadata(&outside).is_err());
+
+    //a fid keeps its directory after the directory is renamed and replaced by a link
+    s.handle(&walk(0, 5, &[b"dir"]));
+    let mut rename = vec![0; 4];
+    put_str(&mut rename, b"dir");
+    put(&mut rename, 0, 4);
+    put_str(&mut rename, b"moved");
+    assert_eq!(s.handle(&msg(TRENAMEAT, &rename))[4], TRENAMEAT + 1);
+    unix_fs::symlink("/etc", root.join("dir")).unwrap();
+    let r = s.handle(&walk(5, 6, &[b"hello"]));
+    assert_eq!(&r[7..9], &[1, 0]);
+    assert_eq!(s.handle(&walk(5, 7, &[b"passwd"]))[4], RLERROR);
+    let mut create = vec![5, 0, 0, 0];
+    put_str(&mut create, b"created");
+    put(&mut create, O_WRONLY, 4);
+    put(&mut create, 0o644, 4);
+    put(&mut create, 0, 4);
+    assert_eq!(s.handle(&msg(TLCREATE, &create))[4], TLCREATE + 1);
+    assert!(root.join("moved/created").is_file());
+    //names of the old path are walked from root and refuse the link
+    assert_eq!(&s.handle(&msg(TREMOVE, &[6, 0, 0, 0]))[4..], &[RLERROR, 0, 0, ENOTDIR as u8, 0, 0, 0]);
+    assert!(root.join("moved/hello").is_file());
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,235 @@
+use terminus_spaceport::devices::TERM;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use crate::devices::virtio::{VirtioDevice, GuestMemory, Queue};
+use crate::devices::net::remove_stale_socket;
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+//emerg_wr of config, after cols, rows and max_nr_ports
+const EMERG_WR: u64 = 8;
+
+const RX: usize = 0;
+const TX: usize = 1;
+const INPUT_SIZE: usize = 4096;
+
+//host side of a console
+pub enum ConsoleBackend {
+    Stdio,
+    //output only
+    File(File),
+    //one client at a time, output is dropped while no client is connected
+    Unix(UnixListener, Option<UnixStream>),
+}
+
+impl ConsoleBackend {
+    pub fn file(path: &str) -> Result<ConsoleBackend, String> {
+        Ok(ConsoleBackend::File(File::create(path).map_err(|e| { format!("{}: {}", path, e) })?))
+    }
+
+    pub fn unix(path: &str) -> Result<ConsoleBackend, String> {
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path).map_err(|e| { format!("{}: {}", path, e) })?;
+        listener.set_nonblocking(true).map_err(|e| { format!("{}: {}", path, e) })?;
+        Ok(ConsoleBackend::Unix(listener, None))
+    }
+
+    fn accept(&mut self) {
+        if let ConsoleBackend::Unix(ref listener, ref mut client @ None) = self {
+            if let Ok((stream, _)) = listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    *client = Some(stream)
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        self.accept();
+        match self {
+            ConsoleBackend::Stdio => {
+                let stdout = TERM.stdout();
+                let mut handle = stdout.lock();
+                if let Err(e) = handle.write_all(data).and_then(|_| { handle.flush() }) {
+                    eprintln!("console: {}", e)
+                }
+            }
+            ConsoleBackend::File(file) => {
+                if let Err(e) = file.write_all(data) {
+                    eprintln!("console: {}", e)
+                }
+            }
+            ConsoleBackend::Unix(_, client) => {
+                if let Some(ref mut stream) = client {
+                    if stream.write_all(data).is_err() {
+                        *client = None
+                    }
+                }
+            }
+        }
+    }
+
+    //bytes available without blocking
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.accept();
+        match self {
+            ConsoleBackend::Stdio => {
+                let mut len = 0;
+                while len < buf.len() {
+                    match TERM.stdin().lock().read_exact(&mut buf[len..len + 1]) {
+                        Ok(_) => len += 1,
+                        //no input, or input is closed
+                        Err(_) => break
+                    }
+                }
+                len
+            }
+            ConsoleBackend::File(_) => 0,
+            ConsoleBackend::Unix(_, client) => {
+                let result = if let Some(ref mut stream) = client {
+                    stream.read(buf)
+                } else {
+                    return 0;
+                };
+                match result {
+                    Ok(0) => {
+                        *client = None;
+                        0
+                    }
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+                    Err(_) => {
+                        *client = None;
+                        0
+                    }
+                }
+            }
+        }
+    }
+}
+
+//single port virtio console, e.g. hvc1 of linux. emergency writes to config are output too
+pub struct VirtioConsole {
+    backend: ConsoleBackend,
+    //read from the backend, waiting for receive buffers
+    input: VecDeque<u8>,
+}
+
+impl VirtioConsole {
+    pub fn new(backend: ConsoleBackend) -> VirtioConsole {
+        VirtioConsole {
+            backend,
+            input: VecDeque::new(),
+        }
+    }
+
+    fn transmit(&mut self, q: &mut Queue, mem: &GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = q.pop(mem) {
+            if let Ok(data) = chain.read_all(mem) {
+                self.backend.write(&data)
+            }
+            used |= q.push(mem, chain.head(), 0).is_ok()
+        }
+        used
+    }
+
+    fn receive(&mut self, q: &mut Queue, mem: &GuestMemory) -> bool {
+        if !q.has_avail(mem) {
+            return false;
+        }
+        if self.input.len() < INPUT_SIZE {
+            let mut buf = vec![0; INPUT_SIZE - self.input.len()];
+            let len = self.backend.read(&mut buf);
+            self.input.extend(&buf[..len]);
+        }
+        let mut used = false;
+        while !self.input.is_empty() {
+            let chain = if let Some(chain) = q.pop(mem) {
+                chain
+            } else {
+                break;
+            };
+            let len = chain.writable_len().min(self.input.len());
+            let data = self.input.drain(..len).collect::<Vec<u8>>();
+            let written = chain.write_all(mem, &data).unwrap_or(0);
+            used |= q.push(mem, chain.head(), written as u32).is_ok()
+        }
+        used
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        data.iter_mut().for_each(|v| { *v = 0 })
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset == EMERG_WR && !data.is_empty() {
+            self.backend.write(&data[..1])
+        }
+    }
+
+    fn reset(&mut self) {
+        self.input.clear()
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], mem: &GuestMemory) -> bool {
+        match queue {
+            TX => self.transmit(&mut queues[TX], mem),
+            RX => self.receive(&mut queues[RX], mem),
+            _ => false
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], mem: &GuestMemory) -> bool {
+        self.receive(&mut queues[RX], mem)
+    }
+}
+
+#[test]
+fn virtio_console_test() {
+    use terminus_spaceport::memory::region::{Region, GHEAP};
+    use crate::devices::virtio::test_queue;
+    let mem = GuestMemory::new(vec![Region::remap(0x80000000, &GHEAP.alloc(0x10000, 1).unwrap())]);
+    let path = std::env::temp_dir().join(format!("terminus_console_test_{}", std::process::id()));
+    let mut console = VirtioConsole::new(ConsoleBackend::unix(path.to_str().unwrap()).unwrap());
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(b"input").unwrap();
+    let mut queues = vec![test_queue(&mem, 0x80000000, 3, true), test_queue(&mem, 0x80001000, 6, false)];
+    //input is kept until more buffers come
+    assert!(console.poll(&mut queues, &mem));
+    assert_eq!(mem.read_u32(0x80000400 + 8).unwrap(), 3);
+    let mut data = [0; 3];
+    mem.read(0x80000800, &mut data).unwrap();
+    assert_eq!(&data, b"inp");
+    assert_eq!(console.input.len(), 2);
+
+    mem.write(0x80001800, b"output").unwrap();
+    assert!(console.notify(TX, &mut queues, &mem));
+    console.write_config(EMERG_WR, &[b'!', 0, 0, 0]);
+    let mut data = [0; 7];
+    client.read_exact(&mut data).unwrap();
+    assert_eq!(&data, b"output!");
+    std::fs::remove_file(&path).unwrap();
+
+    //other files are not taken as stale sockets
+    std::fs::write(&path, b"keep").unwrap();
+    assert!(ConsoleBackend::unix(path.to_str().unwrap()).is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"keep");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,387 @@
+use terminus_spaceport::memory::prelude::*;
+use terminus_macros::*;
+use std::sync::{Arc, Mutex};
+use crate::devices::device::{Device, IrqLine};
+use crate::devices::clint::Timer;
+use crate::devices::virtio::{VirtioDevice, GuestMemory, Queue, VIRTIO_F_VERSION_1};
+use crate::system::fdt::{FdtNode, FdtProp};
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+const MAGIC: u32 = 0x74726976;
+const VENDOR: u32 = 0x554d4551;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const INT_USED_BUFFER: u32 = 1;
+
+fn set_low(v: &mut u64, data: u32) {
+    *v = (*v & !0xffff_ffff) | data as u64
+}
+
+fn set_high(v: &mut u64, data: u32) {
+    *v = (*v & 0xffff_ffff) | ((data as u64) << 32)
+}
+
+struct VirtioInner {
+    device: Option<Box<dyn VirtioDevice>>,
+    mem: GuestMemory,
+    irq: Option<IrqLine>,
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+}
+
+impl VirtioInner {
+    fn update_irq(&self) {
+        if let Some(ref irq) = self.irq {
+            irq.set(self.interrupt_status != 0)
+        }
+    }
+
+    fn used_buffer(&mut self) {
+        self.interrupt_status |= INT_USED_BUFFER;
+        self.update_irq()
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues.iter_mut().for_each(|q| { q.reset() });
+        self.interrupt_status = 0;
+        if let Some(ref mut device) = self.device {
+            device.reset()
+        }
+        self.update_irq()
+    }
+
+    fn notify(&mut self, queue: usize) {
+        let used = match self.device {
+            Some(ref mut device) if queue < self.queues.len() && self.status & STATUS_DRIVER_OK != 0 => {
+                device.notify(queue, &mut self.queues, &self.mem)
+            }
+            _ => false
+        };
+        if used {
+            self.used_buffer()
+        }
+    }
+
+    fn poll(&mut self) {
+        let used = match self.device {
+            Some(ref mut device) if self.status & STATUS_DRIVER_OK != 0 => device.poll(&mut self.queues, &self.mem),
+            _ => false
+        };
+        if used {
+            self.used_buffer()
+        }
+    }
+
+    fn read(&self, addr: u64) -> u32 {
+        let device = if let Some(ref device) = self.device {
+            device
+        } else {
+            return match addr {
+                MAGIC_VALUE => MAGIC,
+                VERSION => 2,
+                VENDOR_ID => VENDOR,
+                _ => 0
+            };
+        };
+        let queue = self.queues.get(self.queue_sel as usize);
+        match addr {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => {
+                let features = device.features() | VIRTIO_F_VERSION_1;
+                match self.device_features_sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0
+                }
+            }
+            QUEUE_NUM_MAX => queue.map_or(0, |_| { device.queue_max() as u32 }),
+            QUEUE_READY => queue.map_or(0, |q| { q.ready as u32 }),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, addr: u64, data: u32) {
+        if self.device.is_none() {
+            return;
+        }
+        match addr {
+            DEVICE_FEATURES_SEL => self.device_features_sel = data,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, data),
+                1 => set_high(&mut self.driver_features, data),
+                _ => {}
+            }
+            DRIVER_FEATURES_SEL => self.driver_features_sel = data,
+            QUEUE_SEL => self.queue_sel = data,
+            QUEUE_NOTIFY => self.notify(data as usize),
+            INTERRUPT_ACK => {
+                self.interrupt_status &= !data;
+                self.update_irq()
+            }
+            STATUS => {
+                if data == 0 {
+                    self.reset()
+                } else {
+                    let driver_ok = data & STATUS_DRIVER_OK != 0 && self.status & STATUS_DRIVER_OK == 0;
+                    self.status = data;
+                    if driver_ok {
+                        let features = self.driver_features;
+                        if let Some(ref mut device) = self.device {
+                            device.activate(features)
+                        }
+                    }
+                }
+            }
+            _ => {
+                let max = self.device.as_ref().map_or(0, |d| { d.queue_max() });
+                if let Some(q) = self.queue() {
+                    match addr {
+                        QUEUE_NUM if data <= max as u32 && data.is_power_of_two() => q.num = data as u16,
+                        QUEUE_READY => q.ready = data & 1 == 1,
+                        QUEUE_DESC_LOW => set_low(&mut q.desc, data),
+                        QUEUE_DESC_HIGH => set_high(&mut q.desc, data),
+                        QUEUE_DRIVER_LOW => set_low(&mut q.driver, data),
+                        QUEUE_DRIVER_HIGH => set_high(&mut q.driver, data),
+                        QUEUE_DEVICE_LOW => set_low(&mut q.device, data),
+                        QUEUE_DEVICE_HIGH => set_high(&mut q.device, data),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+//virtio-mmio transport of version 2, device id 0 tells the driver to skip an empty slot.
+//buffers are accessed in guest memory directly, used buffers are notified by the irq line
+#[derive_io(Bytes, U8, U32)]
+pub struct VirtioMmio {
+    inner: Arc<Mutex<VirtioInner>>,
+}
+
+impl VirtioMmio {
+    pub fn empty() -> VirtioMmio {
+        VirtioMmio::with_device(None, GuestMemory::default(), None)
+    }
+
+    pub fn new(device: Box<dyn VirtioDevice>, mem: GuestMemory, irq: Option<IrqLine>) -> VirtioMmio {
+        VirtioMmio::with_device(Some(device), mem, irq)
+    }
+
+    fn with_device(device: Option<Box<dyn VirtioDevice>>, mem: GuestMemory, irq: Option<IrqLine>) -> VirtioMmio {
+        let num_queues = device.as_ref().map_or(0, |d| { d.num_queues() });
+        VirtioMmio {
+            inner: Arc::new(Mutex::new(VirtioInner {
+                device,
+                mem,
+                irq,
+                status: 0,
+                device_features_sel: 0,
+                driver_features: 0,
+                driver_features_sel: 0,
+                queue_sel: 0,
+                queues: (0..num_queues).map(|_| { Queue::default() }).collect(),
+                interrupt_status: 0,
+            }))
+        }
+    }
+
+    //poll the device for host input every period ticks of timer
+    pub fn poll(&self, timer: &Arc<Timer>, period: u64) {
+        fn schedule(inner: Arc<Mutex<VirtioInner>>, timer: &Arc<Timer>, period: u64) {
+            let weak = Arc::downgrade(timer);
+            timer.schedule_after(period, move |_| {
+                inner.lock().unwrap().poll();
+                if let Some(timer) = weak.upgrade() {
+                    schedule(inner, &timer, period)
+                }
+            });
+        }
+        schedule(self.inner.clone(), timer, period)
+    }
+}
+
+impl Clone for VirtioMmio {
+    fn clone(&self) -> VirtioMmio {
+        VirtioMmio {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Device for VirtioMmio {
+    fn reset(&self) {
+        self.inner.lock().unwrap().reset()
+    }
+
+    fn fdt_node(&self, base: u64, size: u64, _harts: &[u32]) -> Option<FdtNode> {
+        let mut node = FdtNode::new(&format!("virtio_mmio@{:x}", base));
+        node.add_prop(FdtProp::str_prop("compatible", vec!["virtio,mmio"]));
+        node.add_prop(FdtProp::u64_prop("reg", vec![base, size]));
+        Some(node)
+    }
+
+    fn irq_lines(&self) -> Vec<IrqLine> {
+        self.inner.lock().unwrap().irq.iter().cloned().collect()
+    }
+
+    //backends hold host files and sockets, only empty slots can be saved
+    fn can_save(&self) -> bool {
+        self.inner.lock().unwrap().device.is_none()
+    }
+}
+
+//registers are accessed by words, config space by any size
+impl BytesAccess for VirtioMmio {
+    fn write(&self, addr: &u64, data: &[u8]) {
+        if *addr >= CONFIG {
+            if let Some(ref mut device) = self.inner.lock().unwrap().device {
+                device.write_config(*addr - CONFIG, data)
+            }
+        } else if data.len() == 4 {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(data);
+            U32Access::write(self, addr, u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn read(&self, addr: &u64, data: &mut [u8]) {
+        if *addr >= CONFIG {
+            if let Some(ref device) = self.inner.lock().unwrap().device {
+                device.read_config(*addr - CONFIG, data)
+            }
+        } else if data.len() == 4 {
+            data.copy_from_slice(&U32Access::read(self, addr).to_le_bytes())
+        }
+    }
+}
+
+impl U8Access for VirtioMmio {
+    fn write(&self, addr: &u64, data: u8) {
+        BytesAccess::write(self, addr, &[data])
+    }
+
+    fn read(&self, addr: &u64) -> u8 {
+        let mut data = [0];
+        BytesAccess::read(self, addr, &mut data);
+        data[0]
+    }
+}
+
+impl U32Access for VirtioMmio {
+    fn write(&self, addr: &u64, data: u32) {
+        if *addr >= CONFIG {
+            BytesAccess::write(self, addr, &data.to_le_bytes())
+        } else {
+            self.inner.lock().unwrap().write(*addr, data)
+        }
+    }
+
+    fn read(&self, addr: &u64) -> u32 {
+        if *addr >= CONFIG {
+            let mut data = [0; 4];
+            BytesAccess::read(self, addr, &mut data);
+            u32::from_le_bytes(data)
+        } else {
+            self.inner.lock().unwrap().read(*addr)
+        }
+    }
+}
+
+#[test]
+fn virtio_mmio_test() {
+    use terminus_spaceport::memory::region::{Region, GHEAP};
+    use crate::devices::virtio_rng::{VirtioRng, RngSource};
+    let mem = GuestMemory::new(vec![Region::remap(0x80000000, &GHEAP.alloc(0x1000, 1).unwrap())]);
+    let empty = VirtioMmio::empty();
+    assert_eq!(U32Access::read(&empty, &MAGIC_VALUE), MAGIC);
+    assert_eq!(U32Access::read(&empty, &DEVICE_ID), 0);
+    assert!(empty.can_save());
+
+    let dev = VirtioMmio::new(Box::new(VirtioRng::new(RngSource::Seeded(1)).unwrap()), mem.clone(), None);
+    assert!(!dev.can_save());
+    assert_eq!(U32Access::read(&dev, &VERSION), 2);
+    assert_eq!(U32Access::read(&dev, &DEVICE_ID), 4);
+    assert_eq!(U32Access::read(&dev, &VENDOR_ID), VENDOR);
+    U32Access::write(&dev, &DEVICE_FEATURES_SEL, 1);
+    assert_eq!(U32Access::read(&dev, &DEVICE_FEATURES), 1);
+    assert_eq!(U32Access::read(&dev, &QUEUE_NUM_MAX), 256);
+    //queue 1 does not exist
+    U32Access::write(&dev, &QUEUE_SEL, 1);
+    assert_eq!(U32Access::read(&dev, &QUEUE_NUM_MAX), 0);
+    U32Access::write(&dev, &QUEUE_SEL, 0);
+    //sizes should be powers of 2 up to the max
+    U32Access::write(&dev, &QUEUE_NUM, 6);
+    assert_eq!(dev.inner.lock().unwrap().queues[0].num, 0);
+    U32Access::write(&dev, &QUEUE_NUM, 8);
+    assert_eq!(dev.inner.lock().unwrap().queues[0].num, 8);
+
+    //descriptors at the end of the address space wrap around, and the request is dropped
+    for (reg, value) in [(QUEUE_DESC_LOW, 0xffff_fff0), (QUEUE_DESC_HIGH, 0xffff_ffff), (QUEUE_DRIVER_LOW, 0x80000200), (QUEUE_DEVICE_LOW, 0x80000400),
+        (QUEUE_READY, 1), (STATUS, 0xf)].iter() {
+        U32Access::write(&dev, reg, *value)
+    }
+    assert_eq!(dev.inner.lock().unwrap().queues[0].desc, 0xffff_ffff_ffff_fff0);
+    assert_eq!(U32Access::read(&dev, &QUEUE_READY), 1);
+    mem.write_u16(0x80000200 + 2, 1).unwrap();
+    mem.write_u16(0x80000200 + 4, 1).unwrap();
+    U32Access::write(&dev, &QUEUE_NOTIFY, 0);
+    assert_eq!(U32Access::read(&dev, &INTERRUPT_STATUS), 0);
+    assert_eq!(mem.read_u16(0x80000400 + 2).unwrap(), 0);
+
+    //used buffers are acked by the driver
+    dev.inner.lock().unwrap().used_buffer();
+    assert_eq!(U32Access::read(&dev, &INTERRUPT_STATUS), INT_USED_BUFFER);
+    U32Access::write(&dev, &INTERRUPT_ACK, INT_USED_BUFFER);
+    assert_eq!(U32Access::read(&dev, &INTERRUPT_STATUS), 0);
+
+    //writing 0 to status resets the device
+    U32Access::write(&dev, &STATUS, 0);
+    assert_eq!(U32Access::read(&dev, &STATUS), 0);
+    assert_eq!(U32Access::read(&dev, &QUEUE_READY), 0);
+    assert_eq!(dev.inner.lock().unwrap().queues[0].desc, 0);
+}
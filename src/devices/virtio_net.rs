@@ -0,0 +1,121 @@
+use crate::devices::virtio::{VirtioDevice, GuestMemory, Queue, read_config_bytes};
+use crate::devices::net::NetBackend;
+
+const VIRTIO_ID_NET: u32 = 1;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+//virtio_net_hdr_v1, no offload is offered so only num_buffers is set
+const HDR_SIZE: usize = 12;
+const RX: usize = 0;
+const TX: usize = 1;
+
+//network card exchanging frames through the backend, the link is always up
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+}
+
+impl VirtioNet {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> VirtioNet {
+        VirtioNet {
+            mac,
+            backend,
+        }
+    }
+
+    fn transmit(&mut self, q: &mut Queue, mem: &GuestMemory) -> bool {
+        let mut used = false;
+        while let Some(chain) = q.pop(mem) {
+            if let Ok(packet) = chain.read_all(mem) {
+                if packet.len() > HDR_SIZE {
+                    self.backend.send(&packet[HDR_SIZE..])
+                }
+            }
+            used |= q.push(mem, chain.head(), 0).is_ok()
+        }
+        used
+    }
+
+    //frames are kept in the backend until the driver provides buffers
+    fn receive(&mut self, q: &mut Queue, mem: &GuestMemory) -> bool {
+        let mut used = false;
+        while q.has_avail(mem) {
+            let frame = if let Some(frame) = self.backend.recv() {
+                frame
+            } else {
+                break;
+            };
+            if let Some(chain) = q.pop(mem) {
+                let mut packet = vec![0; HDR_SIZE];
+                packet[10] = 1;
+                packet.extend_from_slice(&frame);
+                let len = chain.write_all(mem, &packet).unwrap_or(0);
+                used |= q.push(mem, chain.head(), len as u32).is_ok()
+            }
+        }
+        used
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    //mac and status
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        read_config_bytes(&config, offset, data)
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], mem: &GuestMemory) -> bool {
+        match queue {
+            TX => self.transmit(&mut queues[TX], mem),
+            RX => self.receive(&mut queues[RX], mem),
+            _ => false
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], mem: &GuestMemory) -> bool {
+        self.receive(&mut queues[RX], mem)
+    }
+}
+
+#[test]
+fn virtio_net_test() {
+    use terminus_spaceport::memory::region::{Region, GHEAP};
+    use crate::devices::net::Loopback;
+    use crate::devices::virtio::test_queue;
+    let mem = GuestMemory::new(vec![Region::remap(0x80000000, &GHEAP.alloc(0x10000, 1).unwrap())]);
+    let (a, b) = Loopback::pair();
+    let mut nic_a = VirtioNet::new([0x52, 0x54, 0, 0x12, 0x34, 0x56], Box::new(a));
+    let mut nic_b = VirtioNet::new([0x52, 0x54, 0, 0x12, 0x34, 0x57], Box::new(b));
+    let mut config = [0u8; 8];
+    nic_b.read_config(0, &mut config);
+    assert_eq!(config, [0x52, 0x54, 0, 0x12, 0x34, 0x57, 1, 0]);
+
+    let frame = (0..64u8).collect::<Vec<u8>>();
+    mem.write(0x80000800 + HDR_SIZE as u64, &frame).unwrap();
+    let mut queues_a = vec![Queue::default(), test_queue(&mem, 0x80000000, (HDR_SIZE + frame.len()) as u32, false)];
+    assert!(nic_a.notify(TX, &mut queues_a, &mem));
+    assert_eq!(mem.read_u16(0x80000400 + 2).unwrap(), 1);
+
+    let mut queues_b = vec![test_queue(&mem, 0x80001000, 2048, true), Queue::default()];
+    assert!(nic_b.poll(&mut queues_b, &mem));
+    assert_eq!(mem.read_u32(0x80001400 + 4 + 4).unwrap(), (HDR_SIZE + frame.len()) as u32);
+    let mut received = vec![0u8; frame.len()];
+    mem.read(0x80001800 + HDR_SIZE as u64, &mut received).unwrap();
+    assert_eq!(received, frame);
+    assert!(!nic_b.poll(&mut queues_b, &mem));
+}
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io::Read;
+use crate::devices::virtio::{VirtioDevice, GuestMemory, Queue};
+
+const VIRTIO_ID_RNG: u32 = 4;
+//bytes given to a request at most, the driver asks again for more
+const MAX_REQUEST: usize = 64 * 1024;
+
+//source of the entropy given to the guest
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RngSource {
+    Host,
+    //splitmix64 from the seed, restarted by reset, so runs are reproducible
+    Seeded(u64),
+}
+
+//entropy device, every buffer of the request queue is filled up
+pub struct VirtioRng {
+    source: RngSource,
+    urandom: Option<File>,
+    state: u64,
+}
+
+impl VirtioRng {
+    pub fn new(source: RngSource) -> Result<VirtioRng, String> {
+        let (urandom, state) = match source {
+            RngSource::Host => (Some(File::open("/dev/urandom").map_err(|e| { format!("/dev/urandom: {}", e) })?), 0),
+            RngSource::Seeded(seed) => (None, seed)
+        };
+        Ok(VirtioRng {
+            source,
+            urandom,
+            state,
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        if let Some(ref mut urandom) = self.urandom {
+            if urandom.read_exact(buf).is_ok() {
+                return;
+            }
+        }
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()])
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        data.iter_mut().for_each(|v| { *v = 0 })
+    }
+
+    fn reset(&mut self) {
+        if let RngSource::Seeded(seed) = self.source {
+            self.state = seed
+        }
+    }
+
+    fn notify(&mut self, _queue: usize, queues: &mut [Queue], mem: &GuestMemory) -> bool {
+        let q = &mut queues[0];
+        let mut used = false;
+        while let Some(chain) = q.pop(mem) {
+            let mut data = vec![0; chain.writable_len().min(MAX_REQUEST)];
+            self.fill(&mut data);
+            let len = chain.write_all(mem, &data).unwrap_or(0);
+            used |= q.push(mem, chain.head(), len as u32).is_ok()
+        }
+        used
+    }
+}
+
+#[test]
+fn virtio_rng_test() {
+    let mut a = VirtioRng::new(RngSource::Seeded(1)).unwrap();
+    let mut b = VirtioRng::new(RngSource::Seeded(1)).unwrap();
+    let mut data_a = [0; 13];
+    let mut data_b = [0; 13];
+    a.fill(&mut data_a);
+    b.fill(&mut data_b);
+    assert_eq!(data_a, data_b);
+    assert_ne!(data_a, [0; 13]);
+    a.fill(&mut data_b);
+    assert_ne!(data_a, data_b);
+    a.reset();
+    a.fill(&mut data_b);
+    assert_eq!(data_a, data_b);
+}
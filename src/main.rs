@@ -1,3 +1,689 @@
+use terminus::processor::{ProcessorCfg, Privilege};
+use terminus::system::{System, SystemBuilder};
+use terminus::system::config::MachineCfg;
+use terminus::system::gdb::{GdbServer, GdbExit};
+use terminus::system::fdt::{self, FdtNode};
+use terminus::devices::clint::Clint;
+use terminus::devices::aclint::{Mswi, Mtimer, Sswi, MSWI_SIZE, MTIMER_SIZE, SSWI_SIZE};
+use terminus::devices::net::{NetBackend, PcapBackend, UnixDgramBackend};
+use terminus::devices::virtio::VirtioDevice;
+use terminus::devices::virtio_net::VirtioNet;
+use terminus::devices::virtio_9p::Virtio9p;
+use terminus::devices::virtio_console::{VirtioConsole, ConsoleBackend};
+use terminus::devices::virtio_rng::{VirtioRng, RngSource};
+use terminus::devices::framebuffer::{Framebuffer, FbFormat};
+use terminus::processor::cache::{HierarchyCfg, CacheCfg, Replacement, WritePolicy};
+use terminus::processor::timing::{PipelineModel, PipelineCfg};
+use terminus_spaceport::memory::region::GHEAP;
+use terminus_spaceport::devices::term_exit;
+use terminus_spaceport::EXIT_CTRL;
+use std::process;
+use std::convert::TryFrom;
+
+//interrupt identities of imsic files and sources of aplic added by --device
+const IMSIC_NUM_IDS: u32 = 255;
+const APLIC_NUM_SOURCES: usize = 96;
+const CLIC_NUM_INTS: usize = 256;
+//source of rtc on aplic
+const RTC_IRQ: usize = 11;
+//virtio devices added by --net, --9p, --console and --rng in order, the nth one is at VIRTIO_BASE + n * 0x1000
+//and interrupts aplic at source VIRTIO_IRQ + n
+const VIRTIO_BASE: u64 = 0x10001000;
+const VIRTIO_IRQ: usize = 16;
+const FB_BASE: u64 = 0x40000000;
+
+const USAGE: &str = "usage: terminus [options] <elf> [args...]
+       terminus [options] --image <file>[@<addr>] --entry <addr>
+       terminus [options] --machine <file> [elf]
+       terminus [options] --dt <file> [elf]
+       terminus [options] --user <elf> [args...]
+options:
+    --machine <file>            build machine from .toml or .json description, elf overrides the one in file,
+                                options describing the machine are ignored
+    --dt <file>                 build harts, memories and devices from device tree blob or source, which is
+                                also passed to the guest. options describing harts, memories and devices are ignored
+    --isa <isa>                 isa string, e.g. rv64gcsu_zicsr_zifencei, default rv64imafdcsu. i implies zicsr and
+                                zifencei unless either is given
+    -p <n>                      number of harts, default 1
+    -m <base>:<size>            add memory region, can be repeated, default 0x80000000:0x80000000
+    --image <file>[@<addr>]     load image, can be repeated. elf, intel hex (.hex) and s-record (.srec) are
+                                placed at their addresses, or moved to addr if given. others are raw binaries
+                                placed at addr
+    --initrd <file>@<addr>      load initrd to addr and report it in /chosen of device tree
+    --dtb <file>                use the device tree blob instead of the generated one
+    --bootargs <args>           bootargs of device tree, default \"console=hvc0 earlycon=sbi\"
+    --stdout-path <path>        stdout-path of device tree
+    --boot-rom <addr>           generate boot rom with device tree at addr, harts start from it
+    --entry <addr>              override entry of harts
+    --elf-vaddr                 place elf segments at virtual addresses instead of physical ones
+    --sbi <addr>                boot in s-mode with built-in sbi firmware, device tree is placed at addr
+    --user                      run static rv64 linux elf in u-mode with emulated system calls,
+                                options describing memory, devices and boot are ignored
+    --semihosting               serve riscv semihosting requests of harts
+    --semihosting-root <dir>    confine host files opened by semihosting in dir, default .
+    --htif-root <dir>           host directory seen as \"/\" by htif system calls of riscv-pk, default .
+    --device <name>[@<base>]    add device, can be repeated, default clint@0x2000000
+                                devices: clint, aclint(mswi, mtimer and sswi at base, +0x4000 and +0xc000),
+                                imsic(m and s interrupt files at base and +0x4000000, default 0x24000000),
+                                aplic(s domain at base, default 0xd000000, sends msis to imsic if added before),
+                                clic(of hart i at base + i * 0x2000, default 0x2800000),
+                                syscon(sifive,test poweroff and reboot, default 0x100000),
+                                rtc(goldfish, default 0x101000, interrupts aplic at source 11 if added before)
+    --net <backend>             add virtio-net nic, can be repeated. backend is pcap:<out>[,<in>] to capture
+                                sent frames and replay received ones in simulated time, or unix:<path>:<peer>
+                                to exchange frames as datagrams with the unix socket peer
+    --9p <tag>:<dir>[,ro]       share host dir by virtio-9p, can be repeated. the guest can not leave dir,
+                                mount it by \"mount -t 9p -o trans=virtio,version=9p2000.L <tag> <path>\"
+    --console <backend>         add virtio-console, can be repeated. backend is stdio, file:<path> for output
+                                only, or unix:<path> to listen for a client
+    --rng <source>              add virtio-rng, source is host for host entropy, or seed:<n> for reproducible
+                                pseudo random numbers
+                                virtio devices interrupt aplic from source 16, which should be added before
+    --fb <w>x<h>[@<base>]       add simple-framebuffer of w x h pixels described in /chosen of device tree,
+                                default base 0x40000000
+    --fb-stride <bytes>         bytes of a framebuffer line, default w * bytes of a pixel
+    --fb-format <format>        pixel format of framebuffer, r5g6b5, r8g8b8, a8r8g8b8, x8r8g8b8, a8b8g8r8
+                                or x8b8g8r8, default a8r8g8b8
+    --fb-dump <file>            dump framebuffer to file at exit, png if file ends with .png, ppm otherwise.
+                                framebuffer can also be dumped by \"monitor fbdump <file>\" of gdb
+    --fb-dump-every <ms>        also dump framebuffer every ms of simulated time, to <file>-<time in ms>
+    --rtc-epoch <secs>          rtc starts from unix time secs and follows simulated time, default host time
+    --no-default-devices        do not add default devices
+    --freq <hz>                 hart frequency, default 1000000000
+    --timebase <hz>             timer frequency, default 10000000
+    --max-insns <n>             stop when a hart retired n instructions
+    --cache <l1>[,<l2>]         model l1i and l1d of each hart and a shared l2 of <size>:<ways>, with 64-byte
+                                lines, lru and write-back, e.g. 32768:4,262144:8. statistics are printed at exit
+    --pipeline                  model an in-order pipeline instead of one cycle per instruction, timing
+                                statistics of harts are printed at exit
+    --profile <dir>             sample pc of harts and write folded call stacks to dir at exit
+    --profile-interval <n>      sample every n retired instructions, default 1000
+    --trace                     print state of harts after every instruction
+    --gdb <port>                wait for gdb on 127.0.0.1:port
+    -h, --help                  print this message
+arguments after elf are passed to the guest, as argv of --user program, by htif to riscv-pk,
+or as command line of semihosting
+";
+
+struct Options {
+    isa: String,
+    harts: usize,
+    mems: Vec<(u64, u64)>,
+    images: Vec<(String, Option<u64>)>,
+    initrd: Option<(String, u64)>,
+    dtb: Option<String>,
+    bootargs: Option<String>,
+    stdout_path: Option<String>,
+    boot_rom: Option<u64>,
+    entry: Option<u64>,
+    elf_vaddr: bool,
+    sbi: Option<u64>,
+    devices: Vec<(String, Option<u64>)>,
+    default_devices: bool,
+    freq: usize,
+    timebase: usize,
+    nets: Vec<String>,
+    shares: Vec<String>,
+    consoles: Vec<String>,
+    rng: Option<String>,
+    fb: Option<(u32, u32, u64)>,
+    fb_stride: Option<u32>,
+    fb_format: String,
+    fb_dump: Option<String>,
+    fb_dump_every: Option<u64>,
+    rtc_epoch: Option<u64>,
+    max_insns: Option<u64>,
+    cache: Option<HierarchyCfg>,
+    pipeline: bool,
+    profile: Option<String>,
+    profile_interval: Option<u64>,
+    trace: bool,
+    gdb: Option<u16>,
+    machine: Option<String>,
+    dt: Option<String>,
+    elf: Option<String>,
+    user: bool,
+    semihosting: bool,
+    semihosting_root: String,
+    htif_root: String,
+    guest_args: Vec<String>,
+}
+
+fn parse_u64(s: &str) -> Result<u64, String> {
+    let result = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<u64>()
+    };
+    result.map_err(|_| { format!("invalid number \"{}\"!", s) })
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    u32::try_from(parse_u64(s)?).map_err(|_| { format!("number \"{}\" is out of 32 bits!", s) })
+}
+
+//<size>:<ways> of 64-byte lines, lru and write-back with write allocate
+fn parse_cache(s: &str) -> Result<CacheCfg, String> {
+    let (size, ways) = parse_pair(s, ':')?;
+    Ok(CacheCfg {
+        size: parse_u64(size)? as usize,
+        ways: parse_u64(ways)? as usize,
+        line_size: 64,
+        replacement: Replacement::LRU,
+        write: WritePolicy::WriteBack,
+        write_allocate: true,
+    })
+}
+
+fn parse_pair<'a>(s: &'a str, sep: char) -> Result<(&'a str, &'a str), String> {
+    let mut iter = s.splitn(2, sep);
+    match (iter.next(), iter.next()) {
+        (Some(a), Some(b)) => Ok((a, b)),
+        _ => Err(format!("\"{}\" should be in format of \"a{}b\"!", s, sep))
+    }
+}
+
+fn parse_args<I: Iterator<Item=String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        isa: "rv64imafdcsu".to_string(),
+        harts: 1,
+        mems: vec![],
+        images: vec![],
+        initrd: None,
+        dtb: None,
+        bootargs: None,
+        stdout_path: None,
+        boot_rom: None,
+        entry: None,
+        elf_vaddr: false,
+        sbi: None,
+        devices: vec![],
+        default_devices: true,
+        freq: 1000000000,
+        timebase: 10000000,
+        nets: vec![],
+        shares: vec![],
+        consoles: vec![],
+        rng: None,
+        fb: None,
+        fb_stride: None,
+        fb_format: "a8r8g8b8".to_string(),
+        fb_dump: None,
+        fb_dump_every: None,
+        rtc_epoch: None,
+        max_insns: None,
+        cache: None,
+        pipeline: false,
+        profile: None,
+        profile_interval: None,
+        trace: false,
+        gdb: None,
+        machine: None,
+        dt: None,
+        elf: None,
+        user: false,
+        semihosting: false,
+        semihosting_root: ".".to_string(),
+        htif_root: ".".to_string(),
+        guest_args: vec![],
+    };
+    while let Some(arg) = args.next() {
+        if options.elf.is_some() {
+            options.guest_args.push(arg);
+            continue;
+        }
+        let mut value = || { args.next().ok_or(format!("{} requires a value!", arg)) };
+        match arg.as_str() {
+            "--isa" => options.isa = value()?,
+            "-p" => options.harts = parse_u64(&value()?)? as usize,
+            "-m" => {
+                let v = value()?;
+                let (base, size) = parse_pair(&v, ':')?;
+                options.mems.push((parse_u64(base)?, parse_u64(size)?))
+            }
+            "--image" => {
+                let v = value()?;
+                if let Ok((file, addr)) = parse_pair(&v, '@') {
+                    options.images.push((file.to_string(), Some(parse_u64(addr)?)))
+                } else {
+                    options.images.push((v.clone(), None))
+                }
+            }
+            "--initrd" => {
+                let v = value()?;
+                let (file, addr) = parse_pair(&v, '@')?;
+                options.initrd = Some((file.to_string(), parse_u64(addr)?))
+            }
+            "--dtb" => options.dtb = Some(value()?),
+            "--bootargs" => options.bootargs = Some(value()?),
+            "--stdout-path" => options.stdout_path = Some(value()?),
+            "--boot-rom" => options.boot_rom = Some(parse_u64(&value()?)?),
+            "--entry" => options.entry = Some(parse_u64(&value()?)?),
+            "--elf-vaddr" => options.elf_vaddr = true,
+            "--sbi" => options.sbi = Some(parse_u64(&value()?)?),
+            "--device" => {
+                let v = value()?;
+                if let Ok((name, base)) = parse_pair(&v, '@') {
+                    options.devices.push((name.to_string(), Some(parse_u64(base)?)))
+                } else {
+                    options.devices.push((v.clone(), None))
+                }
+            }
+            "--no-default-devices" => options.default_devices = false,
+            "--freq" => options.freq = parse_u64(&value()?)? as usize,
+            "--timebase" => options.timebase = parse_u64(&value()?)? as usize,
+            "--net" => options.nets.push(value()?),
+            "--9p" => options.shares.push(value()?),
+            "--console" => options.consoles.push(value()?),
+            "--rng" => options.rng = Some(value()?),
+            "--fb" => {
+                let v = value()?;
+                let (size, base) = parse_pair(&v, '@').map_or((v.as_str(), None), |(s, b)| { (s, Some(b)) });
+                let (width, height) = parse_pair(size, 'x')?;
+                let base = base.map(parse_u64).transpose()?.unwrap_or(FB_BASE);
+                options.fb = Some((parse_u32(width)?, parse_u32(height)?, base))
+            }
+            "--fb-stride" => options.fb_stride = Some(parse_u32(&value()?)?),
+            "--fb-format" => options.fb_format = value()?,
+            "--fb-dump" => options.fb_dump = Some(value()?),
+            "--fb-dump-every" => options.fb_dump_every = Some(parse_u64(&value()?)?),
+            "--rtc-epoch" => {
+                let secs = parse_u64(&value()?)?;
+                options.rtc_epoch = Some(secs.checked_mul(1_000_000_000).ok_or(format!("rtc epoch {} is too large!", secs))?)
+            }
+            "--max-insns" => options.max_insns = Some(parse_u64(&value()?)?),
+            "--cache" => {
+                let v = value()?;
+                let (l1, l2) = parse_pair(&v, ',').map_or((v.as_str(), None), |(l1, l2)| { (l1, Some(l2)) });
+                let l1 = parse_cache(l1)?;
+                options.cache = Some(HierarchyCfg { l1i: l1.clone(), l1d: l1, l2: l2.map(parse_cache).transpose()? })
+            }
+            "--pipeline" => options.pipeline = true,
+            "--profile" => options.profile = Some(value()?),
+            "--profile-interval" => options.profile_interval = Some(parse_u64(&value()?)?),
+            "--trace" => options.trace = true,
+            "--gdb" => options.gdb = Some(parse_u64(&value()?)? as u16),
+            "--machine" => options.machine = Some(value()?),
+            "--dt" => options.dt = Some(value()?),
+            "--user" => options.user = true,
+            "--semihosting" => options.semihosting = true,
+            "--semihosting-root" => options.semihosting_root = value()?,
+            "--htif-root" => options.htif_root = value()?,
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0)
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}!", arg)),
+            _ => options.elf = Some(arg.clone())
+        }
+    }
+    if options.elf.is_none() && options.machine.is_none() && (options.images.is_empty() || options.entry.is_none()) {
+        return Err("elf, or images with entry is required!".to_string());
+    }
+    if options.user && options.elf.is_none() {
+        return Err("--user requires elf!".to_string());
+    }
+    if options.semihosting && (options.user || options.sbi.is_some()) {
+        return Err("--semihosting can not be used with --user or --sbi!".to_string());
+    }
+    let virtio = !options.nets.is_empty() || !options.shares.is_empty() || !options.consoles.is_empty() || options.rng.is_some();
+    if options.dt.is_some() && (options.dtb.is_some() || options.user || virtio || options.fb.is_some()) {
+        return Err("--dt can not be used with --dtb, --user, virtio devices or --fb!".to_string());
+    }
+    if options.fb_dump_every.is_some() && options.fb_dump.is_none() {
+        return Err("--fb-dump-every requires --fb-dump!".to_string());
+    }
+    if options.fb_dump_every == Some(0) {
+        return Err("--fb-dump-every should be greater than 0!".to_string());
+    }
+    if options.profile_interval.is_some() && options.profile.is_none() {
+        return Err("--profile-interval requires --profile!".to_string());
+    }
+    if options.profile_interval == Some(0) {
+        return Err("--profile-interval should be greater than 0!".to_string());
+    }
+    if options.sbi.is_some() && options.boot_rom.is_some() {
+        return Err("--sbi and --boot-rom can not be used together!".to_string());
+    }
+    if options.harts == 0 {
+        return Err("number of harts should be greater than 0!".to_string());
+    }
+    if options.dt.is_some() {
+        options.mems.clear();
+        options.devices.clear();
+        return Ok(options);
+    }
+    if options.mems.is_empty() {
+        options.mems.push((0x80000000, 0x80000000))
+    }
+    if options.default_devices && !options.devices.iter().any(|(name, _)| { name == "clint" || name == "aclint" }) {
+        options.devices.push(("clint".to_string(), None))
+    }
+    Ok(options)
+}
+
+//blob or source detected by magic
+fn read_dt(file: &str) -> Result<FdtNode, String> {
+    let content = std::fs::read(file).map_err(|e| { format!("{}: {}", file, e) })?;
+    let result = if content.starts_with(&[0xd0, 0x0d, 0xfe, 0xed]) {
+        fdt::parse(&content)
+    } else {
+        fdt::parse_dts(&String::from_utf8_lossy(&content))
+    };
+    result.map_err(|e| { format!("{}: {}", file, e) })
+}
+
+fn net_backend(sys: &System, net: &str) -> Result<Box<dyn NetBackend>, String> {
+    let (kind, args) = parse_pair(net, ':')?;
+    match kind {
+        "pcap" => {
+            let (output, input) = parse_pair(args, ',').map_or((args, None), |(o, i)| { (o, Some(i)) });
+            Ok(Box::new(PcapBackend::new(sys.timer(), Some(output), input)?))
+        }
+        "unix" => {
+            let (path, peer) = parse_pair(args, ':')?;
+            Ok(Box::new(UnixDgramBackend::new(path, peer)?))
+        }
+        _ => Err(format!("unknown net backend {}!", kind))
+    }
+}
+
+fn build(options: &Options) -> Result<System, String> {
+    if let Some(ref file) = options.machine {
+        let mut cfg = MachineCfg::from_file(file)?;
+        if let Some(ref elf) = options.elf {
+            cfg.boot.elf = Some(elf.clone())
+        }
+        let mut sys = System::from_config(&cfg).map_err(|e| { format!("{:?}", e) })?;
+        if options.semihosting {
+            let cmdline = cfg.boot.elf.iter().chain(options.guest_args.iter()).cloned().collect::<Vec<String>>().join(" ");
+            sys.enable_semihosting(&options.semihosting_root, &cmdline).map_err(|e| { format!("{:?}", e) })?;
+        }
+        return Ok(sys);
+    }
+    let mut builder = if let Some(ref dt) = options.dt {
+        SystemBuilder::new("terminus").fdt(read_dt(dt)?)
+    } else {
+        let configs = vec![ProcessorCfg::from_isa(&options.isa, options.freq)?; options.harts];
+        SystemBuilder::new("terminus").timer_freq(options.timebase).processors(configs)
+    }.elf_virtual_addr(options.elf_vaddr).htif_root(&options.htif_root);
+    if let Some(epoch) = options.rtc_epoch {
+        builder = builder.rtc_epoch(epoch)
+    }
+    if let Some(ref bootargs) = options.bootargs {
+        builder = builder.bootargs(bootargs)
+    }
+    if let Some(ref path) = options.stdout_path {
+        builder = builder.stdout_path(path)
+    }
+    let mut args = vec![];
+    if let Some(ref elf) = options.elf {
+        builder = builder.elf_file(elf);
+        args.push(elf.clone());
+        args.extend(options.guest_args.iter().cloned());
+        builder = builder.htif_args(args.clone())
+    }
+    let mut sys = builder.build().map_err(|e| { format!("{:?}", e) })?;
+    if options.user {
+        let envs = std::env::vars().map(|(k, v)| { format!("{}={}", k, v) }).collect::<Vec<String>>();
+        sys.boot_linux_user(&args, &envs).map_err(|e| { format!("{:?}", e) })?;
+        return Ok(sys);
+    }
+    for (i, (base, size)) in options.mems.iter().enumerate() {
+        let name = if i == 0 {
+            "main_memory".to_string()
+        } else {
+            format!("memory{}", i)
+        };
+        let mem = GHEAP.alloc(*size, 1).map_err(|e| { format!("{} alloc fail! {:?}", name, e) })?;
+        sys.register_memory(&name, *base, &mem).map_err(|e| { format!("{:?}", e) })?;
+    }
+    let mut imsic_s = None;
+    for (name, base) in options.devices.iter() {
+        let result = match name.as_str() {
+            "clint" => sys.register_device("clint", base.unwrap_or(0x02000000), 0x000c0000, Clint::new(sys.timer())),
+            "aclint" => {
+                let base = base.unwrap_or(0x02000000);
+                let eirqs = sys.processors().iter().map(|p| { p.state().eirq().clone() }).collect();
+                sys.register_device("aclint_mswi", base, MSWI_SIZE, Mswi::new(sys.timer()))
+                    .and_then(|_| { sys.register_device("aclint_mtimer", base + MSWI_SIZE, MTIMER_SIZE, Mtimer::new(sys.timer())) })
+                    .and_then(|_| { sys.register_device("aclint_sswi", base + MSWI_SIZE + MTIMER_SIZE, SSWI_SIZE, Sswi::new(eirqs)) })
+            }
+            "imsic" => {
+                let base = base.unwrap_or(0x24000000);
+                let ssaia = sys.processors().iter().all(|p| { p.state().config().isa.has("ssaia") });
+                sys.register_imsic("imsic_m", base, Privilege::M, IMSIC_NUM_IDS).and_then(|_| {
+                    if ssaia {
+                        imsic_s = Some(sys.register_imsic("imsic_s", base + 0x4000000, Privilege::S, IMSIC_NUM_IDS)?);
+                    }
+                    Ok(())
+                })
+            }
+            "clic" => {
+                let base = base.unwrap_or(0x2800000);
+                (0..sys.processors().len()).try_for_each(|i| {
+                    sys.register_clic(&format!("clic{}", i), base + i as u64 * 0x2000, i, CLIC_NUM_INTS).map(|_| {})
+                })
+            }
+            "syscon" => sys.register_syscon("syscon", base.unwrap_or(0x100000), 0x1000),
+            "rtc" => {
+                let irq = sys.irq_line("aplic_s", RTC_IRQ).ok();
+                sys.register_rtc("rtc", base.unwrap_or(0x101000), irq)
+            }
+            "aplic" => sys.register_aplic("aplic_s", base.unwrap_or(0xd000000), Privilege::S, APLIC_NUM_SOURCES, imsic_s.as_ref().map(|imsic| { ("imsic_s", imsic) })),
+            _ => return Err(format!("unknown device {}!", name))
+        };
+        result.map_err(|e| { format!("{:?}", e) })?;
+    }
+    let mut virtios: Vec<(String, Box<dyn VirtioDevice>)> = vec![];
+    for (i, net) in options.nets.iter().enumerate() {
+        let nic = VirtioNet::new([0x52, 0x54, 0, 0x12, 0x34, (0x56 + i) as u8], net_backend(&sys, net)?);
+        virtios.push((format!("net{}", i), Box::new(nic)))
+    }
+    for (i, share) in options.shares.iter().enumerate() {
+        let (tag, dir) = parse_pair(share, ':')?;
+        let (dir, readonly) = if let Some(dir) = dir.strip_suffix(",ro") {
+            (dir, true)
+        } else {
+            (dir, false)
+        };
+        virtios.push((format!("9p{}", i), Box::new(Virtio9p::new(tag, dir, readonly)?)))
+    }
+    for (i, console) in options.consoles.iter().enumerate() {
+        let backend = match parse_pair(console, ':') {
+            Ok(("file", path)) => ConsoleBackend::file(path)?,
+            Ok(("unix", path)) => ConsoleBackend::unix(path)?,
+            _ if console == "stdio" => ConsoleBackend::Stdio,
+            _ => return Err(format!("unknown console backend {}!", console))
+        };
+        virtios.push((format!("console{}", i), Box::new(VirtioConsole::new(backend))))
+    }
+    if let Some(ref rng) = options.rng {
+        let source = match parse_pair(rng, ':') {
+            Ok(("seed", seed)) => RngSource::Seeded(parse_u64(seed)?),
+            _ if rng == "host" => RngSource::Host,
+            _ => return Err(format!("unknown rng source {}!", rng))
+        };
+        virtios.push(("rng".to_string(), Box::new(VirtioRng::new(source)?)))
+    }
+    for (i, (name, device)) in virtios.into_iter().enumerate() {
+        if VIRTIO_IRQ + i > APLIC_NUM_SOURCES {
+            return Err(format!("too many virtio devices, aplic has {} sources!", APLIC_NUM_SOURCES));
+        }
+        let irq = sys.irq_line("aplic_s", VIRTIO_IRQ + i).map_err(|_| { format!("{} requires aplic, add it by \"--device aplic\"!", name) })?;
+        sys.register_virtio(&name, VIRTIO_BASE + i as u64 * 0x1000, device, Some(irq)).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if let Some((width, height, base)) = options.fb {
+        let format = FbFormat::parse(&options.fb_format)?;
+        sys.register_framebuffer(base, width, height, options.fb_stride, format).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if let Some(ref dtb) = options.dtb {
+        let dtb = std::fs::read(dtb).map_err(|e| { format!("{}: {}", dtb, e) })?;
+        sys.set_dtb(dtb).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if let Some((ref file, addr)) = options.initrd {
+        sys.load_initrd(file, addr).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if let Some(base) = options.boot_rom {
+        sys.make_boot_rom(base, options.entry).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if options.elf.is_some() {
+        sys.load_elf().map_err(|e| { format!("{:?}", e) })?;
+    }
+    for (file, addr) in options.images.iter() {
+        sys.load_file(file, None, *addr).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if let Some(dtb_addr) = options.sbi {
+        sys.boot_sbi(options.entry, dtb_addr).map_err(|e| { format!("{:?}", e) })?;
+        return Ok(sys);
+    }
+    //boot rom jumps to entry
+    let reset_vec = if options.boot_rom.is_some() {
+        None
+    } else {
+        options.entry
+    };
+    let num_harts = sys.processors().len();
+    sys.reset(vec![reset_vec; num_harts]).map_err(|e| { format!("{:?}", e) })?;
+    if options.semihosting {
+        sys.enable_semihosting(&options.semihosting_root, &args.join(" ")).map_err(|e| { format!("{:?}", e) })?;
+    }
+    Ok(sys)
+}
+
+//cache, pipeline and profiler models of harts
+fn instrument(options: &Options, sys: &mut System) -> Result<(), String> {
+    if let Some(ref cfg) = options.cache {
+        sys.enable_mem_hierarchy(cfg.clone()).map_err(|e| { format!("{:?}", e) })?;
+    }
+    if options.pipeline {
+        sys.set_timing_model(|_| { Box::new(PipelineModel::new(PipelineCfg::default()).unwrap()) });
+    }
+    if options.profile.is_some() {
+        sys.enable_profiler(options.profile_interval.unwrap_or(1000));
+    }
+    Ok(())
+}
+
+//statistics of the models, false if the profile can not be written
+fn report(options: &Options, sys: &mut System) -> bool {
+    if let Some(mem_hierarchy) = sys.mem_hierarchy() {
+        eprint!("{}", mem_hierarchy)
+    }
+    for p in sys.processors().iter() {
+        if let Some(stats) = p.timing_stats() {
+            eprintln!("hart{} timing: {}", p.state().hartid(), stats)
+        }
+    }
+    if let Some(ref dir) = options.profile {
+        if let Err(e) = sys.write_profile(dir) {
+            eprintln!("{}: {:?}", dir, e);
+            return false;
+        }
+    }
+    true
+}
+
+fn run(options: &Options, sys: &mut System) -> i32 {
+    if let Some(port) = options.gdb {
+        let result = GdbServer::new(port).and_then(|mut server| { server.serve(sys) });
+        match result {
+            Ok(GdbExit::Exited) => return sys.exit_code().unwrap_or(0) as i32,
+            Ok(GdbExit::Killed) => return 0,
+            Ok(GdbExit::Detached) => {}
+            Err(e) => {
+                eprintln!("gdb: {}", e);
+                return 1;
+            }
+        }
+    }
+    //step harts by interval, instruction limit and tracing need one instruction per step
+    let interval = if options.trace || options.max_insns.is_some() { 1 } else { 100 };
+    loop {
+        if let Ok(msg) = EXIT_CTRL.poll() {
+            eprintln!("{}", msg);
+            return sys.exit_code().unwrap_or(0) as i32;
+        }
+        sys.step(interval);
+        if options.trace {
+            for p in sys.processors() {
+                eprintln!("{}", p.state().trace())
+            }
+        }
+        if let Some(max_insns) = options.max_insns {
+            if sys.processors().iter().any(|p| { *p.state().insns_cnt().borrow() >= max_insns }) {
+                eprintln!("instruction limit {} reached!", max_insns);
+                return 1;
+            }
+        }
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprint!("{}", USAGE);
+            process::exit(2)
+        }
+    };
+    let mut sys = match build(&options).and_then(|mut sys| { instrument(&options, &mut sys).map(|_| { sys }) }) {
+        Ok(sys) => sys,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2)
+        }
+    };
+    //framebuffer is added by --fb or described in --dt
+    let fb = match (&options.fb_dump, sys.framebuffer()) {
+        (Some(_), None) => {
+            eprintln!("--fb-dump requires framebuffer!");
+            process::exit(2)
+        }
+        (_, fb) => fb.cloned()
+    };
+    if let (Some(fb), Some(file), Some(ms)) = (&fb, &options.fb_dump, options.fb_dump_every) {
+        Framebuffer::dump_every(fb, sys.timer(), (sys.timer().freq() as u64 * ms / 1000).max(1), file)
+    }
+    let mut code = run(&options, &mut sys);
+    if !report(&options, &mut sys) {
+        code = 1
+    }
+    if let (Some(fb), Some(file)) = (&fb, &options.fb_dump) {
+        if let Err(e) = fb.dump(file) {
+            eprintln!("{}", e);
+            code = 1
+        }
+    }
+    term_exit();
+    process::exit(code)
+}
+
+#[test]
+fn parse_args_test() {
+    let args = |s: &str| { s.split_whitespace().map(|a| { a.to_string() }).collect::<Vec<_>>().into_iter() };
+    let options = parse_args(args("-m 0x80000000:0x10000000 --fb 640x480@0x50000000 --image a.bin@0x80000000 a.elf x -m")).unwrap();
+    assert_eq!(options.mems, vec![(0x80000000, 0x10000000)]);
+    assert_eq!(options.fb, Some((640, 480, 0x50000000)));
+    assert_eq!(options.images, vec![("a.bin".to_string(), Some(0x80000000))]);
+    assert_eq!(options.elf.as_deref(), Some("a.elf"));
+    assert_eq!(options.guest_args, vec!["x".to_string(), "-m".to_string()]);
+    assert_eq!(options.devices, vec![("clint".to_string(), None)]);
+    let options = parse_args(args("--fb 320x200 a.elf")).unwrap();
+    assert_eq!(options.fb, Some((320, 200, FB_BASE)));
+    assert_eq!(options.mems, vec![(0x80000000, 0x80000000)]);
+    assert!(parse_args(args("--dt a.dts --fb 320x200 a.elf")).is_err());
+    assert!(parse_args(args("--fb 4294967616x200 a.elf")).is_err());
+    assert!(parse_args(args("-m 0x80000000 a.elf")).is_err());
+    assert!(parse_args(args("--fb-dump-every 10 a.elf")).is_err());
+    let options = parse_args(args("--cache 32768:4,0x40000:8 --pipeline --profile out a.elf")).unwrap();
+    let cache = options.cache.unwrap();
+    assert_eq!((cache.l1d.size, cache.l1d.ways, cache.l2.map(|l2| { l2.size })), (32768, 4, Some(0x40000)));
+    assert!(options.pipeline);
+    assert!(parse_args(args("--cache 32768 a.elf")).is_err());
+    assert!(parse_args(args("--profile-interval 10 a.elf")).is_err());
+    assert!(parse_args(args("--user")).is_err());
+    assert!(parse_args(args("--bad a.elf")).is_err());
+    assert_eq!(parse_args(args("a.elf --isa")).unwrap().guest_args, vec!["--isa".to_string()]);
 }
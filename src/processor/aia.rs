@@ -0,0 +1,118 @@
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use terminus_global::{RegT, InsnT, XLen};
+use crate::devices::imsic::ImsicFile;
+use super::{ProcessorState, Privilege};
+use super::trap::Interrupt;
+
+const MISELECT: InsnT = 0x350;
+const MIREG: InsnT = 0x351;
+const MTOPEI: InsnT = 0x35c;
+const MTOPI: InsnT = 0xfb0;
+const SISELECT: InsnT = 0x150;
+const SIREG: InsnT = 0x151;
+const STOPEI: InsnT = 0x15c;
+const STOPI: InsnT = 0xdb0;
+
+//major interrupt priorities, read-only zero so the default order is used
+const IPRIO0: RegT = 0x30;
+const IPRIO15: RegT = 0x3f;
+
+//smaia and ssaia csrs, interrupt file registers behind mireg and sireg are in imsic files attached by System
+pub struct Aia {
+    ssaia: bool,
+    miselect: Cell<RegT>,
+    siselect: Cell<RegT>,
+    //interrupt files of m and s level
+    files: RefCell<[Option<Arc<ImsicFile>>; 2]>,
+}
+
+impl Aia {
+    pub fn new(ssaia: bool) -> Aia {
+        Aia {
+            ssaia,
+            miselect: Cell::new(0),
+            siselect: Cell::new(0),
+            files: RefCell::new([None, None]),
+        }
+    }
+
+    pub fn attach(&self, level: Privilege, file: &Arc<ImsicFile>) -> Result<(), String> {
+        let i = match level {
+            Privilege::M => 0,
+            Privilege::S if self.ssaia => 1,
+            _ => return Err(format!("interrupt file of {:?} mode is not supported!", level))
+        };
+        self.files.borrow_mut()[i] = Some(file.clone());
+        Ok(())
+    }
+
+    fn file(&self, level: Privilege) -> Option<Arc<ImsicFile>> {
+        self.files.borrow()[if level == Privilege::M { 0 } else { 1 }].clone()
+    }
+
+    fn ireg(&self, level: Privilege, select: RegT, xlen: XLen) -> Option<RegT> {
+        match select {
+            IPRIO0..=IPRIO15 if xlen == XLen::X64 && select & 1 == 1 => None,
+            IPRIO0..=IPRIO15 => Some(0),
+            _ => self.file(level).and_then(|f| { f.read_ireg(select, xlen) })
+        }
+    }
+
+    fn set_ireg(&self, level: Privilege, select: RegT, xlen: XLen, value: RegT) -> Option<()> {
+        match select {
+            IPRIO0..=IPRIO15 if xlen == XLen::X64 && select & 1 == 1 => None,
+            IPRIO0..=IPRIO15 => Some(()),
+            _ => self.file(level).and_then(|f| { f.write_ireg(select, xlen, value) })
+        }
+    }
+
+    //identity in both fields, 0 if no interrupt
+    fn topei(&self, level: Privilege) -> Option<RegT> {
+        self.file(level).map(|f| {
+            let id = f.top() as RegT;
+            (id << 16) | id
+        })
+    }
+
+    //major identity and priority 1 of the top interrupt
+    fn topi(int: Option<Interrupt>) -> RegT {
+        int.map_or(0, |i| { (i.code() << 16) | 1 })
+    }
+
+    //none if id is not an aia csr, or the access is illegal
+    pub fn csr_read(&self, state: &ProcessorState, id: InsnT) -> Option<RegT> {
+        let xlen = state.config().xlen;
+        match id {
+            MISELECT => Some(self.miselect.get()),
+            MIREG => self.ireg(Privilege::M, self.miselect.get(), xlen),
+            MTOPEI => self.topei(Privilege::M),
+            MTOPI => Some(Aia::topi(Interrupt::top(state.pending_interrupts().0))),
+            SISELECT if self.ssaia => Some(self.siselect.get()),
+            SIREG if self.ssaia => self.ireg(Privilege::S, self.siselect.get(), xlen),
+            STOPEI if self.ssaia => self.topei(Privilege::S),
+            STOPI if self.ssaia => Some(Aia::topi(Interrupt::top(state.pending_interrupts().1))),
+            _ => None
+        }
+    }
+
+    pub fn csr_write(&self, state: &ProcessorState, id: InsnT, value: RegT) -> Option<()> {
+        let xlen = state.config().xlen;
+        match id {
+            MISELECT => {
+                self.miselect.set(value & 0xfff);
+                Some(())
+            }
+            MIREG => self.set_ireg(Privilege::M, self.miselect.get(), xlen, value),
+            //writing claims the top identity
+            MTOPEI => self.file(Privilege::M).map(|f| { f.claim(); }),
+            SISELECT if self.ssaia => {
+                self.siselect.set(value & 0xfff);
+                Some(())
+            }
+            SIREG if self.ssaia => self.set_ireg(Privilege::S, self.siselect.get(), xlen, value),
+            STOPEI if self.ssaia => self.file(Privilege::S).map(|f| { f.claim(); }),
+            _ => None
+        }
+    }
+}
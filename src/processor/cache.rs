@@ -0,0 +1,517 @@
+use crate::devices::bus::Bus;
+use std::sync::Arc;
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Replacement {
+    LRU,
+    FIFO,
+    Random,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheCfg {
+    pub size: usize,
+    pub ways: usize,
+    pub line_size: usize,
+    pub replacement: Replacement,
+    pub write: WritePolicy,
+    pub write_allocate: bool,
+}
+
+impl CacheCfg {
+    fn sets(&self) -> usize {
+        self.size / (self.ways * self.line_size)
+    }
+
+    fn check(&self, name: &str) -> Result<(), String> {
+        if !self.line_size.is_power_of_two() || self.ways == 0 || self.size == 0 || self.size % (self.ways * self.line_size) != 0 || !self.sets().is_power_of_two() {
+            Err(format!("{}: invalid geometry {:?}, sets and line size must be power of 2!", name, self))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HierarchyCfg {
+    pub l1i: CacheCfg,
+    pub l1d: CacheCfg,
+    pub l2: Option<CacheCfg>,
+}
+
+//where an access is served
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum HitLevel {
+    L1,
+    L2,
+    Memory,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_misses: u64,
+    pub write_misses: u64,
+    pub writebacks: u64,
+    //lines invalidated by writes of other harts
+    pub coherence_invalidations: u64,
+    //dirty lines written back because other harts accessed them
+    pub snoop_writebacks: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.read_misses + self.write_misses
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.accesses() - self.misses()
+    }
+
+    fn merge(&mut self, other: &CacheStats) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.read_misses += other.read_misses;
+        self.write_misses += other.write_misses;
+        self.writebacks += other.writebacks;
+        self.coherence_invalidations += other.coherence_invalidations;
+        self.snoop_writebacks += other.snoop_writebacks;
+    }
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let rate = if self.accesses() == 0 {
+            0.0
+        } else {
+            self.misses() as f64 * 100.0 / self.accesses() as f64
+        };
+        write!(f, "accesses = {}; hits = {}; misses = {}({:.2}%); read_misses = {}; write_misses = {}; writebacks = {}; coherence_invalidations = {}; snoop_writebacks = {};",
+               self.accesses(), self.hits(), self.misses(), rate, self.read_misses, self.write_misses, self.writebacks, self.coherence_invalidations, self.snoop_writebacks)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Line {
+    tag: u64,
+    valid: bool,
+    dirty: bool,
+    stamp: u64,
+}
+
+struct AccessResult {
+    hit: bool,
+    //line address and dirty of victim
+    victim: Option<(u64, bool)>,
+}
+
+struct Cache {
+    cfg: CacheCfg,
+    sets: Vec<Vec<Line>>,
+    clock: u64,
+    seed: u64,
+    stats: CacheStats,
+}
+
+impl Cache {
+    fn new(cfg: &CacheCfg) -> Cache {
+        Cache {
+            cfg: cfg.clone(),
+            sets: vec![vec![Line { tag: 0, valid: false, dirty: false, stamp: 0 }; cfg.ways]; cfg.sets()],
+            clock: 0,
+            seed: 0x2545f4914f6cdd1d,
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn index(&self, addr: u64) -> (usize, u64) {
+        let line = addr / self.cfg.line_size as u64;
+        ((line as usize) & (self.sets.len() - 1), line / self.sets.len() as u64)
+    }
+
+    fn find(&self, addr: u64) -> Option<(usize, usize)> {
+        let (set, tag) = self.index(addr);
+        self.sets[set].iter().position(|l| { l.valid && l.tag == tag }).map(|way| { (set, way) })
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        if let Some(way) = self.sets[set].iter().position(|l| { !l.valid }) {
+            return way;
+        }
+        match self.cfg.replacement {
+            //stamp is updated on every access for LRU, and only on fill for FIFO
+            Replacement::LRU | Replacement::FIFO => {
+                self.sets[set].iter().enumerate().min_by_key(|(_, l)| { l.stamp }).unwrap().0
+            }
+            Replacement::Random => {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                (self.seed as usize) % self.cfg.ways
+            }
+        }
+    }
+
+    //allocate decides whether a missed line is filled
+    fn access(&mut self, addr: u64, write: bool, allocate: bool) -> AccessResult {
+        self.clock += 1;
+        if write {
+            self.stats.writes += 1
+        } else {
+            self.stats.reads += 1
+        }
+        let dirty = write && self.cfg.write == WritePolicy::WriteBack;
+        if let Some((set, way)) = self.find(addr) {
+            let line = &mut self.sets[set][way];
+            if self.cfg.replacement == Replacement::LRU {
+                line.stamp = self.clock;
+            }
+            line.dirty |= dirty;
+            return AccessResult { hit: true, victim: None };
+        }
+        if write {
+            self.stats.write_misses += 1
+        } else {
+            self.stats.read_misses += 1
+        }
+        if !allocate {
+            return AccessResult { hit: false, victim: None };
+        }
+        let (set, tag) = self.index(addr);
+        let way = self.victim(set);
+        let old = self.sets[set][way];
+        let victim = if old.valid {
+            if old.dirty {
+                self.stats.writebacks += 1;
+            }
+            Some(((old.tag * self.sets.len() as u64 + set as u64) * self.cfg.line_size as u64, old.dirty))
+        } else {
+            None
+        };
+        self.sets[set][way] = Line { tag, valid: true, dirty, stamp: self.clock };
+        AccessResult { hit: false, victim }
+    }
+
+    //drop the line, return if it was dirty
+    fn invalidate(&mut self, addr: u64) -> Option<bool> {
+        if let Some((set, way)) = self.find(addr) {
+            let line = &mut self.sets[set][way];
+            line.valid = false;
+            Some(line.dirty)
+        } else {
+            None
+        }
+    }
+
+    fn clean(&mut self, addr: u64) -> bool {
+        if let Some((set, way)) = self.find(addr) {
+            let line = &mut self.sets[set][way];
+            let dirty = line.dirty;
+            line.dirty = false;
+            dirty
+        } else {
+            false
+        }
+    }
+}
+
+struct HierarchyInner {
+    l1i: Vec<Cache>,
+    l1d: Vec<Cache>,
+    l2: Option<Cache>,
+    //(base, size) of memory region -> (hartid -> stats of l1i and l1d)
+    regions: BTreeMap<(u64, u64), Vec<(CacheStats, CacheStats)>>,
+    last_region: Option<(u64, u64)>,
+}
+
+//MemHierarchy models private l1i/l1d of each hart and an optional shared l2,
+//it is driven by physical addresses of Fetcher and LoadStore, memory contents are always accessed through Bus.
+//l1d of harts are kept coherent by write-invalidate snooping. harts of a system share it by Rc.
+pub struct MemHierarchy {
+    bus: Arc<Bus>,
+    cfg: HierarchyCfg,
+    inner: RefCell<HierarchyInner>,
+}
+
+impl MemHierarchy {
+    pub fn new(bus: &Arc<Bus>, cfg: HierarchyCfg, harts: usize) -> Result<MemHierarchy, String> {
+        cfg.l1i.check("l1i")?;
+        cfg.l1d.check("l1d")?;
+        if let Some(ref l2) = cfg.l2 {
+            l2.check("l2")?;
+        }
+        Ok(MemHierarchy {
+            bus: bus.clone(),
+            inner: RefCell::new(HierarchyInner {
+                l1i: (0..harts).map(|_| { Cache::new(&cfg.l1i) }).collect(),
+                l1d: (0..harts).map(|_| { Cache::new(&cfg.l1d) }).collect(),
+                l2: cfg.l2.as_ref().map(|c| { Cache::new(c) }),
+                regions: BTreeMap::new(),
+                last_region: None,
+            }),
+            cfg,
+        })
+    }
+
+    pub fn config(&self) -> &HierarchyCfg {
+        &self.cfg
+    }
+
+    fn region(&self, inner: &mut HierarchyInner, addr: u64) -> Option<(u64, u64)> {
+        if let Some((base, size)) = inner.last_region {
+            if addr >= base && addr - base < size {
+                return inner.last_region;
+            }
+        }
+        let region = self.bus.space().get_region_by_addr(&addr).map(|r| { (r.info.base, r.info.size) });
+        if region.is_some() {
+            inner.last_region = region;
+        }
+        region
+    }
+
+    fn record_region(&self, inner: &mut HierarchyInner, hartid: usize, addr: u64, inst: bool, write: bool, hit: bool) {
+        if let Some(region) = self.region(inner, addr) {
+            let harts = inner.l1d.len();
+            let stats = inner.regions.entry(region).or_insert(vec![(CacheStats::default(), CacheStats::default()); harts]);
+            let s = if inst {
+                &mut stats[hartid].0
+            } else {
+                &mut stats[hartid].1
+            };
+            match (write, hit) {
+                (false, true) => s.reads += 1,
+                (false, false) => {
+                    s.reads += 1;
+                    s.read_misses += 1
+                }
+                (true, true) => s.writes += 1,
+                (true, false) => {
+                    s.writes += 1;
+                    s.write_misses += 1
+                }
+            }
+        }
+    }
+
+    fn l2_access(inner: &mut HierarchyInner, addr: u64, write: bool) -> HitLevel {
+        if let Some(ref mut l2) = inner.l2 {
+            let allocate = !write || l2.cfg.write_allocate;
+            if l2.access(addr, write, allocate).hit {
+                HitLevel::L2
+            } else {
+                HitLevel::Memory
+            }
+        } else {
+            HitLevel::Memory
+        }
+    }
+
+    fn writeback(inner: &mut HierarchyInner, victim: Option<(u64, bool)>) {
+        if let Some((addr, true)) = victim {
+            Self::l2_access(inner, addr, true);
+        }
+    }
+
+    //other harts holding the line: dirty copies are written back, and dropped if invalidate
+    fn snoop(inner: &mut HierarchyInner, hartid: usize, addr: u64, invalidate: bool) {
+        for id in 0..inner.l1d.len() {
+            if id == hartid {
+                continue;
+            }
+            let dirty = if invalidate {
+                match inner.l1d[id].invalidate(addr) {
+                    Some(dirty) => {
+                        inner.l1d[id].stats.coherence_invalidations += 1;
+                        dirty
+                    }
+                    None => false
+                }
+            } else {
+                inner.l1d[id].clean(addr)
+            };
+            if dirty {
+                inner.l1d[id].stats.snoop_writebacks += 1;
+                Self::l2_access(inner, addr, true);
+            }
+        }
+    }
+
+    fn lines(line_size: usize, addr: u64, len: usize) -> impl Iterator<Item=u64> {
+        let line_size = line_size as u64;
+        let first = addr & !(line_size - 1);
+        let last = (addr + std::cmp::max(len, 1) as u64 - 1) & !(line_size - 1);
+        (0..=((last - first) / line_size)).map(move |i| { first + i * line_size })
+    }
+
+    pub fn fetch(&self, hartid: usize, addr: u64, len: usize) -> HitLevel {
+        let mut inner = self.inner.borrow_mut();
+        let mut level = HitLevel::L1;
+        for line in Self::lines(self.cfg.l1i.line_size, addr, len) {
+            let result = inner.l1i[hartid].access(line, false, true);
+            self.record_region(&mut inner, hartid, line, true, false, result.hit);
+            if !result.hit {
+                level = level.max(Self::l2_access(&mut inner, line, false));
+            }
+        }
+        level
+    }
+
+    pub fn load(&self, hartid: usize, addr: u64, len: usize) -> HitLevel {
+        let mut inner = self.inner.borrow_mut();
+        let mut level = HitLevel::L1;
+        for line in Self::lines(self.cfg.l1d.line_size, addr, len) {
+            let result = inner.l1d[hartid].access(line, false, true);
+            self.record_region(&mut inner, hartid, line, false, false, result.hit);
+            if !result.hit {
+                Self::writeback(&mut inner, result.victim);
+                Self::snoop(&mut inner, hartid, line, false);
+                level = level.max(Self::l2_access(&mut inner, line, false));
+            }
+        }
+        level
+    }
+
+    pub fn store(&self, hartid: usize, addr: u64, len: usize) -> HitLevel {
+        let mut inner = self.inner.borrow_mut();
+        let mut level = HitLevel::L1;
+        let write_allocate = self.cfg.l1d.write_allocate;
+        let write_through = self.cfg.l1d.write == WritePolicy::WriteThrough;
+        for line in Self::lines(self.cfg.l1d.line_size, addr, len) {
+            Self::snoop(&mut inner, hartid, line, true);
+            let result = inner.l1d[hartid].access(line, true, write_allocate);
+            self.record_region(&mut inner, hartid, line, false, true, result.hit);
+            Self::writeback(&mut inner, result.victim);
+            if !result.hit && write_allocate {
+                level = level.max(Self::l2_access(&mut inner, line, false));
+            }
+            if write_through || !result.hit && !write_allocate {
+                let l = Self::l2_access(&mut inner, line, true);
+                if !result.hit {
+                    level = level.max(l)
+                }
+            }
+        }
+        level
+    }
+
+    pub fn l1i_stats(&self, hartid: usize) -> CacheStats {
+        self.inner.borrow().l1i[hartid].stats
+    }
+
+    pub fn l1d_stats(&self, hartid: usize) -> CacheStats {
+        self.inner.borrow().l1d[hartid].stats
+    }
+
+    pub fn l2_stats(&self) -> Option<CacheStats> {
+        self.inner.borrow().l2.as_ref().map(|c| { c.stats })
+    }
+}
+
+impl Display for MemHierarchy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        writeln!(f, "cache statistics:")?;
+        for (id, (i, d)) in inner.l1i.iter().zip(inner.l1d.iter()).enumerate() {
+            writeln!(f, "   hart{} l1i: {}", id, i.stats)?;
+            writeln!(f, "   hart{} l1d: {}", id, d.stats)?;
+        }
+        if let Some(ref l2) = inner.l2 {
+            writeln!(f, "   l2: {}", l2.stats)?;
+        }
+        writeln!(f, "regions:")?;
+        for ((base, size), stats) in inner.regions.iter() {
+            let mut total_i = CacheStats::default();
+            let mut total_d = CacheStats::default();
+            for (id, (i, d)) in stats.iter().enumerate() {
+                if i.accesses() != 0 {
+                    writeln!(f, "   {:#x} - {:#x} hart{} l1i: {}", base, base + size, id, i)?;
+                }
+                if d.accesses() != 0 {
+                    writeln!(f, "   {:#x} - {:#x} hart{} l1d: {}", base, base + size, id, d)?;
+                }
+                total_i.merge(i);
+                total_d.merge(d);
+            }
+            writeln!(f, "   {:#x} - {:#x} total l1i: {}", base, base + size, total_i)?;
+            writeln!(f, "   {:#x} - {:#x} total l1d: {}", base, base + size, total_d)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_cfg(size: usize, ways: usize, replacement: Replacement) -> CacheCfg {
+    CacheCfg {
+        size,
+        ways,
+        line_size: 64,
+        replacement,
+        write: WritePolicy::WriteBack,
+        write_allocate: true,
+    }
+}
+
+#[test]
+fn cache_replacement_test() {
+    //one set of 2 ways
+    let mut lru = Cache::new(&test_cfg(128, 2, Replacement::LRU));
+    let mut fifo = Cache::new(&test_cfg(128, 2, Replacement::FIFO));
+    for c in [&mut lru, &mut fifo].iter_mut() {
+        assert!(!c.access(0, false, true).hit);
+        assert!(!c.access(64, true, true).hit);
+        assert!(c.access(0, false, true).hit);
+    }
+    let victim = lru.access(128, false, true).victim;
+    assert_eq!(victim, Some((64, true)));
+    assert_eq!(lru.stats.writebacks, 1);
+    assert_eq!(fifo.access(128, false, true).victim, Some((0, false)));
+    assert_eq!(fifo.stats.writebacks, 0);
+    assert_eq!((lru.stats.reads, lru.stats.writes, lru.stats.misses()), (3, 1, 3));
+    assert!(!lru.access(192, true, false).hit);
+    assert!(lru.find(192).is_none());
+    assert!(CacheCfg { size: 192, ..test_cfg(128, 2, Replacement::LRU) }.check("l1").is_err());
+}
+
+#[test]
+fn mem_hierarchy_test() {
+    let cfg = HierarchyCfg {
+        l1i: test_cfg(128, 2, Replacement::LRU),
+        l1d: test_cfg(128, 2, Replacement::LRU),
+        l2: Some(test_cfg(1024, 4, Replacement::LRU)),
+    };
+    let mem = MemHierarchy::new(&Arc::new(Bus::new()), cfg, 2).unwrap();
+    assert_eq!(mem.load(0, 0x1000, 8), HitLevel::Memory);
+    assert_eq!(mem.load(0, 0x1000, 8), HitLevel::L1);
+    assert_eq!(mem.load(1, 0x1000, 8), HitLevel::L2);
+    //write-invalidate, the dirty copy of hart1 is written back when hart0 reads it again
+    assert_eq!(mem.store(1, 0x1000, 8), HitLevel::L1);
+    assert_eq!(mem.l1d_stats(0).coherence_invalidations, 1);
+    assert_eq!(mem.load(0, 0x1000, 8), HitLevel::L2);
+    assert_eq!(mem.l1d_stats(1).snoop_writebacks, 1);
+    //crossing lines
+    assert_eq!(mem.load(0, 0x103e, 4), HitLevel::Memory);
+    let stats = mem.l1d_stats(0);
+    assert_eq!((stats.reads, stats.read_misses), (5, 3));
+    assert_eq!(mem.fetch(0, 0x1000, 4), HitLevel::L2);
+    assert_eq!(mem.l1i_stats(0).misses(), 1);
+    //the slowest line is reported, the first line from memory is not hidden by the second from l2
+    assert_eq!(mem.load(0, 0x3000, 8), HitLevel::Memory);
+    assert_eq!(mem.load(1, 0x2ffc, 8), HitLevel::Memory);
+    assert_eq!(mem.load(1, 0x2ffc, 8), HitLevel::L1);
+}
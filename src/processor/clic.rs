@@ -0,0 +1,193 @@
+use std::cell::{Cell, RefCell};
+use terminus_global::{RegT, InsnT, XLen};
+use crate::devices::clic::Clic;
+use crate::devices::device::IrqSink;
+use super::{ProcessorState, Privilege};
+
+const MSTATUS: InsnT = 0x300;
+const MIE: InsnT = 0x304;
+const MTVT: InsnT = 0x307;
+const MCAUSE: InsnT = 0x342;
+const MIP: InsnT = 0x344;
+pub const MNXTI: InsnT = 0x345;
+const MINTTHRESH: InsnT = 0x347;
+const MINTSTATUS: InsnT = 0xfb1;
+
+//smclic csrs and the mcause of clic mode, interrupts come from the clic attached by System.
+//clic mode is mtvec.mode == 3, where mie and mip read 0 and the vector base is mtvec aligned to 64 bytes
+pub struct Smclic {
+    mtvt: Cell<RegT>,
+    //mintstatus.mil
+    mil: Cell<u8>,
+    mintthresh: Cell<u8>,
+    //fields of mcause besides mpp and mpie, which are in mstatus
+    int: Cell<bool>,
+    minhv: Cell<bool>,
+    mpil: Cell<u8>,
+    exccode: Cell<RegT>,
+    clic: RefCell<Option<Clic>>,
+}
+
+impl Smclic {
+    pub fn new() -> Smclic {
+        Smclic {
+            mtvt: Cell::new(0),
+            mil: Cell::new(0),
+            mintthresh: Cell::new(0),
+            int: Cell::new(false),
+            minhv: Cell::new(false),
+            mpil: Cell::new(0),
+            exccode: Cell::new(0),
+            clic: RefCell::new(None),
+        }
+    }
+
+    pub fn attach(&self, clic: &Clic) {
+        *self.clic.borrow_mut() = Some(clic.clone())
+    }
+
+    pub fn reset(&self) {
+        self.mtvt.set(0);
+        self.mil.set(0);
+        self.mintthresh.set(0);
+        self.int.set(false);
+        self.minhv.set(false);
+        self.mpil.set(0);
+        self.exccode.set(0);
+    }
+
+    //msip, mtip and meip are inputs 3, 7 and 11 of clic
+    fn sample(&self, state: &ProcessorState, clic: &Clic) {
+        let mip = state.icsrs().mip();
+        clic.set_irq(3, mip.msip() == 1);
+        clic.set_irq(7, mip.mtip() == 1);
+        clic.set_irq(11, mip.meip() == 1);
+    }
+
+    //interrupt taken by the hart, (id, level, shv). interrupts preempt handlers of lower levels,
+    //and are masked by mintthresh in m mode
+    pub fn top(&self, state: &ProcessorState) -> Option<(usize, u8, bool)> {
+        let clic = self.clic.borrow();
+        let clic = clic.as_ref()?;
+        self.sample(state, clic);
+        let (id, level, shv) = clic.top()?;
+        let enabled = match state.privilege() {
+            Privilege::M => state.icsrs().mstatus().mie() == 1 && level > self.mil.get().max(self.mintthresh.get()),
+            _ => level > 0
+        };
+        if enabled {
+            Some((id, level, shv))
+        } else {
+            None
+        }
+    }
+
+    //pending in clic, for wfi
+    pub fn pending(&self, state: &ProcessorState) -> bool {
+        match *self.clic.borrow() {
+            Some(ref clic) => {
+                self.sample(state, clic);
+                clic.top().is_some()
+            }
+            None => false
+        }
+    }
+
+    //update mcause and mil for a trap to m mode, the entry of mtvt is returned for interrupts with hardware vectoring
+    pub fn trap(&self, state: &ProcessorState, int: bool, code: RegT) -> Option<RegT> {
+        self.int.set(int);
+        self.exccode.set(code & 0xfff);
+        self.mpil.set(self.mil.get());
+        if !int {
+            return None;
+        }
+        let clic = self.clic.borrow();
+        let (level, shv) = clic.as_ref()?.top().filter(|(id, _, _)| { *id as RegT == code }).map(|(_, level, shv)| { (level, shv) })?;
+        self.mil.set(level);
+        if shv {
+            clic.as_ref()?.claim(code as usize);
+            self.minhv.set(true);
+            Some(self.entry(state, code))
+        } else {
+            None
+        }
+    }
+
+    //the entry of mtvt has been fetched
+    pub fn vectored(&self) {
+        self.minhv.set(false)
+    }
+
+    pub fn mret(&self) {
+        self.mil.set(self.mpil.get())
+    }
+
+    fn entry(&self, state: &ProcessorState, id: RegT) -> RegT {
+        self.mtvt.get() + id * (state.config().xlen.len() as RegT >> 3)
+    }
+
+    //next non-vectored interrupt above mpil and mintthresh for mnxti, (id, level)
+    fn next(&self) -> Option<(usize, u8)> {
+        let clic = self.clic.borrow();
+        clic.as_ref()?.top().filter(|(_, level, shv)| {
+            !*shv && *level > self.mpil.get().max(self.mintthresh.get())
+        }).map(|(id, level, _)| { (id, level) })
+    }
+
+    fn mcause(&self, state: &ProcessorState) -> RegT {
+        let mstatus = state.icsrs().mstatus();
+        let int_bit = match state.config().xlen {
+            XLen::X32 => 31,
+            XLen::X64 => 63
+        };
+        ((self.int.get() as RegT) << int_bit) | ((self.minhv.get() as RegT) << 30) | (mstatus.mpp() << 28) | (mstatus.mpie() << 27)
+            | ((self.mpil.get() as RegT) << 16) | self.exccode.get()
+    }
+
+    fn set_mcause(&self, state: &ProcessorState, value: RegT) {
+        self.int.set((value >> (state.config().xlen.len() - 1)) & 1 == 1);
+        self.minhv.set((value >> 30) & 1 == 1);
+        state.icsrs().mstatus_mut().set_mpp((value >> 28) & 0x3);
+        state.icsrs().mstatus_mut().set_mpie((value >> 27) & 1);
+        self.mpil.set((value >> 16) as u8);
+        self.exccode.set(value & 0xfff);
+    }
+
+    //none if id is not a smclic csr, or the access is illegal
+    pub fn csr_read(&self, state: &ProcessorState, id: InsnT) -> Option<RegT> {
+        let clic_mode = state.icsrs().mtvec().get() & 0x3 == 0x3;
+        match id {
+            MTVT => Some(self.mtvt.get()),
+            MNXTI => Some(self.next().map_or(0, |(id, _)| { self.entry(state, id as RegT) })),
+            MINTTHRESH => Some(self.mintthresh.get() as RegT),
+            MINTSTATUS => Some((self.mil.get() as RegT) << 24),
+            MCAUSE if clic_mode => Some(self.mcause(state)),
+            MIE | MIP if clic_mode => Some(0),
+            _ => None
+        }
+    }
+
+    pub fn csr_write(&self, state: &ProcessorState, id: InsnT, value: RegT) -> Option<()> {
+        let clic_mode = state.icsrs().mtvec().get() & 0x3 == 0x3;
+        match id {
+            MTVT => self.mtvt.set(value & !0x3f & state.config().xlen.mask()),
+            //value is the new mstatus, the next interrupt is claimed by the handler
+            MNXTI => {
+                let next = self.next();
+                state.set_csr_backdoor(MSTATUS, value)?;
+                if let Some((id, level)) = next {
+                    self.mil.set(level);
+                    self.exccode.set(id as RegT);
+                    if let Some(ref clic) = *self.clic.borrow() {
+                        clic.claim(id)
+                    }
+                }
+            }
+            MINTTHRESH => self.mintthresh.set(value as u8),
+            MCAUSE if clic_mode => self.set_mcause(state, value),
+            MIE | MIP if clic_mode => {}
+            _ => return None
+        }
+        Some(())
+    }
+}
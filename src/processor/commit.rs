@@ -0,0 +1,35 @@
+use terminus_global::{RegT, InsnT};
+use crate::processor::Privilege;
+use crate::processor::extensions::f::FRegT;
+
+//memory write performed by a store or amo, data is the stored value zero-extended to 64 bits
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MemWrite {
+    pub addr: RegT,
+    pub pa: u64,
+    pub len: usize,
+    pub data: u64,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TrapRecord {
+    pub interrupt: bool,
+    pub code: RegT,
+    pub tval: RegT,
+    pub epc: RegT,
+    pub privilege: Privilege,
+}
+
+//architectural effects of one step, used to compare against another model
+#[derive(Debug, Clone, Default)]
+pub struct Commit {
+    pub pc: RegT,
+    //0 if the instruction is not executed
+    pub ir: InsnT,
+    //the instruction was executed, it may still raise an exception after partial execution
+    pub retired: bool,
+    pub xreg_writes: Vec<(InsnT, RegT)>,
+    pub freg_writes: Vec<(InsnT, FRegT)>,
+    pub mem_writes: Vec<MemWrite>,
+    pub traps: Vec<TrapRecord>,
+}
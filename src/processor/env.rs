@@ -0,0 +1,43 @@
+use crate::processor::Processor;
+use crate::processor::trap::Exception;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+//execution environment emulated outside of the hart, e.g. sbi firmware.
+//it sees exceptions before they trap into the hart.
+pub trait Env {
+    //called before every instruction, return false to keep the hart idle in this step
+    fn poll(&mut self, _: &mut Processor) -> bool {
+        true
+    }
+    //return true if the exception is handled, the env is responsible for pc of the next instruction
+    fn trap(&mut self, p: &mut Processor, e: &Exception) -> bool;
+}
+
+//exit code reported by an env when the guest exits, shared with the system
+#[derive(Clone, Default)]
+pub struct ExitCode(Arc<Mutex<Option<u64>>>);
+
+impl ExitCode {
+    pub fn set(&self, code: u64) {
+        *self.0.lock().unwrap() = Some(code)
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        *self.0.lock().unwrap()
+    }
+}
+
+//reset requested by the guest, e.g. through syscon-reboot or sbi, the system reboots after the current step
+#[derive(Clone, Default)]
+pub struct ResetRequest(Arc<AtomicBool>);
+
+impl ResetRequest {
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
@@ -71,6 +71,7 @@ pub struct ExtensionF {
     freg: [FRegT; 32],
     csrs: Rc<FCsrs>,
     dirty: Rc<RefCell<RegT>>,
+    written: u32,
 }
 
 impl ExtensionF {
@@ -80,11 +81,12 @@ impl ExtensionF {
             freg: [0 as FRegT; 32],
             csrs: Rc::new(FCsrs::new(state.config().xlen)),
             dirty: Rc::new(RefCell::new(0)),
+            written: 0,
         };
 
-        if state.config().extensions.contains(&'q') {
+        if state.config().isa.has("q") {
             e.flen = FLen::F128
-        } else if state.config().extensions.contains(&'d') {
+        } else if state.config().isa.has("d") {
             e.flen = FLen::F64
         }
 
@@ -153,6 +155,7 @@ impl ExtensionF {
     pub fn set_freg(&mut self, id: InsnT, value: FRegT) {
         let trip_id = id & 0x1f;
         *self.dirty.borrow_mut() = 0x3;
+        self.written |= 1 << trip_id;
         *unsafe { self.freg.get_unchecked_mut(trip_id as usize) } = value
         // (*self.freg.borrow_mut())[trip_id as usize] = value
     }
@@ -164,6 +167,11 @@ impl ExtensionF {
     pub fn fregs(&self) -> &[FRegT; 32] {
         &self.freg
     }
+
+    //bit mask of fregs written since last call
+    pub fn take_written(&mut self) -> u32 {
+        std::mem::replace(&mut self.written, 0)
+    }
 }
 
 impl HasCsr for ExtensionF {
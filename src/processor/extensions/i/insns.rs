@@ -1025,6 +1025,7 @@ struct FENCEI();
 
 impl Execution for FENCEI {
     fn execute(&self, p: &mut Processor) -> Result<(), Exception> {
+        p.state().check_isa("zifencei")?;
         p.fetcher().flush_icache();
         let pc = *p.state().pc() + 4;
         p.state_mut().set_pc(pc);
@@ -1034,6 +1035,7 @@ impl Execution for FENCEI {
 
 trait CsrAccess: InstructionImp {
     fn csr_access<F: Fn(&ProcessorState, RegT) -> RegT, F1: Fn(&ProcessorState) -> bool, F2: Fn(&ProcessorState) -> bool>(&self, p: &mut Processor, csr_value: F, read_csr: F1, write_csr: F2) -> Result<(), Exception> {
+        p.state().check_isa("zicsr")?;
         let csr = if read_csr(p.state()) {
             p.state().csr(self.imm(p.state().ir()))?
         } else {
@@ -1041,7 +1043,7 @@ trait CsrAccess: InstructionImp {
         };
         if write_csr(p.state()) {
             let id = self.imm(p.state().ir());
-            let value = csr_value(p.state(), csr);
+            let value = csr_value(p.state(), p.state().csr_rmw_value(id, csr)?);
             p.state().set_csr(id, value)?;
         }
         let rd = self.rd(p.state().ir());
@@ -1180,6 +1182,9 @@ impl Execution for MRET {
         csrs.mstatus_mut().set_mpie(1);
         let u_value: u8 = Privilege::U.into();
         csrs.mstatus_mut().set_mpp(u_value as RegT);
+        if let Some(clic) = p.state().clic_mode() {
+            clic.mret()
+        }
         p.mmu().flush_tlb();
         p.fetcher().flush_icache();
         if p.state().check_extension('c').is_err() {
@@ -1206,7 +1211,7 @@ impl Execution for WFI {
         if csrs.mstatus().tw() != 0 && p.state().config().privilege_level() != PrivilegeLevel::M {
             return Err(Exception::IllegalInsn(p.state().ir()));
         }
-        if csrs.mip().get() != 0 {
+        if p.state().interrupt_pending() {
             let pc = *p.state().pc() + 4;
             p.state_mut().set_pc(pc);
         }
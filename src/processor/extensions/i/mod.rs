@@ -89,6 +89,34 @@ impl ExtensionI {
             }
         }
         );
+        e.csrs.cycle_mut().cycle_transform({
+            let count = state.cycles().clone();
+            move |_| {
+                *count.borrow() as RegT
+            }
+        }
+        );
+        e.csrs.cycleh_mut().cycle_transform({
+            let count = state.cycles().clone();
+            move |_| {
+                (*count.borrow() >> 32) as RegT
+            }
+        }
+        );
+        e.csrs.mcycle_mut().cycle_transform({
+            let count = state.cycles().clone();
+            move |_| {
+                *count.borrow() as RegT
+            }
+        }
+        );
+        e.csrs.mcycleh_mut().cycle_transform({
+            let count = state.cycles().clone();
+            move |_| {
+                (*count.borrow() >> 32) as RegT
+            }
+        }
+        );
         e
     }
 
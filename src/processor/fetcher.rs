@@ -5,8 +5,10 @@ use crate::processor::mmu::Mmu;
 use crate::processor::trap::Exception;
 use crate::processor::decode::*;
 use std::sync::Arc;
+use std::rc::Rc;
 use crate::devices::bus::Bus;
-use std::cell::RefCell;
+use std::cell::{RefCell, Cell};
+use crate::processor::cache::{MemHierarchy, HitLevel};
 
 struct ICacheEntry {
     tag: u64,
@@ -57,6 +59,8 @@ impl ICache {
 pub struct Fetcher {
     bus: Arc<Bus>,
     icache: RefCell<ICache>,
+    mem_hierarchy: Option<Rc<MemHierarchy>>,
+    level: Cell<Option<HitLevel>>,
 }
 
 impl Fetcher {
@@ -64,8 +68,14 @@ impl Fetcher {
         Fetcher {
             bus: bus.clone(),
             icache: RefCell::new(ICache::new(1024)),
+            mem_hierarchy: None,
+            level: Cell::new(None),
         }
     }
+
+    pub fn set_mem_hierarchy(&mut self, mem_hierarchy: &Rc<MemHierarchy>) {
+        self.mem_hierarchy = Some(mem_hierarchy.clone())
+    }
     #[cfg_attr(feature = "no-inline", inline(never))]
     fn fetch_u16_slow(&self, addr: &u64, pc: &u64, data: &mut u16) -> Result<(), Exception> {
         match self.bus.read_u16(addr, data) {
@@ -90,12 +100,25 @@ impl Fetcher {
     }
 
     pub fn fetch(&self, state: &ProcessorState, mmu: &Mmu) -> Result<(InsnT, &'static Instruction), Exception> {
+        let (pa, ir, insn) = self.fetch_insn(state, mmu)?;
+        if let Some(ref mem_hierarchy) = self.mem_hierarchy {
+            self.level.set(Some(mem_hierarchy.fetch(state.hartid(), pa, if ir & 0x3 == 0x3 { 4 } else { 2 })));
+        }
+        Ok((ir, insn))
+    }
+
+    //where the last fetch was served, only available with MemHierarchy
+    pub fn take_level(&self) -> Option<HitLevel> {
+        self.level.take()
+    }
+
+    fn fetch_insn(&self, state: &ProcessorState, mmu: &Mmu) -> Result<(u64, InsnT, &'static Instruction), Exception> {
         let mut icache = self.icache.borrow_mut();
         let pc = state.pc();
         if pc.trailing_zeros() == 1 {
             let pa = mmu.fetch_translate(state, pc, 2)?;
-            if let Some(res) = icache.get_insn(pa) {
-                Ok(*res)
+            if let Some((ir, insn)) = icache.get_insn(pa) {
+                Ok((pa, *ir, *insn))
             } else {
                 let mut data_low = 0;
                 self.fetch_u16_slow(&pa, pc, &mut data_low)?;
@@ -103,7 +126,7 @@ impl Fetcher {
                     let data = data_low as u16 as InsnT;
                     let insn = GDECODER.decode(data)?;
                     icache.set_entry(pa, data, insn);
-                    Ok((data, insn))
+                    Ok((pa, data, insn))
                 } else {
                     let pa_high = if (*pc & 0xfff) == 0xffe {
                         mmu.fetch_translate(state, &(*pc + 2), 2)?
@@ -115,13 +138,13 @@ impl Fetcher {
                     let data = data_low as u16 as InsnT | ((data_high as u16 as InsnT) << 16);
                     let insn = GDECODER.decode(data)?;
                     icache.set_entry(pa, data, insn);
-                    Ok((data, insn))
+                    Ok((pa, data, insn))
                 }
             }
         } else {
             let pa = mmu.fetch_translate(state, pc, 4)?;
-            if let Some(res) = icache.get_insn(pa) {
-                Ok(*res)
+            if let Some((ir, insn)) = icache.get_insn(pa) {
+                Ok((pa, *ir, *insn))
             } else {
                 let mut data = 0;
                 self.fetch_u32_slow(&pa, pc, &mut data)?;
@@ -129,11 +152,11 @@ impl Fetcher {
                     let data_low = data as u16 as InsnT;
                     let insn = GDECODER.decode(data_low)?;
                     icache.set_entry(pa, data_low, insn);
-                    Ok((data_low, insn))
+                    Ok((pa, data_low, insn))
                 } else {
                     let insn = GDECODER.decode(data)?;
                     icache.set_entry(pa, data, insn);
-                    Ok((data, insn))
+                    Ok((pa, data, insn))
                 }
             }
         }
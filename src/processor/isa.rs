@@ -0,0 +1,241 @@
+use terminus_global::*;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+//canonical order of single-letter extensions, s and u stand for supervisor and user mode as in misa
+const SINGLE_ORDER: &str = "iemafdqlcbkjtpvnhsu";
+
+//implemented by terminus
+const SUPPORTED: &[&str] = &["i", "m", "a", "f", "d", "c", "s", "u", "zicsr", "zifencei", "smaia", "smclic", "ssaia"];
+
+//(extension, required extension)
+const DEPENDENCIES: &[(&str, &str)] = &[
+    ("d", "f"),
+    ("q", "d"),
+    ("f", "zicsr"),
+    ("s", "u"),
+    ("zfh", "f"),
+    ("zfhmin", "f"),
+    ("zdinx", "zfinx"),
+    ("zhinx", "zfinx"),
+    ("zvfh", "v"),
+    ("smaia", "zicsr"),
+    ("smclic", "zicsr"),
+    ("ssaia", "s"),
+];
+
+const IMPLIED: &[(&str, &[&str])] = &[
+    ("g", &["i", "m", "a", "f", "d", "zicsr", "zifencei"]),
+];
+
+//zicsr and zifencei were part of i before they were split out, keep old isa strings naming neither working
+const SPLIT_FROM_I: &[&str] = &["zicsr", "zifencei"];
+
+//parsed isa string, e.g. "rv64imafdc_zicsr_zifencei"
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Isa {
+    xlen: XLen,
+    //in canonical order
+    singles: Vec<char>,
+    //in canonical order
+    multis: Vec<String>,
+}
+
+fn single_order(c: char) -> Option<usize> {
+    SINGLE_ORDER.find(c)
+}
+
+//z extensions first, ordered by the category of the second letter then alphabetically, then s and x extensions alphabetically
+fn multi_order(name: &str) -> (usize, usize, &str) {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some('z') => (0, chars.next().and_then(single_order).unwrap_or(SINGLE_ORDER.len()), name),
+        Some('s') => (1, 0, name),
+        _ => (2, 0, name)
+    }
+}
+
+//"zicsr2p0" -> "zicsr", "m2" -> "m"
+fn strip_version(s: &str) -> &str {
+    let s = s.trim_end_matches(|c: char| { c.is_ascii_digit() });
+    match s.strip_suffix('p') {
+        Some(major) if major.ends_with(|c: char| { c.is_ascii_digit() }) => major.trim_end_matches(|c: char| { c.is_ascii_digit() }),
+        _ => s
+    }
+}
+
+impl Isa {
+    pub fn parse(isa: &str) -> Result<Isa, String> {
+        let lower = isa.to_lowercase();
+        let err = |msg: String| -> String { format!("invalid isa \"{}\": {}!", isa, msg) };
+        let (xlen, rest) = if let Some(rest) = lower.strip_prefix("rv32") {
+            (XLen::X32, rest)
+        } else if let Some(rest) = lower.strip_prefix("rv64") {
+            (XLen::X64, rest)
+        } else {
+            return Err(err("should start with rv32 or rv64".to_string()));
+        };
+        let mut parts = rest.split('_');
+        let first = parts.next().unwrap();
+
+        //single-letter extensions, a multi-letter extension may follow without '_'
+        let mut singles: Vec<char> = vec![];
+        let mut multis: Vec<String> = vec![];
+        let mut chars = first.char_indices().peekable();
+        match chars.next() {
+            Some((_, 'g')) => singles.push('g'),
+            Some((_, 'i')) => singles.push('i'),
+            Some((_, 'e')) => singles.push('e'),
+            _ => return Err(err("base should be i, e or g".to_string()))
+        }
+        while let Some((pos, c)) = chars.next() {
+            if c.is_ascii_digit() || c == 'p' && matches!(chars.peek(), Some((_, n)) if n.is_ascii_digit()) {
+                //version
+                continue;
+            }
+            if c == 'z' || c == 'x' {
+                multis.push(strip_version(&first[pos..]).to_string());
+                break;
+            }
+            let order = single_order(c).ok_or_else(|| { err(format!("unknown extension '{}'", c)) })?;
+            let last = *singles.last().unwrap();
+            let last_order = if last == 'g' { single_order('d').unwrap() } else { single_order(last).unwrap() };
+            if order == last_order || c == 'i' || c == 'e' {
+                return Err(err(format!("extension '{}' is duplicated", c)));
+            }
+            if order < last_order {
+                return Err(err(format!("extension '{}' should be before '{}'", c, last)));
+            }
+            singles.push(c)
+        }
+
+        //multi-letter extensions
+        for part in parts {
+            let name = strip_version(part);
+            if name.len() < 2 || !name.starts_with(|c: char| { c == 'z' || c == 's' || c == 'x' }) || !name.chars().all(|c| { c.is_ascii_alphanumeric() }) {
+                return Err(err(format!("invalid multi-letter extension \"{}\"", part)));
+            }
+            if let Some(last) = multis.last() {
+                if last == name {
+                    return Err(err(format!("extension \"{}\" is duplicated", name)));
+                }
+                if multi_order(name) < multi_order(last) {
+                    return Err(err(format!("extension \"{}\" should be before \"{}\"", name, last)));
+                }
+            }
+            multis.push(name.to_string())
+        }
+
+        //expand implied extensions
+        let mut names: Vec<String> = vec![];
+        if singles.contains(&'i') && !multis.iter().any(|e| { SPLIT_FROM_I.contains(&e.as_str()) }) {
+            names.extend(SPLIT_FROM_I.iter().map(|e| { e.to_string() }))
+        }
+        for ext in singles.iter().map(|c| { c.to_string() }).chain(multis) {
+            if let Some((_, implied)) = IMPLIED.iter().find(|(e, _)| { *e == ext }) {
+                names.extend(implied.iter().map(|e| { e.to_string() }))
+            }
+            if ext != "g" {
+                names.push(ext)
+            }
+        }
+        let mut isa = Isa {
+            xlen,
+            singles: vec![],
+            multis: vec![],
+        };
+        for name in names {
+            if name.len() == 1 {
+                let c = name.chars().next().unwrap();
+                if !isa.singles.contains(&c) {
+                    isa.singles.push(c)
+                }
+            } else if !isa.multis.contains(&name) {
+                isa.multis.push(name)
+            }
+        }
+        isa.singles.sort_by_key(|c| { single_order(*c) });
+        isa.multis.sort_by(|a, b| { multi_order(a).cmp(&multi_order(b)) });
+
+        for (ext, required) in DEPENDENCIES.iter() {
+            if isa.has(ext) && !isa.has(required) {
+                return Err(err(format!("extension \"{}\" requires \"{}\"", ext, required)));
+            }
+        }
+        Ok(isa)
+    }
+
+    //parsed extensions not implemented by terminus are rejected when building harts
+    pub fn check_supported(&self) -> Result<(), String> {
+        match self.extensions().into_iter().find(|e| { !SUPPORTED.contains(&e.as_str()) }) {
+            Some(ext) => Err(format!("invalid isa \"{}\": extension \"{}\" is not supported, supported extensions are {:?}!", self, ext, SUPPORTED)),
+            None => Ok(())
+        }
+    }
+
+    pub fn xlen(&self) -> XLen {
+        self.xlen
+    }
+
+    //single-letter or multi-letter extension
+    pub fn has(&self, ext: &str) -> bool {
+        if ext.len() == 1 {
+            self.singles.iter().any(|c| { ext.starts_with(*c) })
+        } else {
+            self.multis.iter().any(|e| { e == ext })
+        }
+    }
+
+    pub fn singles(&self) -> &[char] {
+        &self.singles
+    }
+
+    //all extensions in canonical order
+    pub fn extensions(&self) -> Vec<String> {
+        self.singles.iter().map(|c| { c.to_string() }).chain(self.multis.iter().cloned()).collect()
+    }
+
+    //extension bits of misa
+    pub fn misa(&self) -> RegT {
+        self.singles.iter().fold(0, |acc, c| { acc | (1 << (*c as u8 - b'a')) })
+    }
+
+    //"riscv,isa-extensions" of device tree, privilege modes are not extensions there
+    pub fn fdt_extensions(&self) -> Vec<String> {
+        self.extensions().into_iter().filter(|e| { e != "s" && e != "u" }).collect()
+    }
+}
+
+impl Display for Isa {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "rv{}", self.xlen.len())?;
+        for c in self.singles.iter() {
+            write!(f, "{}", c)?;
+        }
+        for e in self.multis.iter() {
+            write!(f, "_{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn isa_parse_test() {
+    let isa = Isa::parse("RV64GCsu").unwrap();
+    assert_eq!(isa.to_string(), "rv64imafdcsu_zicsr_zifencei");
+    assert_eq!(isa.misa(), 0x14112d);
+    assert_eq!(Isa::parse("rv32i2p1mac_zicsr2p0").unwrap().to_string(), "rv32imac_zicsr");
+    assert_eq!(Isa::parse("rv64imac").unwrap().to_string(), "rv64imac_zicsr_zifencei");
+    assert_eq!(Isa::parse(&isa.to_string()).unwrap(), isa);
+    assert!(isa.fdt_extensions().iter().all(|e| { e != "s" }));
+    assert!(Isa::parse("rv64imd").unwrap_err().contains("requires \"f\""));
+    assert!(Isa::parse("rv64imafdcs").unwrap_err().contains("requires \"u\""));
+    assert!(Isa::parse("rv64imfa").unwrap_err().contains("should be before"));
+    assert!(Isa::parse("rv64imm").unwrap_err().contains("duplicated"));
+    let isa = Isa::parse("rv64imafdc_zicsr_zba_sstc").unwrap();
+    assert_eq!(isa.to_string(), "rv64imafdc_zicsr_zba_sstc");
+    assert!(isa.has("zba") && !isa.has("zifencei"));
+    assert!(isa.check_supported().unwrap_err().contains("\"zba\" is not supported"));
+    assert!(Isa::parse("rv64i_sstc_zba").unwrap_err().contains("should be before"));
+    assert!(Isa::parse("rv128i").is_err());
+}
@@ -3,23 +3,80 @@ use terminus_global::RegT;
 use crate::processor::mmu::{Mmu, MmuOpt};
 use crate::processor::trap::Exception;
 use std::sync::Arc;
+use std::rc::Rc;
 use crate::devices::bus::Bus;
+use crate::processor::cache::{MemHierarchy, HitLevel};
+use std::cell::{Cell, RefCell};
+use crate::processor::commit::MemWrite;
+use std::cmp::max;
 
 pub struct LoadStore {
     bus: Arc<Bus>,
+    mem_hierarchy: Option<Rc<MemHierarchy>>,
+    level: Cell<Option<HitLevel>>,
+    mem_log: RefCell<Option<Vec<MemWrite>>>,
 }
 
 impl LoadStore {
     pub fn new(bus: &Arc<Bus>) -> LoadStore {
         LoadStore {
             bus: bus.clone(),
+            mem_hierarchy: None,
+            level: Cell::new(None),
+            mem_log: RefCell::new(None),
         }
     }
+
+    pub fn set_mem_hierarchy(&mut self, mem_hierarchy: &Rc<MemHierarchy>) {
+        self.mem_hierarchy = Some(mem_hierarchy.clone())
+    }
+
+    fn cache_load(&self, state: &ProcessorState, pa: u64, len: usize) {
+        if let Some(ref mem_hierarchy) = self.mem_hierarchy {
+            let level = mem_hierarchy.load(state.hartid, pa, len);
+            self.level.set(max(self.level.get(), Some(level)))
+        }
+    }
+
+    fn cache_store(&self, state: &ProcessorState, pa: u64, len: usize) {
+        if let Some(ref mem_hierarchy) = self.mem_hierarchy {
+            let level = mem_hierarchy.store(state.hartid, pa, len);
+            self.level.set(max(self.level.get(), Some(level)))
+        }
+    }
+
+    fn log_write(&self, addr: &RegT, pa: u64, len: usize, data: u64) {
+        if let Some(ref mut log) = *self.mem_log.borrow_mut() {
+            log.push(MemWrite {
+                addr: *addr,
+                pa,
+                len,
+                data,
+            })
+        }
+    }
+
+    //record memory writes until take_mem_writes()
+    pub fn start_mem_log(&self) {
+        *self.mem_log.borrow_mut() = Some(vec![])
+    }
+
+    pub fn take_mem_writes(&self) -> Vec<MemWrite> {
+        self.mem_log.borrow_mut().take().unwrap_or_default()
+    }
+
+    //the slowest level accessed since last call, only available with MemHierarchy
+    pub fn take_level(&self) -> Option<HitLevel> {
+        self.level.take()
+    }
     #[cfg_attr(feature = "no-inline", inline(never))]
     pub fn load_byte(&self, state: &ProcessorState, addr: &RegT, data: &mut u8, mmu: &Mmu) -> Result<(), Exception> {
         let pa = mmu.ls_translate(state, addr, 1, MmuOpt::Load)?;
         match self.bus.read_u8(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_load(state, pa, 1);
+                Ok(())
+            }
             Err(_) => Err(Exception::LoadAccess(*addr)),
         }
     }
@@ -30,7 +87,10 @@ impl LoadStore {
         }
         let pa = mmu.ls_translate(state, addr, 2, MmuOpt::Load)?;
         match self.bus.read_u16(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_load(state, pa, 2);
+                Ok(())
+            }
             Err(_) => Err(Exception::LoadAccess(*addr)),
         }
     }
@@ -40,7 +100,10 @@ impl LoadStore {
         }
         let pa = mmu.ls_translate(state, addr, 4, MmuOpt::Load)?;
         match self.bus.read_u32(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_load(state, pa, 4);
+                Ok(())
+            }
             Err(_) => Err(Exception::LoadAccess(*addr)),
         }
     }
@@ -50,7 +113,10 @@ impl LoadStore {
         }
         let pa = mmu.ls_translate(state, addr, 8, MmuOpt::Load)?;
         match self.bus.read_u64(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_load(state, pa, 8);
+                Ok(())
+            }
             Err(_) => Err(Exception::LoadAccess(*addr)),
         }
     }
@@ -62,7 +128,11 @@ impl LoadStore {
             }
         }
         match self.bus.write_u8(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_store(state, pa, 1);
+                self.log_write(addr, pa, 1, *data as u64);
+                Ok(())
+            }
             Err(_) => Err(Exception::StoreAccess(*addr)),
         }
     }
@@ -77,7 +147,11 @@ impl LoadStore {
             }
         }
         match self.bus.write_u16(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_store(state, pa, 2);
+                self.log_write(addr, pa, 2, *data as u64);
+                Ok(())
+            }
             Err(_) => Err(Exception::StoreAccess(*addr)),
         }
     }
@@ -92,7 +166,11 @@ impl LoadStore {
             }
         }
         match self.bus.write_u32(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_store(state, pa, 4);
+                self.log_write(addr, pa, 4, *data as u64);
+                Ok(())
+            }
             Err(_) => Err(Exception::StoreAccess(*addr)),
         }
     }
@@ -107,7 +185,11 @@ impl LoadStore {
             }
         }
         match self.bus.write_u64(&pa, data) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                self.cache_store(state, pa, 8);
+                self.log_write(addr, pa, 8, *data as u64);
+                Ok(())
+            }
             Err(_) => Err(Exception::StoreAccess(*addr)),
         }
     }
@@ -122,8 +204,13 @@ impl LoadStore {
                 self.bus.invalid_lock(addr, 4, lock_holder);
             }
         }
-        match self.bus.amo_u32(&pa, f) {
-            Ok(data) => Ok(data as RegT),
+        match self.bus.amo_u32(&pa, &f) {
+            Ok(data) => {
+                self.cache_load(state, pa, 4);
+                self.cache_store(state, pa, 4);
+                self.log_write(addr, pa, 4, f(data) as u64);
+                Ok(data as RegT)
+            }
             Err(_) => Err(Exception::StoreAccess(*addr)),
         }
     }
@@ -137,8 +224,13 @@ impl LoadStore {
                 self.bus.invalid_lock(addr, 8, lock_holder);
             }
         }
-        match self.bus.amo_u64(&pa, f) {
-            Ok(data) => Ok(data as RegT),
+        match self.bus.amo_u64(&pa, &f) {
+            Ok(data) => {
+                self.cache_load(state, pa, 8);
+                self.cache_store(state, pa, 8);
+                self.log_write(addr, pa, 8, f(data));
+                Ok(data as RegT)
+            }
             Err(_) => Err(Exception::StoreAccess(*addr)),
         }
     }
@@ -151,7 +151,8 @@ impl Mmu {
         Ok(())
     }
     #[cfg_attr(feature = "no-inline", inline(never))]
-    fn pt_walk(&self, state: &ProcessorState, vaddr: &Vaddr, opt: &MmuOpt, privilege: &u8, info: &PteInfo) -> Result<u64, Exception> {
+    //accessed and dirty bits are not updated if !update
+    fn pt_walk(&self, state: &ProcessorState, vaddr: &Vaddr, opt: &MmuOpt, privilege: &u8, info: &PteInfo, update: bool) -> Result<u64, Exception> {
         //step 1
         let ppn = state.scsrs().satp().ppn();
         let mut a = (ppn << info.page_size_shift) as RegT;
@@ -192,7 +193,7 @@ impl Mmu {
             }
         }
         //step 7
-        if leaf_pte.attr().d() == 0 && *opt == MmuOpt::Store || leaf_pte.attr().a() == 0 {
+        if update && (leaf_pte.attr().d() == 0 && *opt == MmuOpt::Store || leaf_pte.attr().a() == 0) {
             if state.config().enable_dirty {
                 let mut new_attr = leaf_pte.attr();
                 new_attr.set_a(1);
@@ -247,7 +248,7 @@ impl Mmu {
             let pa = (*ppn << (info.page_size_shift as u64)) | vaddr.offset();
             return Ok(pa);
         }
-        match self.pt_walk(state, &vaddr, &opt, &privilege, &info) {
+        match self.pt_walk(state, &vaddr, &opt, &privilege, &info, true) {
             Ok(pa) => if !self.check_pmp(state, &pa, len as usize, &opt, &privilege) {
                 return Err(opt.access_exception(*va));
             } else {
@@ -260,30 +261,42 @@ impl Mmu {
         }
     }
 
+    //load translation for debuggers and semihosting, without side effects:
+    //tlbs are neither looked up nor filled, and accessed/dirty bits of ptes are not updated
+    pub fn debug_translate(&self, state: &ProcessorState, va: &RegT) -> Option<u64> {
+        let privilege = self.get_privileage(state, &MmuOpt::Load);
+        if privilege == 3 {
+            return Some(*va as u64);
+        }
+        let info = PteInfo::new(state.scsrs().satp().deref());
+        if info.mode == PTE_BARE {
+            return Some(*va as u64);
+        }
+        let pa = self.pt_walk(state, &Vaddr::new(info.mode, *va), &MmuOpt::Load, &privilege, &info, false).ok()?;
+        if self.check_pmp(state, &pa, 1, &MmuOpt::Load, &privilege) {
+            Some(pa)
+        } else {
+            None
+        }
+    }
+
     #[cfg_attr(feature = "no-inline", inline(never))]
     pub fn fetch_translate(&self, state: &ProcessorState, va: &RegT, len: usize) -> Result<u64, Exception> {
         self.translate(state, va, len, MmuOpt::Fetch, (*state.privilege()).into(), self.fetch_tlb.borrow_mut().deref_mut())
     }
 }
 
-#[cfg(test)]
-use terminus_global::XLen;
 #[cfg(test)]
 use crate::processor::ProcessorCfg;
 #[cfg(test)]
-use crate::system::System;
+use crate::system::SystemBuilder;
 use std::cell::RefCell;
 use std::ops::DerefMut;
 
 #[test]
 fn pmp_basic_test() {
-    let mut sys = System::new("test", "top_tests/elf/rv64ui-p-add", vec![ProcessorCfg {
-        xlen: XLen::X32,
-        enable_dirty: true,
-        extensions: vec![].into_boxed_slice(),
-        freq: 1000000000,
-    }], 100);
-    sys.reset(vec![-1i64 as u64]).unwrap();
+    let mut sys = SystemBuilder::new("test").timer_freq(100).processor(ProcessorCfg::from_isa("rv32i", 1000000000).unwrap()).build().unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
 
     let p = sys.processor(0).unwrap();
     //no valid region
@@ -314,4 +327,34 @@ fn pmp_basic_test() {
     assert_eq!(p.mmu().match_pmpcfg_entry(p.state(), &0x2002_0000, 4), None);
     p.state().icsrs().pmpcfg3_mut().set_bit_range(23, 23, 1);
     assert!(p.mmu().match_pmpcfg_entry(p.state(), &0x2001_0000, 4).is_some());
-}
\ No newline at end of file
+}
+#[test]
+fn debug_translate_test() {
+    use terminus_spaceport::memory::region::GHEAP;
+    let mut cfg = ProcessorCfg::from_isa("rv64imafdcsu", 1000000000).unwrap();
+    cfg.enable_dirty = true;
+    let mut sys = SystemBuilder::new("test").timer_freq(100).processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x10000, 1).unwrap()).unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
+    //sv39 gigapage 0x0 -> 0x80000000, rwx with accessed bit cleared
+    let pte: u64 = (0x80000 << 10) | 0xf;
+    sys.write_mem(0x80001000, &pte.to_le_bytes()).unwrap();
+    let p = sys.processor(0).unwrap();
+    //s-mode can access everything by a tor region
+    p.state().set_csr_backdoor(0x3b0, (1 << 54) - 1).unwrap();
+    p.state().set_csr_backdoor(0x3a0, 0xf).unwrap();
+    p.state().set_csr_backdoor(0x180, (8 << 60) | 0x80001).unwrap();
+    //loads of m-mode are translated in s-mode by mprv
+    p.state().set_csr_backdoor(0x300, (1 << 17) | (1 << 11)).unwrap();
+    assert_eq!(p.debug_translate(0x1234), Some(0x80001234));
+    assert!(p.mmu().load_tlb.borrow().get_ppn(1).is_none());
+    let mut data = [0; 8];
+    sys.read_mem(0x80001000, &mut data).unwrap();
+    assert_eq!(u64::from_le_bytes(data), pte);
+    //while loads set the accessed bit and fill the tlb
+    let p = sys.processor(0).unwrap();
+    assert_eq!(p.mmu().ls_translate(p.state(), &0x1234, 1, MmuOpt::Load), Ok(0x80001234));
+    assert!(p.mmu().load_tlb.borrow().get_ppn(1).is_some());
+    sys.read_mem(0x80001000, &mut data).unwrap();
+    assert_eq!(u64::from_le_bytes(data), pte | 0x40);
+}
@@ -7,6 +7,8 @@ use std::cell::RefCell;
 use std::fmt::{Display, Formatter};
 use terminus_spaceport::irq::IrqVec;
 use crate::devices::bus::Bus;
+use crate::devices::imsic::ImsicFile;
+use crate::devices::clic::Clic;
 use std::mem::MaybeUninit;
 
 pub mod decode;
@@ -19,9 +21,22 @@ use trap::{Exception, Trap, Interrupt};
 
 pub mod extensions;
 
+pub mod isa;
+
+mod aia;
+
+use aia::Aia;
+
+mod clic;
+
+use clic::Smclic;
+
+use isa::Isa;
+
 use extensions::*;
 use extensions::i::csrs::*;
 use extensions::s::csrs::*;
+use extensions::f::{FRegT, FLen};
 
 mod mmu;
 
@@ -35,6 +50,26 @@ mod load_store;
 
 use load_store::*;
 
+pub mod profiler;
+
+use profiler::Profiler;
+
+pub mod cache;
+
+use cache::MemHierarchy;
+
+pub mod timing;
+
+use timing::{TimingModel, Functional, RetireInfo, TimingStats};
+
+pub mod commit;
+
+use commit::{Commit, TrapRecord};
+
+pub mod env;
+
+use env::Env;
+
 #[derive(IntoPrimitive, TryFromPrimitive, Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u8)]
 pub enum PrivilegeLevel {
@@ -55,14 +90,26 @@ pub enum Privilege {
 pub struct ProcessorCfg {
     pub xlen: XLen,
     pub enable_dirty: bool,
-    pub extensions: Box<[char]>,
+    pub isa: Isa,
     pub freq: usize,
 }
 
 impl ProcessorCfg {
+    //"rv64imafdcsu", "rv64gcsu_zicsr_zifencei", refer to isa::Isa::parse
+    pub fn from_isa(isa: &str, freq: usize) -> Result<ProcessorCfg, String> {
+        let isa = Isa::parse(isa)?;
+        isa.check_supported()?;
+        Ok(ProcessorCfg {
+            xlen: isa.xlen(),
+            enable_dirty: true,
+            isa,
+            freq,
+        })
+    }
+
     fn privilege_level(&self) -> PrivilegeLevel {
-        if self.extensions.contains(&'u') {
-            if self.extensions.contains(&'s') {
+        if self.isa.has("u") {
+            if self.isa.has("s") {
                 PrivilegeLevel::MSU
             } else {
                 PrivilegeLevel::MU
@@ -79,17 +126,26 @@ pub struct ProcessorState {
     config: ProcessorCfg,
     privilege: Privilege,
     xreg: [RegT; 32],
+    xreg_written: u32,
     extensions: [Extension; 26],
     pc: RegT,
     next_pc: RegT,
     ir: InsnT,
     clint: Arc<IrqVec>,
+    //external interrupts, 0:meip, 1:seip, 2:stip driven by sbi timer, 3:ssip set by aclint sswi,
+    //4 and 5: m and s interrupt files of imsic
+    eirq: Arc<IrqVec>,
+    //smaia and ssaia csrs
+    aia: Option<Aia>,
+    //smclic csrs
+    clic: Option<Smclic>,
     insns_cnt: Rc<RefCell<u64>>,
+    cycles: Rc<RefCell<u64>>,
 }
 
 impl ProcessorState {
     pub fn trace(&self) -> String {
-        format!("hartid = {}; privilege = {:?};pc = {:#x}; ir = {:#x}; next_pc = {:#x}; insns_cnt = {};", self.hartid, self.privilege(), self.pc(), self.ir(), self.next_pc(), *self.insns_cnt().borrow())
+        format!("hartid = {}; privilege = {:?};pc = {:#x}; ir = {:#x}; next_pc = {:#x}; insns_cnt = {}; cycles = {};", self.hartid, self.privilege(), self.pc(), self.ir(), self.next_pc(), *self.insns_cnt().borrow(), *self.cycles().borrow())
     }
 }
 
@@ -118,12 +174,23 @@ impl Display for ProcessorState {
 
 
 impl ProcessorState {
-    fn new(hartid: usize, config: ProcessorCfg, clint: &Arc<IrqVec>) -> ProcessorState {
+    fn new(hartid: usize, config: ProcessorCfg, clint: &Arc<IrqVec>) -> Result<ProcessorState, String> {
+        let aia = if config.isa.has("smaia") {
+            Some(Aia::new(config.isa.has("ssaia")))
+        } else {
+            None
+        };
+        let clic = if config.isa.has("smclic") {
+            Some(Smclic::new())
+        } else {
+            None
+        };
         let mut state = ProcessorState {
             hartid,
             config,
             privilege: Privilege::M,
             xreg: [0 as RegT; 32],
+            xreg_written: 0,
             extensions: unsafe {
                 let mut arr: MaybeUninit<[Extension; 26]> = MaybeUninit::uninit();
                 for i in 0..26 {
@@ -135,10 +202,20 @@ impl ProcessorState {
             next_pc: 0,
             ir: 0,
             clint: clint.clone(),
+            eirq: {
+                let eirq = Arc::new(IrqVec::new(6));
+                for i in 0..6 {
+                    eirq.set_enable(i).unwrap();
+                }
+                eirq
+            },
+            aia,
+            clic,
             insns_cnt: Rc::new(RefCell::new(0)),
+            cycles: Rc::new(RefCell::new(0)),
         };
-        state.add_extension().expect("add extension error!");
-        state
+        state.add_extension()?;
+        Ok(state)
     }
 
     fn reset(&mut self, start_address: u64) -> Result<(), String> {
@@ -149,6 +226,9 @@ impl ProcessorState {
         self.pc = 0;
         self.next_pc = start_address;
         self.ir = 0;
+        if let Some(ref clic) = self.clic {
+            clic.reset()
+        }
         let csrs = self.icsrs();
         //register clint:0:msip, 1:mtip
         csrs.mip_mut().msip_transform({
@@ -163,41 +243,30 @@ impl ProcessorState {
                 clint.pending(1).unwrap() as RegT
             }
         });
+        //external interrupts, seip is or-ed with the software writable bit
+        csrs.mip_mut().meip_transform({
+            let eirq = self.eirq.clone();
+            move |_| {
+                (eirq.pending(0).unwrap() || eirq.pending(4).unwrap()) as RegT
+            }
+        });
+        csrs.mip_mut().seip_transform({
+            let eirq = self.eirq.clone();
+            move |v| {
+                v | (eirq.pending(1).unwrap() || eirq.pending(5).unwrap()) as RegT
+            }
+        });
+        csrs.mip_mut().stip_transform({
+            let eirq = self.eirq.clone();
+            move |v| {
+                v | eirq.pending(2).unwrap() as RegT
+            }
+        });
         //hartid
         csrs.mhartid_mut().set(self.hartid as RegT);
         //extensions config, only f, d can disable
         let mut misa = csrs.misa_mut();
-        for ext in self.config().extensions.iter() {
-            match ext {
-                'a' => misa.set_a(1),
-                'b' => misa.set_b(1),
-                'c' => misa.set_c(1),
-                'd' => misa.set_d(1),
-                'e' => misa.set_e(1),
-                'f' => misa.set_f(1),
-                'g' => misa.set_g(1),
-                'h' => misa.set_h(1),
-                'i' => misa.set_i(1),
-                'j' => misa.set_j(1),
-                'k' => misa.set_k(1),
-                'l' => misa.set_l(1),
-                'm' => misa.set_m(1),
-                'n' => misa.set_n(1),
-                'o' => misa.set_o(1),
-                'p' => misa.set_p(1),
-                'q' => misa.set_q(1),
-                'r' => misa.set_r(1),
-                's' => misa.set_s(1),
-                't' => misa.set_t(1),
-                'u' => misa.set_u(1),
-                'v' => misa.set_v(1),
-                'w' => misa.set_w(1),
-                'x' => misa.set_x(1),
-                'y' => misa.set_y(1),
-                'z' => misa.set_z(1),
-                _ => unreachable!()
-            }
-        }
+        misa.set_bit_range(25, 0, self.config().isa.misa());
         //xlen config
         match self.config().xlen {
             XLen::X32 => {
@@ -214,7 +283,10 @@ impl ProcessorState {
     }
 
     fn add_extension(&mut self) -> Result<(), String> {
-        let exts = self.config().extensions.iter().filter(|&e| { *e != 'i' }).map(|e| { *e }).collect::<Vec<char>>();
+        if self.config().isa.xlen() != self.config().xlen {
+            return Err(format!("cpu{}:xlen {:?} mismatches isa {}!", self.hartid, self.config().xlen, self.config().isa));
+        }
+        let exts = self.config().isa.singles().iter().filter(|&e| { *e != 'i' }).copied().collect::<Vec<char>>();
         let mut add_one_extension = |id: char| -> Result<(), String>  {
             let ext = Extension::new(self, id)?;
             self.extensions[(id as u8 - 'a' as u8) as usize] = ext;
@@ -242,8 +314,7 @@ impl ProcessorState {
     }
 
     pub fn isa_string(&self) -> String {
-        let exts: String = self.config().extensions.iter().collect();
-        format!("rv{}{}", self.config().xlen.len(), exts)
+        self.config().isa.to_string()
     }
 
     pub fn icsrs(&self) -> &Rc<ICsrs> {
@@ -279,10 +350,31 @@ impl ProcessorState {
         self.hartid
     }
 
+    fn csr_read(&self, id: InsnT) -> Option<RegT> {
+        self.aia.as_ref().and_then(|aia| { aia.csr_read(self, id) })
+            .or_else(|| { self.clic.as_ref().and_then(|clic| { clic.csr_read(self, id) }) })
+            .or_else(|| { self.extensions().iter().find_map(|e| { e.csr_read(self, id) }) })
+    }
+
+    fn csr_write(&self, id: InsnT, value: RegT) -> Option<()> {
+        self.aia.as_ref().and_then(|aia| { aia.csr_write(self, id, value) })
+            .or_else(|| { self.clic.as_ref().and_then(|clic| { clic.csr_write(self, id, value) }) })
+            .or_else(|| { self.extensions().iter().find_map(|e| { e.csr_write(self, id, value) }) })
+    }
+
+    //mnxti reads the next interrupt, while the operand of csr instructions is applied to mstatus
+    pub fn csr_rmw_value(&self, id: InsnT, value: RegT) -> Result<RegT, Exception> {
+        if self.clic.is_some() && id & 0xfff == clic::MNXTI {
+            self.csr(0x300)
+        } else {
+            Ok(value)
+        }
+    }
+
     pub fn csr(&self, id: InsnT) -> Result<RegT, Exception> {
         let trip_id = id & 0xfff;
         self.csr_privilege_check(trip_id)?;
-        match self.extensions().iter().find_map(|e| { e.csr_read(self, trip_id) }) {
+        match self.csr_read(trip_id) {
             Some(v) => Ok(v),
             None => Err(Exception::IllegalInsn(self.ir()))
         }
@@ -291,12 +383,90 @@ impl ProcessorState {
     pub fn set_csr(&self, id: InsnT, value: RegT) -> Result<(), Exception> {
         let trip_id = id & 0xfff;
         self.csr_privilege_check(trip_id)?;
-        match self.extensions().iter().find_map(|e| { e.csr_write(self, trip_id, value) }) {
+        match self.csr_write(trip_id, value) {
             Some(_) => Ok(()),
             None => Err(Exception::IllegalInsn(self.ir()))
         }
     }
 
+    //access csr without privilege check, for debuggers and co-simulation
+    pub fn csr_backdoor(&self, id: InsnT) -> Option<RegT> {
+        self.csr_read(id & 0xfff)
+    }
+
+    pub fn set_csr_backdoor(&self, id: InsnT, value: RegT) -> Option<()> {
+        self.csr_write(id & 0xfff, value)
+    }
+
+    //attach the interrupt file of imsic at level, accessed through mireg/sireg and mtopei/stopei
+    pub fn attach_imsic(&self, level: Privilege, file: &Arc<ImsicFile>) -> Result<(), String> {
+        match self.aia {
+            Some(ref aia) => aia.attach(level, file).map_err(|e| { format!("cpu{}:{}", self.hartid, e) }),
+            None => Err(format!("cpu{}:imsic requires smaia!", self.hartid))
+        }
+    }
+
+    pub fn attach_clic(&self, clic: &Clic) -> Result<(), String> {
+        match self.clic {
+            Some(ref smclic) => {
+                smclic.attach(clic);
+                Ok(())
+            }
+            None => Err(format!("cpu{}:clic requires smclic!", self.hartid))
+        }
+    }
+
+    //smclic in clic mode, mtvec.mode == 3
+    fn clic_mode(&self) -> Option<&Smclic> {
+        self.clic.as_ref().filter(|_| { self.icsrs().mtvec().get() & 0x3 == 0x3 })
+    }
+
+    //interrupts pending for wfi
+    pub fn interrupt_pending(&self) -> bool {
+        match self.clic_mode() {
+            Some(clic) => clic.pending(self),
+            None => self.icsrs().mip().get() != 0
+        }
+    }
+
+    //pending and enabled interrupts taken in m mode and s mode, regardless of global interrupt enables
+    fn pending_interrupts(&self) -> (RegT, RegT) {
+        let csrs = self.icsrs();
+        let pendings = csrs.mip().get() & csrs.mie().get();
+        (pendings & !csrs.mideleg().get(), pendings & csrs.mideleg().get())
+    }
+
+    //fregs and flen, none if f extension is not enabled
+    pub fn fregs(&self) -> Option<(&[FRegT; 32], FLen)> {
+        if let Extension::F(ref float) = self.get_extension('f') {
+            Some((float.fregs(), float.flen))
+        } else {
+            None
+        }
+    }
+
+    pub fn eirq(&self) -> &Arc<IrqVec> {
+        &self.eirq
+    }
+
+    //drive interrupt line from outside, id is the bit position in mip
+    pub fn set_irq(&self, id: usize, level: bool) -> Result<(), String> {
+        let (irq_vec, line) = match id {
+            3 => (&self.clint, 0),
+            7 => (&self.clint, 1),
+            5 => (&self.eirq, 2),
+            9 => (&self.eirq, 1),
+            11 => (&self.eirq, 0),
+            _ => return Err(format!("cpu{}:interrupt {} can not be driven from outside!", self.hartid, id))
+        };
+        let result = if level {
+            irq_vec.set_pending(line)
+        } else {
+            irq_vec.clr_pending(line)
+        };
+        result.map_err(|e| { format!("cpu{}:{:?}", self.hartid, e) })
+    }
+
     pub fn check_extension(&self, ext: char) -> Result<(), Exception> {
         if self.icsrs().misa().get() & ((1 as RegT) << ((ext as u8 - 'a' as u8) as RegT)) != 0 {
             Ok(())
@@ -305,6 +475,15 @@ impl ProcessorState {
         }
     }
 
+    //multi-letter extensions have no misa bit, they are fixed by isa
+    pub fn check_isa(&self, ext: &str) -> Result<(), Exception> {
+        if self.config().isa.has(ext) {
+            Ok(())
+        } else {
+            Err(Exception::IllegalInsn(self.ir()))
+        }
+    }
+
     pub fn check_xlen(&self, xlen: XLen) -> Result<(), Exception> {
         if xlen == self.config().xlen {
             Ok(())
@@ -372,6 +551,10 @@ impl ProcessorState {
         &self.insns_cnt
     }
 
+    pub fn cycles(&self) -> &Rc<RefCell<u64>> {
+        &self.cycles
+    }
+
     pub fn xreg(&self, id: InsnT) -> &RegT {
         let trip_id = id & 0x1f;
         if trip_id == 0 {
@@ -385,6 +568,7 @@ impl ProcessorState {
     pub fn set_xreg(&mut self, id: InsnT, value: RegT) {
         let trip_id = id & 0x1f;
         if trip_id != 0 {
+            self.xreg_written |= 1 << trip_id;
             *unsafe { self.xreg.get_unchecked_mut(trip_id as usize) } = value
             // self.xreg[trip_id as usize] = value
         }
@@ -396,20 +580,28 @@ pub struct Processor {
     mmu: Mmu,
     fetcher: Fetcher,
     load_store: LoadStore,
+    profiler: Option<Profiler>,
+    timing: Box<dyn TimingModel>,
+    commit: Option<Commit>,
+    env: Option<Box<dyn Env>>,
 }
 
 impl Processor {
-    pub fn new(hartid: usize, config: ProcessorCfg, bus: &Arc<Bus>, clint: &Arc<IrqVec>) -> Processor {
-        let state = ProcessorState::new(hartid, config, clint);
+    pub fn new(hartid: usize, config: ProcessorCfg, bus: &Arc<Bus>, clint: &Arc<IrqVec>) -> Result<Processor, String> {
+        let state = ProcessorState::new(hartid, config, clint)?;
         let mmu = Mmu::new(bus);
         let fetcher = Fetcher::new(bus);
         let load_store = LoadStore::new(bus);
-        Processor {
+        Ok(Processor {
             state,
             mmu,
             fetcher,
             load_store,
-        }
+            profiler: None,
+            timing: Box::new(Functional),
+            commit: None,
+            env: None,
+        })
     }
 
     pub fn reset(&mut self, start_address: u64) -> Result<(), String> {
@@ -420,6 +612,11 @@ impl Processor {
         Ok(())
     }
 
+    pub fn set_mem_hierarchy(&mut self, mem_hierarchy: &Rc<MemHierarchy>) {
+        self.fetcher.set_mem_hierarchy(mem_hierarchy);
+        self.load_store.set_mem_hierarchy(mem_hierarchy);
+    }
+
     pub fn fetcher(&self) -> &Fetcher {
         &self.fetcher
     }
@@ -436,6 +633,52 @@ impl Processor {
         &self.state
     }
 
+    pub fn enable_profiler(&mut self, interval: u64) {
+        self.profiler = Some(Profiler::new(interval))
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn set_timing_model(&mut self, timing: Box<dyn TimingModel>) {
+        self.timing = timing
+    }
+
+    pub fn timing_stats(&self) -> Option<&TimingStats> {
+        self.timing.stats()
+    }
+
+    pub fn set_env(&mut self, env: Box<dyn Env>) {
+        self.env = Some(env)
+    }
+
+    fn env_poll(&mut self) -> bool {
+        if let Some(mut env) = self.env.take() {
+            let running = env.poll(self);
+            self.env = Some(env);
+            running
+        } else {
+            true
+        }
+    }
+
+    fn env_trap(&mut self, e: &Exception) -> bool {
+        if let Some(mut env) = self.env.take() {
+            let handled = env.trap(self, e);
+            self.env = Some(env);
+            handled
+        } else {
+            false
+        }
+    }
+
+    //translate va for debuggers with current privilege and satp, none if not accessible.
+    //tlbs and ptes are not touched
+    pub fn debug_translate(&self, va: RegT) -> Option<u64> {
+        self.mmu.debug_translate(&self.state, &va)
+    }
+
     pub fn state_mut(&mut self) -> &mut ProcessorState {
         &mut self.state
     }
@@ -444,68 +687,75 @@ impl Processor {
         self.state_mut().pc = self.state.next_pc;
         let (ir, inst) = self.fetcher.fetch(self.state(), self.mmu())?;
         self.state.ir = ir;
+        let privilege = self.state.privilege;
         match inst.execute(self) {
             Ok(_) => {
-                *self.state.insns_cnt.deref().borrow_mut() += 1;
+                self.retire(privilege, true);
                 Ok(())
             }
             Err(e) => {
                 if e.executed() {
-                    *self.state.insns_cnt.deref().borrow_mut() += 1;
+                    self.retire(privilege, false);
                 }
                 Err(e)
             }
         }
     }
 
+    fn retire(&mut self, privilege: Privilege, completed: bool) {
+        *self.state.insns_cnt.deref().borrow_mut() += 1;
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.retire(&self.state, privilege, completed)
+        }
+        let info = RetireInfo {
+            pc: self.state.pc,
+            ir: self.state.ir,
+            next_pc: self.state.next_pc,
+            xlen: self.state.config().xlen,
+            fetch_level: self.fetcher.take_level(),
+            mem_level: self.load_store.take_level(),
+        };
+        *self.state.cycles.deref().borrow_mut() += self.timing.retire(&info);
+        if let Some(ref mut commit) = self.commit {
+            commit.ir = self.state.ir;
+            commit.retired = true;
+        }
+    }
+
     fn take_interrupt(&self) -> Result<(), Interrupt> {
+        //all interrupts are from clic in clic mode
+        if let Some(clic) = self.state().clic_mode() {
+            return match clic.top(self.state()) {
+                Some((id, _, _)) => Err(Interrupt::ClicInt(id as RegT)),
+                None => Ok(())
+            };
+        }
         let csrs = self.state().icsrs();
-        let pendings = csrs.mip().get() & csrs.mie().get();
+        let (m_pendings, s_pendings) = self.state().pending_interrupts();
         let mie = csrs.mstatus().mie();
         let m_enabled = *self.state().privilege() != Privilege::M || (*self.state().privilege() == Privilege::M && mie == 1);
-        let m_pendings = pendings & !csrs.mideleg().get() & sext(m_enabled as RegT, 1);
+        let m_pendings = m_pendings & sext(m_enabled as RegT, 1);
         let sie = csrs.mstatus().sie();
         let s_enabled = *self.state().privilege() == Privilege::U || (*self.state().privilege() == Privilege::S && sie == 1);
-        let s_pendings = pendings & csrs.mideleg().get() & sext(s_enabled as RegT, 1);
-
-        //m_pendings > s_pendings
-        let interrupts = Mip::new(self.state().config().xlen,
-                                  if m_pendings == 0 {
-                                      s_pendings
-                                  } else {
-                                      m_pendings
-                                  });
-        if interrupts.get() == 0 {
-            Ok(())
-        } else {
-            // MEI > MSI > MTI > SEI > SSI > STI
-            if interrupts.meip() == 1 {
-                return Err(Interrupt::MEInt);
-            } else if interrupts.msip() == 1 {
-                return Err(Interrupt::MSInt);
-            } else if interrupts.mtip() == 1 {
-                return Err(Interrupt::MTInt);
-            } else if interrupts.seip() == 1 {
-                return Err(Interrupt::SEInt);
-            } else if interrupts.ssip() == 1 {
-                return Err(Interrupt::SSInt);
-            } else if interrupts.stip() == 1 {
-                return Err(Interrupt::STInt);
-            } else {
-                unreachable!()
-            }
+        let s_pendings = s_pendings & sext(s_enabled as RegT, 1);
+
+        //m_pendings > s_pendings, the minor identity of external interrupts is read from mtopei/stopei by the handler
+        match Interrupt::top(if m_pendings == 0 { s_pendings } else { m_pendings }) {
+            Some(int) => Err(int),
+            None => Ok(())
         }
     }
 
     fn handle_trap(&mut self, trap: Trap) {
         let mcsrs = self.state().icsrs();
         let scsrs = self.state().scsrs();
+        let clic_int = matches!(trap, Trap::Interrupt(Interrupt::ClicInt(_)));
         let (int_flag, deleg, code, tval) = match trap {
             Trap::Exception(e) => (0 as RegT, mcsrs.medeleg().get(), e.code(), e.tval()),
             Trap::Interrupt(i) => (1 as RegT, mcsrs.mideleg().get(), i.code(), i.tval()),
         };
         //deleg to s-mode
-        let degeged = *self.state().privilege() != Privilege::M && (deleg >> code) & 1 == 1;
+        let degeged = *self.state().privilege() != Privilege::M && !clic_int && (deleg >> code) & 1 == 1;
         let (pc, privilege) = if degeged {
             let tvec = scsrs.stvec();
             let offset = if tvec.mode() == 1 && int_flag == 1 {
@@ -534,7 +784,12 @@ impl Processor {
             } else {
                 0
             };
-            let pc = (tvec.base() << 2) + offset;
+            //traps of clic mode go to the base aligned to 64 bytes
+            let pc = if self.state().clic_mode().is_some() {
+                tvec.get() & !0x3f
+            } else {
+                (tvec.base() << 2) + offset
+            };
             mcsrs.mcause_mut().set_code(code);
             mcsrs.mcause_mut().set_int(int_flag);
             mcsrs.mepc_mut().set(*self.state().pc());
@@ -549,17 +804,81 @@ impl Processor {
             self.fetcher().flush_icache();
             (pc, Privilege::M)
         };
+        let from = *self.state().privilege();
+        let epc = *self.state().pc();
         self.state_mut().set_pc(pc);
-        self.state_mut().set_privilege(privilege);
+        let to = self.state_mut().set_privilege(privilege);
+        //interrupts with hardware vectoring jump to the entry of mtvt, handlers of lower levels are preempted
+        let vector = match self.state().clic_mode() {
+            Some(clic) if to == Privilege::M => clic.trap(self.state(), int_flag == 1, code),
+            _ => None
+        };
+        if let Some(ref mut commit) = self.commit {
+            commit.traps.push(TrapRecord {
+                interrupt: int_flag == 1,
+                code,
+                tval,
+                epc,
+                privilege: to,
+            })
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.trap(from, to)
+        }
+        //drop levels of the trapped instruction
+        self.fetcher.take_level();
+        self.load_store.take_level();
+        *self.state.cycles.deref().borrow_mut() += self.timing.trap();
+        if let Some(entry) = vector {
+            self.fetch_vector(entry)
+        }
+    }
+
+    //a failed fetch of the mtvt entry traps with mepc pointing to the entry and mcause.minhv set
+    fn fetch_vector(&mut self, entry: RegT) {
+        let result = match self.state().config().xlen {
+            XLen::X32 => {
+                let mut data = 0u32;
+                self.load_store().load_word(self.state(), &entry, &mut data, self.mmu()).map(|_| { data as RegT })
+            }
+            XLen::X64 => {
+                let mut data = 0u64;
+                self.load_store().load_double_word(self.state(), &entry, &mut data, self.mmu()).map(|_| { data })
+            }
+        };
+        match result {
+            Ok(pc) => {
+                if let Some(ref clic) = self.state.clic {
+                    clic.vectored()
+                }
+                self.state_mut().set_pc(pc & !1)
+            }
+            Err(_) => {
+                self.state_mut().set_pc(entry);
+                self.handle_trap(Trap::Exception(Exception::FetchAccess(entry)))
+            }
+        }
     }
 
     pub fn step(&mut self, n: usize) {
         assert!(n > 0);
         for _ in 0..n {
+            if !self.env_poll() {
+                //idle harts keep time going
+                *self.state.cycles.deref().borrow_mut() += 1;
+                continue;
+            }
             if let Err(exct) = self.one_insn() {
-                self.handle_trap(Trap::Exception(exct))
+                if !self.env_trap(&exct) {
+                    self.handle_trap(Trap::Exception(exct))
+                }
             }
         }
+        //ssip set by sswi is an edge, latch it into the software writable bit
+        if self.state.eirq.pending(3).unwrap() {
+            self.state.eirq.clr_pending(3).unwrap();
+            self.state.icsrs().mip_mut().set_ssip(1)
+        }
         if let Err(int) = self.take_interrupt() {
             self.handle_trap(Trap::Interrupt(int))
         }
@@ -567,4 +886,33 @@ impl Processor {
             ext.step_cb(self)
         }
     }
+
+    //step one instruction and collect its architectural effects, including traps taken in this step
+    pub fn step_commit(&mut self) -> Commit {
+        self.state.xreg_written = 0;
+        if let Extension::F(ref mut float) = self.state.get_extension_mut('f') {
+            float.take_written();
+        }
+        self.load_store.start_mem_log();
+        self.commit = Some(Commit::default());
+        self.step(1);
+        let mut commit = self.commit.take().unwrap();
+        commit.pc = self.state.pc;
+        let xreg_written = std::mem::replace(&mut self.state.xreg_written, 0);
+        for i in 1..32 {
+            if (xreg_written >> i) & 1 == 1 {
+                commit.xreg_writes.push((i, *self.state.xreg(i)))
+            }
+        }
+        if let Extension::F(ref mut float) = self.state.get_extension_mut('f') {
+            let freg_written = float.take_written();
+            for i in 0..32 {
+                if (freg_written >> i) & 1 == 1 {
+                    commit.freg_writes.push((i, *float.freg(i)))
+                }
+            }
+        }
+        commit.mem_writes = self.load_store.take_mem_writes();
+        commit
+    }
 }
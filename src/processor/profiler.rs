@@ -0,0 +1,185 @@
+use terminus_global::*;
+use crate::processor::{ProcessorState, Privilege};
+use crate::system::elf::SymbolTable;
+use std::collections::HashMap;
+
+//deepest shadow call stack kept, outer frames are dropped when exceeded
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Flow {
+    Call,
+    Return,
+    ReturnCall,
+    Other,
+}
+
+//x1(ra) and x5(t0) are link registers, refer to "return-address stack prediction hints" in the unprivileged spec
+fn is_link(r: InsnT) -> bool {
+    r == 1 || r == 5
+}
+
+fn jalr_flow(rd: InsnT, rs1: InsnT) -> Flow {
+    match (is_link(rd), is_link(rs1)) {
+        (false, false) => Flow::Other,
+        (false, true) => Flow::Return,
+        (true, false) => Flow::Call,
+        (true, true) => if rd == rs1 {
+            Flow::Call
+        } else {
+            Flow::ReturnCall
+        }
+    }
+}
+
+pub(crate) fn flow(ir: InsnT, xlen: XLen) -> Flow {
+    if ir & 0x3 == 0x3 {
+        let rd = (ir >> 7) & 0x1f;
+        let rs1 = (ir >> 15) & 0x1f;
+        match ir & 0x7f {
+            //jal
+            0x6f => if is_link(rd) {
+                Flow::Call
+            } else {
+                Flow::Other
+            },
+            //jalr
+            0x67 => jalr_flow(rd, rs1),
+            _ => Flow::Other
+        }
+    } else {
+        let rs1 = (ir >> 7) & 0x1f;
+        let rs2 = (ir >> 2) & 0x1f;
+        match (ir & 0x3, (ir >> 13) & 0x7) {
+            //c.jal
+            (1, 1) if xlen == XLen::X32 => Flow::Call,
+            //c.jr, c.jalr
+            (2, 4) if rs2 == 0 && rs1 != 0 => if (ir >> 12) & 0x1 == 0 {
+                jalr_flow(0, rs1)
+            } else {
+                jalr_flow(1, rs1)
+            },
+            _ => Flow::Other
+        }
+    }
+}
+
+//Profiler samples pc of a hart every interval retired instructions.
+//Call stacks are rebuilt from jal/jalr link register conventions, a shadow stack is kept for each privilege.
+pub struct Profiler {
+    interval: u64,
+    cnt: u64,
+    stacks: [Vec<u64>; 4],
+    samples: HashMap<(u8, Vec<u64>, u64), u64>,
+}
+
+impl Profiler {
+    pub fn new(interval: u64) -> Profiler {
+        assert!(interval > 0);
+        Profiler {
+            interval,
+            cnt: 0,
+            stacks: [vec![], vec![], vec![], vec![]],
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    //privilege is the privilege the instruction executed in, next_pc of state is the target of jumps
+    pub fn retire(&mut self, state: &ProcessorState, privilege: Privilege, completed: bool) {
+        let priv_value: u8 = privilege.into();
+        self.cnt += 1;
+        if self.cnt % self.interval == 0 {
+            let stack = self.stacks[priv_value as usize].clone();
+            *self.samples.entry((priv_value, stack, *state.pc())).or_insert(0) += 1;
+        }
+        if !completed {
+            return;
+        }
+        let stack = &mut self.stacks[priv_value as usize];
+        match flow(state.ir(), state.config().xlen) {
+            Flow::Call => {
+                if stack.len() == MAX_DEPTH {
+                    stack.remove(0);
+                }
+                stack.push(*state.next_pc())
+            }
+            Flow::Return => {
+                stack.pop();
+            }
+            Flow::ReturnCall => {
+                stack.pop();
+                stack.push(*state.next_pc())
+            }
+            Flow::Other => {}
+        }
+    }
+
+    //trap handler starts from an empty stack when entering a different privilege
+    pub fn trap(&mut self, from: Privilege, to: Privilege) {
+        if from != to {
+            let priv_value: u8 = to.into();
+            self.stacks[priv_value as usize].clear()
+        }
+    }
+
+    pub fn samples_cnt(&self) -> u64 {
+        self.samples.values().sum()
+    }
+
+    //fold samples of privilege to "root;frame1;frame2" lines, frames are resolved by symbols
+    pub fn fold(&self, root: &str, privilege: Privilege, symbols: &SymbolTable, folded: &mut HashMap<String, u64>) {
+        let priv_value: u8 = privilege.into();
+        for ((p, stack, pc), cnt) in self.samples.iter() {
+            if *p != priv_value {
+                continue;
+            }
+            let mut frames = vec![root.to_string()];
+            frames.extend(stack.iter().map(|addr| { symbols.resolve(*addr) }));
+            let leaf = symbols.resolve(*pc);
+            if stack.is_empty() || frames.last() != Some(&leaf) {
+                frames.push(leaf)
+            }
+            *folded.entry(frames.join(";")).or_insert(0) += *cnt;
+        }
+    }
+}
+
+#[test]
+fn profiler_test() {
+    use crate::system::elf::Symbol;
+    //jal ra; jal x0; ret; jalr ra, a0; jalr t0, ra
+    assert_eq!(flow(0x000000ef, XLen::X64), Flow::Call);
+    assert_eq!(flow(0x0000006f, XLen::X64), Flow::Other);
+    assert_eq!(flow(0x00008067, XLen::X64), Flow::Return);
+    assert_eq!(flow(0x000500e7, XLen::X64), Flow::Call);
+    assert_eq!(flow(0x000082e7, XLen::X64), Flow::ReturnCall);
+    //c.jr ra; c.jalr a0; c.jr a0; c.jal is c.addiw in rv64
+    assert_eq!(flow(0x8082, XLen::X64), Flow::Return);
+    assert_eq!(flow(0x9502, XLen::X64), Flow::Call);
+    assert_eq!(flow(0x8502, XLen::X64), Flow::Other);
+    assert_eq!(flow(0x2001, XLen::X32), Flow::Call);
+    assert_eq!(flow(0x2001, XLen::X64), Flow::Other);
+
+    let symbols = SymbolTable::new(vec![
+        Symbol { name: "foo".to_string(), addr: 0x2000, size: 0x10 },
+        Symbol { name: "main".to_string(), addr: 0x1000, size: 0x100 },
+    ]);
+    let m: u8 = Privilege::M.into();
+    let s: u8 = Privilege::S.into();
+    let mut profiler = Profiler::new(1);
+    profiler.samples.insert((m, vec![0x1008], 0x2004), 2);
+    profiler.samples.insert((m, vec![], 0x1004), 1);
+    //recursion does not repeat the leaf
+    profiler.samples.insert((m, vec![0x1008, 0x2008], 0x2004), 3);
+    profiler.samples.insert((s, vec![], 0x1004), 4);
+    assert_eq!(profiler.samples_cnt(), 10);
+    let mut folded = HashMap::new();
+    profiler.fold("hart0", Privilege::M, &symbols, &mut folded);
+    let mut lines = folded.into_iter().collect::<Vec<_>>();
+    lines.sort();
+    assert_eq!(lines, vec![("hart0;main".to_string(), 1), ("hart0;main;foo".to_string(), 5)]);
+}
@@ -0,0 +1,463 @@
+use terminus_global::*;
+use crate::processor::cache::HitLevel;
+use crate::processor::profiler::{flow, Flow};
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InsnClass {
+    Alu,
+    Mul,
+    Div,
+    Load,
+    Store,
+    Amo,
+    Branch,
+    Jump,
+    FpAlu,
+    FpMul,
+    FpDiv,
+    FpLoad,
+    FpStore,
+    Csr,
+    System,
+}
+
+pub fn classify(ir: InsnT, xlen: XLen) -> InsnClass {
+    if ir & 0x3 == 0x3 {
+        let funct3 = (ir >> 12) & 0x7;
+        let funct7 = ir >> 25;
+        match ir & 0x7f {
+            0x03 => InsnClass::Load,
+            0x23 => InsnClass::Store,
+            0x07 => InsnClass::FpLoad,
+            0x27 => InsnClass::FpStore,
+            0x2f => InsnClass::Amo,
+            0x63 => InsnClass::Branch,
+            0x67 | 0x6f => InsnClass::Jump,
+            0x33 | 0x3b if funct7 == 1 => if funct3 < 4 {
+                InsnClass::Mul
+            } else {
+                InsnClass::Div
+            },
+            0x43 | 0x47 | 0x4b | 0x4f => InsnClass::FpMul,
+            0x53 => match funct7 >> 2 {
+                0x2 => InsnClass::FpMul,
+                0x3 | 0xb => InsnClass::FpDiv,
+                _ => InsnClass::FpAlu
+            },
+            0x73 => if funct3 == 0 {
+                InsnClass::System
+            } else {
+                InsnClass::Csr
+            },
+            0x0f => InsnClass::System,
+            _ => InsnClass::Alu
+        }
+    } else {
+        let funct3 = (ir >> 13) & 0x7;
+        match (ir & 0x3, funct3) {
+            (0, 1) => InsnClass::FpLoad,
+            (0, 2) => InsnClass::Load,
+            (0, 3) => if xlen == XLen::X32 {
+                InsnClass::FpLoad
+            } else {
+                InsnClass::Load
+            },
+            (0, 5) => InsnClass::FpStore,
+            (0, 6) => InsnClass::Store,
+            (0, 7) => if xlen == XLen::X32 {
+                InsnClass::FpStore
+            } else {
+                InsnClass::Store
+            },
+            (1, 1) if xlen == XLen::X32 => InsnClass::Jump,
+            (1, 5) => InsnClass::Jump,
+            (1, 6) | (1, 7) => InsnClass::Branch,
+            (2, 1) => InsnClass::FpLoad,
+            (2, 2) => InsnClass::Load,
+            (2, 3) => if xlen == XLen::X32 {
+                InsnClass::FpLoad
+            } else {
+                InsnClass::Load
+            },
+            (2, 4) => {
+                let rs1 = (ir >> 7) & 0x1f;
+                let rs2 = (ir >> 2) & 0x1f;
+                if rs2 == 0 && rs1 != 0 {
+                    InsnClass::Jump
+                } else if rs2 == 0 && rs1 == 0 {
+                    InsnClass::System
+                } else {
+                    InsnClass::Alu
+                }
+            }
+            (2, 5) => InsnClass::FpStore,
+            (2, 6) => InsnClass::Store,
+            (2, 7) => if xlen == XLen::X32 {
+                InsnClass::FpStore
+            } else {
+                InsnClass::Store
+            },
+            _ => InsnClass::Alu
+        }
+    }
+}
+
+//destination register of an integer load
+fn load_dest(ir: InsnT, class: InsnClass) -> Option<InsnT> {
+    if ir & 0x3 == 0x3 {
+        Some((ir >> 7) & 0x1f)
+    } else if ir & 0x3 == 0 {
+        //c.lw, c.ld, c.flw, c.fld
+        Some(((ir >> 2) & 0x7) + 8)
+    } else if class == InsnClass::Load || class == InsnClass::FpLoad {
+        //c.lwsp, c.ldsp, c.flwsp, c.fldsp
+        Some((ir >> 7) & 0x1f)
+    } else {
+        None
+    }
+}
+
+//integer source registers, fields of fp registers are excluded
+fn sources(ir: InsnT, xlen: XLen) -> [Option<InsnT>; 2] {
+    if ir & 0x3 == 0x3 {
+        let rs1 = Some((ir >> 15) & 0x1f);
+        let rs2 = Some((ir >> 20) & 0x1f);
+        match ir & 0x7f {
+            //lui, auipc, jal, fused multiply-add
+            0x37 | 0x17 | 0x6f | 0x43 | 0x47 | 0x4b | 0x4f => [None, None],
+            //loads, fp stores, alu-imm, jalr, system
+            0x03 | 0x07 | 0x27 | 0x13 | 0x1b | 0x67 | 0x73 => [rs1, None],
+            //fcvt.fmt.w[u]/l[u] and fmv.fmt.x read rs1 from x registers
+            0x53 => if ir >> 27 == 0x1a || ir >> 27 == 0x1e {
+                [rs1, None]
+            } else {
+                [None, None]
+            },
+            _ => [rs1, rs2]
+        }
+    } else {
+        let quadrant = ir & 0x3;
+        let funct3 = (ir >> 13) & 0x7;
+        let rs1_c = Some(((ir >> 7) & 0x7) + 8);
+        let rs2_c = Some(((ir >> 2) & 0x7) + 8);
+        let rs1 = Some((ir >> 7) & 0x1f);
+        let rs2 = Some((ir >> 2) & 0x1f);
+        match (quadrant, funct3) {
+            (0, 0) => [Some(2), None],
+            (0, f) if f < 4 => [rs1_c, None],
+            //c.fsd, c.fsw
+            (0, 5) => [rs1_c, None],
+            (0, 7) if xlen == XLen::X32 => [rs1_c, None],
+            (0, _) => [rs1_c, rs2_c],
+            (1, 0) | (1, 1) | (1, 2) | (1, 3) => [rs1, None],
+            (1, 4) => [rs1_c, rs2_c],
+            (1, 5) => [None, None],
+            (1, _) => [rs1_c, None],
+            (2, 0) => [rs1, None],
+            (2, f) if f < 4 => [Some(2), None],
+            (2, 4) => [rs1, rs2],
+            //c.fsdsp, c.fswsp
+            (2, 5) => [Some(2), None],
+            (2, 7) if xlen == XLen::X32 => [Some(2), None],
+            _ => [Some(2), rs2]
+        }
+    }
+}
+
+//information of a retired instruction
+pub struct RetireInfo {
+    pub pc: RegT,
+    pub ir: InsnT,
+    pub next_pc: RegT,
+    pub xlen: XLen,
+    pub fetch_level: Option<HitLevel>,
+    pub mem_level: Option<HitLevel>,
+}
+
+impl RetireInfo {
+    pub fn class(&self) -> InsnClass {
+        classify(self.ir, self.xlen)
+    }
+
+    fn len(&self) -> RegT {
+        if self.ir & 0x3 == 0x3 {
+            4
+        } else {
+            2
+        }
+    }
+
+    pub fn taken(&self) -> bool {
+        self.next_pc != self.pc.wrapping_add(self.len())
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TimingStats {
+    pub cycles: u64,
+    pub instructions: u64,
+    pub branches: u64,
+    pub mispredicts: u64,
+    pub load_use_stalls: u64,
+    pub cache_stall_cycles: u64,
+    pub traps: u64,
+}
+
+impl Display for TimingStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let ipc = if self.cycles == 0 {
+            0.0
+        } else {
+            self.instructions as f64 / self.cycles as f64
+        };
+        write!(f, "cycles = {}; instructions = {}; ipc = {:.3}; branches = {}; mispredicts = {}; load_use_stalls = {}; cache_stall_cycles = {}; traps = {};",
+               self.cycles, self.instructions, ipc, self.branches, self.mispredicts, self.load_use_stalls, self.cache_stall_cycles, self.traps)
+    }
+}
+
+//TimingModel returns cycles consumed by every retired instruction and trap,
+//the sum of them is the cycle counter of the hart.
+pub trait TimingModel {
+    fn retire(&mut self, insn: &RetireInfo) -> u64;
+    fn trap(&mut self) -> u64 {
+        0
+    }
+    fn stats(&self) -> Option<&TimingStats> {
+        None
+    }
+}
+
+//one instruction per cycle, default model
+pub struct Functional;
+
+impl TimingModel for Functional {
+    fn retire(&mut self, _: &RetireInfo) -> u64 {
+        1
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineCfg {
+    pub alu: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub load: u64,
+    pub store: u64,
+    pub amo: u64,
+    pub branch: u64,
+    pub jump: u64,
+    pub fp_alu: u64,
+    pub fp_mul: u64,
+    pub fp_div: u64,
+    pub csr: u64,
+    pub system: u64,
+    pub branch_mispredict_penalty: u64,
+    pub load_use_penalty: u64,
+    //entries of 2-bit counters, power of 2
+    pub bht_entries: usize,
+    pub ras_entries: usize,
+    //extra cycles when an access is served by l2 or memory, only take effect with MemHierarchy enabled
+    pub l2_penalty: u64,
+    pub memory_penalty: u64,
+}
+
+impl Default for PipelineCfg {
+    fn default() -> Self {
+        PipelineCfg {
+            alu: 1,
+            mul: 3,
+            div: 20,
+            load: 1,
+            store: 1,
+            amo: 4,
+            branch: 1,
+            jump: 1,
+            fp_alu: 2,
+            fp_mul: 4,
+            fp_div: 20,
+            csr: 1,
+            system: 3,
+            branch_mispredict_penalty: 3,
+            load_use_penalty: 1,
+            bht_entries: 512,
+            ras_entries: 8,
+            l2_penalty: 10,
+            memory_penalty: 100,
+        }
+    }
+}
+
+//in-order pipeline approximation:
+//fixed latency per instruction class, bimodal predictor for branches, return address stack for returns,
+//other indirect jumps are always mispredicted.
+pub struct PipelineModel {
+    cfg: PipelineCfg,
+    bht: Vec<u8>,
+    ras: Vec<RegT>,
+    last_load: Option<InsnT>,
+    stats: TimingStats,
+}
+
+impl PipelineModel {
+    pub fn new(cfg: PipelineCfg) -> Result<PipelineModel, String> {
+        if !cfg.bht_entries.is_power_of_two() {
+            return Err(format!("bht_entries {} must be power of 2!", cfg.bht_entries));
+        }
+        if cfg.ras_entries == 0 {
+            return Err("ras_entries must be greater than 0!".to_string());
+        }
+        Ok(PipelineModel {
+            bht: vec![1; cfg.bht_entries],
+            ras: vec![],
+            last_load: None,
+            stats: TimingStats::default(),
+            cfg,
+        })
+    }
+
+    fn latency(&self, class: InsnClass) -> u64 {
+        let latency = match class {
+            InsnClass::Alu => self.cfg.alu,
+            InsnClass::Mul => self.cfg.mul,
+            InsnClass::Div => self.cfg.div,
+            InsnClass::Load | InsnClass::FpLoad => self.cfg.load,
+            InsnClass::Store | InsnClass::FpStore => self.cfg.store,
+            InsnClass::Amo => self.cfg.amo,
+            InsnClass::Branch => self.cfg.branch,
+            InsnClass::Jump => self.cfg.jump,
+            InsnClass::FpAlu => self.cfg.fp_alu,
+            InsnClass::FpMul => self.cfg.fp_mul,
+            InsnClass::FpDiv => self.cfg.fp_div,
+            InsnClass::Csr => self.cfg.csr,
+            InsnClass::System => self.cfg.system,
+        };
+        std::cmp::max(latency, 1)
+    }
+
+    fn level_penalty(&self, level: Option<HitLevel>) -> u64 {
+        match level {
+            Some(HitLevel::L2) => self.cfg.l2_penalty,
+            Some(HitLevel::Memory) => self.cfg.memory_penalty,
+            _ => 0
+        }
+    }
+
+    //return true if mispredicted
+    fn predict_branch(&mut self, insn: &RetireInfo) -> bool {
+        let idx = ((insn.pc >> 1) as usize) & (self.bht.len() - 1);
+        let counter = self.bht[idx];
+        let taken = insn.taken();
+        self.bht[idx] = if taken {
+            std::cmp::min(counter + 1, 3)
+        } else {
+            counter.saturating_sub(1)
+        };
+        (counter >= 2) != taken
+    }
+
+    fn predict_jump(&mut self, insn: &RetireInfo) -> bool {
+        let f = flow(insn.ir, insn.xlen);
+        let mispredict = match f {
+            Flow::Return | Flow::ReturnCall => self.ras.pop() != Some(insn.next_pc),
+            //jal, and jalr without link registers
+            _ => insn.ir & 0x7f == 0x67 || insn.ir & 0x3 != 0x3 && (insn.ir >> 13) & 0x7 == 4
+        };
+        if f == Flow::Call || f == Flow::ReturnCall {
+            if self.ras.len() == self.cfg.ras_entries {
+                self.ras.remove(0);
+            }
+            self.ras.push(insn.pc.wrapping_add(insn.len()))
+        }
+        mispredict
+    }
+}
+
+impl TimingModel for PipelineModel {
+    fn retire(&mut self, insn: &RetireInfo) -> u64 {
+        let class = insn.class();
+        let mut cycles = self.latency(class);
+        if let Some(rd) = self.last_load.take() {
+            if rd != 0 && sources(insn.ir, insn.xlen).iter().any(|s| { *s == Some(rd) }) {
+                self.stats.load_use_stalls += 1;
+                cycles += self.cfg.load_use_penalty;
+            }
+        }
+        if class == InsnClass::Load {
+            self.last_load = load_dest(insn.ir, class)
+        }
+        let mispredict = match class {
+            InsnClass::Branch => {
+                self.stats.branches += 1;
+                self.predict_branch(insn)
+            }
+            InsnClass::Jump => {
+                self.stats.branches += 1;
+                self.predict_jump(insn)
+            }
+            _ => false
+        };
+        if mispredict {
+            self.stats.mispredicts += 1;
+            cycles += self.cfg.branch_mispredict_penalty;
+        }
+        let stall = self.level_penalty(insn.fetch_level) + self.level_penalty(insn.mem_level);
+        self.stats.cache_stall_cycles += stall;
+        cycles += stall;
+        self.stats.instructions += 1;
+        self.stats.cycles += cycles;
+        cycles
+    }
+
+    fn trap(&mut self) -> u64 {
+        self.last_load = None;
+        self.stats.traps += 1;
+        self.stats.cycles += self.cfg.branch_mispredict_penalty;
+        self.cfg.branch_mispredict_penalty
+    }
+
+    fn stats(&self) -> Option<&TimingStats> {
+        Some(&self.stats)
+    }
+}
+
+#[test]
+fn pipeline_test() {
+    assert!(PipelineModel::new(PipelineCfg { ras_entries: 0, ..PipelineCfg::default() }).is_err());
+    assert!(PipelineModel::new(PipelineCfg { bht_entries: 100, ..PipelineCfg::default() }).is_err());
+    let insn = |pc: RegT, ir: InsnT, next_pc: RegT| -> RetireInfo {
+        RetireInfo { pc, ir, next_pc, xlen: XLen::X64, fetch_level: None, mem_level: None }
+    };
+    let mut model = PipelineModel::new(PipelineCfg::default()).unwrap();
+    //ld a0, 0(a1); add a2, a0, a1
+    assert_eq!(model.retire(&insn(0, 0x0005b503, 4)), 1);
+    assert_eq!(model.retire(&insn(4, 0x00b50633, 8)), 2);
+    //fsd fa0, 0(sp) and fadd.d fa0, fa0, fa0 do not read a0
+    model.retire(&insn(0, 0x0005b503, 4));
+    assert_eq!(model.retire(&insn(4, 0x00a13027, 8)), 1);
+    model.retire(&insn(0, 0x0005b503, 4));
+    assert_eq!(model.retire(&insn(4, 0x02a57553, 8)), 2);
+    //fcvt.d.l fa0, a0 does
+    model.retire(&insn(0, 0x0005b503, 4));
+    assert_eq!(model.retire(&insn(4, 0xd2257553, 8)), 3);
+    assert_eq!(model.stats().unwrap().load_use_stalls, 2);
+
+    //beq x0, x0, 8 is weakly not taken at first
+    assert_eq!(model.retire(&insn(0x100, 0x00000463, 0x108)), 4);
+    assert_eq!(model.retire(&insn(0x100, 0x00000463, 0x108)), 1);
+    //jal ra, 0x100; ret hits the return address stack until it is empty
+    assert_eq!(model.retire(&insn(0x200, 0x100000ef, 0x300)), 1);
+    assert_eq!(model.retire(&insn(0x300, 0x00008067, 0x204)), 1);
+    assert_eq!(model.retire(&insn(0x300, 0x00008067, 0x204)), 4);
+    let stats = *model.stats().unwrap();
+    assert_eq!((stats.branches, stats.mispredicts), (5, 2));
+
+    //the oldest return address is dropped when the stack is full
+    let mut model = PipelineModel::new(PipelineCfg { ras_entries: 1, ..PipelineCfg::default() }).unwrap();
+    model.retire(&insn(0x200, 0x100000ef, 0x300));
+    model.retire(&insn(0x300, 0x100000ef, 0x400));
+    assert_eq!(model.retire(&insn(0x400, 0x00008067, 0x304)), 1);
+    assert_eq!(model.retire(&insn(0x304, 0x00008067, 0x204)), 4);
+    assert_eq!(model.trap(), 3);
+}
@@ -96,9 +96,19 @@ pub enum Interrupt {
     UEInt,
     SEInt,
     MEInt,
+    //interrupt id of clic
+    ClicInt(RegT),
 }
 
 impl Interrupt {
+    //default priority order of major interrupts
+    pub const PRIORITY: [Interrupt; 6] = [Interrupt::MEInt, Interrupt::MSInt, Interrupt::MTInt, Interrupt::SEInt, Interrupt::SSInt, Interrupt::STInt];
+
+    //highest priority interrupt in bits of mip
+    pub fn top(pendings: RegT) -> Option<Interrupt> {
+        Interrupt::PRIORITY.iter().find(|i| { (pendings >> i.code()) & 1 == 1 }).copied()
+    }
+
     pub fn code(&self) -> RegT {
         match self {
             Interrupt::USInt => 0,
@@ -110,6 +120,7 @@ impl Interrupt {
             Interrupt::UEInt => 8,
             Interrupt::SEInt => 9,
             Interrupt::MEInt => 11,
+            Interrupt::ClicInt(id) => *id,
         }
     }
     pub fn tval(&self) -> RegT {
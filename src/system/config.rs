@@ -0,0 +1,395 @@
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use std::path::Path;
+use std::fs;
+use crate::system::image::ImageFormat;
+use crate::devices::aclint::{MSWI_SIZE, MTIMER_SIZE, SSWI_SIZE};
+use crate::devices::rtc::RTC_SIZE;
+
+//machine description, refer to examples/machines/*.toml
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MachineCfg {
+    pub name: String,
+    #[serde(default = "default_timer_freq", deserialize_with = "de_usize")]
+    pub timer_freq: usize,
+    //ns since unix epoch, rtcs follow the host clock if absent
+    #[serde(default, deserialize_with = "de_opt_u64")]
+    pub rtc_epoch: Option<u64>,
+    pub harts: Vec<HartCfg>,
+    pub memories: Vec<MemoryCfg>,
+    #[serde(default)]
+    pub devices: Vec<DeviceCfg>,
+    pub boot: BootCfg,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HartCfg {
+    pub isa: String,
+    #[serde(default = "default_count", deserialize_with = "de_usize")]
+    pub count: usize,
+    #[serde(default = "default_freq", deserialize_with = "de_usize")]
+    pub freq: usize,
+}
+
+//the first memory is named main_memory if name is absent
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryCfg {
+    pub name: Option<String>,
+    #[serde(deserialize_with = "de_u64")]
+    pub base: u64,
+    #[serde(deserialize_with = "de_u64")]
+    pub size: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceCfg {
+    #[serde(rename = "type")]
+    pub kind: String,
+    //default to type
+    pub name: Option<String>,
+    #[serde(deserialize_with = "de_u64")]
+    pub base: u64,
+    //default size of the device type if absent
+    #[serde(default, deserialize_with = "de_opt_u64")]
+    pub size: Option<u64>,
+    //interrupt id of uart and rtc on interrupt_parent
+    pub irq: Option<u32>,
+    //name of the plic or aplic irq is wired to, default to the only one
+    pub interrupt_parent: Option<String>,
+    //sources of plic and aplic, default 96
+    pub num_sources: Option<u32>,
+}
+
+//format is one of elf, raw, ihex and srec, detected if absent. addr is required by raw images,
+//images of other formats are moved to it if given
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImageCfg {
+    pub file: String,
+    #[serde(default, deserialize_with = "de_opt_u64")]
+    pub addr: Option<u64>,
+    pub format: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct InitrdCfg {
+    pub file: String,
+    #[serde(deserialize_with = "de_u64")]
+    pub addr: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BootCfg {
+    //entry is required without elf
+    pub elf: Option<String>,
+    //place elf segments at virtual addresses instead of physical ones
+    #[serde(default)]
+    pub elf_virtual_addr: bool,
+    #[serde(default)]
+    pub images: Vec<ImageCfg>,
+    pub initrd: Option<InitrdCfg>,
+    //device tree blob replacing the generated one
+    pub dtb: Option<String>,
+    //generate boot rom with device tree, harts start from it
+    #[serde(default, deserialize_with = "de_opt_u64")]
+    pub boot_rom: Option<u64>,
+    #[serde(default, deserialize_with = "de_opt_u64")]
+    pub entry: Option<u64>,
+    //boot in s-mode with built-in sbi firmware, device tree is placed at this address
+    #[serde(default, deserialize_with = "de_opt_u64")]
+    pub sbi: Option<u64>,
+    pub bootargs: Option<String>,
+    pub stdout_path: Option<String>,
+    //host directory seen as "/" by htif syscalls of riscv-pk
+    pub htif_root: Option<String>,
+}
+
+fn default_timer_freq() -> usize {
+    10000000
+}
+
+fn default_count() -> usize {
+    1
+}
+
+fn default_freq() -> usize {
+    1000000000
+}
+
+//"0x1000", "4096", "4K", "256M", "2G"
+pub fn parse_num(s: &str) -> Result<u64, String> {
+    let s = s.trim().replace('_', "");
+    let (digits, shift) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 10),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 20),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 30),
+        _ => (&s[..], 0)
+    };
+    let v = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse::<u64>()
+    }.map_err(|_| { format!("invalid number \"{}\"!", s) })?;
+    v.checked_mul(1 << shift).ok_or(format!("number \"{}\" overflow!", s))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Num {
+    Int(u64),
+    Str(String),
+}
+
+impl Num {
+    fn value(self) -> Result<u64, String> {
+        match self {
+            Num::Int(v) => Ok(v),
+            Num::Str(s) => parse_num(&s)
+        }
+    }
+}
+
+//json has no hex literal, numbers can also be strings
+fn de_u64<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    Num::deserialize(d)?.value().map_err(D::Error::custom)
+}
+
+fn de_opt_u64<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    match Option::<Num>::deserialize(d)? {
+        Some(n) => n.value().map(Some).map_err(D::Error::custom),
+        None => Ok(None)
+    }
+}
+
+fn de_usize<'de, D: Deserializer<'de>>(d: D) -> Result<usize, D::Error> {
+    de_u64(d).map(|v| { v as usize })
+}
+
+//device types and default size
+pub const DEVICE_TYPES: &[(&str, u64)] = &[("clint", 0x000c0000), ("aclint_mswi", MSWI_SIZE), ("aclint_mtimer", MTIMER_SIZE), ("aclint_sswi", SSWI_SIZE),
+    ("syscon", 0x1000), ("rtc", RTC_SIZE), ("plic", 0x4000000), ("aplic", 0x8000), ("uart", 0x100)];
+//plic has m and s contexts of every hart, aplic delivers to seip of harts
+pub const IRQ_CONTROLLERS: &[&str] = &["plic", "aplic"];
+pub const IRQ_DEVICES: &[&str] = &["uart", "rtc"];
+pub const DEFAULT_NUM_SOURCES: u32 = 96;
+
+impl MachineCfg {
+    pub fn from_toml(s: &str) -> Result<MachineCfg, String> {
+        toml::from_str(s).map_err(|e| { e.to_string() })
+    }
+
+    pub fn from_json(s: &str) -> Result<MachineCfg, String> {
+        serde_json::from_str(s).map_err(|e| { e.to_string() })
+    }
+
+    //format is decided by extension, .toml or .json
+    pub fn from_file(file: &str) -> Result<MachineCfg, String> {
+        let content = fs::read_to_string(file).map_err(|e| { format!("{}: {}", file, e) })?;
+        let result = match Path::new(file).extension().and_then(|e| { e.to_str() }) {
+            Some("toml") => Self::from_toml(&content),
+            Some("json") => Self::from_json(&content),
+            _ => Err("machine description should be .toml or .json!".to_string())
+        };
+        result.map_err(|e| { format!("{}: {}", file, e) })
+    }
+
+    pub fn memory_name(&self, idx: usize) -> String {
+        if let Some(ref name) = self.memories[idx].name {
+            name.clone()
+        } else if idx == 0 {
+            "main_memory".to_string()
+        } else {
+            format!("memory{}", idx)
+        }
+    }
+
+    pub fn device_name(&self, idx: usize) -> String {
+        let d = &self.devices[idx];
+        d.name.clone().unwrap_or_else(|| { d.kind.clone() })
+    }
+
+    pub fn device_size(&self, idx: usize) -> u64 {
+        let d = &self.devices[idx];
+        d.size.unwrap_or_else(|| {
+            DEVICE_TYPES.iter().find(|(t, _)| { *t == d.kind }).map_or(0, |(_, size)| { *size })
+        })
+    }
+
+    pub fn num_sources(&self, idx: usize) -> u32 {
+        self.devices[idx].num_sources.unwrap_or(DEFAULT_NUM_SOURCES)
+    }
+
+    //index of the interrupt controller of device idx
+    pub fn irq_parent(&self, idx: usize) -> Result<usize, String> {
+        let name = self.device_name(idx);
+        let controllers = (0..self.devices.len()).filter(|i| { IRQ_CONTROLLERS.contains(&self.devices[*i].kind.as_str()) }).collect::<Vec<_>>();
+        match self.devices[idx].interrupt_parent {
+            Some(ref parent) => controllers.into_iter().find(|i| { &self.device_name(*i) == parent })
+                .ok_or(format!("interrupt parent \"{}\" of device \"{}\" is not a plic or an aplic!", parent, name)),
+            None if controllers.len() == 1 => Ok(controllers[0]),
+            None if controllers.is_empty() => Err(format!("device \"{}\" has irq but there is no plic or aplic!", name)),
+            None => Err(format!("device \"{}\" should choose one of plics and aplics by interrupt_parent!", name))
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.harts.iter().map(|h| { h.count }).sum::<usize>() == 0 {
+            return Err("at least one hart is required!".to_string());
+        }
+        if self.memories.is_empty() {
+            return Err("at least one memory is required!".to_string());
+        }
+        let mut regions = vec![];
+        for (i, m) in self.memories.iter().enumerate() {
+            regions.push((self.memory_name(i), m.base, m.size))
+        }
+        if !regions.iter().any(|(name, _, _)| { name == "main_memory" }) {
+            return Err("memory \"main_memory\" is required!".to_string());
+        }
+        for (i, d) in self.devices.iter().enumerate() {
+            if !DEVICE_TYPES.iter().any(|(t, _)| { *t == d.kind }) {
+                return Err(format!("unknown device type \"{}\", supported types are {:?}!", d.kind, DEVICE_TYPES.iter().map(|(t, _)| { *t }).collect::<Vec<_>>()));
+            }
+            if d.kind == "clint" && self.device_name(i) != "clint" {
+                return Err("device \"clint\" can not be renamed!".to_string());
+            }
+            if IRQ_CONTROLLERS.contains(&d.kind.as_str()) && !(1..=1023).contains(&self.num_sources(i)) {
+                return Err(format!("num_sources {} of device \"{}\" should be in [1, 1023]!", self.num_sources(i), self.device_name(i)));
+            }
+            if d.num_sources.is_some() && !IRQ_CONTROLLERS.contains(&d.kind.as_str()) {
+                return Err(format!("device \"{}\" is not a plic or an aplic, num_sources is not allowed!", self.device_name(i)));
+            }
+            match d.irq {
+                Some(irq) => {
+                    if !IRQ_DEVICES.contains(&d.kind.as_str()) {
+                        return Err(format!("device \"{}\" can not be wired to irq, irq devices are {:?}!", self.device_name(i), IRQ_DEVICES));
+                    }
+                    let parent = self.irq_parent(i)?;
+                    if irq == 0 || irq > self.num_sources(parent) {
+                        return Err(format!("irq {} of device \"{}\" is not a source of \"{}\"!", irq, self.device_name(i), self.device_name(parent)));
+                    }
+                }
+                None if d.interrupt_parent.is_some() => return Err(format!("interrupt_parent of device \"{}\" requires irq!", self.device_name(i))),
+                None => {}
+            }
+            regions.push((self.device_name(i), d.base, self.device_size(i)))
+        }
+        if self.boot.elf.is_none() && self.boot.entry.is_none() {
+            return Err("boot.entry is required without boot.elf!".to_string());
+        }
+        for image in self.boot.images.iter() {
+            if let Some(ref format) = image.format {
+                ImageFormat::from_name(format)?;
+            }
+        }
+        if self.boot.sbi.is_some() && self.boot.boot_rom.is_some() {
+            return Err("boot.sbi and boot.boot_rom can not be used together!".to_string());
+        }
+        let has_device = |kind: &str| { self.devices.iter().any(|d| { d.kind == kind }) };
+        if !has_device("clint") && !has_device("aclint_mtimer") {
+            return Err("device \"clint\" or \"aclint_mtimer\" is required!".to_string());
+        }
+        //both are backed by msip, mtimecmp and mtime of the system timer
+        if has_device("clint") && (has_device("aclint_mswi") || has_device("aclint_mtimer")) {
+            return Err("device \"clint\" can not be used with \"aclint_mswi\" or \"aclint_mtimer\"!".to_string());
+        }
+        for (i, (name, base, size)) in regions.iter().enumerate() {
+            if *size == 0 {
+                return Err(format!("size of \"{}\" is 0!", name));
+            }
+            if base.checked_add(*size).is_none() {
+                return Err(format!("\"{}\" [{:#x}, +{:#x}) exceeds address space!", name, base, size));
+            }
+            if let Some((other, other_base, other_size)) = regions[..i].iter().find(|(n, b, s)| { n == name || *base < *b + *s && *b < *base + *size }) {
+                return Err(if other == name {
+                    format!("name \"{}\" is used twice!", name)
+                } else {
+                    format!("\"{}\" [{:#x}, +{:#x}) overlaps \"{}\" [{:#x}, +{:#x})!", name, base, size, other, other_base, other_size)
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+const TEST_MACHINE: &str = r#"
+name = "test"
+timer_freq = 10000000
+
+[[harts]]
+isa = "rv64gc"
+count = 2
+
+[[memories]]
+base = 0x80000000
+size = "256M"
+
+[[devices]]
+type = "clint"
+base = 0x02000000
+
+[boot]
+elf = "top_tests/elf/rv64ui-p-add"
+"#;
+
+#[test]
+fn machine_cfg_test() {
+    let cfg = MachineCfg::from_toml(TEST_MACHINE).unwrap();
+    assert_eq!(cfg.timer_freq, 10000000);
+    assert_eq!(cfg.harts[0].count, 2);
+    assert_eq!(cfg.memories[0].size, 0x10000000);
+    assert_eq!(cfg.memory_name(0), "main_memory");
+    assert_eq!(cfg.device_size(0), 0xc0000);
+    cfg.validate().unwrap();
+
+    let json = MachineCfg::from_json(r#"{"name":"test", "harts":[{"isa":"rv32imac"}], "memories":[{"base":"0x80000000", "size":4096}], "devices":[{"type":"clint", "base":"0x80000800"}], "boot":{"elf":"a.elf"}}"#).unwrap();
+    assert!(json.validate().unwrap_err().contains("overlaps"));
+
+    let mut missing = cfg.clone();
+    missing.devices.clear();
+    assert!(missing.validate().unwrap_err().contains("clint"));
+    let mut no_elf = cfg.clone();
+    no_elf.boot.elf = None;
+    assert!(no_elf.validate().unwrap_err().contains("boot.entry"));
+    assert!(MachineCfg::from_toml("name = \"test\"\nunknown = 1").is_err());
+}
+
+#[cfg(test)]
+const TEST_IRQ_DEVICES: &str = r#"
+[[devices]]
+type = "plic"
+base = 0x0c000000
+num_sources = 16
+
+[[devices]]
+type = "uart"
+base = 0x10000000
+irq = 10
+"#;
+
+#[test]
+fn machine_cfg_irq_test() {
+    let cfg = MachineCfg::from_toml(&(TEST_MACHINE.to_string() + TEST_IRQ_DEVICES)).unwrap();
+    cfg.validate().unwrap();
+    assert_eq!(cfg.irq_parent(2), Ok(1));
+    assert_eq!(cfg.device_size(1), 0x4000000);
+    let mut bad = cfg.clone();
+    bad.devices[2].irq = Some(17);
+    assert!(bad.validate().unwrap_err().contains("not a source"));
+    bad.devices[2].irq = Some(1);
+    bad.devices[2].interrupt_parent = Some("clint".to_string());
+    assert!(bad.validate().unwrap_err().contains("not a plic"));
+    let mut no_controller = cfg.clone();
+    no_controller.devices.remove(1);
+    assert!(no_controller.validate().unwrap_err().contains("no plic"));
+    let mut clint_irq = cfg.clone();
+    clint_irq.devices[0].irq = Some(1);
+    assert!(clint_irq.validate().unwrap_err().contains("can not be wired"));
+}
@@ -1,22 +1,78 @@
 extern crate xmas_elf;
 
 use xmas_elf::ElfFile;
-use xmas_elf::program::SegmentData;
+use xmas_elf::program::{SegmentData, Type as SegmentType};
 use xmas_elf::header;
-use std::{fs, io};
+use std::fs;
 use xmas_elf::sections::SectionData::{SymbolTable64, SymbolTable32};
-use xmas_elf::symbol_table::Entry;
+use xmas_elf::symbol_table::{Entry, Type};
+use terminus_global::XLen;
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+//function symbols sorted by address
+#[derive(Default)]
+pub struct SymbolTable(Vec<Symbol>);
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> SymbolTable {
+        symbols.sort_by_key(|s| { s.addr });
+        SymbolTable(symbols)
+    }
+
+    pub fn find(&self, addr: u64) -> Option<&Symbol> {
+        let idx = match self.0.binary_search_by_key(&addr, |s| { s.addr }) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1
+        };
+        let s = &self.0[idx];
+        if s.size == 0 || addr < s.addr + s.size {
+            Some(s)
+        } else {
+            None
+        }
+    }
+
+    pub fn resolve(&self, addr: u64) -> String {
+        if let Some(s) = self.find(addr) {
+            s.name.clone()
+        } else {
+            format!("{:#x}", addr)
+        }
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.0
+    }
+}
+
+//end of a segment placed at addr, malformed sizes may wrap the address space
+fn segment_end(addr: u64, mem_size: u64) -> Result<u64, String> {
+    addr.checked_add(mem_size).ok_or(format!("segment at {:#x} of size {:#x} exceeds address space!", addr, mem_size))
+}
 
 pub struct ElfLoader {
     content: Box<[u8]>
 }
 
 impl ElfLoader {
-    pub fn new(file: &str) -> io::Result<ElfLoader> {
-        let content = fs::read(file)?.into_boxed_slice();
-        Ok(ElfLoader {
-            content
-        })
+    pub fn new(file: &str) -> Result<ElfLoader, String> {
+        let content = fs::read(file).map_err(|e| { format!("{}: {}", file, e) })?;
+        Self::from_bytes(content)
+    }
+
+    pub fn from_bytes(content: Vec<u8>) -> Result<ElfLoader, String> {
+        let loader = ElfLoader {
+            content: content.into_boxed_slice()
+        };
+        loader.check_header()?;
+        Ok(loader)
     }
 
     fn elf(&self) -> Result<ElfFile<'_>, String> {
@@ -72,31 +128,158 @@ impl ElfLoader {
                     }
                 }
             }
-            Ok(Some((s.address(), tohost.unwrap(), fromhost)))
+            Ok(Some((s.address(), tohost.ok_or("no tohost symbol in .htif!".to_string())?, fromhost)))
         } else {
             Ok(None)
         }
     }
 
+    pub fn symbols(&self) -> Result<SymbolTable, String> {
+        let elf = self.elf()?;
+        let mut symbols = vec![];
+        fn collect<'a, E: Entry>(elf: &ElfFile<'a>, table: &[E], symbols: &mut Vec<Symbol>) {
+            for e in table {
+                if let Ok(Type::Func) = e.get_type() {
+                    if let Ok(name) = e.get_name(elf) {
+                        symbols.push(Symbol { name: name.to_string(), addr: e.value(), size: e.size() })
+                    }
+                }
+            }
+        }
+        if let Some(syn) = elf.find_section_by_name(".symtab") {
+            if let Ok(SymbolTable64(table)) = syn.get_data(&elf) {
+                collect(&elf, table, &mut symbols)
+            } else if let Ok(SymbolTable32(table)) = syn.get_data(&elf) {
+                collect(&elf, table, &mut symbols)
+            }
+        }
+        Ok(SymbolTable::new(symbols))
+    }
+
     pub fn entry_point(&self) -> Result<u64, String> {
         Ok(self.elf()?.header.pt2.entry_point())
     }
 
-    pub fn load<F: Fn(u64, &[u8]) -> Result<(), String>>(&self, f: F) -> Result<(), String> {
-        self.check_header()?;
+    //address, entry size and number of program headers in loaded image, for auxv of user-mode programs
+    pub fn program_headers(&self) -> Result<(u64, u64, u64), String> {
         let elf = self.elf()?;
-        let result = elf.program_iter().map(|p| {
-            let data = match p.get_data(&elf)? {
-                SegmentData::Undefined(d) => Ok(d),
-                _ => Err("Only support Undefined SectionData for now!")
-            };
-            f(p.virtual_addr(), data?)
-        });
-        for r in result {
-            if let Err(e) = r {
-                return Err(e);
+        let phoff = elf.header.pt2.ph_offset();
+        let addr = elf.program_iter().find_map(|p| {
+            match p.get_type() {
+                Ok(SegmentType::Phdr) => Some(p.virtual_addr()),
+                Ok(SegmentType::Load) if p.offset() <= phoff && phoff - p.offset() < p.file_size() => Some(p.virtual_addr() + phoff - p.offset()),
+                _ => None
             }
+        }).ok_or("program headers are not loaded!".to_string())?;
+        Ok((addr, elf.header.pt2.ph_entry_size() as u64, elf.header.pt2.ph_count() as u64))
+    }
+
+    //entry translated by the PT_LOAD segment containing it
+    pub fn physical_entry_point(&self) -> Result<u64, String> {
+        let elf = self.elf()?;
+        let entry = elf.header.pt2.entry_point();
+        Ok(elf.program_iter().find(|p| {
+            matches!(p.get_type(), Ok(SegmentType::Load)) && p.virtual_addr() <= entry && entry - p.virtual_addr() < p.mem_size()
+        }).map_or(entry, |p| { entry - p.virtual_addr() + p.physical_addr() }))
+    }
+
+    pub fn xlen(&self) -> Result<XLen, String> {
+        match self.elf()?.header.pt1.class() {
+            header::Class::ThirtyTwo => Ok(XLen::X32),
+            header::Class::SixtyFour => Ok(XLen::X64),
+            c => Err(format!("Invalid class {:?}!", c))
         }
-        Ok(())
     }
-}
\ No newline at end of file
+
+    //end of PT_LOAD segments at physical or virtual addresses
+    pub fn image_end(&self, virtual_addr: bool) -> Result<u64, String> {
+        let elf = self.elf()?;
+        let mut end = 0;
+        for p in elf.program_iter().filter(|p| { matches!(p.get_type(), Ok(SegmentType::Load)) }) {
+            let addr = if virtual_addr { p.virtual_addr() } else { p.physical_addr() };
+            end = std::cmp::max(end, segment_end(addr, p.mem_size())?)
+        }
+        Ok(end)
+    }
+
+    //place PT_LOAD segments at physical or virtual addresses, bytes beyond file size are zero-filled.
+    //return end of the image
+    pub fn load<F: Fn(u64, &[u8]) -> Result<(), String>>(&self, virtual_addr: bool, f: F) -> Result<u64, String> {
+        const CHUNK: u64 = 0x10_0000;
+        let elf = self.elf()?;
+        let mut end = 0;
+        for p in elf.program_iter() {
+            if let Ok(SegmentType::Load) = p.get_type() {
+                let addr = if virtual_addr { p.virtual_addr() } else { p.physical_addr() };
+                let seg_end = segment_end(addr, p.mem_size())?;
+                if p.file_size() > 0 {
+                    match p.get_data(&elf)? {
+                        SegmentData::Undefined(data) => f(addr, data)?,
+                        _ => return Err(format!("unexpected data of segment at {:#x}!", addr))
+                    }
+                }
+                let zeros = vec![0; std::cmp::min(p.mem_size().saturating_sub(p.file_size()), CHUNK) as usize];
+                let mut offset = p.file_size();
+                while offset < p.mem_size() {
+                    let n = std::cmp::min(p.mem_size() - offset, CHUNK);
+                    f(addr + offset, &zeros[..n as usize])?;
+                    offset += n
+                }
+                end = std::cmp::max(end, seg_end)
+            }
+        }
+        Ok(end)
+    }
+}
+#[test]
+fn elf_load_test() {
+    let (vaddr, paddr, data_off) = (0xffff_ffff_8000_0000u64, 0x8000_0000u64, 176u64);
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    //elf header: type, machine, version, entry, phoff, shoff, flags, ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+    elf.extend(2u16.to_le_bytes().iter().chain(243u16.to_le_bytes().iter()).chain(1u32.to_le_bytes().iter()));
+    elf.extend(vaddr.to_le_bytes().iter().chain(64u64.to_le_bytes().iter()).chain(0u64.to_le_bytes().iter()).chain(0u32.to_le_bytes().iter()));
+    elf.extend([64u16, 56, 2, 64, 0, 0].iter().flat_map(|v| { v.to_le_bytes().to_vec() }));
+    //PT_NOTE is not loaded
+    elf.extend(4u32.to_le_bytes().iter().chain(4u32.to_le_bytes().iter()));
+    elf.extend([data_off, 0, 0, 8, 8, 4].iter().flat_map(|v: &u64| { v.to_le_bytes().to_vec() }));
+    //PT_LOAD with 8 bytes bss
+    elf.extend(1u32.to_le_bytes().iter().chain(7u32.to_le_bytes().iter()));
+    elf.extend([data_off, vaddr, paddr, 8, 0x10, 0x1000].iter().flat_map(|v: &u64| { v.to_le_bytes().to_vec() }));
+    elf.extend([0xffu8; 8].iter());
+
+    let loader = ElfLoader::from_bytes(elf.clone()).unwrap();
+    assert_eq!(loader.xlen().unwrap(), XLen::X64);
+    assert_eq!(loader.physical_entry_point().unwrap(), paddr);
+    let loaded = std::cell::RefCell::new(vec![]);
+    let end = loader.load(false, |addr, data| {
+        loaded.borrow_mut().push((addr, data.to_vec()));
+        Ok(())
+    }).unwrap();
+    assert_eq!(end, paddr + 0x10);
+    assert_eq!(*loaded.borrow(), vec![(paddr, vec![0xff; 8]), (paddr + 8, vec![0; 8])]);
+    assert_eq!(loader.load(true, |_, _| { Ok(()) }).unwrap(), vaddr + 0x10);
+
+    //p_memsz of PT_LOAD wraps the virtual address
+    elf[160..168].copy_from_slice(&0x8000_0000u64.to_le_bytes());
+    let loader = ElfLoader::from_bytes(elf).unwrap();
+    assert!(loader.image_end(true).unwrap_err().contains("exceeds"));
+    assert!(loader.load(true, |_, _| { panic!("nothing should be loaded") }).unwrap_err().contains("exceeds"));
+}
+
+#[test]
+fn symbol_table_test() {
+    let symbols = SymbolTable::new(vec![
+        Symbol { name: "b".to_string(), addr: 0x2000, size: 0x10 },
+        Symbol { name: "a".to_string(), addr: 0x1000, size: 0x100 },
+        //size unknown, covers everything up to the next symbol
+        Symbol { name: "c".to_string(), addr: 0x3000, size: 0 },
+    ]);
+    assert!(symbols.find(0xfff).is_none());
+    assert_eq!(symbols.find(0x1000).unwrap().name, "a");
+    assert_eq!(symbols.find(0x10ff).unwrap().name, "a");
+    assert!(symbols.find(0x1100).is_none());
+    assert_eq!(symbols.find(0x200f).unwrap().name, "b");
+    assert_eq!(symbols.find(0x8000).unwrap().name, "c");
+    assert_eq!(symbols.resolve(0x2010), "0x2010");
+    assert_eq!(symbols.resolve(0x2008), "b");
+}
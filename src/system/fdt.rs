@@ -1,13 +1,15 @@
 use std::fmt::{Display, Formatter};
 use std::fmt;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::iter::Peekable;
+use std::str::Chars;
 const FDT_MAGIC: u32 = 0xd00dfeed;
 const FDT_VERSION: u32 = 17;
 
 const FDT_BEGIN_NODE: u32 = 1;
 const FDT_END_NODE: u32 = 2;
 const FDT_PROP: u32 = 3;
-#[allow(dead_code)]
 const FDT_NOP: u32 = 4;
 const FDT_END: u32 = 9;
 
@@ -108,10 +110,13 @@ struct FdtRsvEntry {
     size: u64,
 }
 
+#[derive(Clone)]
 enum FdtPropValue {
     Null,
     Str(Vec<String>),
     U32(Vec<u32>),
+    //parsed values whose type is unknown
+    Bytes(Vec<u8>),
 }
 
 impl FdtPropValue {
@@ -139,6 +144,7 @@ impl FdtPropValue {
                 }
                 res
             }
+            FdtPropValue::Bytes(value) => value.clone()
         }
     }
 }
@@ -153,10 +159,12 @@ impl Display for FdtPropValue {
                 write!(f, "{}", values.iter().map(|v| { format!("{:#x}", v) }).collect::<Vec<String>>().join(" "))?;
                 write!(f, ">")
             }
+            FdtPropValue::Bytes(values) => write!(f, "= [{}]", values.iter().map(|v| { format!("{:02x}", v) }).collect::<Vec<String>>().join(" "))
         }
     }
 }
 
+#[derive(Clone)]
 pub struct FdtProp {
     indent: usize,
     name: String,
@@ -200,6 +208,37 @@ impl FdtProp {
         }
     }
 
+    pub fn bytes_prop(name: &str, value: Vec<u8>) -> FdtProp {
+        FdtProp {
+            indent: 0,
+            name: name.to_string(),
+            value: FdtPropValue::Bytes(value),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    //raw value as in the blob
+    pub fn data(&self) -> Vec<u8> {
+        self.value.pack()
+    }
+
+    pub fn u32s(&self) -> Vec<u32> {
+        self.data().chunks_exact(4).map(|c| { u32::from_be_bytes([c[0], c[1], c[2], c[3]]) }).collect()
+    }
+
+    pub fn strs(&self) -> Vec<String> {
+        let data = self.data();
+        let mut strs = data.split(|c| { *c == 0 }).map(|s| { String::from_utf8_lossy(s).to_string() }).collect::<Vec<String>>();
+        //string list is terminated by nul, and maybe padded
+        while matches!(strs.last(), Some(s) if s.is_empty()) {
+            strs.pop();
+        }
+        strs
+    }
+
     fn pack(&self, state: &mut FdtState) {
         state.struct_buffer.append(&mut FDT_PROP.to_be_bytes().to_vec());
         let mut data = self.value.pack();
@@ -216,6 +255,7 @@ impl Display for FdtProp {
     }
 }
 
+#[derive(Clone)]
 pub struct FdtNode {
     indent: usize,
     name: String,
@@ -257,6 +297,48 @@ impl FdtNode {
         self.nodes.push(Box::new(node))
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn props(&self) -> impl Iterator<Item=&FdtProp> {
+        self.props.iter()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item=&FdtNode> {
+        self.nodes.iter().map(|n| { n.as_ref() })
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&FdtProp> {
+        self.props.iter().find(|p| { p.name == name })
+    }
+
+    //child by full name, or by name without unit address if it is unique
+    pub fn node(&self, name: &str) -> Option<&FdtNode> {
+        self.nodes().find(|n| { n.name == name }).or_else(|| {
+            let mut iter = self.nodes().filter(|n| { n.name.split('@').next() == Some(name) });
+            match (iter.next(), iter.next()) {
+                (Some(n), None) => Some(n),
+                _ => None
+            }
+        })
+    }
+
+    pub fn node_mut(&mut self, name: &str) -> Option<&mut FdtNode> {
+        self.nodes.iter_mut().find(|n| { n.name == name }).map(|n| { n.as_mut() })
+    }
+
+    //replace the prop of the same name
+    pub fn set_prop(&mut self, prop: FdtProp) {
+        self.props.retain(|p| { p.name != prop.name });
+        self.add_prop(prop)
+    }
+
+    //"/cpus/cpu@0"
+    pub fn find(&self, path: &str) -> Option<&FdtNode> {
+        path.split('/').filter(|n| { !n.is_empty() }).try_fold(self, |node, name| { node.node(name) })
+    }
+
     fn pack_name(&self) -> Vec<u8> {
         let mut res = self.name.as_bytes().to_vec();
         res.push(0);
@@ -296,6 +378,597 @@ impl Display for FdtNode {
     }
 }
 
+fn align4(v: usize) -> usize {
+    (v + 3) & !3
+}
+
+//parse a device tree blob, values of props are kept as bytes. memory reservations are ignored.
+pub fn parse(dtb: &[u8]) -> Result<FdtNode, String> {
+    let word = |off: usize| -> Result<u32, String> {
+        dtb.get(off..off + 4).map(|b| { u32::from_be_bytes([b[0], b[1], b[2], b[3]]) }).ok_or(format!("offset {:#x} exceeds device tree blob!", off))
+    };
+    let cstr = |off: usize| -> Result<(String, usize), String> {
+        let len = dtb.get(off..).and_then(|s| { s.iter().position(|c| { *c == 0 }) }).ok_or(format!("unterminated string at {:#x}!", off))?;
+        Ok((String::from_utf8_lossy(&dtb[off..off + len]).to_string(), off + len + 1))
+    };
+    if word(0)? != FDT_MAGIC {
+        return Err("invalid device tree blob!".to_string());
+    }
+    if word(4)? as usize > dtb.len() {
+        return Err(format!("device tree blob is truncated, {:#x} bytes are expected!", word(4)?));
+    }
+    if word(20)? < 16 {
+        return Err(format!("device tree blob version {} is not supported!", word(20)?));
+    }
+    let off_dt_strings = word(12)? as usize;
+    let mut pos = word(8)? as usize;
+    let mut stack: Vec<FdtNode> = vec![];
+    let mut root = None;
+    loop {
+        let token = word(pos)?;
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let (name, end) = cstr(pos)?;
+                pos = align4(end);
+                stack.push(FdtNode::new(&name))
+            }
+            FDT_END_NODE => {
+                let node = stack.pop().ok_or(format!("unexpected node end at {:#x}!", pos - 4))?;
+                if let Some(parent) = stack.last_mut() {
+                    parent.add_node(node)
+                } else {
+                    root = Some(node)
+                }
+            }
+            FDT_PROP => {
+                let len = word(pos)? as usize;
+                let (name, _) = cstr(off_dt_strings + word(pos + 4)? as usize)?;
+                pos += 8;
+                let data = dtb.get(pos..pos + len).ok_or(format!("prop \"{}\" exceeds device tree blob!", name))?.to_vec();
+                pos = align4(pos + len);
+                stack.last_mut().ok_or(format!("prop \"{}\" is out of node!", name))?.add_prop(FdtProp::bytes_prop(&name, data))
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            t => return Err(format!("invalid token {:#x} at {:#x}!", t, pos - 4))
+        }
+    }
+    if !stack.is_empty() {
+        return Err(format!("node \"{}\" is not closed!", stack.last().unwrap().name));
+    }
+    root.ok_or("no root node!".to_string())
+}
+
+//dts source is supposed to be preprocessed, expressions, /bits/ and /include/ are not supported.
+//phandles are allocated for referenced nodes without one, /memreserve/ is ignored.
+pub fn parse_dts(text: &str) -> Result<FdtNode, String> {
+    let toks = DtsLexer::new(text).tokens()?;
+    DtsParser { toks, pos: 0 }.parse()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum DtsTok {
+    Word(String),
+    Str(String),
+    //&label or &{/path}
+    Ref(String),
+    //"/dts-v1/", "/delete-node/"...
+    Keyword(String),
+    Punct(char),
+}
+
+struct DtsLexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> DtsLexer<'a> {
+    fn new(text: &'a str) -> DtsLexer<'a> {
+        DtsLexer {
+            chars: text.chars().peekable(),
+            line: 1,
+        }
+    }
+
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || ",._+*#?@-".contains(c)
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1
+        }
+        c
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> String {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !f(c) {
+                break;
+            }
+            s.push(c);
+            self.next_char();
+        }
+        s
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        loop {
+            match self.next_char() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next_char() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('0') => s.push('\0'),
+                    Some('x') => {
+                        let hex = (0..2).filter_map(|_| {
+                            if matches!(self.chars.peek(), Some(c) if c.is_ascii_hexdigit()) { self.next_char() } else { None }
+                        }).collect::<String>();
+                        s.push(u8::from_str_radix(&hex, 16).map_err(|_| { format!("line {}: invalid escape!", self.line) })? as char)
+                    }
+                    Some(c) => s.push(c),
+                    None => break
+                },
+                Some(c) => s.push(c),
+                None => break
+            }
+        }
+        Err(format!("line {}: unterminated string!", self.line))
+    }
+
+    fn tokens(mut self) -> Result<Vec<(DtsTok, usize)>, String> {
+        let mut toks = vec![];
+        while let Some(&c) = self.chars.peek() {
+            let line = self.line;
+            if c.is_whitespace() {
+                self.next_char();
+                continue;
+            }
+            self.next_char();
+            let tok = match c {
+                '/' if self.chars.peek() == Some(&'/') => {
+                    self.take_while(|c| { c != '\n' });
+                    continue;
+                }
+                '/' if self.chars.peek() == Some(&'*') => {
+                    self.next_char();
+                    let mut last = ' ';
+                    loop {
+                        match self.next_char() {
+                            Some('/') if last == '*' => break,
+                            Some(c) => last = c,
+                            None => return Err(format!("line {}: unterminated comment!", line))
+                        }
+                    }
+                    continue;
+                }
+                '/' if matches!(self.chars.peek(), Some(c) if c.is_ascii_alphabetic()) => {
+                    let word = self.take_while(|c| { c.is_ascii_alphanumeric() || c == '-' });
+                    if self.next_char() != Some('/') {
+                        return Err(format!("line {}: invalid keyword \"/{}\"!", line, word));
+                    }
+                    DtsTok::Keyword(word)
+                }
+                '"' => DtsTok::Str(self.string()?),
+                '&' if self.chars.peek() == Some(&'{') => {
+                    self.next_char();
+                    let path = self.take_while(|c| { c != '}' });
+                    self.next_char();
+                    DtsTok::Ref(path)
+                }
+                '&' => DtsTok::Ref(self.take_while(|c| { c.is_ascii_alphanumeric() || c == '_' })),
+                '{' | '}' | ';' | '=' | '<' | '>' | '[' | ']' | ',' | ':' | '/' | '(' => DtsTok::Punct(c),
+                c if Self::is_name_char(c) => {
+                    let mut word = c.to_string();
+                    word.push_str(&self.take_while(Self::is_name_char));
+                    DtsTok::Word(word)
+                }
+                c => return Err(format!("line {}: unexpected '{}'!", line, c))
+            };
+            toks.push((tok, line))
+        }
+        Ok(toks)
+    }
+}
+
+enum DtsCell {
+    Num(u32),
+    Ref(String),
+}
+
+enum DtsPiece {
+    Str(String),
+    Cells(Vec<DtsCell>),
+    Bytes(Vec<u8>),
+    //path of the referenced node
+    PathRef(String),
+}
+
+//node statements are kept in order, so that nodes defined more than once are merged like dtc
+enum DtsItem {
+    Prop(String, Vec<DtsPiece>),
+    Node(DtsNode),
+    DeleteProp(String),
+    DeleteNode(String),
+}
+
+struct DtsNode {
+    name: String,
+    labels: Vec<String>,
+    items: Vec<DtsItem>,
+}
+
+impl DtsNode {
+    fn new(name: &str) -> DtsNode {
+        DtsNode {
+            name: name.to_string(),
+            labels: vec![],
+            items: vec![],
+        }
+    }
+
+    fn child_mut(&mut self, name: &str) -> Option<&mut DtsNode> {
+        self.items.iter_mut().find_map(|item| {
+            match item {
+                DtsItem::Node(n) if n.name == name => Some(n),
+                _ => None
+            }
+        })
+    }
+
+    fn merge(&mut self, other: DtsNode) {
+        self.labels.extend(other.labels);
+        for item in other.items {
+            match item {
+                DtsItem::Prop(name, value) => {
+                    self.items.retain(|i| { !matches!(i, DtsItem::Prop(n, _) if *n == name) });
+                    self.items.push(DtsItem::Prop(name, value))
+                }
+                DtsItem::Node(node) => {
+                    if let Some(n) = self.child_mut(&node.name) {
+                        n.merge(node)
+                    } else {
+                        self.items.push(DtsItem::Node(node))
+                    }
+                }
+                DtsItem::DeleteProp(name) => self.items.retain(|i| { !matches!(i, DtsItem::Prop(n, _) if *n == name) }),
+                DtsItem::DeleteNode(name) => self.items.retain(|i| {
+                    !matches!(i, DtsItem::Node(n) if n.name == name || n.name.split('@').next() == Some(name.as_str()))
+                }),
+            }
+        }
+    }
+
+    fn nodes(&self) -> impl Iterator<Item=&DtsNode> {
+        self.items.iter().filter_map(|item| {
+            if let DtsItem::Node(n) = item { Some(n) } else { None }
+        })
+    }
+
+    fn props(&self) -> impl Iterator<Item=(&String, &Vec<DtsPiece>)> {
+        self.items.iter().filter_map(|item| {
+            if let DtsItem::Prop(name, value) = item { Some((name, value)) } else { None }
+        })
+    }
+
+    //label -> path of all nodes
+    fn collect_labels(&self, path: &str, labels: &mut HashMap<String, String>) {
+        for label in self.labels.iter() {
+            labels.insert(label.clone(), if path.is_empty() { "/".to_string() } else { path.to_string() });
+        }
+        for n in self.nodes() {
+            n.collect_labels(&format!("{}/{}", path, n.name), labels)
+        }
+    }
+
+    fn collect_phandles(&self, path: &str, phandles: &mut HashMap<String, u32>, refs: &mut Vec<String>) {
+        for (name, value) in self.props() {
+            for piece in value.iter() {
+                match piece {
+                    DtsPiece::Cells(cells) if name == "phandle" || name == "linux,phandle" => {
+                        if let Some(DtsCell::Num(v)) = cells.first() {
+                            phandles.insert(if path.is_empty() { "/".to_string() } else { path.to_string() }, *v);
+                        }
+                    }
+                    DtsPiece::Cells(cells) => {
+                        refs.extend(cells.iter().filter_map(|c| { if let DtsCell::Ref(r) = c { Some(r.clone()) } else { None } }))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for n in self.nodes() {
+            n.collect_phandles(&format!("{}/{}", path, n.name), phandles, refs)
+        }
+    }
+
+    fn to_fdt(&self, path: &str, resolve: &dyn Fn(&str) -> Result<String, String>, phandles: &HashMap<String, u32>) -> Result<FdtNode, String> {
+        let mut node = FdtNode::new(&self.name);
+        let node_path = if path.is_empty() { "/".to_string() } else { path.to_string() };
+        for (name, value) in self.props() {
+            let prop = if value.is_empty() {
+                FdtProp::null_prop(name)
+            } else if value.iter().all(|p| { matches!(p, DtsPiece::Str(_) | DtsPiece::PathRef(_)) }) {
+                let strs = value.iter().map(|p| {
+                    match p {
+                        DtsPiece::Str(s) => Ok(s.clone()),
+                        DtsPiece::PathRef(r) => resolve(r),
+                        _ => unreachable!()
+                    }
+                }).collect::<Result<Vec<String>, String>>()?;
+                FdtProp::str_prop(name, strs.iter().map(|s| { s.as_str() }).collect())
+            } else {
+                let mut cells_only = true;
+                let mut data = vec![];
+                for piece in value.iter() {
+                    match piece {
+                        DtsPiece::Str(s) => {
+                            cells_only = false;
+                            data.extend_from_slice(s.as_bytes());
+                            data.push(0)
+                        }
+                        DtsPiece::PathRef(r) => {
+                            cells_only = false;
+                            data.extend_from_slice(resolve(r)?.as_bytes());
+                            data.push(0)
+                        }
+                        DtsPiece::Bytes(b) => {
+                            cells_only = false;
+                            data.extend_from_slice(b)
+                        }
+                        DtsPiece::Cells(cells) => for c in cells.iter() {
+                            let v = match c {
+                                DtsCell::Num(v) => *v,
+                                DtsCell::Ref(r) => phandles[&resolve(r)?]
+                            };
+                            data.extend_from_slice(&v.to_be_bytes())
+                        }
+                    }
+                }
+                if cells_only {
+                    FdtProp::u32_prop(name, data.chunks_exact(4).map(|c| { u32::from_be_bytes([c[0], c[1], c[2], c[3]]) }).collect())
+                } else {
+                    FdtProp::bytes_prop(name, data)
+                }
+            };
+            node.add_prop(prop)
+        }
+        if let Some(phandle) = phandles.get(&node_path) {
+            if node.prop("phandle").is_none() {
+                node.add_prop(FdtProp::u32_prop("phandle", vec![*phandle]))
+            }
+        }
+        for n in self.nodes() {
+            node.add_node(n.to_fdt(&format!("{}/{}", path, n.name), resolve, phandles)?)
+        }
+        Ok(node)
+    }
+}
+
+struct DtsParser {
+    toks: Vec<(DtsTok, usize)>,
+    pos: usize,
+}
+
+impl DtsParser {
+    fn peek(&self) -> Option<&DtsTok> {
+        self.toks.get(self.pos).map(|(t, _)| { t })
+    }
+
+    fn line(&self) -> usize {
+        self.toks.get(self.pos).or_else(|| { self.toks.last() }).map_or(0, |(_, l)| { *l })
+    }
+
+    fn next(&mut self) -> Result<DtsTok, String> {
+        let tok = self.toks.get(self.pos).map(|(t, _)| { t.clone() }).ok_or(format!("line {}: unexpected end of file!", self.line()))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        let line = self.line();
+        match self.next()? {
+            DtsTok::Punct(p) if p == c => Ok(()),
+            t => Err(format!("line {}: '{}' is expected, but found {:?}!", line, c, t))
+        }
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        let line = self.line();
+        match self.next()? {
+            DtsTok::Word(w) => Ok(w),
+            t => Err(format!("line {}: name is expected, but found {:?}!", line, t))
+        }
+    }
+
+    fn number(&self, word: &str) -> Result<u64, String> {
+        let s = word.trim_end_matches(|c| { c == 'U' || c == 'L' || c == 'u' || c == 'l' });
+        let result = if s.starts_with("0x") || s.starts_with("0X") {
+            u64::from_str_radix(&s[2..], 16)
+        } else if s.len() > 1 && s.starts_with('0') {
+            u64::from_str_radix(&s[1..], 8)
+        } else {
+            s.parse::<u64>()
+        };
+        result.map_err(|_| { format!("line {}: invalid number \"{}\"!", self.line(), word) })
+    }
+
+    fn parse(mut self) -> Result<FdtNode, String> {
+        let mut root = DtsNode::new("");
+        //nodes referred by label are merged after all nodes are known
+        let mut refs = vec![];
+        while let Some(tok) = self.peek().cloned() {
+            match tok {
+                DtsTok::Keyword(ref k) if k == "dts-v1" || k == "plugin" => {
+                    self.next()?;
+                    self.expect(';')?
+                }
+                DtsTok::Keyword(ref k) if k == "memreserve" => {
+                    while self.next()? != DtsTok::Punct(';') {}
+                }
+                DtsTok::Punct('/') => {
+                    self.next()?;
+                    let node = self.node_body("")?;
+                    root.merge(node)
+                }
+                DtsTok::Ref(r) => {
+                    self.next()?;
+                    let node = self.node_body("")?;
+                    refs.push((r, node))
+                }
+                DtsTok::Word(ref w) if w.starts_with('#') => return Err(format!("line {}: \"{}\" should be handled by preprocessor!", self.line(), w)),
+                t => return Err(format!("line {}: unexpected {:?}!", self.line(), t))
+            }
+        }
+        for (r, node) in refs {
+            let mut labels = HashMap::new();
+            root.collect_labels("", &mut labels);
+            let path = if r.starts_with('/') { r.clone() } else { labels.get(&r).cloned().ok_or(format!("label \"{}\" is not defined!", r))? };
+            let names = path.split('/').filter(|n| { !n.is_empty() }).map(|n| { n.to_string() }).collect::<Vec<String>>();
+            let mut target = &mut root;
+            for name in names.iter() {
+                target = target.child_mut(name).ok_or(format!("node \"{}\" is not defined!", path))?
+            }
+            target.merge(node)
+        }
+        let mut labels = HashMap::new();
+        root.collect_labels("", &mut labels);
+        let resolve = |r: &str| -> Result<String, String> {
+            if r.starts_with('/') {
+                Ok(r.to_string())
+            } else {
+                labels.get(r).cloned().ok_or(format!("label \"{}\" is not defined!", r))
+            }
+        };
+        let mut phandles = HashMap::new();
+        let mut refs = vec![];
+        root.collect_phandles("", &mut phandles, &mut refs);
+        let mut next_phandle = phandles.values().max().map_or(1, |v| { v + 1 });
+        for r in refs {
+            let path = resolve(&r)?;
+            if let Entry::Vacant(e) = phandles.entry(path) {
+                e.insert(next_phandle);
+                next_phandle += 1
+            }
+        }
+        root.to_fdt("", &resolve, &phandles)
+    }
+
+    //"{ ... };" with the name already consumed
+    fn node_body(&mut self, name: &str) -> Result<DtsNode, String> {
+        let mut node = DtsNode::new(name);
+        self.expect('{')?;
+        loop {
+            let mut labels = vec![];
+            let line = self.line();
+            let name = match self.next()? {
+                DtsTok::Punct('}') => break,
+                DtsTok::Keyword(ref k) if k == "delete-node" => {
+                    let name = self.word()?;
+                    self.expect(';')?;
+                    node.items.push(DtsItem::DeleteNode(name));
+                    continue;
+                }
+                DtsTok::Keyword(ref k) if k == "delete-property" => {
+                    let name = self.word()?;
+                    self.expect(';')?;
+                    node.items.push(DtsItem::DeleteProp(name));
+                    continue;
+                }
+                DtsTok::Word(mut w) => {
+                    while self.peek() == Some(&DtsTok::Punct(':')) {
+                        self.next()?;
+                        labels.push(w);
+                        w = self.word()?
+                    }
+                    w
+                }
+                t => return Err(format!("line {}: unexpected {:?}!", line, t))
+            };
+            match self.peek() {
+                Some(DtsTok::Punct('{')) => {
+                    let mut child = self.node_body(&name)?;
+                    child.labels = labels;
+                    node.items.push(DtsItem::Node(child))
+                }
+                Some(DtsTok::Punct('=')) => {
+                    self.next()?;
+                    let value = self.prop_value()?;
+                    node.items.push(DtsItem::Prop(name, value))
+                }
+                Some(DtsTok::Punct(';')) => {
+                    self.next()?;
+                    node.items.push(DtsItem::Prop(name, vec![]))
+                }
+                _ => return Err(format!("line {}: '{{', '=' or ';' is expected after \"{}\"!", self.line(), name))
+            }
+        }
+        self.expect(';')?;
+        Ok(node)
+    }
+
+    //pieces separated by ',' and terminated by ';'
+    fn prop_value(&mut self) -> Result<Vec<DtsPiece>, String> {
+        let mut pieces = vec![];
+        loop {
+            let line = self.line();
+            let piece = match self.next()? {
+                DtsTok::Str(s) => DtsPiece::Str(s),
+                DtsTok::Ref(r) => DtsPiece::PathRef(r),
+                DtsTok::Punct('<') => {
+                    let mut cells = vec![];
+                    loop {
+                        let line = self.line();
+                        match self.next()? {
+                            DtsTok::Punct('>') => break,
+                            DtsTok::Ref(r) => cells.push(DtsCell::Ref(r)),
+                            DtsTok::Word(w) => {
+                                let v = self.number(&w)?;
+                                if v > u32::MAX as u64 {
+                                    return Err(format!("line {}: {:#x} exceeds 32-bit cell!", line, v));
+                                }
+                                cells.push(DtsCell::Num(v as u32))
+                            }
+                            DtsTok::Punct('(') => return Err(format!("line {}: expressions are not supported!", line)),
+                            t => return Err(format!("line {}: unexpected {:?} in cells!", line, t))
+                        }
+                    }
+                    DtsPiece::Cells(cells)
+                }
+                DtsTok::Punct('[') => {
+                    let mut hex = String::new();
+                    loop {
+                        match self.next()? {
+                            DtsTok::Punct(']') => break,
+                            DtsTok::Word(w) => hex.push_str(&w),
+                            t => return Err(format!("line {}: unexpected {:?} in bytes!", line, t))
+                        }
+                    }
+                    if hex.len() & 1 != 0 {
+                        return Err(format!("line {}: odd number of hex digits!", line));
+                    }
+                    DtsPiece::Bytes((0..hex.len()).step_by(2).map(|i| {
+                        u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| { format!("line {}: invalid bytes!", line) })
+                    }).collect::<Result<Vec<u8>, String>>()?)
+                }
+                DtsTok::Keyword(k) => return Err(format!("line {}: \"/{}/\" is not supported!", line, k)),
+                t => return Err(format!("line {}: unexpected {:?} in value!", line, t))
+            };
+            pieces.push(piece);
+            match self.next()? {
+                DtsTok::Punct(',') => {}
+                DtsTok::Punct(';') => return Ok(pieces),
+                t => return Err(format!("line {}: ',' or ';' is expected, but found {:?}!", self.line(), t))
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     extern crate device_tree;
@@ -380,4 +1053,30 @@ mod test {
         let dt = device_tree::DeviceTree::load(res.as_slice()).unwrap();
         println!("{:?}", dt);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fdt_parse_test() {
+        let root = parse(&compile(&build_test_fdt())).unwrap();
+        assert_eq!(root.find("/cpus/cpu@2").unwrap().prop("riscv,isa").unwrap().strs(), vec!["acdfimsu"]);
+        assert_eq!(root.find("/soc/clint").unwrap().prop("reg").unwrap().u32s(), vec![0, 0x20000, 0, 0x10000]);
+        let dts = r#"
+/dts-v1/;
+/ {
+    #address-cells = <2>;
+    chosen { stdout-path = &uart0; };
+    soc {
+        plic: interrupt-controller@c000000 { interrupt-controller; reg = <0x0 0xc000000 0x0 0x4000000>; };
+        uart0: serial@10000000 { interrupts = <10>; interrupt-parent = <&plic>; compatible = "ns16550a"; };
+    };
+};
+&uart0 { clock-frequency = <0x384000>; /* baud */ };
+"#;
+        let root = parse_dts(dts).unwrap();
+        assert_eq!(root.find("/chosen").unwrap().prop("stdout-path").unwrap().strs(), vec!["/soc/serial@10000000"]);
+        let uart = root.find("/soc/serial").unwrap();
+        assert_eq!(uart.prop("interrupt-parent").unwrap().u32s(), root.find("/soc/interrupt-controller").unwrap().prop("phandle").unwrap().u32s());
+        assert_eq!(uart.prop("clock-frequency").unwrap().u32s(), vec![0x384000]);
+        assert!(parse(&compile(&root)).is_ok());
+        assert!(parse_dts("/ { a = <(1 + 1)>; };").is_err());
+    }
+}
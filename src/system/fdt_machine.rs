@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use terminus_spaceport::memory::region::GHEAP;
+use crate::processor::ProcessorCfg;
+use crate::devices::clint::Clint;
+use crate::devices::aclint::{Mswi, Mtimer, Sswi, MSWI_SIZE, MTIMER_SIZE, SSWI_SIZE};
+use crate::devices::plic::Plic;
+use crate::processor::Privilege;
+use crate::devices::uart::Ns16550a;
+use crate::devices::device::IrqLine;
+use crate::devices::framebuffer::FbFormat;
+use crate::system::fdt::FdtNode;
+use crate::system::{System, Error, Result};
+
+//machine described by a device tree: harts come from /cpus, memories from nodes of device_type "memory",
+//and devices are mapped to models by compatible. nodes with status "disabled" are skipped.
+const CLINT: &[&str] = &["riscv,clint0", "sifive,clint0"];
+const ACLINT_MSWI: &[&str] = &["riscv,aclint-mswi"];
+const ACLINT_MTIMER: &[&str] = &["riscv,aclint-mtimer"];
+const ACLINT_SSWI: &[&str] = &["riscv,aclint-sswi"];
+const PLIC: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+const IMSIC: &[&str] = &["riscv,imsics"];
+const APLIC: &[&str] = &["riscv,aplic"];
+const UART: &[&str] = &["ns16550a", "ns16550"];
+const RTC: &[&str] = &["google,goldfish-rtc"];
+const SYSCON: &[&str] = &["sifive,test0", "sifive,test1", "syscon"];
+//usually in /chosen, which should have empty ranges
+const FRAMEBUFFER: &[&str] = &["simple-framebuffer"];
+//described for the guest only, or served without a node
+const IGNORED: &[&str] = &["simple-bus", "riscv,cpu-intc", "syscon-poweroff", "syscon-reboot", "ucb,htif0"];
+//uart input is polled UART_POLL_HZ times per simulated second for receive interrupts
+pub(crate) const UART_POLL_HZ: u64 = 1000;
+
+fn fdt_err<T>(msg: String) -> Result<T> {
+    Err(Error::FdtErr(msg))
+}
+
+fn u32_prop(node: &FdtNode, name: &str) -> Option<u32> {
+    node.prop(name).and_then(|p| { p.u32s().first().cloned() })
+}
+
+fn str_prop(node: &FdtNode, name: &str) -> Option<String> {
+    node.prop(name).and_then(|p| { p.strs().first().cloned() })
+}
+
+fn disabled(node: &FdtNode) -> bool {
+    matches!(str_prop(node, "status"), Some(ref s) if s != "okay" && s != "ok")
+}
+
+fn compatible(node: &FdtNode, models: &[&str]) -> bool {
+    node.prop("compatible").iter().flat_map(|p| { p.strs() }).any(|c| { models.contains(&c.as_str()) })
+}
+
+fn value(cells: &[u32]) -> u64 {
+    cells.iter().fold(0, |v, c| { v << 32 | *c as u64 })
+}
+
+//riscv,isa, or riscv,isa-base with riscv,isa-extensions
+fn isa(cpu: &FdtNode) -> Option<String> {
+    if let Some(isa) = str_prop(cpu, "riscv,isa") {
+        return Some(isa);
+    }
+    let base = str_prop(cpu, "riscv,isa-base")?;
+    let exts = cpu.prop("riscv,isa-extensions")?.strs();
+    let mut isa = base.trim_end_matches('i').to_string();
+    isa.extend(exts.iter().filter(|e| { e.len() == 1 }).map(|e| { e.as_str() }));
+    for e in exts.iter().filter(|e| { e.len() > 1 }) {
+        isa.push('_');
+        isa.push_str(e)
+    }
+    Some(isa)
+}
+
+//timer frequency and harts ordered by hartid, hartids should be 0..n
+pub fn processors(root: &FdtNode) -> Result<(usize, Vec<ProcessorCfg>)> {
+    let cpus = root.node("cpus").ok_or(Error::FdtErr("\"/cpus\" is required!".to_string()))?;
+    let mut harts = vec![];
+    let mut timebase = u32_prop(cpus, "timebase-frequency");
+    for cpu in cpus.nodes().filter(|n| { str_prop(n, "device_type").as_deref() == Some("cpu") && !disabled(n) }) {
+        let hartid = u32_prop(cpu, "reg").ok_or(Error::FdtErr(format!("\"reg\" of {} is required!", cpu.name())))?;
+        let isa = isa(cpu).ok_or(Error::FdtErr(format!("\"riscv,isa\" of {} is required!", cpu.name())))?;
+        let freq = u32_prop(cpu, "clock-frequency").map_or(1000000000, |f| { f as usize });
+        timebase = timebase.or_else(|| { u32_prop(cpu, "timebase-frequency") });
+        let cfg = ProcessorCfg::from_isa(&isa, freq).map_err(|e| { Error::FdtErr(format!("{}: {}", cpu.name(), e)) })?;
+        harts.push((hartid, cfg))
+    }
+    if harts.is_empty() {
+        return fdt_err("no cpu in \"/cpus\"!".to_string());
+    }
+    harts.sort_by_key(|(id, _)| { *id });
+    if harts.iter().enumerate().any(|(i, (id, _))| { i as u32 != *id }) {
+        return fdt_err("hartids of cpus should be 0..n!".to_string());
+    }
+    let timebase = timebase.ok_or(Error::FdtErr("\"timebase-frequency\" of cpus is required!".to_string()))?;
+    Ok((timebase as usize, harts.into_iter().map(|(_, cfg)| { cfg }).collect()))
+}
+
+struct DtDevice<'a> {
+    node: &'a FdtNode,
+    name: String,
+    regs: Vec<(u64, u64)>,
+    //phandle of the interrupt parent
+    irq_parent: Option<u32>,
+}
+
+//a bus translates child addresses by ranges, none if ranges is absent
+type Ranges = Option<Vec<(u64, u64, u64)>>;
+
+struct Walker<'a> {
+    devices: Vec<DtDevice<'a>>,
+    memories: Vec<(u64, u64)>,
+}
+
+impl<'a> Walker<'a> {
+    fn translate(addr: u64, buses: &[Ranges]) -> Option<u64> {
+        buses.iter().rev().try_fold(addr, |a, ranges| {
+            let ranges = ranges.as_ref()?;
+            if ranges.is_empty() {
+                return Some(a);
+            }
+            ranges.iter().find(|(child, _, size)| { a >= *child && a - *child < *size }).map(|(child, parent, _)| { a - child + parent })
+        })
+    }
+
+    fn regs(node: &FdtNode, addr_cells: usize, size_cells: usize, buses: &[Ranges]) -> Result<Vec<(u64, u64)>> {
+        let cells = node.prop("reg").map(|p| { p.u32s() }).unwrap_or_default();
+        if addr_cells + size_cells == 0 {
+            return Ok(vec![]);
+        }
+        cells.chunks(addr_cells + size_cells).map(|c| {
+            if c.len() != addr_cells + size_cells {
+                return fdt_err(format!("invalid \"reg\" of {}!", node.name()));
+            }
+            let addr = Self::translate(value(&c[..addr_cells]), buses).ok_or(Error::FdtErr(format!("{} is not mapped to cpu address space!", node.name())))?;
+            Ok((addr, value(&c[addr_cells..])))
+        }).collect()
+    }
+
+    //buses are ranges from the root to node
+    fn walk(&mut self, node: &'a FdtNode, path: &str, buses: &mut Vec<Ranges>, irq_parent: Option<u32>) -> Result<()> {
+        let addr_cells = u32_prop(node, "#address-cells").unwrap_or(2) as usize;
+        let size_cells = u32_prop(node, "#size-cells").unwrap_or(1) as usize;
+        let irq_parent = u32_prop(node, "interrupt-parent").or(irq_parent);
+        for child in node.nodes().filter(|n| { !disabled(n) && n.name() != "cpus" }) {
+            let child_path = format!("{}/{}", path, child.name());
+            if str_prop(child, "device_type").as_deref() == Some("memory") {
+                self.memories.extend(Self::regs(child, addr_cells, size_cells, buses)?);
+                continue;
+            }
+            if child.prop("compatible").is_some() && !compatible(child, IGNORED) {
+                self.devices.push(DtDevice {
+                    node: child,
+                    name: child_path.trim_start_matches('/').to_string(),
+                    regs: Self::regs(child, addr_cells, size_cells, buses)?,
+                    irq_parent: u32_prop(child, "interrupt-parent").or(irq_parent),
+                })
+            }
+            if child.nodes().next().is_some() {
+                //(child address, address of this node, size)
+                let child_addr_cells = u32_prop(child, "#address-cells").unwrap_or(2) as usize;
+                let child_size_cells = u32_prop(child, "#size-cells").unwrap_or(1) as usize;
+                let entry = child_addr_cells + addr_cells + child_size_cells;
+                buses.push(child.prop("ranges").map(|p| {
+                    p.u32s().chunks_exact(entry).map(|c| {
+                        (value(&c[..child_addr_cells]), value(&c[child_addr_cells..child_addr_cells + addr_cells]), value(&c[child_addr_cells + addr_cells..]))
+                    }).collect()
+                }));
+                let result = self.walk(child, &child_path, buses, irq_parent);
+                buses.pop();
+                result?
+            }
+        }
+        Ok(())
+    }
+}
+
+//the first interrupt of d on a plic or an aplic, controllers are indexed by phandle
+fn irq_line(sys: &System, d: &DtDevice, controllers: &HashMap<u32, String>) -> Result<Option<IrqLine>> {
+    match (d.node.prop("interrupts"), d.irq_parent) {
+        (Some(irqs), Some(parent)) => {
+            let controller = controllers.get(&parent).ok_or(Error::FdtErr(format!("interrupt parent of {} is not a plic or an aplic!", d.name)))?;
+            irqs.u32s().first().map(|id| { sys.irq_line(controller, *id as usize) }).transpose()
+        }
+        _ => Ok(None)
+    }
+}
+
+//privilege level of a controller wired to harts by "interrupts-extended", 11: meip, 9: seip
+fn irq_level(node: &FdtNode) -> Option<Privilege> {
+    match node.prop("interrupts-extended").and_then(|p| { p.u32s().get(1).cloned() }) {
+        Some(11) => Some(Privilege::M),
+        Some(9) => Some(Privilege::S),
+        _ => None
+    }
+}
+
+//register memories and devices, plic, imsic and aplic in direct mode should be wired to harts by "interrupts-extended"
+pub fn map(sys: &mut System, root: &FdtNode) -> Result<()> {
+    let mut walker = Walker { devices: vec![], memories: vec![] };
+    walker.walk(root, "", &mut vec![], None)?;
+    if walker.memories.is_empty() {
+        return fdt_err("no memory node!".to_string());
+    }
+    for (i, (base, size)) in walker.memories.iter().enumerate() {
+        let name = if i == 0 { "main_memory".to_string() } else { format!("memory{}", i) };
+        let mem = GHEAP.alloc(*size, 1).map_err(|e| { Error::ConfigErr(format!("{} alloc fail! {:?}", name, e)) })?;
+        sys.register_memory(&name, *base, &mem)?;
+    }
+
+    //phandle of riscv,cpu-intc -> hartid
+    let mut intcs = HashMap::new();
+    if let Some(cpus) = root.node("cpus") {
+        for cpu in cpus.nodes() {
+            if let (Some(hartid), Some(intc)) = (u32_prop(cpu, "reg"), cpu.nodes().find(|n| { compatible(n, &["riscv,cpu-intc"]) })) {
+                if let Some(phandle) = u32_prop(intc, "phandle") {
+                    intcs.insert(phandle, hartid as usize);
+                }
+            }
+        }
+    }
+    let eirqs = sys.processors().iter().map(|p| { p.state().eirq().clone() }).collect::<Vec<_>>();
+
+    //interrupt controllers first, imsics before aplics sending msis to them
+    let mut imsics = HashMap::new();
+    for d in walker.devices.iter().filter(|d| { compatible(d.node, IMSIC) }) {
+        let base = d.regs.first().ok_or(Error::FdtErr(format!("\"reg\" of {} is required!", d.name)))?.0;
+        let level = irq_level(d.node).ok_or(Error::FdtErr(format!("{} should be wired to meip or seip of harts!", d.name)))?;
+        let num_ids = u32_prop(d.node, "riscv,num-ids").ok_or(Error::FdtErr(format!("\"riscv,num-ids\" of {} is required!", d.name)))?;
+        let imsic = sys.register_imsic(&d.name, base, level, num_ids)?;
+        if let Some(phandle) = u32_prop(d.node, "phandle") {
+            imsics.insert(phandle, (d.name.clone(), imsic));
+        }
+    }
+    //phandle -> name of plics and aplics
+    let mut controllers = HashMap::new();
+    for d in walker.devices.iter().filter(|d| { compatible(d.node, APLIC) }) {
+        let base = d.regs.first().ok_or(Error::FdtErr(format!("\"reg\" of {} is required!", d.name)))?.0;
+        let num_sources = u32_prop(d.node, "riscv,num-sources").ok_or(Error::FdtErr(format!("\"riscv,num-sources\" of {} is required!", d.name)))?;
+        let msi = u32_prop(d.node, "msi-parent").map(|phandle| {
+            imsics.get(&phandle).ok_or(Error::FdtErr(format!("msi parent of {} is not an imsic!", d.name)))
+        }).transpose()?;
+        let level = match msi {
+            Some((_, imsic)) => imsic.level(),
+            None => irq_level(d.node).ok_or(Error::FdtErr(format!("{} should have \"msi-parent\" or be wired to meip or seip of harts!", d.name)))?
+        };
+        sys.register_aplic(&d.name, base, level, num_sources as usize, msi.map(|(name, imsic)| { (name.as_str(), imsic) }))?;
+        if let Some(phandle) = u32_prop(d.node, "phandle") {
+            controllers.insert(phandle, d.name.clone());
+        }
+    }
+    for d in walker.devices.iter().filter(|d| { compatible(d.node, PLIC) }) {
+        let (base, size) = *d.regs.first().ok_or(Error::FdtErr(format!("\"reg\" of {} is required!", d.name)))?;
+        let ndev = u32_prop(d.node, "riscv,ndev").ok_or(Error::FdtErr(format!("\"riscv,ndev\" of {} is required!", d.name)))?;
+        let cells = d.node.prop("interrupts-extended").map(|p| { p.u32s() }).unwrap_or_default();
+        let contexts = cells.chunks_exact(2).map(|c| {
+            //11: meip, 9: seip, others (usually -1) are not connected
+            if c[1] != 11 && c[1] != 9 {
+                return Ok(None);
+            }
+            let hartid = intcs.get(&c[0]).ok_or(Error::FdtErr(format!("interrupt parent {:#x} of {} is not a hart!", c[0], d.name)))?;
+            Ok(eirqs.get(*hartid).map(|eirq| { (*hartid, eirq.clone(), c[1] as usize) }))
+        }).collect::<Result<Vec<_>>>()?;
+        let plic = Plic::new(ndev as usize, contexts);
+        if let Some(phandle) = u32_prop(d.node, "phandle") {
+            controllers.insert(phandle, d.name.clone());
+        }
+        sys.register_device(&d.name, base, size, plic)?;
+    }
+
+    let mut unsupported = vec![];
+    for d in walker.devices.iter().filter(|d| { !compatible(d.node, PLIC) && !compatible(d.node, IMSIC) && !compatible(d.node, APLIC) }) {
+        let (base, size) = if let Some(reg) = d.regs.first() {
+            *reg
+        } else {
+            unsupported.push(d.name.clone());
+            continue;
+        };
+        if compatible(d.node, CLINT) {
+            sys.register_device(&d.name, base, size, Clint::new(sys.timer()))?
+        } else if compatible(d.node, ACLINT_MSWI) {
+            sys.register_device(&d.name, base, MSWI_SIZE, Mswi::new(sys.timer()))?
+        } else if compatible(d.node, ACLINT_MTIMER) {
+            //"reg" is mtime followed by mtimecmps, mtime is at a fixed offset of the model
+            let mtimecmp = d.regs.get(1).ok_or(Error::FdtErr(format!("\"reg\" of {} should be mtime and mtimecmp!", d.name)))?.0;
+            if base != mtimecmp + MTIMER_SIZE - 8 {
+                return fdt_err(format!("mtime of {} should be at mtimecmp + {:#x}!", d.name, MTIMER_SIZE - 8));
+            }
+            sys.register_device(&d.name, mtimecmp, MTIMER_SIZE, Mtimer::new(sys.timer()))?
+        } else if compatible(d.node, ACLINT_SSWI) {
+            let eirqs = sys.processors().iter().map(|p| { p.state().eirq().clone() }).collect();
+            sys.register_device(&d.name, base, SSWI_SIZE, Sswi::new(eirqs))?
+        } else if compatible(d.node, UART) {
+            let irq = irq_line(sys, d, &controllers)?;
+            let uart = Ns16550a::new(u32_prop(d.node, "reg-shift").unwrap_or(0), irq);
+            //at least every tick for timebases below UART_POLL_HZ
+            uart.poll_input(sys.timer(), (sys.timer().freq() as u64 / UART_POLL_HZ).max(1));
+            sys.register_device(&d.name, base, size, uart)?
+        } else if compatible(d.node, SYSCON) {
+            sys.register_syscon(&d.name, base, size)?
+        } else if compatible(d.node, RTC) {
+            let irq = irq_line(sys, d, &controllers)?;
+            sys.register_rtc(&d.name, base, irq)?
+        } else if compatible(d.node, FRAMEBUFFER) {
+            let prop = |name: &str| { u32_prop(d.node, name).ok_or(Error::FdtErr(format!("\"{}\" of {} is required!", name, d.name))) };
+            let format = str_prop(d.node, "format").ok_or(Error::FdtErr(format!("\"format\" of {} is required!", d.name)))?;
+            let format = FbFormat::parse(&format).map_err(|e| { Error::FdtErr(format!("{}: {}", d.name, e)) })?;
+            let (width, height, stride) = (prop("width")?, prop("height")?, prop("stride")?);
+            if stride as u64 * height as u64 > size {
+                return fdt_err(format!("\"reg\" of {} is smaller than stride * height!", d.name));
+            }
+            sys.register_framebuffer(base, width, height, Some(stride), format)?;
+        } else {
+            unsupported.push(format!("{}({})", d.name, d.node.prop("compatible").map(|p| { p.strs().join(", ") }).unwrap_or_default()))
+        }
+    }
+    if !unsupported.is_empty() {
+        return fdt_err(format!("no model for {}, remove them or set status = \"disabled\"!", unsupported.join(", ")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+const TEST_DTS: &str = r#"
+/dts-v1/;
+/ {
+    #address-cells = <2>;
+    #size-cells = <2>;
+    cpus {
+        #address-cells = <1>;
+        #size-cells = <0>;
+        timebase-frequency = <10000000>;
+        cpu@0 {
+            device_type = "cpu";
+            reg = <0>;
+            riscv,isa = "rv64imac";
+            cpu0_intc: interrupt-controller { compatible = "riscv,cpu-intc"; interrupt-controller; #interrupt-cells = <1>; };
+        };
+    };
+    memory@80000000 { device_type = "memory"; reg = <0x0 0x80000000 0x0 0x100000>; };
+    chosen {
+        #address-cells = <2>;
+        #size-cells = <2>;
+        ranges;
+        framebuffer@40000000 {
+            compatible = "simple-framebuffer";
+            reg = <0x0 0x40000000 0x0 0x4000>;
+            width = <64>;
+            height = <64>;
+            stride = <256>;
+            format = "a8r8g8b8";
+        };
+    };
+    soc {
+        #address-cells = <2>;
+        #size-cells = <2>;
+        compatible = "simple-bus";
+        ranges;
+        clint@2000000 { compatible = "riscv,clint0"; reg = <0x0 0x2000000 0x0 0x10000>; };
+        plic: plic@c000000 {
+            compatible = "sifive,plic-1.0.0";
+            reg = <0x0 0xc000000 0x0 0x600000>;
+            riscv,ndev = <16>;
+            interrupts-extended = <&cpu0_intc 11 &cpu0_intc 9>;
+        };
+        serial@10000000 { compatible = "ns16550a"; reg = <0x0 0x10000000 0x0 0x100>; interrupt-parent = <&plic>; interrupts = <10>; };
+        rtc@101000 { compatible = "google,goldfish-rtc"; reg = <0x0 0x101000 0x0 0x1000>; status = "disabled"; };
+    };
+};
+"#;
+
+#[test]
+fn fdt_machine_test() {
+    use crate::system::SystemBuilder;
+    use crate::system::fdt;
+    let root = fdt::parse_dts(TEST_DTS).unwrap();
+    let sys = SystemBuilder::new("test").fdt(root).build().unwrap();
+    assert_eq!(sys.timer().freq(), 10000000);
+    let space = sys.bus().space();
+    assert_eq!(space.get_region("main_memory").unwrap().info.size, 0x100000);
+    assert_eq!(space.get_region("soc/serial@10000000").unwrap().info.base, 0x10000000);
+    assert!(space.get_region("soc/plic@c000000").is_some());
+    assert!(space.get_region("soc/rtc@101000").is_none());
+    assert_eq!(space.get_region("framebuffer").unwrap().info.base, 0x40000000);
+    let mut lsr = 0u8;
+    sys.bus().read_u8(&0x10000005, &mut lsr).unwrap();
+    assert_eq!(lsr & 0x60, 0x60);
+}
+
+#[test]
+fn fdt_machine_low_timebase_test() {
+    use crate::system::SystemBuilder;
+    use crate::system::fdt;
+    let dtb = fdt::compile(&fdt::parse_dts(&TEST_DTS.replace("<10000000>", "<100>")).unwrap());
+    let sys = SystemBuilder::new("test").fdt(fdt::parse(&dtb).unwrap()).build().unwrap();
+    assert_eq!(sys.timer().freq(), 100);
+    //uart input is polled every tick instead of rescheduled without delay
+    assert_eq!(sys.timer().next_event(), Some(1));
+    sys.timer().tick(1);
+    assert_eq!(sys.timer().next_event(), Some(2));
+}
+
+#[test]
+fn fdt_machine_unsupported_test() {
+    use crate::system::SystemBuilder;
+    use crate::system::fdt;
+    let dts = TEST_DTS.replace("rtc@101000 {", "virtio_mmio@10001000 { compatible = \"virtio,mmio\"; reg = <0x0 0x10001000 0x0 0x1000>; };\n        rtc@101000 {");
+    let err = SystemBuilder::new("test").fdt(fdt::parse_dts(&dts).unwrap()).build().err().unwrap();
+    assert!(matches!(err, Error::FdtErr(ref msg) if msg.contains("virtio_mmio@10001000(virtio,mmio)")));
+}
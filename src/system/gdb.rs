@@ -0,0 +1,394 @@
+use crate::system::System;
+use terminus_global::{RegT, XLen};
+use terminus_spaceport::EXIT_CTRL;
+use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::io;
+use std::collections::HashSet;
+
+//gdb remote serial protocol server, all-stop mode.
+//Harts are reported as threads, thread id = hartid + 1. Memory addresses are translated by the selected hart.
+pub struct GdbServer {
+    stream: TcpStream,
+    breakpoints: HashSet<u64>,
+    hart: usize,
+}
+
+//why serve() returned
+#[derive(Debug, Eq, PartialEq)]
+pub enum GdbExit {
+    Detached,
+    Killed,
+    Exited,
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| { acc.wrapping_add(*b) })
+}
+
+fn hex_le(v: u128, bytes: usize) -> String {
+    (0..bytes).map(|i| { format!("{:02x}", (v >> (i * 8)) as u8) }).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn parse_hex_le(s: &str) -> Option<u64> {
+    if s.len() % 2 != 0 || s.len() > 16 {
+        return None;
+    }
+    let mut v = 0u64;
+    for i in 0..s.len() / 2 {
+        v |= (u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()? as u64) << (i * 8);
+    }
+    Some(v)
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len() / 2).map(|i| { u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok() }).collect()
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| { format!("{:02x}", b) }).collect()
+}
+
+//"monitor <cmd>" of gdb, returns the output
+fn monitor(sys: &System, cmd: &str) -> String {
+    let mut words = cmd.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("fbdump"), Some(file)) => match sys.framebuffer() {
+            Some(fb) => match fb.dump(file) {
+                Ok(_) => format!("framebuffer is dumped to {}\n", file),
+                Err(e) => format!("{}\n", e)
+            },
+            None => "no framebuffer!\n".to_string()
+        },
+        _ => "commands:\n    fbdump <file>    dump framebuffer to ppm, or png if file ends with .png\n".to_string()
+    }
+}
+
+impl GdbServer {
+    //block until gdb connects
+    pub fn new(port: u16) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("waiting for gdb on 127.0.0.1:{}...", port);
+        let (stream, addr) = listener.accept()?;
+        eprintln!("gdb connected from {}", addr);
+        stream.set_nodelay(true)?;
+        Ok(GdbServer {
+            stream,
+            breakpoints: HashSet::new(),
+            hart: 0,
+        })
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut b = [0u8; 1];
+        self.stream.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    //return None for ctrl-c
+    fn recv(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                b'$' => break,
+                0x03 => return Ok(None),
+                _ => {}
+            }
+        }
+        let mut data = vec![];
+        loop {
+            match self.read_byte()? {
+                b'#' => break,
+                b => data.push(b)
+            }
+        }
+        let mut cs = [0u8; 2];
+        self.stream.read_exact(&mut cs)?;
+        if std::str::from_utf8(&cs).ok().and_then(|s| { u8::from_str_radix(s, 16).ok() }) == Some(checksum(&data)) {
+            self.stream.write_all(b"+")?;
+            Ok(Some(String::from_utf8_lossy(&data).to_string()))
+        } else {
+            self.stream.write_all(b"-")?;
+            self.recv()
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()?;
+        //ack
+        self.read_byte()?;
+        Ok(())
+    }
+
+    //ctrl-c received while running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut b = [0u8; 1];
+        let result = match self.stream.read(&mut b) {
+            Ok(1) => Ok(b[0] == 0x03),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gdb disconnected!")),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e)
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    fn xlen_bytes(sys: &mut System, hart: usize) -> usize {
+        match sys.processor(hart).unwrap().state().config().xlen {
+            XLen::X32 => 4,
+            XLen::X64 => 8,
+        }
+    }
+
+    //register numbers follow gdb riscv target: 0-31 x, 32 pc, 33-64 f, 65+ csr
+    fn read_reg(&self, sys: &mut System, id: usize) -> Option<String> {
+        let bytes = Self::xlen_bytes(sys, self.hart);
+        let state = sys.processor(self.hart)?.state();
+        match id {
+            0..=31 => Some(hex_le(*state.xreg(id as u32) as u128, bytes)),
+            32 => Some(hex_le(*state.next_pc() as u128, bytes)),
+            33..=64 => state.fregs().map(|(fregs, flen)| { hex_le(fregs[id - 33], flen.size()) }),
+            65..=4160 => state.csr_backdoor((id - 65) as u32).map(|v| { hex_le(v as u128, bytes) }),
+            _ => None
+        }
+    }
+
+    fn write_reg(&self, sys: &mut System, id: usize, value: RegT) -> bool {
+        let p = if let Some(p) = sys.processor(self.hart) {
+            p
+        } else {
+            return false;
+        };
+        match id {
+            0..=31 => {
+                p.state_mut().set_xreg(id as u32, value);
+                true
+            }
+            32 => {
+                p.state_mut().set_pc(value);
+                true
+            }
+            65..=4160 => p.state().set_csr_backdoor((id - 65) as u32, value).is_some(),
+            _ => false
+        }
+    }
+
+    fn read_mem(&self, sys: &mut System, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        let mut offset = 0;
+        while offset < len {
+            let va = addr + offset as u64;
+            let chunk = std::cmp::min(len - offset, (0x1000 - (va & 0xfff)) as usize);
+            let pa = sys.processor(self.hart)?.debug_translate(va)?;
+            sys.read_mem(pa, &mut data[offset..offset + chunk]).ok()?;
+            offset += chunk
+        }
+        Some(data)
+    }
+
+    fn write_mem(&self, sys: &mut System, addr: u64, data: &[u8]) -> Option<()> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let va = addr + offset as u64;
+            let chunk = std::cmp::min(data.len() - offset, (0x1000 - (va & 0xfff)) as usize);
+            let pa = sys.processor(self.hart)?.debug_translate(va)?;
+            sys.write_mem(pa, &data[offset..offset + chunk]).ok()?;
+            offset += chunk
+        }
+        for p in sys.processors().iter() {
+            p.fetcher().flush_icache()
+        }
+        Some(())
+    }
+
+    fn stop_reply(&self) -> String {
+        format!("T05thread:{:x};", self.hart + 1)
+    }
+
+    fn resume(&mut self, sys: &mut System, resume: Resume) -> io::Result<Option<GdbExit>> {
+        let mut cnt = 0u64;
+        loop {
+            sys.step(1);
+            if let Ok(msg) = EXIT_CTRL.poll() {
+                eprintln!("{}", msg);
+                let code = sys.exit_code().unwrap_or(0);
+                self.send(&format!("W{:02x}", code as u8))?;
+                return Ok(Some(GdbExit::Exited));
+            }
+            if let Resume::Step = resume {
+                break;
+            }
+            if let Some(hart) = sys.processors().iter().position(|p| { self.breakpoints.contains(p.state().next_pc()) }) {
+                self.hart = hart;
+                break;
+            }
+            cnt += 1;
+            if cnt % 0x10000 == 0 && self.interrupted()? {
+                break;
+            }
+        }
+        let reply = self.stop_reply();
+        self.send(&reply)?;
+        Ok(None)
+    }
+
+    //handle gdb commands until detached, killed or the guest exited
+    pub fn serve(&mut self, sys: &mut System) -> io::Result<GdbExit> {
+        loop {
+            let packet = match self.recv()? {
+                Some(p) => p,
+                None => {
+                    let reply = self.stop_reply();
+                    self.send(&reply)?;
+                    continue;
+                }
+            };
+            let (cmd, args) = packet.split_at(std::cmp::min(1, packet.len()));
+            let reply = match cmd {
+                "?" => self.stop_reply(),
+                "g" => {
+                    (0..33).map(|i| { self.read_reg(sys, i).unwrap() }).collect::<String>()
+                }
+                "G" => {
+                    let bytes = Self::xlen_bytes(sys, self.hart) * 2;
+                    if args.len() < bytes * 33 {
+                        "E01".to_string()
+                    } else {
+                        for i in 0..33 {
+                            if let Some(v) = parse_hex_le(&args[i * bytes..(i + 1) * bytes]) {
+                                self.write_reg(sys, i, v);
+                            }
+                        }
+                        "OK".to_string()
+                    }
+                }
+                "p" => parse_hex(args).and_then(|id| { self.read_reg(sys, id as usize) }).unwrap_or("E01".to_string()),
+                "P" => {
+                    let mut iter = args.splitn(2, '=');
+                    match (iter.next().and_then(parse_hex), iter.next().and_then(parse_hex_le)) {
+                        (Some(id), Some(v)) if self.write_reg(sys, id as usize, v) => "OK".to_string(),
+                        _ => "E01".to_string()
+                    }
+                }
+                "m" => {
+                    let mut iter = args.splitn(2, ',');
+                    match (iter.next().and_then(parse_hex), iter.next().and_then(parse_hex)) {
+                        (Some(addr), Some(len)) => self.read_mem(sys, addr, len as usize)
+                            .map(|data| { encode_hex(&data) })
+                            .unwrap_or("E14".to_string()),
+                        _ => "E01".to_string()
+                    }
+                }
+                "M" => {
+                    let mut iter = args.splitn(2, ':');
+                    let mut head = iter.next().unwrap_or("").splitn(2, ',');
+                    match (head.next().and_then(parse_hex), iter.next().and_then(decode_hex)) {
+                        (Some(addr), Some(data)) => self.write_mem(sys, addr, &data).map(|_| { "OK".to_string() }).unwrap_or("E14".to_string()),
+                        _ => "E01".to_string()
+                    }
+                }
+                "Z" | "z" => {
+                    let mut iter = args.split(',');
+                    match (iter.next(), iter.next().and_then(parse_hex)) {
+                        (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                            if cmd == "Z" {
+                                self.breakpoints.insert(addr);
+                            } else {
+                                self.breakpoints.remove(&addr);
+                            }
+                            "OK".to_string()
+                        }
+                        _ => String::new()
+                    }
+                }
+                "c" | "s" => {
+                    if let Some(addr) = parse_hex(args) {
+                        if let Some(p) = sys.processor(self.hart) {
+                            p.state_mut().set_pc(addr)
+                        }
+                    }
+                    if let Some(exit) = self.resume(sys, if cmd == "c" { Resume::Continue } else { Resume::Step })? {
+                        return Ok(exit);
+                    }
+                    continue;
+                }
+                "H" => {
+                    //Hg<tid>, Hc<tid>, -1 and 0 mean any
+                    match i64::from_str_radix(args.get(1..).unwrap_or(""), 16) {
+                        Ok(tid) if tid > 0 && (tid as usize) <= sys.processors().len() => {
+                            if args.starts_with('g') {
+                                self.hart = tid as usize - 1
+                            }
+                            "OK".to_string()
+                        }
+                        Ok(_) => "OK".to_string(),
+                        Err(_) => "E01".to_string()
+                    }
+                }
+                "T" => match parse_hex(args) {
+                    Some(tid) if tid > 0 && (tid as usize) <= sys.processors().len() => "OK".to_string(),
+                    _ => "E01".to_string()
+                },
+                "q" => {
+                    if args.starts_with("Supported") {
+                        "PacketSize=4000".to_string()
+                    } else if args == "Attached" {
+                        "1".to_string()
+                    } else if args == "C" {
+                        format!("QC{:x}", self.hart + 1)
+                    } else if args == "fThreadInfo" {
+                        format!("m{}", (1..=sys.processors().len()).map(|t| { format!("{:x}", t) }).collect::<Vec<String>>().join(","))
+                    } else if args == "sThreadInfo" {
+                        "l".to_string()
+                    } else if let Some(cmd) = args.strip_prefix("Rcmd,") {
+                        match decode_hex(cmd) {
+                            Some(cmd) => {
+                                self.send(&format!("O{}", encode_hex(monitor(sys, &String::from_utf8_lossy(&cmd)).as_bytes())))?;
+                                "OK".to_string()
+                            }
+                            None => "E01".to_string()
+                        }
+                    } else {
+                        String::new()
+                    }
+                }
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(GdbExit::Detached);
+                }
+                "k" => return Ok(GdbExit::Killed),
+                _ => String::new()
+            };
+            self.send(&reply)?;
+        }
+    }
+}
+
+#[test]
+fn gdb_packet_test() {
+    assert_eq!(checksum(b"OK"), 0x9a);
+    assert_eq!(checksum(b""), 0);
+    assert_eq!(hex_le(0x1234, 4), "34120000");
+    assert_eq!(parse_hex_le("34120000"), Some(0x1234));
+    assert_eq!(parse_hex_le(&hex_le(0x8000_0000_dead_beef, 8)), Some(0x8000_0000_dead_beef));
+    assert_eq!(parse_hex_le("123"), None);
+    assert_eq!(parse_hex_le("zz"), None);
+    assert_eq!(parse_hex("80000000"), Some(0x80000000));
+    assert_eq!(decode_hex("6662"), Some(b"fb".to_vec()));
+    assert_eq!(decode_hex("666"), None);
+    assert_eq!(decode_hex(&encode_hex(b"fbdump a.ppm")), Some(b"fbdump a.ppm".to_vec()));
+}
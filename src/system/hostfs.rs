@@ -0,0 +1,265 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf, Component};
+
+//host files accessed by guest system calls, shared by linux user mode and htif syscall proxy.
+//flags, errno and struct stat follow asm-generic, which riscv uses.
+
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const ENOTDIR: i64 = 20;
+pub const EINVAL: i64 = 22;
+pub const ENOTTY: i64 = 25;
+pub const ESPIPE: i64 = 29;
+pub const EROFS: i64 = 30;
+pub const ERANGE: i64 = 34;
+pub const ENOSYS: i64 = 38;
+pub const ELOOP: i64 = 40;
+pub const EPROTO: i64 = 71;
+pub const EOPNOTSUPP: i64 = 95;
+
+pub const AT_FDCWD: u64 = -100i64 as u64;
+pub const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+pub const AT_REMOVEDIR: u64 = 0x200;
+pub const AT_EMPTY_PATH: u64 = 0x1000;
+
+pub const O_WRONLY: u64 = 0x1;
+pub const O_RDWR: u64 = 0x2;
+pub const O_CREAT: u64 = 0x40;
+pub const O_EXCL: u64 = 0x80;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+pub const O_DIRECTORY: u64 = 0x10000;
+pub const O_NOFOLLOW: u64 = 0x20000;
+
+pub fn errno(e: io::Error) -> i64 {
+    e.raw_os_error().map_or(EIO, |e| { e as i64 })
+}
+
+pub enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File, PathBuf),
+}
+
+impl Fd {
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, i64> {
+        match self {
+            Fd::Stdin => io::stdin().read(buf).map_err(errno),
+            Fd::File(file, _) => file.read(buf).map_err(errno),
+            _ => Err(EBADF)
+        }
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, i64> {
+        match self {
+            Fd::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write_all(buf).and_then(|_| { stdout.flush() }).map(|_| { buf.len() }).map_err(errno)
+            }
+            Fd::Stderr => io::stderr().write_all(buf).map(|_| { buf.len() }).map_err(errno),
+            Fd::File(file, _) => file.write(buf).map_err(errno),
+            _ => Err(EBADF)
+        }
+    }
+
+    pub fn file(&mut self) -> Result<&mut File, i64> {
+        match self {
+            Fd::File(file, _) => Ok(file),
+            _ => Err(ESPIPE)
+        }
+    }
+
+    //whence is SEEK_SET, SEEK_CUR or SEEK_END
+    pub fn seek(&mut self, offset: u64, whence: u64) -> Result<u64, i64> {
+        let pos = match whence {
+            0 => SeekFrom::Start(offset),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return Err(EINVAL)
+        };
+        self.file()?.seek(pos).map_err(errno)
+    }
+
+    //none for stdio
+    pub fn metadata(&self) -> Result<Option<fs::Metadata>, i64> {
+        match self {
+            Fd::File(file, _) => file.metadata().map(Some).map_err(errno),
+            _ => Ok(None)
+        }
+    }
+
+    fn try_clone(&self) -> Result<Fd, i64> {
+        Ok(match self {
+            Fd::Stdin => Fd::Stdin,
+            Fd::Stdout => Fd::Stdout,
+            Fd::Stderr => Fd::Stderr,
+            Fd::File(file, path) => Fd::File(file.try_clone().map_err(errno)?, path.clone()),
+        })
+    }
+}
+
+//fd 0, 1 and 2 are stdio of the simulator, new fd is the lowest free one
+pub struct FdTable(Vec<Option<Fd>>);
+
+impl FdTable {
+    pub fn new() -> FdTable {
+        FdTable(vec![Some(Fd::Stdin), Some(Fd::Stdout), Some(Fd::Stderr)])
+    }
+
+    pub fn get(&mut self, fd: u64) -> Result<&mut Fd, i64> {
+        self.0.get_mut(fd as usize).and_then(|f| { f.as_mut() }).ok_or(EBADF)
+    }
+
+    pub fn alloc(&mut self, fd: Fd) -> u64 {
+        if let Some(i) = self.0.iter().position(|f| { f.is_none() }) {
+            self.0[i] = Some(fd);
+            i as u64
+        } else {
+            self.0.push(Some(fd));
+            (self.0.len() - 1) as u64
+        }
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<u64, i64> {
+        self.0.get_mut(fd as usize).and_then(|f| { f.take() }).map(|_| { 0 }).ok_or(EBADF)
+    }
+
+    //duplicate to target, or to the lowest free fd
+    pub fn dup(&mut self, fd: u64, target: Option<u64>) -> Result<u64, i64> {
+        let new = self.get(fd)?.try_clone()?;
+        if let Some(target) = target {
+            let target = target as usize;
+            if target >= self.0.len() {
+                self.0.resize_with(target + 1, || { None })
+            }
+            self.0[target] = Some(new);
+            Ok(target as u64)
+        } else {
+            Ok(self.alloc(new))
+        }
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn open(path: &Path, flags: u64, mode: u64) -> Result<File, i64> {
+    let access = flags & 0x3;
+    let mut opts = OpenOptions::new();
+    opts.read(access != O_WRONLY)
+        .write(access == O_WRONLY || access == O_RDWR)
+        .append(flags & O_APPEND != 0)
+        .truncate(flags & O_TRUNC != 0)
+        .mode(mode as u32);
+    if flags & O_NOFOLLOW != 0 {
+        opts.custom_flags(libc::O_NOFOLLOW);
+    }
+    if flags & O_CREAT != 0 {
+        if flags & O_EXCL != 0 {
+            opts.create_new(true);
+        } else {
+            opts.create(true);
+        }
+    }
+    let file = opts.open(path).map_err(errno)?;
+    if flags & O_DIRECTORY != 0 && !file.metadata().map_err(errno)?.is_dir() {
+        return Err(ENOTDIR);
+    }
+    Ok(file)
+}
+
+//resolve guest path under base, ".." and symbolic links can not leave base.
+//existing components are resolved by the host, the rest are appended as is
+pub fn confine(base: &Path, guest: &Path) -> Result<PathBuf, i64> {
+    let root = base.canonicalize().map_err(errno)?;
+    let mut path = root.clone();
+    for c in guest.components() {
+        match c {
+            Component::Normal(name) => {
+                path.push(name);
+                if fs::symlink_metadata(&path).is_ok() {
+                    //dangling links are rejected too, creating files through them would leave base
+                    path = path.canonicalize().map_err(|_| { EACCES })?;
+                    if !path.starts_with(&root) {
+                        return Err(EACCES);
+                    }
+                }
+            }
+            Component::ParentDir => {
+                if path == root {
+                    return Err(EACCES);
+                }
+                path.pop();
+            }
+            _ => {}
+        }
+    }
+    Ok(path)
+}
+
+//struct stat, a character device if meta is none
+pub fn stat(meta: Option<&fs::Metadata>) -> [u8; 128] {
+    let mut stat = [0u8; 128];
+    let mut put = |offset: usize, value: u64, size: usize| {
+        stat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size])
+    };
+    if let Some(m) = meta {
+        put(0, m.dev(), 8);
+        put(8, m.ino(), 8);
+        put(16, m.mode() as u64, 4);
+        put(20, m.nlink(), 4);
+        put(24, m.uid() as u64, 4);
+        put(28, m.gid() as u64, 4);
+        put(32, m.rdev(), 8);
+        put(48, m.size(), 8);
+        put(56, m.blksize(), 4);
+        put(64, m.blocks(), 8);
+        put(72, m.atime() as u64, 8);
+        put(80, m.atime_nsec() as u64, 8);
+        put(88, m.mtime() as u64, 8);
+        put(96, m.mtime_nsec() as u64, 8);
+        put(104, m.ctime() as u64, 8);
+        put(112, m.ctime_nsec() as u64, 8);
+    } else {
+        put(16, 0o20620, 4);
+        put(20, 1, 4);
+        put(56, 1024, 4);
+    }
+    stat
+}
+
+#[test]
+fn confine_test() {
+    let root = std::env::temp_dir().join(format!("terminus_hostfs_test_{}", std::process::id()));
+    let outside = std::env::temp_dir().join(format!("terminus_hostfs_test_{}_outside", std::process::id()));
+    fs::create_dir_all(root.join("dir")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+    std::os::unix::fs::symlink(outside.join("new"), root.join("dangling")).unwrap();
+    std::os::unix::fs::symlink("dir", root.join("in")).unwrap();
+    let canonical = root.canonicalize().unwrap();
+
+    assert_eq!(confine(&root, Path::new("dir/../a")), Ok(canonical.join("a")));
+    assert_eq!(confine(&root, Path::new("/dir/new")), Ok(canonical.join("dir/new")));
+    assert_eq!(confine(&root, Path::new("in/new")), Ok(canonical.join("dir/new")));
+    assert_eq!(confine(&root, Path::new("..")), Err(EACCES));
+    assert_eq!(confine(&root, Path::new("dir/../../a")), Err(EACCES));
+    assert_eq!(confine(&root, Path::new("out")), Err(EACCES));
+    assert_eq!(confine(&root, Path::new("out/a")), Err(EACCES));
+    assert_eq!(confine(&root, Path::new("dangling")), Err(EACCES));
+    assert!(open(&confine(&root, Path::new("in/f")).unwrap(), O_CREAT | O_WRONLY, 0o644).is_ok());
+    assert!(root.join("dir/f").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_dir_all(&outside).unwrap();
+}
@@ -0,0 +1,253 @@
+use std::fs;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::cmp::min;
+use terminus_spaceport::EXIT_CTRL;
+use crate::devices::bus::Bus;
+use crate::devices::htif::HTIFSyscallPort;
+use crate::processor::env::ExitCode;
+use crate::system::hostfs::{self, Fd, FdTable, errno, EFAULT, ENOMEM, ENOTDIR, ERANGE, ENOSYS, AT_FDCWD, AT_SYMLINK_NOFOLLOW, AT_REMOVEDIR};
+#[cfg(test)]
+use terminus_spaceport::memory::region::{Region, GHEAP, U64Access};
+#[cfg(test)]
+use crate::devices::htif::HTIF;
+#[cfg(test)]
+use crate::system::hostfs::EACCES;
+
+//system calls of riscv-pk, forwarded through htif device 0 command 0 like fesvr.
+//magic_mem is 8 u64 {n, a0..a6}, the return value is written back to magic_mem[0].
+//paths are passed with length, and resolved inside root, where "/" and the working directory are.
+const SYS_MKDIRAT: u64 = 34;
+const SYS_UNLINKAT: u64 = 35;
+const SYS_GETCWD: u64 = 17;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_PREAD: u64 = 67;
+const SYS_PWRITE: u64 = 68;
+const SYS_FSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_GETMAINVARS: u64 = 2011;
+
+pub struct HtifProxy {
+    port: HTIFSyscallPort,
+    exit_code: ExitCode,
+    fds: FdTable,
+    root: PathBuf,
+    //argv of pk, e.g. ["pk", "hello", "arg1"]
+    args: Vec<String>,
+}
+
+impl HtifProxy {
+    pub fn new(port: HTIFSyscallPort, exit_code: &ExitCode, root: &str, args: &[String]) -> HtifProxy {
+        HtifProxy {
+            port,
+            exit_code: exit_code.clone(),
+            fds: FdTable::new(),
+            root: fs::canonicalize(root).unwrap_or_else(|_| { PathBuf::from(root) }),
+            args: args.to_vec(),
+        }
+    }
+
+    //serve the pending syscall if any, the guest spins on fromhost until it is served
+    pub fn serve(&mut self, bus: &Bus) {
+        if let Some(magic_mem) = self.port.request() {
+            let mut bytes = [0u8; 64];
+            let ret = if bus.space().read_bytes(&magic_mem, &mut bytes).is_ok() {
+                let mut args = [0u64; 8];
+                for (i, arg) in args.iter_mut().enumerate() {
+                    let mut word = [0u8; 8];
+                    word.copy_from_slice(&bytes[i * 8..i * 8 + 8]);
+                    *arg = u64::from_le_bytes(word)
+                }
+                match self.syscall(bus, &args) {
+                    Ok(v) => v,
+                    Err(e) => (-e) as u64
+                }
+            } else {
+                (-EFAULT) as u64
+            };
+            //nothing to report if magic_mem is not accessible
+            bus.space().write_bytes(&magic_mem, &ret.to_le_bytes()).ok();
+            self.port.respond()
+        }
+    }
+
+    fn read_mem(bus: &Bus, addr: u64, data: &mut [u8]) -> Result<(), i64> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        bus.space().read_bytes(&addr, data).map_err(|_| { EFAULT })
+    }
+
+    fn write_mem(bus: &Bus, addr: u64, data: &[u8]) -> Result<(), i64> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        bus.space().write_bytes(&addr, data).map_err(|_| { EFAULT })
+    }
+
+    //guest path is len bytes including the terminating nul
+    fn path(&mut self, bus: &Bus, dirfd: u64, addr: u64, len: u64) -> Result<PathBuf, i64> {
+        let mut bytes = vec![0u8; min(len, 4096) as usize];
+        Self::read_mem(bus, addr, &mut bytes)?;
+        if let Some(end) = bytes.iter().position(|c| { *c == 0 }) {
+            bytes.truncate(end)
+        }
+        let guest = String::from_utf8(bytes).map_err(|_| { EFAULT })?;
+        let guest = Path::new(&guest);
+        let base = if guest.is_absolute() || dirfd == AT_FDCWD {
+            &self.root
+        } else if let Fd::File(_, dir) = self.fds.get(dirfd)? {
+            &*dir
+        } else {
+            return Err(ENOTDIR);
+        };
+        hostfs::confine(base, guest)
+    }
+
+    fn stat(bus: &Bus, addr: u64, meta: Option<&fs::Metadata>) -> Result<u64, i64> {
+        Self::write_mem(bus, addr, &hostfs::stat(meta))?;
+        Ok(0)
+    }
+
+    //argc, argv[], 0, envp[] = {0}, followed by strings
+    fn mainvars(&self, bus: &Bus, addr: u64, limit: u64) -> Result<u64, i64> {
+        let mut words = vec![self.args.len() as u64];
+        let mut strings = vec![];
+        let strings_base = addr + (self.args.len() as u64 + 3) * 8;
+        for arg in self.args.iter() {
+            words.push(strings_base + strings.len() as u64);
+            strings.extend_from_slice(arg.as_bytes());
+            strings.push(0)
+        }
+        words.extend_from_slice(&[0, 0]);
+        let mut bytes = words.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+        bytes.extend(strings);
+        if bytes.len() as u64 > limit {
+            return Err(ENOMEM);
+        }
+        Self::write_mem(bus, addr, &bytes)?;
+        Ok(0)
+    }
+
+    fn syscall(&mut self, bus: &Bus, args: &[u64; 8]) -> Result<u64, i64> {
+        let (n, a0, a1, a2, a3, a4) = (args[0], args[1], args[2], args[3], args[4], args[5]);
+        match n {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code.set(a0);
+                EXIT_CTRL.exit(&format!("pk program exited with {}!", a0)).unwrap();
+                Ok(0)
+            }
+            SYS_READ => {
+                let mut data = vec![0u8; min(a2, 0x10_0000) as usize];
+                let n = self.fds.get(a0)?.read(&mut data)?;
+                Self::write_mem(bus, a1, &data[..n])?;
+                Ok(n as u64)
+            }
+            SYS_WRITE => {
+                let mut data = vec![0u8; min(a2, 0x10_0000) as usize];
+                Self::read_mem(bus, a1, &mut data)?;
+                Ok(self.fds.get(a0)?.write(&data)? as u64)
+            }
+            SYS_PREAD => {
+                let mut data = vec![0u8; min(a2, 0x10_0000) as usize];
+                let n = self.fds.get(a0)?.file()?.read_at(&mut data, a3).map_err(errno)?;
+                Self::write_mem(bus, a1, &data[..n])?;
+                Ok(n as u64)
+            }
+            SYS_PWRITE => {
+                let mut data = vec![0u8; min(a2, 0x10_0000) as usize];
+                Self::read_mem(bus, a1, &mut data)?;
+                Ok(self.fds.get(a0)?.file()?.write_at(&data, a3).map_err(errno)? as u64)
+            }
+            SYS_OPENAT => {
+                let path = self.path(bus, a0, a1, a2)?;
+                let file = hostfs::open(&path, a3, a4)?;
+                Ok(self.fds.alloc(Fd::File(file, path)))
+            }
+            SYS_CLOSE => self.fds.close(a0),
+            SYS_LSEEK => self.fds.get(a0)?.seek(a1, a2),
+            SYS_FSTAT => {
+                let meta = self.fds.get(a0)?.metadata()?;
+                Self::stat(bus, a1, meta.as_ref())
+            }
+            SYS_FSTATAT => {
+                let path = self.path(bus, a0, a1, a2)?;
+                let meta = if a4 & AT_SYMLINK_NOFOLLOW != 0 {
+                    fs::symlink_metadata(&path)
+                } else {
+                    fs::metadata(&path)
+                }.map_err(errno)?;
+                Self::stat(bus, a3, Some(&meta))
+            }
+            SYS_FACCESSAT => {
+                let path = self.path(bus, a0, a1, a2)?;
+                fs::metadata(&path).map(|_| { 0 }).map_err(errno)
+            }
+            SYS_MKDIRAT => {
+                let path = self.path(bus, a0, a1, a2)?;
+                fs::create_dir(&path).map(|_| { 0 }).map_err(errno)
+            }
+            SYS_UNLINKAT => {
+                let path = self.path(bus, a0, a1, a2)?;
+                if a3 & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(&path)
+                } else {
+                    fs::remove_file(&path)
+                }.map(|_| { 0 }).map_err(errno)
+            }
+            SYS_GETCWD => {
+                if a1 < 2 {
+                    return Err(ERANGE);
+                }
+                Self::write_mem(bus, a0, b"/\0")?;
+                Ok(a0)
+            }
+            SYS_GETMAINVARS => self.mainvars(bus, a0, a1),
+            _ => Err(ENOSYS)
+        }
+    }
+}
+
+#[test]
+fn htif_proxy_test() {
+    let root = std::env::temp_dir().join(format!("terminus_htif_proxy_{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let bus = Bus::new();
+    bus.space_mut().add_region("main_memory", &Region::remap(0x80000000, &GHEAP.alloc(0x1000, 1).unwrap())).unwrap();
+    let htif = HTIF::new(0, Some(8));
+    let mut proxy = HtifProxy::new(htif.syscall_port(), &ExitCode::default(), root.to_str().unwrap(), &["pk".to_string(), "hello".to_string()]);
+    let mut call = |args: &[u64]| -> u64 {
+        let bytes = args.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+        bus.space().write_bytes(&0x80000000, &bytes).unwrap();
+        U64Access::write(&htif, &0, 0x80000000);
+        proxy.serve(&bus);
+        assert_eq!(U64Access::read(&htif, &0), 0);
+        assert_eq!(U64Access::read(&htif, &8), 1);
+        U64Access::write(&htif, &8, 0);
+        let mut ret = [0u8; 8];
+        bus.space().read_bytes(&0x80000000, &mut ret).unwrap();
+        u64::from_le_bytes(ret)
+    };
+    bus.space().write_bytes(&0x80000100, b"/out.txt\0../escape\0data").unwrap();
+    //O_WRONLY | O_CREAT | O_TRUNC
+    let fd = call(&[SYS_OPENAT, AT_FDCWD, 0x80000100, 9, 0x241, 0o644]);
+    assert_eq!(fd, 3);
+    assert_eq!(call(&[SYS_WRITE, fd, 0x80000113, 4]), 4);
+    assert_eq!(call(&[SYS_CLOSE, fd]), 0);
+    assert_eq!(fs::read(root.join("out.txt")).unwrap(), b"data");
+    assert_eq!(call(&[SYS_OPENAT, AT_FDCWD, 0x80000109, 10, 0, 0]), (-EACCES) as u64);
+    assert_eq!(call(&[SYS_GETMAINVARS, 0x80000200, 0x100]), 0);
+    let mut argv1 = [0u8; 8];
+    bus.space().read_bytes(&0x80000210, &mut argv1).unwrap();
+    let mut name = [0u8; 6];
+    bus.space().read_bytes(&u64::from_le_bytes(argv1), &mut name).unwrap();
+    assert_eq!(&name, b"hello\0");
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,171 @@
+use std::path::Path;
+use crate::system::elf::ElfLoader;
+
+//formats of image files loaded to memory. raw binaries are placed at the given address,
+//the others carry their own addresses, which are moved as a whole if an address is given
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ImageFormat {
+    Elf,
+    Raw,
+    IHex,
+    Srec,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Result<ImageFormat, String> {
+        match name {
+            "elf" => Ok(ImageFormat::Elf),
+            "raw" | "bin" => Ok(ImageFormat::Raw),
+            "ihex" | "hex" => Ok(ImageFormat::IHex),
+            "srec" => Ok(ImageFormat::Srec),
+            _ => Err(format!("unknown image format \"{}\", supported formats are elf, raw, ihex and srec!", name))
+        }
+    }
+
+    //elf is detected by magic, ihex and srec by extension, others are raw
+    pub fn detect(file: &str, content: &[u8]) -> ImageFormat {
+        if content.starts_with(b"\x7fELF") {
+            return ImageFormat::Elf;
+        }
+        match Path::new(file).extension().and_then(|e| { e.to_str() }) {
+            Some("hex") | Some("ihex") => ImageFormat::IHex,
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => ImageFormat::Srec,
+            _ => ImageFormat::Raw
+        }
+    }
+}
+
+//data chunks at physical addresses
+pub struct Image {
+    pub chunks: Vec<(u64, Vec<u8>)>,
+}
+
+impl Image {
+    pub fn parse(format: ImageFormat, content: Vec<u8>, addr: Option<u64>) -> Result<Image, String> {
+        let mut image = match format {
+            ImageFormat::Raw => {
+                let addr = addr.ok_or("address of raw image is required!".to_string())?;
+                return Ok(Image { chunks: vec![(addr, content)] });
+            }
+            ImageFormat::Elf => {
+                let chunks = std::cell::RefCell::new(vec![]);
+                ElfLoader::from_bytes(content)?.load(false, |addr, data| {
+                    chunks.borrow_mut().push((addr, data.to_vec()));
+                    Ok(())
+                })?;
+                Image { chunks: chunks.into_inner() }
+            }
+            ImageFormat::IHex => Self::parse_ihex(&content)?,
+            ImageFormat::Srec => Self::parse_srec(&content)?,
+        };
+        if let (Some(addr), Some(start)) = (addr, image.start()) {
+            for (a, _) in image.chunks.iter_mut() {
+                *a = *a - start + addr
+            }
+        }
+        Ok(image)
+    }
+
+    pub fn start(&self) -> Option<u64> {
+        self.chunks.iter().map(|(a, _)| { *a }).min()
+    }
+
+    pub fn end(&self) -> Option<u64> {
+        self.chunks.iter().map(|(a, d)| { *a + d.len() as u64 }).max()
+    }
+
+    //bytes of a text record in hex digits, with line number in errors
+    fn record_bytes(line: &str, n: usize) -> Result<Vec<u8>, String> {
+        if line.len() & 1 != 0 || !line.is_ascii() {
+            return Err(format!("line {}: invalid record!", n));
+        }
+        (0..line.len()).step_by(2).map(|i| {
+            u8::from_str_radix(&line[i..i + 2], 16).map_err(|_| { format!("line {}: invalid record!", n) })
+        }).collect()
+    }
+
+    fn push(&mut self, addr: u64, data: &[u8]) {
+        if let Some((a, d)) = self.chunks.last_mut() {
+            if *a + d.len() as u64 == addr {
+                d.extend_from_slice(data);
+                return;
+            }
+        }
+        self.chunks.push((addr, data.to_vec()))
+    }
+
+    //intel hex: ":" count, address, type, data, checksum
+    fn parse_ihex(content: &[u8]) -> Result<Image, String> {
+        let text = String::from_utf8_lossy(content);
+        let mut image = Image { chunks: vec![] };
+        let mut base = 0u64;
+        for (i, line) in text.lines().enumerate().map(|(i, l)| { (i + 1, l.trim()) }).filter(|(_, l)| { !l.is_empty() }) {
+            if !line.starts_with(':') {
+                return Err(format!("line {}: record should start with ':'!", i));
+            }
+            let bytes = Self::record_bytes(&line[1..], i)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(format!("line {}: invalid record length!", i));
+            }
+            if bytes.iter().fold(0u8, |s, b| { s.wrapping_add(*b) }) != 0 {
+                return Err(format!("line {}: checksum mismatch!", i));
+            }
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0 => image.push(base + offset, data),
+                1 => break,
+                2 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u64) << 4,
+                4 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u64) << 16,
+                //start addresses
+                3 | 5 => {}
+                t => return Err(format!("line {}: invalid record type {}!", i, t))
+            }
+        }
+        Ok(image)
+    }
+
+    //motorola s-record: "S" type, count, address, data, checksum
+    fn parse_srec(content: &[u8]) -> Result<Image, String> {
+        let text = String::from_utf8_lossy(content);
+        let mut image = Image { chunks: vec![] };
+        for (i, line) in text.lines().enumerate().map(|(i, l)| { (i + 1, l.trim()) }).filter(|(_, l)| { !l.is_empty() }) {
+            if line.len() < 2 || !line.is_ascii() || !line.starts_with('S') {
+                return Err(format!("line {}: record should start with 'S'!", i));
+            }
+            let bytes = Self::record_bytes(&line[2..], i)?;
+            if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+                return Err(format!("line {}: invalid record length!", i));
+            }
+            if bytes.iter().fold(0u8, |s, b| { s.wrapping_add(*b) }) != 0xff {
+                return Err(format!("line {}: checksum mismatch!", i));
+            }
+            let addr_len = match &line[1..2] {
+                "1" => 2,
+                "2" => 3,
+                "3" => 4,
+                //header, count and start address
+                "0" | "5" | "6" | "7" | "8" | "9" => continue,
+                t => return Err(format!("line {}: invalid record type S{}!", i, t))
+            };
+            if bytes.len() < addr_len + 2 {
+                return Err(format!("line {}: invalid record length!", i));
+            }
+            let addr = bytes[1..1 + addr_len].iter().fold(0u64, |a, b| { a << 8 | *b as u64 });
+            image.push(addr, &bytes[1 + addr_len..bytes.len() - 1])
+        }
+        Ok(image)
+    }
+}
+
+#[test]
+fn image_parse_test() {
+    let ihex = b":020000040000FA\n:0400100001020304E2\n:020014000506DF\n:00000001FF\n".to_vec();
+    let image = Image::parse(ImageFormat::IHex, ihex, None).unwrap();
+    assert_eq!(image.chunks, vec![(0x10, vec![1, 2, 3, 4, 5, 6])]);
+    let srec = b"S00600004844521B\nS107001001020304DE\nS5030001FB\nS9030000FC\n".to_vec();
+    let image = Image::parse(ImageFormat::Srec, srec, Some(0x80000000)).unwrap();
+    assert_eq!(image.chunks, vec![(0x80000000, vec![1, 2, 3, 4])]);
+    assert!(Image::parse(ImageFormat::IHex, b":0400100001020304E3\n".to_vec(), None).is_err());
+    assert_eq!(ImageFormat::detect("fw.bin", b"\x7fELF"), ImageFormat::Elf);
+}
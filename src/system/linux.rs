@@ -0,0 +1,647 @@
+use std::fs;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::min;
+use terminus_global::*;
+use terminus_spaceport::EXIT_CTRL;
+use crate::devices::bus::Bus;
+use crate::devices::clint::Timer;
+use crate::processor::{Processor, Privilege};
+use crate::processor::env::{Env, ExitCode};
+use crate::processor::trap::Exception;
+use crate::system::hostfs::{self, Fd, FdTable, errno, ENOENT, EBADF, ENOMEM, EFAULT, ENOTDIR, EINVAL, ENOTTY, ERANGE, ENOSYS, AT_FDCWD, AT_SYMLINK_NOFOLLOW, AT_REMOVEDIR, AT_EMPTY_PATH};
+
+//guest virtual memory is mapped 1:1 to physical memory [USER_BASE, USER_BASE + USER_MEM_SIZE),
+//from bottom to top: elf image, brk heap, mmap area growing down, stack
+pub const USER_BASE: u64 = 0x1000;
+pub const USER_MEM_SIZE: u64 = 0x4000_0000;
+const STACK_SIZE: u64 = 0x80_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const MAP_FIXED: RegT = 0x10;
+const MAP_ANONYMOUS: RegT = 0x20;
+
+const RLIMIT_STACK: RegT = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+//auxv types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+fn page_align(v: u64) -> u64 {
+    (v + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+//linux system calls of a u-mode program, translated to host file system and time.
+//the program is single-threaded, signals are not delivered.
+pub struct LinuxUser {
+    bus: Arc<Bus>,
+    timer: Arc<Timer>,
+    exit_code: ExitCode,
+    exe: PathBuf,
+    //working directory of the program, the host's is never changed
+    cwd: PathBuf,
+    fds: FdTable,
+    brk_start: u64,
+    brk: u64,
+    mmap_top: u64,
+    stack_top: u64,
+    rand: u64,
+    exited: bool,
+}
+
+impl LinuxUser {
+    //image_end is the end of loaded elf, where brk starts
+    pub fn new(bus: &Arc<Bus>, timer: &Arc<Timer>, exit_code: &ExitCode, exe: &str, image_end: u64) -> LinuxUser {
+        let stack_top = USER_BASE + USER_MEM_SIZE;
+        LinuxUser {
+            bus: bus.clone(),
+            timer: timer.clone(),
+            exit_code: exit_code.clone(),
+            exe: fs::canonicalize(exe).unwrap_or_else(|_| { PathBuf::from(exe) }),
+            cwd: std::env::current_dir().unwrap_or_else(|_| { PathBuf::from("/") }),
+            fds: FdTable::new(),
+            brk_start: page_align(image_end),
+            brk: page_align(image_end),
+            mmap_top: stack_top - STACK_SIZE,
+            stack_top,
+            rand: 0x2545f4914f6cdd1d,
+            exited: false,
+        }
+    }
+
+    //build argv, envp and auxv on the stack and point sp to argc, phdrs is (address, entry size, number) of program headers
+    pub fn init_stack(&mut self, p: &mut Processor, args: &[String], envs: &[String], entry: u64, phdrs: (u64, u64, u64)) -> Result<(), String> {
+        if self.brk_start >= self.mmap_top {
+            return Err(format!("elf image ends at {:#x}, beyond user memory!", self.brk_start));
+        }
+        let random = self.random_bytes(16);
+        let err = |addr: u64| -> String { format!("stack overflow at {:#x}!", addr) };
+        let mut sp = self.stack_top;
+        let mut push = |data: &[u8]| -> Result<u64, String> {
+            sp -= data.len() as u64;
+            self.write_mem(sp, data).map_err(|_| { err(sp) })?;
+            Ok(sp)
+        };
+        let random = push(&random)?;
+        let execfn = push(format!("{}\0", self.exe.display()).as_bytes())?;
+        let envp = envs.iter().map(|e| { push(format!("{}\0", e).as_bytes()) }).collect::<Result<Vec<u64>, String>>()?;
+        let argv = args.iter().map(|a| { push(format!("{}\0", a).as_bytes()) }).collect::<Result<Vec<u64>, String>>()?;
+
+        //only standard single-letter extensions are reported
+        let hwcap = p.state().config().isa.misa() & "imafdcqv".chars().fold(0, |acc, c| { acc | (1 << (c as u8 - b'a')) });
+        let auxv = [
+            (AT_PHDR, phdrs.0),
+            (AT_PHENT, phdrs.1),
+            (AT_PHNUM, phdrs.2),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, hwcap),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+        let mut words = vec![argv.len() as u64];
+        words.extend(argv.iter());
+        words.push(0);
+        words.extend(envp.iter());
+        words.push(0);
+        words.extend(auxv.iter().flat_map(|(t, v)| { vec![*t, *v] }));
+
+        let xlen = p.state().config().xlen;
+        let size = (words.len() * xlen.size()) as u64;
+        let sp = (sp - size) & !0xf;
+        let bytes = words.iter().flat_map(|w| { w.to_le_bytes()[..xlen.size()].to_vec() }).collect::<Vec<u8>>();
+        self.write_mem(sp, &bytes).map_err(|_| { err(sp) })?;
+        p.state_mut().set_xreg(2, sp);
+        Ok(())
+    }
+
+    fn read_mem(&self, addr: u64, data: &mut [u8]) -> Result<(), i64> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.bus.space().read_bytes(&addr, data).map_err(|_| { EFAULT })
+    }
+
+    fn write_mem(&self, addr: u64, data: &[u8]) -> Result<(), i64> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.bus.space().write_bytes(&addr, data).map_err(|_| { EFAULT })
+    }
+
+    fn zero_mem(&self, addr: u64, len: u64) -> Result<(), i64> {
+        const CHUNK: u64 = 0x10_0000;
+        let zeros = vec![0u8; min(len, CHUNK) as usize];
+        let mut offset = 0;
+        while offset < len {
+            let n = min(len - offset, CHUNK);
+            self.write_mem(addr + offset, &zeros[..n as usize])?;
+            offset += n
+        }
+        Ok(())
+    }
+
+    fn write_u64s(&self, addr: u64, values: &[u64]) -> Result<(), i64> {
+        self.write_mem(addr, &values.iter().flat_map(|v| { v.to_le_bytes().to_vec() }).collect::<Vec<u8>>())
+    }
+
+    fn read_u64(&self, addr: u64) -> Result<u64, i64> {
+        let mut bytes = [0u8; 8];
+        self.read_mem(addr, &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_str(&self, addr: u64) -> Result<String, i64> {
+        let mut bytes = vec![];
+        let mut c = [0u8; 1];
+        loop {
+            self.read_mem(addr + bytes.len() as u64, &mut c)?;
+            if c[0] == 0 {
+                break;
+            }
+            bytes.push(c[0]);
+        }
+        String::from_utf8(bytes).map_err(|_| { EINVAL })
+    }
+
+    //xorshift, guest randomness is reproducible
+    fn random_bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| {
+            self.rand ^= self.rand << 13;
+            self.rand ^= self.rand >> 7;
+            self.rand ^= self.rand << 17;
+            self.rand as u8
+        }).collect()
+    }
+
+    //path relative to dirfd
+    fn path(&mut self, dirfd: RegT, addr: u64) -> Result<PathBuf, i64> {
+        let path = PathBuf::from(self.read_str(addr)?);
+        if path.is_absolute() {
+            Ok(path)
+        } else if dirfd == AT_FDCWD {
+            Ok(self.cwd.join(path))
+        } else if let Fd::File(_, dir) = self.fds.get(dirfd)? {
+            Ok(dir.join(path))
+        } else {
+            Err(ENOTDIR)
+        }
+    }
+
+    fn exit(&mut self, code: u64) {
+        self.exited = true;
+        self.exit_code.set(code);
+        EXIT_CTRL.exit(&format!("user program exited with {}!", code)).unwrap();
+    }
+
+    fn stat(&self, addr: u64, meta: Option<&fs::Metadata>) -> Result<RegT, i64> {
+        self.write_mem(addr, &hostfs::stat(meta))?;
+        Ok(0)
+    }
+
+    fn fstat(&mut self, fd: RegT, addr: u64) -> Result<RegT, i64> {
+        let meta = self.fds.get(fd)?.metadata()?;
+        self.stat(addr, meta.as_ref())
+    }
+
+    fn openat(&mut self, dirfd: RegT, path: u64, flags: RegT, mode: RegT) -> Result<RegT, i64> {
+        let path = self.path(dirfd, path)?;
+        let file = hostfs::open(&path, flags, mode)?;
+        Ok(self.fds.alloc(Fd::File(file, path)))
+    }
+
+    fn read(&mut self, fd: RegT, addr: u64, len: u64) -> Result<RegT, i64> {
+        let mut data = vec![0u8; min(len, 0x10_0000) as usize];
+        let n = self.fds.get(fd)?.read(&mut data)?;
+        self.write_mem(addr, &data[..n])?;
+        Ok(n as RegT)
+    }
+
+    fn write(&mut self, fd: RegT, addr: u64, len: u64) -> Result<RegT, i64> {
+        let mut data = vec![0u8; min(len, 0x10_0000) as usize];
+        self.read_mem(addr, &mut data)?;
+        let n = self.fds.get(fd)?.write(&data)?;
+        Ok(n as RegT)
+    }
+
+    //iovec is {base, len} of xlen words
+    fn iovec(&self, addr: u64, cnt: u64, xlen: XLen) -> Result<Vec<(u64, u64)>, i64> {
+        (0..cnt).map(|i| -> Result<(u64, u64), i64> {
+            let entry = addr + i * 2 * xlen.size() as u64;
+            let base = self.read_u64(entry)? & xlen.mask();
+            let len = self.read_u64(entry + xlen.size() as u64)? & xlen.mask();
+            Ok((base, len))
+        }).collect()
+    }
+
+    fn readv(&mut self, fd: RegT, addr: u64, cnt: u64, xlen: XLen) -> Result<RegT, i64> {
+        let mut total = 0;
+        for (base, len) in self.iovec(addr, cnt, xlen)? {
+            let n = self.read(fd, base, len)?;
+            total += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn writev(&mut self, fd: RegT, addr: u64, cnt: u64, xlen: XLen) -> Result<RegT, i64> {
+        let mut total = 0;
+        for (base, len) in self.iovec(addr, cnt, xlen)? {
+            total += self.write(fd, base, len)?;
+        }
+        Ok(total)
+    }
+
+    fn clock(&self, clock: RegT) -> (u64, u64) {
+        match clock {
+            //realtime follows host, others follow simulated time
+            0 => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                (now.as_secs(), now.subsec_nanos() as u64)
+            }
+            _ => {
+                let time = self.timer.time() as u128;
+                let freq = self.timer.freq() as u128;
+                ((time / freq) as u64, (time % freq * 1_000_000_000 / freq) as u64)
+            }
+        }
+    }
+
+    fn brk(&mut self, addr: u64) -> Result<RegT, i64> {
+        if addr >= self.brk_start && addr < self.mmap_top {
+            if addr > self.brk {
+                self.zero_mem(self.brk, addr - self.brk)?;
+            }
+            self.brk = addr
+        }
+        Ok(self.brk)
+    }
+
+    //private mappings only, file contents are copied and never written back
+    fn mmap(&mut self, addr: u64, len: u64, flags: RegT, fd: RegT, offset: u64) -> Result<RegT, i64> {
+        if len == 0 {
+            return Err(EINVAL);
+        }
+        if len > USER_MEM_SIZE {
+            return Err(ENOMEM);
+        }
+        let len = page_align(len);
+        //file is read before the range is reserved, a bad fd leaves the mmap area unchanged
+        let mut data = vec![];
+        if flags & MAP_ANONYMOUS == 0 {
+            data.resize(len as usize, 0);
+            let n = self.fds.get(fd)?.file().map_err(|_| { EBADF })?.read_at(&mut data, offset).map_err(errno)?;
+            data.truncate(n);
+        }
+        let addr = if flags & MAP_FIXED != 0 {
+            if addr & (PAGE_SIZE - 1) != 0 || addr < USER_BASE || addr.checked_add(len).filter(|end| { *end <= self.stack_top }).is_none() {
+                return Err(EINVAL);
+            }
+            addr
+        } else {
+            let brk = self.brk;
+            self.mmap_top = self.mmap_top.checked_sub(len).filter(|top| { *top >= brk }).ok_or(ENOMEM)?;
+            self.mmap_top
+        };
+        self.zero_mem(addr, len)?;
+        self.write_mem(addr, &data)?;
+        Ok(addr)
+    }
+
+    fn rlimit(&self, resource: RegT, addr: u64) -> Result<RegT, i64> {
+        if addr != 0 {
+            let limit = if resource == RLIMIT_STACK { STACK_SIZE } else { RLIM_INFINITY };
+            self.write_u64s(addr, &[limit, limit])?;
+        }
+        Ok(0)
+    }
+
+    fn uname(&self, addr: u64) -> Result<RegT, i64> {
+        let mut uts = [0u8; 65 * 6];
+        for (i, field) in ["Linux", "terminus", "5.15.0", "#1", "riscv64", ""].iter().enumerate() {
+            uts[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes())
+        }
+        self.write_mem(addr, &uts)?;
+        Ok(0)
+    }
+
+    fn kill(&mut self, sig: RegT) -> Result<RegT, i64> {
+        if sig != 0 {
+            eprintln!("user program killed by signal {}!", sig);
+            self.exit(128 + sig);
+        }
+        Ok(0)
+    }
+
+    fn syscall(&mut self, p: &mut Processor) -> Result<RegT, i64> {
+        let xlen = p.state().config().xlen;
+        let a = |i: InsnT| -> RegT { *p.state().xreg(10 + i) };
+        match *p.state().xreg(17) {
+            //getcwd
+            17 => {
+                let bytes = format!("{}\0", self.cwd.display()).into_bytes();
+                if bytes.len() as u64 > a(1) {
+                    return Err(ERANGE);
+                }
+                self.write_mem(a(0), &bytes)?;
+                Ok(bytes.len() as RegT)
+            }
+            //dup, dup3
+            23 => self.fds.dup(a(0), None),
+            24 => self.fds.dup(a(0), Some(a(1))),
+            //fcntl, only F_DUPFD, F_GETFD, F_SETFD, F_GETFL and F_DUPFD_CLOEXEC
+            25 => match a(1) {
+                0 | 1030 => self.fds.dup(a(0), None),
+                1..=3 => self.fds.get(a(0)).map(|_| { 0 }),
+                _ => Err(EINVAL)
+            },
+            //ioctl, no terminal
+            29 => self.fds.get(a(0)).and(Err(ENOTTY)),
+            //mkdirat
+            34 => {
+                let path = self.path(a(0), a(1))?;
+                fs::create_dir(path).map(|_| { 0 }).map_err(errno)
+            }
+            //unlinkat
+            35 => {
+                let path = self.path(a(0), a(1))?;
+                let result = if a(2) & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(path)
+                } else {
+                    fs::remove_file(path)
+                };
+                result.map(|_| { 0 }).map_err(errno)
+            }
+            //ftruncate
+            46 => {
+                let len = a(1);
+                self.fds.get(a(0))?.file()?.set_len(len).map(|_| { 0 }).map_err(errno)
+            }
+            //faccessat
+            48 => {
+                let path = self.path(a(0), a(1))?;
+                if path.exists() {
+                    Ok(0)
+                } else {
+                    Err(ENOENT)
+                }
+            }
+            //chdir
+            49 => {
+                let path = fs::canonicalize(self.path(AT_FDCWD, a(0))?).map_err(errno)?;
+                if !fs::metadata(&path).map_err(errno)?.is_dir() {
+                    return Err(ENOTDIR);
+                }
+                self.cwd = path;
+                Ok(0)
+            }
+            56 => self.openat(a(0), a(1), a(2), a(3)),
+            //close
+            57 => self.fds.close(a(0)),
+            62 => self.fds.get(a(0))?.seek(a(1), a(2)),
+            63 => self.read(a(0), a(1), a(2)),
+            64 => self.write(a(0), a(1), a(2)),
+            65 => self.readv(a(0), a(1), a(2), xlen),
+            66 => self.writev(a(0), a(1), a(2), xlen),
+            //pread64
+            67 => {
+                let mut data = vec![0u8; min(a(2), 0x10_0000) as usize];
+                let n = self.fds.get(a(0))?.file()?.read_at(&mut data, a(3)).map_err(errno)?;
+                self.write_mem(a(1), &data[..n])?;
+                Ok(n as RegT)
+            }
+            //pwrite64
+            68 => {
+                let mut data = vec![0u8; min(a(2), 0x10_0000) as usize];
+                self.read_mem(a(1), &mut data)?;
+                let n = self.fds.get(a(0))?.file()?.write_at(&data, a(3)).map_err(errno)?;
+                Ok(n as RegT)
+            }
+            //readlinkat
+            78 => {
+                let path = self.path(a(0), a(1))?;
+                let target = if path.to_str() == Some("/proc/self/exe") {
+                    self.exe.clone()
+                } else {
+                    fs::read_link(path).map_err(errno)?
+                };
+                let bytes = target.to_string_lossy().into_owned().into_bytes();
+                let len = min(bytes.len(), a(3) as usize);
+                self.write_mem(a(2), &bytes[..len])?;
+                Ok(len as RegT)
+            }
+            //newfstatat
+            79 => {
+                if a(3) & AT_EMPTY_PATH != 0 && self.read_str(a(1))?.is_empty() {
+                    return self.fstat(a(0), a(2));
+                }
+                let path = self.path(a(0), a(1))?;
+                let meta = if a(3) & AT_SYMLINK_NOFOLLOW != 0 {
+                    fs::symlink_metadata(path)
+                } else {
+                    fs::metadata(path)
+                }.map_err(errno)?;
+                self.stat(a(2), Some(&meta))
+            }
+            80 => self.fstat(a(0), a(1)),
+            //exit, exit_group
+            93 | 94 => {
+                self.exit(a(0) & 0xff);
+                Ok(0)
+            }
+            //set_tid_address, gettid, getpid
+            96 | 178 | 172 => Ok(std::process::id() as RegT),
+            //futex, set_robust_list, nanosleep, clock_nanosleep, sched_yield, mprotect, madvise, munmap
+            98 | 99 | 101 | 115 | 124 | 226 | 233 | 215 => Ok(0),
+            //clock_gettime
+            113 => {
+                let (sec, nsec) = self.clock(a(0));
+                self.write_u64s(a(1), &[sec, nsec])?;
+                Ok(0)
+            }
+            //clock_getres
+            114 => {
+                if a(1) != 0 {
+                    self.write_u64s(a(1), &[0, 1])?;
+                }
+                Ok(0)
+            }
+            //kill, tkill, tgkill
+            129 | 130 => self.kill(a(1)),
+            131 => self.kill(a(2)),
+            //rt_sigaction, k_sigaction has no restorer on riscv
+            134 => {
+                if a(2) != 0 {
+                    self.write_u64s(a(2), &[0, 0, 0])?;
+                }
+                Ok(0)
+            }
+            //rt_sigprocmask
+            135 => {
+                if a(2) != 0 {
+                    self.zero_mem(a(2), a(3))?;
+                }
+                Ok(0)
+            }
+            160 => self.uname(a(0)),
+            163 => self.rlimit(a(0), a(1)),
+            //gettimeofday
+            169 => {
+                if a(0) != 0 {
+                    let (sec, nsec) = self.clock(0);
+                    self.write_u64s(a(0), &[sec, nsec / 1000])?;
+                }
+                Ok(0)
+            }
+            //getppid, getuid, geteuid, getgid, getegid
+            173..=177 => Ok(0),
+            214 => self.brk(a(0)),
+            //mremap, let libc fall back to mmap
+            216 => Err(ENOMEM),
+            222 => self.mmap(a(0), a(1), a(3), a(4), a(5)),
+            //riscv_flush_icache
+            259 => {
+                p.fetcher().flush_icache();
+                Ok(0)
+            }
+            //prlimit64
+            261 => self.rlimit(a(1), a(3)),
+            //getrandom
+            278 => {
+                let bytes = self.random_bytes(a(1) as usize);
+                self.write_mem(a(0), &bytes)?;
+                Ok(a(1))
+            }
+            _ => Err(ENOSYS)
+        }
+    }
+}
+
+impl Env for LinuxUser {
+    fn poll(&mut self, _: &mut Processor) -> bool {
+        !self.exited
+    }
+
+    fn trap(&mut self, p: &mut Processor, e: &Exception) -> bool {
+        if *p.state().privilege() != Privilege::U {
+            return false;
+        }
+        let pc = *p.state().pc();
+        let sig = match e {
+            Exception::UCall => {
+                let ret = match self.syscall(p) {
+                    Ok(v) => v,
+                    Err(n) => (-n) as RegT
+                };
+                let mask = p.state().config().xlen.mask();
+                p.state_mut().set_xreg(10, ret & mask);
+                p.state_mut().set_pc(pc + 4);
+                return true;
+            }
+            Exception::IllegalInsn(_) => 4,
+            Exception::Breakpoint => 5,
+            Exception::FetchMisaligned(_) | Exception::LoadMisaligned(_) | Exception::StoreMisaligned(_) => 7,
+            _ => 11,
+        };
+        eprintln!("user program: {:?} at pc {:#x}", e, pc);
+        self.kill(sig).unwrap();
+        true
+    }
+}
+
+#[cfg(test)]
+use crate::system::SystemBuilder;
+#[cfg(test)]
+use crate::processor::ProcessorCfg;
+
+#[test]
+fn linux_user_test() {
+    //li a0, 0; li a7, 214(brk); ecall; mv s1, a0; ld a0, 0(sp)(argc); addi a0, a0, 6; li a7, 93(exit); ecall; j .
+    let program = [0x00000513u32, 0x0d600893, 0x00000073, 0x00050493, 0x00013503, 0x00650513, 0x05d00893, 0x00000073, 0x0000006f];
+    let code = program.iter().flat_map(|i| { i.to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+    let (base, code_off) = (0x10000u64, 120u64);
+    let filesz = code_off + code.len() as u64;
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    //elf header: type, machine, version, entry, phoff, shoff, flags, ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+    elf.extend(2u16.to_le_bytes().iter().chain(243u16.to_le_bytes().iter()).chain(1u32.to_le_bytes().iter()));
+    elf.extend((base + code_off).to_le_bytes().iter().chain(64u64.to_le_bytes().iter()).chain(0u64.to_le_bytes().iter()).chain(0u32.to_le_bytes().iter()));
+    elf.extend([64u16, 56, 1, 64, 0, 0].iter().flat_map(|v| { v.to_le_bytes().to_vec() }));
+    //PT_LOAD with 0x100 bytes bss
+    elf.extend(1u32.to_le_bytes().iter().chain(7u32.to_le_bytes().iter()));
+    elf.extend([0, base, base, filesz, filesz + 0x100, 0x1000].iter().flat_map(|v: &u64| { v.to_le_bytes().to_vec() }));
+    elf.extend(code);
+
+    let cfg = ProcessorCfg::from_isa("rv64imacsu", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).elf_bytes(elf).build().unwrap();
+    sys.boot_linux_user(&["test".to_string()], &[]).unwrap();
+    sys.step(20);
+    assert_eq!(*sys.processor(0).unwrap().state().xreg(9), base + PAGE_SIZE);
+    assert_eq!(sys.exit_code(), Some(7));
+}
+
+#[test]
+fn linux_cwd_mmap_test() {
+    use terminus_spaceport::memory::region::GHEAP;
+    let cfg = ProcessorCfg::from_isa("rv64imacsu", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", USER_BASE, &GHEAP.alloc(0x10000, 1).unwrap()).unwrap();
+    let mut user = LinuxUser::new(&sys.bus, sys.timer(), &sys.exit, "test", USER_BASE + 0x1000);
+    let syscall = |user: &mut LinuxUser, sys: &mut crate::system::System, nr: RegT, args: &[RegT]| -> Result<RegT, i64> {
+        let p = &mut sys.processors[0];
+        p.state_mut().set_xreg(17, nr);
+        for (i, arg) in args.iter().enumerate() {
+            p.state_mut().set_xreg(10 + i as InsnT, *arg);
+        }
+        user.syscall(p)
+    };
+    let dir = fs::canonicalize(std::env::temp_dir()).unwrap().join(format!("terminus_linux_cwd_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("file"), b"data").unwrap();
+    let host_cwd = std::env::current_dir().unwrap();
+
+    //chdir, then relative paths are resolved against the new cwd of the program only
+    user.write_mem(USER_BASE, format!("{}\0", dir.display()).as_bytes()).unwrap();
+    assert_eq!(syscall(&mut user, &mut sys, 49, &[USER_BASE]), Ok(0));
+    assert_eq!(std::env::current_dir().unwrap(), host_cwd);
+    user.write_mem(USER_BASE, b"file\0").unwrap();
+    assert_eq!(syscall(&mut user, &mut sys, 48, &[AT_FDCWD, USER_BASE, 0]), Ok(0));
+    assert_eq!(syscall(&mut user, &mut sys, 49, &[USER_BASE]), Err(ENOTDIR));
+    let len = syscall(&mut user, &mut sys, 17, &[USER_BASE + 0x100, 0x100]).unwrap();
+    assert_eq!(user.read_str(USER_BASE + 0x100).unwrap().len() as RegT + 1, len);
+    assert_eq!(PathBuf::from(user.read_str(USER_BASE + 0x100).unwrap()), dir);
+
+    //bad fd does not consume the mmap area
+    let top = user.mmap_top;
+    assert_eq!(user.mmap(0, 0x1000, 0, 99, 0), Err(EBADF));
+    assert_eq!(user.mmap_top, top);
+    fs::remove_dir_all(&dir).unwrap();
+}
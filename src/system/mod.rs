@@ -2,15 +2,35 @@ use terminus_spaceport::memory::MemInfo;
 use terminus_spaceport::space::Space;
 use terminus_spaceport::space;
 use terminus_spaceport::memory::region::{Region, IOAccess, BytesAccess, GHEAP};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::rc::Rc;
 use std::fmt;
-use crate::devices::htif::HTIF;
+use crate::devices::htif::{HTIF, HTIFSyscallPort};
 use crate::devices::bus::Bus;
 use std::fmt::{Display, Formatter};
 use crate::processor::{ProcessorCfg, Processor};
-use std::cmp::min;
-use crate::devices::clint::Timer;
+use std::cmp::{min, max};
+use crate::devices::clint::{Timer, Clint};
+use crate::devices::device::{Device, IrqLine};
+use crate::devices::aclint::{Mswi, Mtimer, Sswi};
+use crate::devices::imsic::Imsic;
+use crate::devices::aplic::Aplic;
+use crate::devices::clic::Clic;
+use crate::devices::plic::Plic;
+use crate::devices::uart::Ns16550a;
+use crate::devices::syscon::SifiveTest;
+use crate::devices::rtc::{GoldfishRtc, RtcClock, RTC_SIZE};
+use crate::devices::virtio::{VirtioDevice, GuestMemory};
+use crate::devices::virtio_mmio::VirtioMmio;
+use crate::devices::framebuffer::{Framebuffer, FbFormat};
+use crate::processor::cache::{MemHierarchy, HierarchyCfg};
 use std::ops::Deref;
+use std::{io, fs};
+use std::collections::HashMap;
+use std::path::Path;
+use crate::processor::Privilege;
+use crate::processor::timing::TimingModel;
+use terminus_spaceport::EXIT_CTRL;
 
 #[derive(Debug)]
 pub enum Error {
@@ -18,6 +38,10 @@ pub enum Error {
     ElfErr(String),
     FdtErr(String),
     ResetErr(String),
+    IoErr(io::Error),
+    ConfigErr(String),
+    LoadErr(String),
+    AccessErr(u64),
 }
 
 impl From<space::Error> for Error {
@@ -26,45 +50,385 @@ impl From<space::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(v: io::Error) -> Error {
+        Error::IoErr(v)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub mod elf;
 
-use elf::ElfLoader;
+use elf::{ElfLoader, SymbolTable};
 use crate::system::fdt::{FdtNode, FdtProp};
 use terminus_global::XLen;
 
 pub mod fdt;
 
+pub mod gdb;
+
+pub mod config;
+
+use config::MachineCfg;
+
+pub mod sbi;
+
+use sbi::Sbi;
+
+pub mod hostfs;
+
+pub mod linux;
+
+use linux::LinuxUser;
+
+pub mod htif_proxy;
+
+use htif_proxy::HtifProxy;
+
+pub mod semihosting;
+
+use semihosting::Semihosting;
+
+pub mod image;
+
+use image::{Image, ImageFormat};
+
+pub mod fdt_machine;
+use crate::processor::env::{ExitCode, ResetRequest};
+
 pub struct System {
     name: String,
     bus: Arc<Bus>,
     timer: Arc<Timer>,
-    elf: ElfLoader,
+    elf: Option<ElfLoader>,
+    //place elf segments at virtual addresses instead of physical ones
+    elf_virtual_addr: bool,
     processors: Vec<Processor>,
+    mem_hierarchy: Option<Rc<MemHierarchy>>,
+    bootargs: String,
+    stdout_path: Option<String>,
+    //[start, end) of initrd
+    initrd: Option<(u64, u64)>,
+    //given device tree blob, replacing the generated one
+    dtb: Option<Vec<u8>>,
+    //device tree describing the machine, it is also passed to the guest
+    fdt: Option<FdtNode>,
+    //exit code of emulated environment
+    exit: ExitCode,
+    //reboot requested by the guest, served at the end of step
+    reset_req: ResetRequest,
+    //how harts were started, repeated by reboot
+    boot: Option<Boot>,
+    //contents loaded to memories, loaded again by reboot
+    images: Mutex<Vec<Loaded>>,
+    rtc_clock: RtcClock,
+    //names of memory regions in space, seen by dma of devices
+    memories: Mutex<Vec<String>>,
+    framebuffer: Option<Arc<Framebuffer>>,
+    htif_proxy: Option<HtifProxy>,
+    devices: Vec<DeviceEntry>,
 }
 
-impl System {
-    pub fn new(name: &str, elf_file: &str, processor_cfgs: Vec<ProcessorCfg>, timer_freq: usize) -> System {
-        let bus = Arc::new(Bus::new());
-        let elf = ElfLoader::new(elf_file).expect(&format!("Invalid Elf {}", elf_file));
-        let mut sys = System {
+#[derive(Clone)]
+enum Boot {
+    Reset(Vec<Option<u64>>),
+    //entry, dtb_addr and the firmware state
+    Sbi(Option<u64>, u64, Arc<Sbi>),
+}
+
+//elfs and files are read again by reboot, only bytes given by callers are copied
+enum Loaded {
+    //segments placed at virtual addresses
+    Elf(bool),
+    File(String, Option<ImageFormat>, Option<u64>),
+    Bytes(u64, Vec<u8>),
+}
+
+//device registered by register_device, its region is backed by a clone of device
+struct DeviceEntry {
+    name: String,
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+}
+
+enum ElfSource {
+    File(String),
+    Bytes(Vec<u8>),
+}
+
+//SystemBuilder::new("sys").processors(cfgs).elf_file("a.elf").build()?, memories and devices are registered to the built system
+pub struct SystemBuilder {
+    name: String,
+    timer_freq: usize,
+    processor_cfgs: Vec<ProcessorCfg>,
+    elf: Option<ElfSource>,
+    elf_virtual_addr: bool,
+    bootargs: String,
+    stdout_path: Option<String>,
+    htif_root: String,
+    htif_args: Option<Vec<String>>,
+    fdt: Option<FdtNode>,
+    rtc_clock: RtcClock,
+}
+
+impl SystemBuilder {
+    pub fn new(name: &str) -> SystemBuilder {
+        SystemBuilder {
             name: name.to_string(),
-            bus,
-            timer: Arc::new(Timer::new(timer_freq)),
+            timer_freq: 10000000,
+            processor_cfgs: vec![],
+            elf: None,
+            elf_virtual_addr: false,
+            bootargs: "console=hvc0 earlycon=sbi".to_string(),
+            stdout_path: None,
+            htif_root: ".".to_string(),
+            htif_args: None,
+            fdt: None,
+            rtc_clock: RtcClock::Host,
+        }
+    }
+
+    pub fn timer_freq(mut self, freq: usize) -> SystemBuilder {
+        self.timer_freq = freq;
+        self
+    }
+
+    pub fn processor(mut self, cfg: ProcessorCfg) -> SystemBuilder {
+        self.processor_cfgs.push(cfg);
+        self
+    }
+
+    pub fn processors(mut self, cfgs: Vec<ProcessorCfg>) -> SystemBuilder {
+        self.processor_cfgs.extend(cfgs);
+        self
+    }
+
+    pub fn elf_file(mut self, file: &str) -> SystemBuilder {
+        self.elf = Some(ElfSource::File(file.to_string()));
+        self
+    }
+
+    pub fn elf_bytes(mut self, content: Vec<u8>) -> SystemBuilder {
+        self.elf = Some(ElfSource::Bytes(content));
+        self
+    }
+
+    //segments are placed at physical addresses by default
+    pub fn elf_virtual_addr(mut self, virtual_addr: bool) -> SystemBuilder {
+        self.elf_virtual_addr = virtual_addr;
+        self
+    }
+
+    pub fn bootargs(mut self, bootargs: &str) -> SystemBuilder {
+        self.bootargs = bootargs.to_string();
+        self
+    }
+
+    //stdout-path of /chosen in the generated device tree
+    pub fn stdout_path(mut self, path: &str) -> SystemBuilder {
+        self.stdout_path = Some(path.to_string());
+        self
+    }
+
+    //host directory seen by htif syscalls of riscv-pk as "/", default to the current directory
+    pub fn htif_root(mut self, dir: &str) -> SystemBuilder {
+        self.htif_root = dir.to_string();
+        self
+    }
+
+    //returned by htif getmainvars, default to [elf file]
+    pub fn htif_args(mut self, args: Vec<String>) -> SystemBuilder {
+        self.htif_args = Some(args);
+        self
+    }
+
+    //harts, timer frequency, memories and devices are described by the device tree, refer to fdt_machine.
+    //bootargs and stdout-path are added to /chosen if absent
+    pub fn fdt(mut self, root: FdtNode) -> SystemBuilder {
+        self.fdt = Some(root);
+        self
+    }
+
+    //rtcs start from epoch in ns and follow the simulated time instead of the host clock
+    pub fn rtc_epoch(mut self, epoch: u64) -> SystemBuilder {
+        self.rtc_clock = RtcClock::Fixed(epoch);
+        self
+    }
+
+    pub fn build(mut self) -> Result<System> {
+        if let Some(ref root) = self.fdt {
+            if !self.processor_cfgs.is_empty() {
+                return Err(Error::ConfigErr("processors are described by device tree!".to_string()));
+            }
+            let (timer_freq, cfgs) = fdt_machine::processors(root)?;
+            self.timer_freq = timer_freq;
+            self.processor_cfgs = cfgs;
+        }
+        if self.processor_cfgs.is_empty() {
+            return Err(Error::ConfigErr("at least one processor is required!".to_string()));
+        }
+        let htif_args = match (self.htif_args, &self.elf) {
+            (Some(args), _) => args,
+            (None, Some(ElfSource::File(file))) => vec![file.clone()],
+            _ => vec!["elf".to_string()]
+        };
+        let elf = match self.elf {
+            Some(ElfSource::File(file)) => Some(ElfLoader::new(&file).map_err(|e| { Error::ElfErr(e) })?),
+            Some(ElfSource::Bytes(content)) => Some(ElfLoader::from_bytes(content).map_err(|e| { Error::ElfErr(e) })?),
+            None => None
+        };
+        let mut sys = System {
+            name: self.name,
+            bus: Arc::new(Bus::new()),
+            timer: Arc::new(Timer::new(self.timer_freq)),
             elf,
+            elf_virtual_addr: self.elf_virtual_addr,
             processors: vec![],
+            mem_hierarchy: None,
+            bootargs: self.bootargs,
+            stdout_path: self.stdout_path,
+            initrd: None,
+            dtb: None,
+            fdt: None,
+            exit: ExitCode::default(),
+            reset_req: ResetRequest::default(),
+            boot: None,
+            images: Mutex::new(vec![]),
+            rtc_clock: self.rtc_clock,
+            memories: Mutex::new(vec![]),
+            framebuffer: None,
+            htif_proxy: None,
+            devices: vec![],
         };
-        sys.try_register_htif();
-        for cfg in processor_cfgs {
-            sys.new_processor(cfg)
+        if let Some(port) = sys.try_register_htif()? {
+            sys.htif_proxy = Some(HtifProxy::new(port, &sys.exit, &self.htif_root, &htif_args))
+        }
+        for cfg in self.processor_cfgs {
+            sys.new_processor(cfg)?
+        }
+        if let Some(root) = self.fdt {
+            fdt_machine::map(&mut sys, &root)?;
+            sys.fdt = Some(root)
+        }
+        Ok(sys)
+    }
+}
+
+impl System {
+    //build, load and reset a system described by cfg
+    pub fn from_config(cfg: &MachineCfg) -> Result<System> {
+        cfg.validate().map_err(|e| { Error::ConfigErr(e) })?;
+        let mut builder = SystemBuilder::new(&cfg.name).timer_freq(cfg.timer_freq);
+        if let Some(epoch) = cfg.rtc_epoch {
+            builder = builder.rtc_epoch(epoch)
+        }
+        for hart in cfg.harts.iter() {
+            let p = ProcessorCfg::from_isa(&hart.isa, hart.freq).map_err(|e| { Error::ConfigErr(e) })?;
+            builder = builder.processors(vec![p; hart.count]);
+        }
+        if let Some(ref elf) = cfg.boot.elf {
+            builder = builder.elf_file(elf).elf_virtual_addr(cfg.boot.elf_virtual_addr)
+        }
+        if let Some(ref bootargs) = cfg.boot.bootargs {
+            builder = builder.bootargs(bootargs)
         }
-        sys
+        if let Some(ref root) = cfg.boot.htif_root {
+            builder = builder.htif_root(root)
+        }
+        if let Some(ref path) = cfg.boot.stdout_path {
+            builder = builder.stdout_path(path)
+        }
+        let mut sys = builder.build()?;
+        for (i, m) in cfg.memories.iter().enumerate() {
+            let name = cfg.memory_name(i);
+            let mem = GHEAP.alloc(m.size, 1).map_err(|e| { Error::ConfigErr(format!("{} alloc fail! {:?}", name, e)) })?;
+            sys.register_memory(&name, m.base, &mem)?;
+        }
+        //interrupt controllers first, devices are wired to them
+        for (i, d) in cfg.devices.iter().enumerate() {
+            let name = cfg.device_name(i);
+            let num_sources = cfg.num_sources(i) as usize;
+            match d.kind.as_str() {
+                "plic" => {
+                    let contexts = sys.processors.iter().enumerate().flat_map(|(hartid, p)| {
+                        let eirq = p.state().eirq().clone();
+                        vec![Some((hartid, eirq.clone(), 11)), Some((hartid, eirq, 9))]
+                    }).collect();
+                    sys.register_device(&name, d.base, cfg.device_size(i), Plic::new(num_sources, contexts))?
+                }
+                "aplic" => sys.register_aplic(&name, d.base, Privilege::S, num_sources, None)?,
+                _ => {}
+            }
+        }
+        for (i, d) in cfg.devices.iter().enumerate() {
+            let name = cfg.device_name(i);
+            let irq = match d.irq {
+                Some(id) => {
+                    let parent = cfg.irq_parent(i).map_err(|e| { Error::ConfigErr(e) })?;
+                    Some(sys.irq_line(&cfg.device_name(parent), id as usize)?)
+                }
+                None => None
+            };
+            match d.kind.as_str() {
+                "plic" | "aplic" => {}
+                "clint" => sys.register_device(&name, d.base, cfg.device_size(i), Clint::new(sys.timer()))?,
+                "aclint_mswi" => sys.register_device(&name, d.base, cfg.device_size(i), Mswi::new(sys.timer()))?,
+                "aclint_mtimer" => sys.register_device(&name, d.base, cfg.device_size(i), Mtimer::new(sys.timer()))?,
+                "aclint_sswi" => {
+                    let eirqs = sys.processors.iter().map(|p| { p.state().eirq().clone() }).collect();
+                    sys.register_device(&name, d.base, cfg.device_size(i), Sswi::new(eirqs))?
+                }
+                "syscon" => sys.register_syscon(&name, d.base, cfg.device_size(i))?,
+                "rtc" => sys.register_rtc(&name, d.base, irq)?,
+                "uart" => {
+                    let uart = Ns16550a::new(0, irq);
+                    uart.poll_input(sys.timer(), (sys.timer().freq() as u64 / fdt_machine::UART_POLL_HZ).max(1));
+                    sys.register_device(&name, d.base, cfg.device_size(i), uart)?
+                }
+                _ => return Err(Error::ConfigErr(format!("unknown device type \"{}\"!", d.kind)))
+            }
+        }
+        if let Some(ref dtb) = cfg.boot.dtb {
+            sys.set_dtb(fs::read(dtb)?)?;
+        }
+        if let Some(ref initrd) = cfg.boot.initrd {
+            sys.load_initrd(&initrd.file, initrd.addr)?;
+        }
+        if let Some(base) = cfg.boot.boot_rom {
+            sys.make_boot_rom(base, cfg.boot.entry)?;
+        }
+        if sys.elf.is_some() {
+            sys.load_elf()?;
+        }
+        for image in cfg.boot.images.iter() {
+            let format = image.format.as_ref().map(|f| { ImageFormat::from_name(f) }).transpose().map_err(|e| { Error::ConfigErr(e) })?;
+            sys.load_file(&image.file, format, image.addr)?;
+        }
+        if let Some(dtb_addr) = cfg.boot.sbi {
+            sys.boot_sbi(cfg.boot.entry, dtb_addr)?;
+            return Ok(sys);
+        }
+        //boot rom jumps to entry
+        let reset_vec = if cfg.boot.boot_rom.is_some() {
+            None
+        } else {
+            cfg.boot.entry
+        };
+        let num_harts = sys.processors.len();
+        sys.reset(vec![reset_vec; num_harts])?;
+        Ok(sys)
     }
 
-    fn new_processor(&mut self, config: ProcessorCfg) {
-        let p = Processor::new(self.processors.len(), config, &self.bus, &self.timer().alloc_irq());
-        self.processors.push(p)
+    pub fn set_bootargs(&mut self, bootargs: &str) {
+        self.bootargs = bootargs.to_string()
+    }
+
+    fn new_processor(&mut self, config: ProcessorCfg) -> Result<()> {
+        let p = Processor::new(self.processors.len(), config, &self.bus, &self.timer().alloc_irq()).map_err(|e| { Error::ConfigErr(e) })?;
+        self.processors.push(p);
+        Ok(())
     }
 
     fn register_region(&self, name: &str, base: u64, region: &Arc<Region>) -> Result<()> {
@@ -72,10 +436,16 @@ impl System {
         Ok(())
     }
 
-    fn try_register_htif(&self) {
-        if let Some((base, tohost, fromhost)) = self.elf.htif_section().expect("Invalid ELF!") {
-            self.register_region("htif", base, &Region::io(0, 0x1000, Box::new(HTIF::new(tohost, fromhost)))).unwrap();
+    fn try_register_htif(&self) -> Result<Option<HTIFSyscallPort>> {
+        if let Some(ref elf) = self.elf {
+            if let Some((base, tohost, fromhost)) = elf.htif_section().map_err(|e| { Error::ElfErr(e) })? {
+                let htif = HTIF::new(tohost, fromhost);
+                let port = htif.syscall_port();
+                self.register_region("htif", base, &Region::io(0, 0x1000, Box::new(htif)))?;
+                return Ok(Some(port));
+            }
         }
+        Ok(None)
     }
 
     pub fn processor(&mut self, hartid: usize) -> Option<&mut Processor> {
@@ -98,18 +468,248 @@ impl System {
         &self.timer
     }
 
-    pub fn register_device<D: IOAccess + 'static>(&self, name: &str, base: u64, size: u64, device: D) -> Result<()> {
-        self.register_region(name, base, &Region::io(0, size, Box::new(device)))
+    //exit code written by guest to htif tohost or reported by emulated environment, none if the guest has not exited
+    pub fn exit_code(&self) -> Option<u64> {
+        if let Some(code) = self.exit.get() {
+            return Some(code);
+        }
+        let (base, tohost, _) = self.elf.as_ref()?.htif_section().ok()??;
+        let mut data: u64 = 0;
+        self.bus.read_u64(&(base + tohost), &mut data).ok()?;
+        if data & 0x1 == 1 {
+            Some(data >> 1)
+        } else {
+            None
+        }
+    }
+
+    //step every hart n instructions at most, and advance timer by the time derived from executed cycles.
+    //steps are shortened to the cycles left before the next timer event, an instruction takes one cycle at least.
+    pub fn step(&mut self, n: usize) {
+        assert!(n > 0);
+        let timer = self.timer.clone();
+        let steps = match timer.next_event() {
+            Some(next) if next > timer.time() => {
+                let target = timer.elapsed() + (next - timer.time());
+                self.processors.iter().map(|p| {
+                    let cycles = *p.state().cycles().borrow();
+                    timer.ticks_to_cycles(target, p.state().config().freq).saturating_sub(cycles)
+                }).min().map_or(n, |s| { min(s, n as u64) as usize })
+            }
+            _ => n
+        };
+        for p in self.processors.iter_mut() {
+            p.step(max(steps, 1))
+        }
+        self.sync()
+    }
+
+    //advance timer to the time of the slowest hart, serve htif proxy and handle reboot requests.
+    //called by step(), harts stepped one by one, e.g. by step_commit(), should call it after every step
+    pub fn sync(&mut self) {
+        if let Some(elapsed) = self.processors.iter().map(|p| {
+            self.timer.cycles_to_ticks(*p.state().cycles().borrow(), p.state().config().freq)
+        }).min() {
+            self.timer.sync(elapsed)
+        }
+        if let Some(ref mut proxy) = self.htif_proxy {
+            proxy.serve(&self.bus)
+        }
+        if self.reset_req.take() {
+            if let Err(e) = self.reboot() {
+                EXIT_CTRL.exit(&format!("reboot fail! {:?}", e)).unwrap()
+            }
+        }
+    }
+
+    //attach cache models to all harts, statistics are reported by mem_hierarchy()
+    pub fn enable_mem_hierarchy(&mut self, cfg: HierarchyCfg) -> Result<()> {
+        let mem_hierarchy = Rc::new(MemHierarchy::new(&self.bus, cfg, self.processors.len()).map_err(|e| { Error::ConfigErr(e) })?);
+        for p in self.processors.iter_mut() {
+            p.set_mem_hierarchy(&mem_hierarchy)
+        }
+        self.mem_hierarchy = Some(mem_hierarchy);
+        Ok(())
+    }
+
+    pub fn mem_hierarchy(&self) -> Option<&Rc<MemHierarchy>> {
+        self.mem_hierarchy.as_ref()
+    }
+
+    //replace the default one-cycle-per-instruction model, f is called with hartid
+    pub fn set_timing_model<F: Fn(usize) -> Box<dyn TimingModel>>(&mut self, f: F) {
+        for p in self.processors.iter_mut() {
+            let hartid = p.state().hartid();
+            p.set_timing_model(f(hartid))
+        }
+    }
+
+    pub fn enable_profiler(&mut self, interval: u64) {
+        for p in self.processors.iter_mut() {
+            p.enable_profiler(interval)
+        }
+    }
+
+    //write folded stacks to "dir/<name>.<privilege>.folded", root frame of each stack is the hart.
+    //symbols are resolved from the loaded elf.
+    pub fn write_profile(&self, dir: &str) -> Result<()> {
+        let symbols = if let Some(ref elf) = self.elf {
+            elf.symbols().map_err(|e| { Error::ElfErr(e) })?
+        } else {
+            SymbolTable::default()
+        };
+        for privilege in [Privilege::M, Privilege::S, Privilege::U].iter() {
+            let mut folded = HashMap::new();
+            for p in self.processors.iter() {
+                if let Some(profiler) = p.profiler() {
+                    profiler.fold(&format!("hart{}", p.state().hartid()), *privilege, &symbols, &mut folded)
+                }
+            }
+            if folded.is_empty() {
+                continue;
+            }
+            let mut lines = folded.iter().map(|(stack, cnt)| { format!("{} {}", stack, cnt) }).collect::<Vec<String>>();
+            lines.sort();
+            let file = Path::new(dir).join(format!("{}.{}.folded", self.name, format!("{:?}", privilege).to_lowercase()));
+            fs::write(file, lines.join("\n") + "\n")?;
+        }
+        Ok(())
+    }
+
+    //devices are reset with harts and described in the generated device tree
+    pub fn register_device<D: Device + IOAccess + Clone + 'static>(&mut self, name: &str, base: u64, size: u64, device: D) -> Result<()> {
+        self.register_region(name, base, &Region::io(0, size, Box::new(device.clone())))?;
+        self.devices.push(DeviceEntry { name: name.to_string(), base, size, device: Box::new(device) });
+        Ok(())
+    }
+
+    //interrupt files of level for all harts, attached to the harts' smaia/ssaia csrs.
+    //num_ids is 64 * n - 1, from 63 to 2047
+    pub fn register_imsic(&mut self, name: &str, base: u64, level: Privilege, num_ids: u32) -> Result<Imsic> {
+        if !(63..=2047).contains(&num_ids) || (num_ids + 1) % 64 != 0 {
+            return Err(Error::ConfigErr(format!("invalid num-ids {} of imsic {}!", num_ids, name)));
+        }
+        let eirqs = self.processors.iter().map(|p| { p.state().eirq().clone() }).collect::<Vec<_>>();
+        let imsic = Imsic::new(level, num_ids, &eirqs);
+        for (p, file) in self.processors.iter().zip(imsic.files().iter()) {
+            p.state().attach_imsic(level, file).map_err(|e| { Error::ConfigErr(e) })?;
+        }
+        self.register_device(name, base, imsic.size(), imsic.clone())?;
+        Ok(imsic)
+    }
+
+    //aplic delivering to harts directly at level, or to the interrupt files of the imsic registered as msi
+    pub fn register_aplic(&mut self, name: &str, base: u64, level: Privilege, num_sources: usize, msi: Option<(&str, &Imsic)>) -> Result<()> {
+        if !(1..=1023).contains(&num_sources) {
+            return Err(Error::ConfigErr(format!("invalid num-sources {} of aplic {}!", num_sources, name)));
+        }
+        let eirqs = self.processors.iter().map(|p| { p.state().eirq().clone() }).collect::<Vec<_>>();
+        let aplic = Aplic::new(num_sources, level, eirqs, msi);
+        self.register_device(name, base, aplic.size(), aplic)
+    }
+
+    //sifive,test finisher, poweroff stops the simulation with the exit code and reset reboots the system
+    pub fn register_syscon(&mut self, name: &str, base: u64, size: u64) -> Result<()> {
+        let syscon = SifiveTest::new(&self.exit, &self.reset_req);
+        self.register_device(name, base, size, syscon)
+    }
+
+    //goldfish rtc following the clock given by SystemBuilder::rtc_epoch
+    pub fn register_rtc(&mut self, name: &str, base: u64, irq: Option<IrqLine>) -> Result<()> {
+        let rtc = GoldfishRtc::new(&self.timer, self.rtc_clock, irq);
+        self.register_device(name, base, RTC_SIZE, rtc)
+    }
+
+    //memories registered so far, dma of devices can not reach memories registered later
+    pub fn guest_memory(&self) -> GuestMemory {
+        let space = self.bus.space();
+        GuestMemory::new(self.memories.lock().unwrap().iter().filter_map(|name| { space.get_region(name) }).collect())
+    }
+
+    //virtio-mmio transport of device, host input of the device is polled every simulated ms
+    pub fn register_virtio(&mut self, name: &str, base: u64, device: Box<dyn VirtioDevice>, irq: Option<IrqLine>) -> Result<VirtioMmio> {
+        let virtio = VirtioMmio::new(device, self.guest_memory(), irq);
+        self.register_device(name, base, 0x1000, virtio.clone())?;
+        virtio.poll(&self.timer, max(self.timer.freq() as u64 / 1000, 1));
+        Ok(virtio)
+    }
+
+    //simple-framebuffer in memory at base, described in /chosen. stride is a line of pixels if none.
+    //a framebuffer inside registered memory uses it, otherwise it gets its own region
+    pub fn register_framebuffer(&mut self, base: u64, width: u32, height: u32, stride: Option<u32>, format: FbFormat) -> Result<Arc<Framebuffer>> {
+        if self.framebuffer.is_some() {
+            return Err(Error::ConfigErr("framebuffer is registered already!".to_string()));
+        }
+        let stride = match stride {
+            Some(stride) => stride,
+            None => width.checked_mul(format.bytes_per_pixel()).ok_or(Error::ConfigErr(format!("framebuffer width {} is too large!", width)))?
+        };
+        let size = stride as u64 * height as u64;
+        let ram = self.bus.space().get_region_by_addr(&base).filter(|r| {
+            base.checked_add(size).map_or(false, |end| { end <= r.info.base + r.info.size }) &&
+                self.memories.lock().unwrap().iter().any(|n| { self.bus.space().get_region(n).map_or(false, |m| { m.info.base == r.info.base }) })
+        });
+        let mem = if let Some(ram) = ram {
+            GuestMemory::new(vec![Region::remap_partial(base, &ram, base - ram.info.base, size)])
+        } else {
+            let region = GHEAP.alloc(size, 1).map_err(|e| { Error::ConfigErr(format!("framebuffer alloc fail! {:?}", e)) })?;
+            self.register_region("framebuffer", base, &region)?;
+            GuestMemory::new(self.bus.space().get_region("framebuffer").into_iter().collect())
+        };
+        let fb = Arc::new(Framebuffer::new(mem, base, width, height, stride, format).map_err(|e| { Error::ConfigErr(e) })?);
+        self.framebuffer = Some(fb.clone());
+        Ok(fb)
+    }
+
+    pub fn framebuffer(&self) -> Option<&Arc<Framebuffer>> {
+        self.framebuffer.as_ref()
+    }
+
+    //clic of the hart, which takes over interrupts of the hart in clic mode
+    pub fn register_clic(&mut self, name: &str, base: u64, hartid: usize, num_ints: usize) -> Result<Clic> {
+        let p = self.processors.get(hartid).ok_or(Error::ConfigErr(format!("hart {} of clic {} does not exist!", hartid, name)))?;
+        let clic = Clic::new(num_ints);
+        p.state().attach_clic(&clic).map_err(|e| { Error::ConfigErr(e) })?;
+        self.register_device(name, base, clic.size(), clic.clone())?;
+        Ok(clic)
+    }
+
+    //line id of the interrupt controller registered as controller
+    pub fn irq_line(&self, controller: &str, id: usize) -> Result<IrqLine> {
+        let sink = self.devices.iter()
+            .find(|d| { d.name == controller })
+            .and_then(|d| { d.device.irq_sink() })
+            .ok_or(Error::ConfigErr(format!("{} is not an interrupt controller!", controller)))?;
+        Ok(IrqLine::new(controller, sink, id))
+    }
+
+    //states of registered devices by name, for snapshots
+    pub fn save_devices(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        if let Some(d) = self.devices.iter().find(|d| { !d.device.can_save() }) {
+            return Err(Error::ConfigErr(format!("device {} can not be saved!", d.name)));
+        }
+        Ok(self.devices.iter().map(|d| { (d.name.clone(), d.device.save()) }).collect())
+    }
+
+    pub fn restore_devices(&self, states: &[(String, Vec<u8>)]) -> Result<()> {
+        for (name, state) in states.iter() {
+            let d = self.devices.iter().find(|d| { &d.name == name }).ok_or(Error::ConfigErr(format!("device {} is not registered!", name)))?;
+            d.device.restore(state).map_err(|e| { Error::LoadErr(format!("{}: {}", name, e)) })?;
+        }
+        Ok(())
     }
 
 
     pub fn register_memory(&self, name: &str, base: u64, mem: &Arc<Region>) -> Result<()> {
         match self.register_region(name, base, &mem) {
-            Ok(_) => { Ok(()) }
+            Ok(_) => {
+                self.memories.lock().unwrap().push(name.to_string());
+                Ok(())
+            }
             Err(e) => {
                 if let Error::SpaceErr(space::Error::Overlap(n, msg)) = e {
                     if n == "htif".to_string() {
-                        let htif_region = self.bus.space().get_region(&n).unwrap();
+                        let htif_region = self.bus.space().get_region(&n).ok_or(Error::ConfigErr(format!("{} is not in memory space!", n)))?;
                         let range0 = if base < htif_region.info.base {
                             Some(MemInfo { base: base, size: htif_region.info.base - base })
                         } else {
@@ -120,12 +720,14 @@ impl System {
                         } else {
                             None
                         };
-                        range0.iter().for_each(|info| {
-                            self.bus.space_mut().add_region(name, &Region::remap_partial(info.base, mem, 0, info.size)).unwrap();
-                        });
-                        range1.iter().for_each(|info| {
-                            self.bus.space_mut().add_region(&format!("{}_1", name), &Region::remap_partial(info.base, mem, info.base - base, info.size)).unwrap();
-                        });
+                        if let Some(info) = range0 {
+                            self.bus.space_mut().add_region(name, &Region::remap_partial(info.base, mem, 0, info.size))?;
+                            self.memories.lock().unwrap().push(name.to_string());
+                        }
+                        if let Some(info) = range1 {
+                            self.bus.space_mut().add_region(&format!("{}_1", name), &Region::remap_partial(info.base, mem, info.base - base, info.size))?;
+                            self.memories.lock().unwrap().push(format!("{}_1", name));
+                        }
                         Ok(())
                     } else {
                         Err(Error::from(space::Error::Overlap(n, msg)))
@@ -138,23 +740,151 @@ impl System {
     }
 
 
+    //data is kept for reboot
+    fn load_bytes(&self, addr: u64, data: &[u8]) -> std::result::Result<(), String> {
+        self.write_bytes(addr, data)?;
+        self.images.lock().unwrap().push(Loaded::Bytes(addr, data.to_vec()));
+        Ok(())
+    }
+
+    fn write_bytes(&self, addr: u64, data: &[u8]) -> std::result::Result<(), String> {
+        fn load(space: &Space, addr: u64, data: &[u8]) -> std::result::Result<(), String> {
+            if data.is_empty() {
+                Ok(())
+            } else {
+                let region = space.get_region_by_addr(&addr).ok_or(format!("not enough memory at {:#x}!", addr))?;
+                let len = min((region.info.base + region.info.size - addr) as usize, data.len());
+                let (head, tails) = data.split_at(len);
+                BytesAccess::write(region.deref(), &addr, head);
+                load(space, region.info.base + region.info.size, tails)
+            }
+        };
+        load(self.bus.space().deref(), addr, data)
+    }
+
+    fn check_elf_xlen(&self, elf: &ElfLoader) -> Result<()> {
+        let xlen = elf.xlen().map_err(|e| { Error::ElfErr(e) })?;
+        if let Some(p) = self.processors.iter().find(|p| { p.state().config().xlen != xlen }) {
+            return Err(Error::ElfErr(format!("rv{} elf can not run on rv{} hart{}!", xlen.len(), p.state().config().xlen.len(), p.state().hartid())));
+        }
+        Ok(())
+    }
+
     pub fn load_elf(&self) -> Result<()> {
-        match self.elf.load(|addr, data| {
-            fn load(space: &Space, addr: u64, data: &[u8]) -> std::result::Result<(), String> {
-                if data.is_empty() {
-                    Ok(())
-                } else {
-                    let region = space.get_region_by_addr(&addr).expect("not enough memory!");
-                    let len = min((region.info.base + region.info.size - addr) as usize, data.len());
-                    let (head, tails) = data.split_at(len);
-                    BytesAccess::write(region.deref(), &addr, head);
-                    load(space, region.info.base + region.info.size, tails)
-                }
-            };
-            load(self.bus.space().deref(), addr, data)
-        }) {
-            Ok(_) => Ok(()),
-            Err(msg) => Err(Error::ElfErr(msg))
+        let elf = self.elf.as_ref().ok_or(Error::ElfErr("no elf is given!".to_string()))?;
+        self.check_elf_xlen(elf)?;
+        elf.load(self.elf_virtual_addr, |addr, data| { self.write_bytes(addr, data) }).map_err(|e| { Error::ElfErr(e) })?;
+        self.images.lock().unwrap().push(Loaded::Elf(self.elf_virtual_addr));
+        Ok(())
+    }
+
+    //entry of harts starting from elf, translated to physical address unless segments are placed at virtual addresses
+    fn elf_entry(&self, elf: &ElfLoader) -> Result<u64> {
+        if self.elf_virtual_addr {
+            elf.entry_point()
+        } else {
+            elf.physical_entry_point()
+        }.map_err(|e| { Error::ElfErr(e) })
+    }
+
+    //copy a raw binary image to physical address addr
+    pub fn load_raw(&self, file: &str, addr: u64) -> Result<()> {
+        self.load_file(file, Some(ImageFormat::Raw), Some(addr))?;
+        Ok(())
+    }
+
+    //load an image file, format is detected if none, refer to image::ImageFormat for addr.
+    //return [start, end) of the image
+    pub fn load_file(&self, file: &str, format: Option<ImageFormat>, addr: Option<u64>) -> Result<(u64, u64)> {
+        let range = self.write_file(file, format, addr)?;
+        self.images.lock().unwrap().push(Loaded::File(file.to_string(), format, addr));
+        Ok(range)
+    }
+
+    fn write_file(&self, file: &str, format: Option<ImageFormat>, addr: Option<u64>) -> Result<(u64, u64)> {
+        let content = fs::read(file).map_err(|e| { Error::LoadErr(format!("{}: {}", file, e)) })?;
+        let format = format.unwrap_or_else(|| { ImageFormat::detect(file, &content) });
+        let image = Image::parse(format, content, addr).map_err(|e| { Error::LoadErr(format!("{}: {}", file, e)) })?;
+        for (addr, data) in image.chunks.iter() {
+            self.write_bytes(*addr, data).map_err(|e| { Error::LoadErr(format!("{}: {}", file, e)) })?;
+        }
+        Ok((image.start().unwrap_or(0), image.end().unwrap_or(0)))
+    }
+
+    //raw initrd reported in /chosen of the generated device tree
+    pub fn load_initrd(&mut self, file: &str, addr: u64) -> Result<()> {
+        let range = self.load_file(file, Some(ImageFormat::Raw), Some(addr))?;
+        self.initrd = Some(range);
+        Ok(())
+    }
+
+    //use the given device tree blob instead of the generated one
+    pub fn set_dtb(&mut self, dtb: Vec<u8>) -> Result<()> {
+        if !dtb.starts_with(&[0xd0, 0x0d, 0xfe, 0xed]) {
+            return Err(Error::FdtErr("invalid device tree blob!".to_string()));
+        }
+        self.dtb = Some(dtb);
+        Ok(())
+    }
+
+    fn dtb(&self) -> Result<Vec<u8>> {
+        if let Some(ref dtb) = self.dtb {
+            Ok(dtb.clone())
+        } else if let Some(ref fdt) = self.fdt {
+            Ok(fdt::compile(&self.chosen_fdt(fdt)))
+        } else {
+            self.compile_fdt()
+        }
+    }
+
+    //describing device tree with boot information in /chosen
+    fn chosen_fdt(&self, fdt: &FdtNode) -> FdtNode {
+        let mut root = fdt.clone();
+        if root.node_mut("chosen").is_none() {
+            root.add_node(FdtNode::new("chosen"))
+        }
+        let chosen = root.node_mut("chosen").unwrap();
+        if chosen.prop("bootargs").is_none() {
+            chosen.add_prop(FdtProp::str_prop("bootargs", vec![&self.bootargs]));
+        }
+        if let Some(ref path) = self.stdout_path {
+            chosen.set_prop(FdtProp::str_prop("stdout-path", vec![path.as_str()]));
+        }
+        if let Some((start, end)) = self.initrd {
+            chosen.set_prop(FdtProp::u64_prop("linux,initrd-start", vec![start]));
+            chosen.set_prop(FdtProp::u64_prop("linux,initrd-end", vec![end]));
+        }
+        if chosen.node("framebuffer").is_none() {
+            self.add_framebuffer(chosen);
+        }
+        root
+    }
+
+    pub fn load_image(&self, addr: u64, data: &[u8]) -> Result<()> {
+        self.load_bytes(addr, data).map_err(|e| { Error::LoadErr(e) })
+    }
+
+    fn entry_point(&self) -> Result<u64> {
+        let elf = self.elf.as_ref().ok_or(Error::ElfErr("no elf is given, entry is required!".to_string()))?;
+        self.elf_entry(elf)
+    }
+
+    //physical memory access bypassing harts and caches
+    pub fn read_mem(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        self.bus.space().read_bytes(&addr, data).map_err(|a| { Error::AccessErr(a) })
+    }
+
+    pub fn write_mem(&self, addr: u64, data: &[u8]) -> Result<()> {
+        self.bus.space().write_bytes(&addr, data).map_err(|a| { Error::AccessErr(a) })
+    }
+
+    //reg of the framebuffer is translated to the root by the empty ranges of /chosen
+    fn add_framebuffer(&self, chosen: &mut FdtNode) {
+        if let Some(ref fb) = self.framebuffer {
+            chosen.set_prop(FdtProp::u32_prop("#address-cells", vec![2]));
+            chosen.set_prop(FdtProp::u32_prop("#size-cells", vec![2]));
+            chosen.set_prop(FdtProp::null_prop("ranges"));
+            chosen.add_node(fb.fdt_node())
         }
     }
 
@@ -166,7 +896,15 @@ impl System {
         root.add_prop(FdtProp::str_prop("model", vec!["ucbbar,terminus-bare"]));
 
         let mut chosen = FdtNode::new("chosen");
-        chosen.add_prop(FdtProp::str_prop("bootargs", vec!["console=hvc0 earlycon=sbi"]));
+        chosen.add_prop(FdtProp::str_prop("bootargs", vec![&self.bootargs]));
+        if let Some(ref path) = self.stdout_path {
+            chosen.add_prop(FdtProp::str_prop("stdout-path", vec![path.as_str()]));
+        }
+        if let Some((start, end)) = self.initrd {
+            chosen.add_prop(FdtProp::u64_prop("linux,initrd-start", vec![start]));
+            chosen.add_prop(FdtProp::u64_prop("linux,initrd-end", vec![end]));
+        }
+        self.add_framebuffer(&mut chosen);
         root.add_node(chosen);
 
         let mut cpus = FdtNode::new("cpus");
@@ -181,6 +919,10 @@ impl System {
             cpu.add_prop(FdtProp::str_prop("status", vec!["okay"]));
             cpu.add_prop(FdtProp::str_prop("compatible", vec!["riscv"]));
             cpu.add_prop(FdtProp::str_prop("riscv,isa", vec![&p.state().isa_string()]));
+            let isa = &p.state().config().isa;
+            cpu.add_prop(FdtProp::str_prop("riscv,isa-base", vec![&format!("rv{}i", isa.xlen().len())]));
+            let exts = isa.fdt_extensions();
+            cpu.add_prop(FdtProp::str_prop("riscv,isa-extensions", exts.iter().map(|e| { e.as_str() }).collect()));
             cpu.add_prop(FdtProp::u32_prop("clock-frequency", vec![p.state().config().freq as u32]));
             match p.state().config().xlen {
                 XLen::X64 => cpu.add_prop(FdtProp::str_prop("mmu-type", vec!["riscv,sv48"])),
@@ -201,7 +943,7 @@ impl System {
             let mut size = main_memory.info.size;
             //because of htif...
             if let Some(main_memory_1) = self.bus.space().get_region("main_memory_1") {
-                let htif_region = self.bus.space().get_region("htif").unwrap();
+                let htif_region = self.bus.space().get_region("htif").ok_or(Error::FdtErr("\"htif\" is not in memory space!".to_string()))?;
                 size += main_memory_1.info.size + htif_region.info.size
             }
             let mut memory = FdtNode::new_with_num("memory", base);
@@ -218,21 +960,34 @@ impl System {
         soc.add_prop(FdtProp::str_prop("compatible", vec!["ucbbar,terminus-bare-soc", "simple-bus"]));
         soc.add_prop(FdtProp::null_prop("range"));
 
-        if let Some(clint_region) = self.bus.space().get_region("clint") {
-            let mut clint = FdtNode::new_with_num("clint", clint_region.info.base);
-            clint.add_prop(FdtProp::str_prop("compatible", vec!["riscv,clint0"]));
-            let mut interrupts_extended = vec![];
-            for p in self.processors.iter() {
-                interrupts_extended.push((p.state().hartid() + 1) as u32);
-                interrupts_extended.push(3 as u32);
-                interrupts_extended.push((p.state().hartid() + 1) as u32);
-                interrupts_extended.push(7 as u32);
+        //phandles of cpu interrupt controllers are hartid + 1, devices follow in registration order
+        let harts = self.processors.iter().map(|p| { (p.state().hartid() + 1) as u32 }).collect::<Vec<_>>();
+        let phandles = self.devices.iter().enumerate().map(|(i, d)| { (d.name.as_str(), (harts.len() + 1 + i) as u32) }).collect::<HashMap<_, _>>();
+        let phandle = |device: &str| -> Result<u32> {
+            phandles.get(device).cloned().ok_or(Error::FdtErr(format!("device {} is not registered!", device)))
+        };
+        for d in self.devices.iter() {
+            if let Some(mut node) = d.device.fdt_node(d.base, d.size, &harts) {
+                node.add_prop(FdtProp::u32_prop("phandle", vec![phandle(&d.name)?]));
+                for (prop, device) in d.device.fdt_refs().iter() {
+                    node.add_prop(FdtProp::u32_prop(prop, vec![phandle(device)?]));
+                }
+                let lines = d.device.irq_lines();
+                if let Some(first) = lines.first() {
+                    if lines.iter().all(|l| { l.controller() == first.controller() }) {
+                        node.add_prop(FdtProp::u32_prop("interrupt-parent", vec![phandle(first.controller())?]));
+                        node.add_prop(FdtProp::u32_prop("interrupts", lines.iter().flat_map(|l| { l.cells() }).collect()));
+                    } else {
+                        let mut interrupts_extended = vec![];
+                        for l in lines.iter() {
+                            interrupts_extended.push(phandle(l.controller())?);
+                            interrupts_extended.extend(l.cells());
+                        }
+                        node.add_prop(FdtProp::u32_prop("interrupts-extended", interrupts_extended));
+                    }
+                }
+                soc.add_node(node)
             }
-            clint.add_prop(FdtProp::u32_prop("interrupts-extended", interrupts_extended));
-            clint.add_prop(FdtProp::u64_prop("reg", vec![clint_region.info.base, clint_region.info.size]));
-            soc.add_node(clint);
-        } else {
-            return Err(Error::FdtErr("\"clint\" is not in memory space!".to_string()));
         }
 
         let mut htif = FdtNode::new("htif");
@@ -245,18 +1000,19 @@ impl System {
         Ok(fdt::compile(&root))
     }
 
-    pub fn make_boot_rom(&mut self, base: u64, entry: u64) -> Result<()> {
-        let start_address = if entry == -1i64 as u64 {
-            self.elf.entry_point().unwrap()
-        } else {
+    //boot rom jumps to entry, or the elf entry if entry is none, with a1 pointing to the device tree in it
+    pub fn make_boot_rom(&mut self, base: u64, entry: Option<u64>) -> Result<()> {
+        let start_address = if let Some(entry) = entry {
             entry
+        } else {
+            self.entry_point()?
         };
-        let mut dtb = self.compile_fdt()?;
+        let mut dtb = self.dtb()?;
         let mut reset_vec: Vec<u32> = vec![
             0x297,                                                            //auipc t0, 0x0
             0,                                                                //placeholder[addi   a1, t0, &dtb]
             0xf1402573,                                                       //csrr   a0, mhartid
-            match self.processor(0).unwrap().state().config().xlen {
+            match self.processors[0].state().config().xlen {
                 XLen::X64 => 0x0182b283,                                      // ld     t0,24(t0)
                 XLen::X32 => 0x0182a283,                                      //lw     t0,24(t0)
             },
@@ -271,31 +1027,144 @@ impl System {
             rom.append(&mut i.to_le_bytes().to_vec());
         }
         rom.append(&mut dtb);
-        let rom_mem = GHEAP.alloc(rom.len() as u64, 1).expect("boot rom alloc fail!");
+        let rom_mem = GHEAP.alloc(rom.len() as u64, 1).map_err(|e| { Error::ConfigErr(format!("boot rom alloc fail! {:?}", e)) })?;
         BytesAccess::write(rom_mem.deref(), &rom_mem.info.base, &rom);
         self.register_memory("boot_rom", base, &rom_mem)?;
         Ok(())
     }
 
-    pub fn reset(&mut self, reset_vecs: Vec<u64>) -> Result<()> {
+    //harts without reset vector start from boot rom if exists, otherwise from elf entry
+    pub fn reset(&mut self, reset_vecs: Vec<Option<u64>>) -> Result<()> {
         if reset_vecs.len() != self.processors.len() {
             return Err(Error::ResetErr(format!("reset_vecs size {} is not match with processor num {}!", reset_vecs.len(), self.processors.len())));
         }
-        let boot_rom = self.bus.space().get_region("boot_rom");
-        let entry_point = self.elf.entry_point().unwrap();
-        for (i, p) in self.processors().iter_mut().enumerate() {
-            if let Err(msg) = if reset_vecs[i] == -1i64 as u64 {
-                if let Some(ref boot_rom) = boot_rom {
-                    p.reset(boot_rom.info.base)
-                } else {
-                    p.reset(entry_point)
+        let default_vec = if let Some(boot_rom) = self.bus.space().get_region("boot_rom") {
+            Some(boot_rom.info.base)
+        } else if let Some(ref elf) = self.elf {
+            Some(self.elf_entry(elf)?)
+        } else {
+            None
+        };
+        for d in self.devices.iter() {
+            d.device.reset()
+        }
+        for (p, reset_vec) in self.processors.iter_mut().zip(reset_vecs.iter()) {
+            let start_address = reset_vec.or(default_vec).ok_or(Error::ResetErr(format!("cpu{}:no reset vector, boot rom or elf!", p.state().hartid())))?;
+            p.reset(start_address).map_err(|e| { Error::ResetErr(e) })?;
+        }
+        self.boot = Some(Boot::Reset(reset_vecs));
+        Ok(())
+    }
+
+    //reset devices, load images again and start harts the way they were booted, memories are not cleared
+    pub fn reboot(&mut self) -> Result<()> {
+        let boot = self.boot.clone().ok_or(Error::ResetErr("the system has not been booted!".to_string()))?;
+        for image in self.images.lock().unwrap().iter() {
+            match image {
+                Loaded::Elf(virtual_addr) => {
+                    let elf = self.elf.as_ref().ok_or(Error::ElfErr("no elf is given!".to_string()))?;
+                    elf.load(*virtual_addr, |addr, data| { self.write_bytes(addr, data) }).map_err(|e| { Error::ElfErr(e) })?;
                 }
-            } else {
-                p.reset(reset_vecs[i])
-            } {
-                return Err(Error::ResetErr(msg));
+                Loaded::File(file, format, addr) => {
+                    self.write_file(file, *format, *addr)?;
+                }
+                Loaded::Bytes(addr, data) => self.write_bytes(*addr, data).map_err(|e| { Error::LoadErr(e) })?
             }
         }
+        match boot {
+            Boot::Reset(reset_vecs) => self.reset(reset_vecs),
+            Boot::Sbi(entry, dtb_addr, sbi) => {
+                sbi.stop_timers();
+                for d in self.devices.iter() {
+                    d.device.reset()
+                }
+                self.start_sbi(entry, dtb_addr)
+            }
+        }
+    }
+
+    //boot supervisor software without m-mode firmware, sbi calls are served by the simulator.
+    //hart 0 starts from entry, or the elf entry if entry is none, in s-mode with a0 = 0 and a1 = dtb_addr,
+    //where the generated device tree is placed. other harts wait for hart_start.
+    pub fn boot_sbi(&mut self, entry: Option<u64>, dtb_addr: u64) -> Result<()> {
+        let dtb = self.dtb()?;
+        self.load_bytes(dtb_addr, &dtb).map_err(|e| { Error::LoadErr(format!("dtb: {}", e)) })?;
+        self.start_sbi(entry, dtb_addr)
+    }
+
+    fn start_sbi(&mut self, entry: Option<u64>, dtb_addr: u64) -> Result<()> {
+        let start_address = if let Some(entry) = entry {
+            entry
+        } else {
+            self.entry_point()?
+        };
+        let eirqs = self.processors.iter().map(|p| { p.state().eirq().clone() }).collect();
+        let sbi = Arc::new(Sbi::new(&self.timer, eirqs, 0, &self.exit, &self.reset_req));
+        for p in self.processors.iter_mut() {
+            p.reset(start_address).map_err(|e| { Error::ResetErr(e) })?;
+            Sbi::attach(&sbi, p, dtb_addr);
+        }
+        self.boot = Some(Boot::Sbi(entry, dtb_addr, sbi));
+        Ok(())
+    }
+
+    //run the elf as a static linux program in u-mode, system calls are served on the host like qemu-user.
+    //memory is registered by this function and mapped 1:1 at linux::USER_BASE, args[0] is the program name.
+    pub fn boot_linux_user(&mut self, args: &[String], envs: &[String]) -> Result<()> {
+        if self.processors.len() != 1 {
+            return Err(Error::ConfigErr("user mode supports exactly one hart!".to_string()));
+        }
+        //stat and uname of the emulated system calls are in rv64 layout
+        if self.processors[0].state().config().xlen != XLen::X64 {
+            return Err(Error::ConfigErr("user mode supports rv64 only!".to_string()));
+        }
+        let exe = args.first().ok_or(Error::ConfigErr("program name is required!".to_string()))?;
+        let mem = GHEAP.alloc(linux::USER_MEM_SIZE, 1).map_err(|e| { Error::ConfigErr(format!("main_memory alloc fail! {:?}", e)) })?;
+        self.register_memory("main_memory", linux::USER_BASE, &mem)?;
+        let elf = self.elf.as_ref().ok_or(Error::ElfErr("no elf is given!".to_string()))?;
+        self.check_elf_xlen(elf)?;
+        //user mode does not reboot, images are not kept
+        let image_end = elf.load(true, |addr, data| { self.write_bytes(addr, data) }).map_err(|e| { Error::ElfErr(e) })?;
+        let entry = elf.entry_point().map_err(|e| { Error::ElfErr(e) })?;
+        let phdrs = elf.program_headers().map_err(|e| { Error::ElfErr(e) })?;
+
+        let mut user = LinuxUser::new(&self.bus, &self.timer, &self.exit, exe, image_end);
+        let p = &mut self.processors[0];
+        p.reset(entry).map_err(|e| { Error::ResetErr(e) })?;
+        if p.state_mut().set_privilege(Privilege::U) != Privilege::U {
+            return Err(Error::ConfigErr("user mode requires \"u\" extension!".to_string()));
+        }
+        //no translation and no pmp restriction, but counters are readable
+        let csrs = p.state().icsrs();
+        csrs.pmpaddr0_mut().set(u64::MAX >> 10);
+        csrs.pmpcfg0_mut().set(0xf);
+        csrs.mcounteren_mut().set(0x7);
+        user.init_stack(p, args, envs, entry, phdrs).map_err(|e| { Error::LoadErr(e) })?;
+        p.set_env(Box::new(user));
+        Ok(())
+    }
+
+    //serve semihosting requests of all harts, host files are confined in root.
+    //heap info reports the heap from the elf image end, and the stack at the top 1MB of main_memory
+    pub fn enable_semihosting(&mut self, root: &str, cmdline: &str) -> Result<()> {
+        const STACK_SIZE: u64 = 0x10_0000;
+        let main_memory = self.bus.space().get_region("main_memory").ok_or(Error::ConfigErr("\"main_memory\" is not in memory space!".to_string()))?;
+        let mem_base = main_memory.info.base;
+        let mem_end = if let Some(main_memory_1) = self.bus.space().get_region("main_memory_1") {
+            main_memory_1.info.base + main_memory_1.info.size
+        } else {
+            mem_base + main_memory.info.size
+        };
+        let heap_base = if let Some(ref elf) = self.elf {
+            max(elf.image_end(self.elf_virtual_addr).map_err(|e| { Error::ElfErr(e) })?, mem_base)
+        } else {
+            mem_base
+        };
+        let stack_limit = max(mem_end.saturating_sub(STACK_SIZE), heap_base);
+        let sh = Arc::new(Semihosting::new(&self.bus, &self.timer, &self.exit, root, cmdline, [heap_base, stack_limit, mem_end, stack_limit]));
+        for p in self.processors.iter_mut() {
+            Semihosting::attach(&sh, p)
+        }
         Ok(())
     }
 }
@@ -308,3 +1177,260 @@ impl Display for System {
 }
 
 
+
+#[test]
+fn system_builder_test() {
+    assert!(matches!(SystemBuilder::new("test").build(), Err(Error::ConfigErr(_))));
+    let cfg = ProcessorCfg::from_isa("rv64imac", 1000000000).unwrap();
+    assert!(matches!(SystemBuilder::new("test").processor(cfg.clone()).elf_bytes(vec![0; 64]).build(), Err(Error::ElfErr(_))));
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    assert!(matches!(sys.reset(vec![None]), Err(Error::ResetErr(_))));
+    //addi a0, a0, 1; j .-4
+    let program = [0x00150513u32, 0xffdff06f].iter().flat_map(|i| { i.to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+    sys.load_image(0x80000000, &program).unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
+    sys.step(10);
+    assert_eq!(*sys.processor(0).unwrap().state().xreg(10), 5);
+}
+
+#[test]
+fn isa_gating_test() {
+    use terminus_global::RegT;
+    //csrr a0, mscratch; fence.i
+    let program = [0x34002573u32, 0x0000100f].iter().flat_map(|i| { i.to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+    let run = |isa: &str, steps: usize| -> (RegT, RegT) {
+        let cfg = ProcessorCfg::from_isa(isa, 1000000000).unwrap();
+        let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+        sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+        sys.load_image(0x80000000, &program).unwrap();
+        sys.reset(vec![Some(0x80000000)]).unwrap();
+        sys.processor(0).unwrap().state().set_csr_backdoor(0x305, 0x80000100).unwrap();
+        sys.step(steps);
+        let state = sys.processor(0).unwrap().state();
+        (*state.pc(), state.icsrs().mcause().get())
+    };
+    assert_eq!(run("rv64im_zifencei", 1), (0x80000100, 2));
+    assert_eq!(run("rv64im_zicsr", 1), (0x80000004, 0));
+    assert_eq!(run("rv64im_zicsr", 2), (0x80000100, 2));
+    assert_eq!(run("rv64im", 2), (0x80000008, 0));
+    assert!(ProcessorCfg::from_isa("rv64imafdc_zicsr_zba_sstc", 1000000000).unwrap_err().contains("not supported"));
+}
+
+#[test]
+fn device_fdt_test() {
+    use crate::devices::plic::Plic;
+    use crate::devices::uart::Ns16550a;
+    let cfg = ProcessorCfg::from_isa("rv64imac", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    let eirq = sys.processor(0).unwrap().state().eirq().clone();
+    sys.register_device("plic", 0xc000000, 0x4000000, Plic::new(16, vec![Some((0, eirq, 11))])).unwrap();
+    let irq = sys.irq_line("plic", 10).unwrap();
+    sys.register_device("uart", 0x10000000, 0x100, Ns16550a::new(0, Some(irq))).unwrap();
+    assert!(matches!(sys.irq_line("uart", 1), Err(Error::ConfigErr(_))));
+    let root = fdt::parse(&sys.compile_fdt().unwrap()).unwrap();
+    let plic = root.find("/soc/plic@c000000").unwrap();
+    assert_eq!(plic.prop("interrupts-extended").unwrap().u32s(), vec![1, 11]);
+    let uart = root.find("/soc/uart@10000000").unwrap();
+    assert_eq!(uart.prop("interrupt-parent").unwrap().u32s(), plic.prop("phandle").unwrap().u32s());
+    assert_eq!(uart.prop("interrupts").unwrap().u32s(), vec![10]);
+    //scratch register
+    sys.write_mem(0x10000007, &[0x5a]).unwrap();
+    let states = sys.save_devices().unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
+    let mut scr = [0u8];
+    sys.read_mem(0x10000007, &mut scr).unwrap();
+    assert_eq!(scr[0], 0);
+    sys.restore_devices(&states).unwrap();
+    sys.read_mem(0x10000007, &mut scr).unwrap();
+    assert_eq!(scr[0], 0x5a);
+}
+
+#[test]
+fn config_irq_test() {
+    let cfg = MachineCfg::from_toml(r#"
+name = "test"
+[[harts]]
+isa = "rv64imac"
+[[memories]]
+base = 0x80000000
+size = 0x1000
+[[devices]]
+type = "clint"
+base = 0x02000000
+[[devices]]
+type = "plic"
+base = 0x0c000000
+num_sources = 16
+[[devices]]
+type = "uart"
+base = 0x10000000
+irq = 10
+[boot]
+entry = 0x80000000
+"#).unwrap();
+    let sys = System::from_config(&cfg).unwrap();
+    let root = fdt::parse(&sys.compile_fdt().unwrap()).unwrap();
+    let plic = root.find("/soc/plic@c000000").unwrap();
+    assert_eq!(plic.prop("interrupts-extended").unwrap().u32s(), vec![1, 11, 1, 9]);
+    let uart = root.find("/soc/uart@10000000").unwrap();
+    assert_eq!(uart.prop("interrupt-parent").unwrap().u32s(), plic.prop("phandle").unwrap().u32s());
+    assert_eq!(uart.prop("interrupts").unwrap().u32s(), vec![10]);
+    //thr interrupt of the uart is pending in the plic
+    sys.write_mem(0x10000001, &[0x2]).unwrap();
+    let mut pending = [0u8; 4];
+    sys.read_mem(0x0c001000, &mut pending).unwrap();
+    assert_eq!(u32::from_le_bytes(pending), 1 << 10);
+}
+
+#[test]
+fn reboot_test() {
+    let cfg = ProcessorCfg::from_isa("rv64imac", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    sys.register_syscon("syscon", 0x100000, 0x1000).unwrap();
+    //lui t0, 0x100; lui t1, 0x7; addi t1, t1, 0x777; sw t1, 0(t0); j .
+    let program = [0x001002b7u32, 0x00007337, 0x77730313, 0x0062a023, 0x0000006f].iter().flat_map(|i| { i.to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+    sys.load_image(0x80000000, &program).unwrap();
+    sys.load_image(0x80000800, &[1, 2, 3, 4]).unwrap();
+    //files are read again
+    let file = std::env::temp_dir().join(format!("terminus_reboot_test_{}", std::process::id()));
+    fs::write(&file, &[5, 6]).unwrap();
+    sys.load_raw(file.to_str().unwrap(), 0x80000900).unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
+    sys.write_mem(0x80000800, &[0; 4]).unwrap();
+    fs::write(&file, &[7, 8]).unwrap();
+    sys.step(4);
+    assert_eq!(*sys.processor(0).unwrap().state().pc(), 0x80000000);
+    let mut data = [0u8; 4];
+    sys.read_mem(0x80000800, &mut data).unwrap();
+    assert_eq!(data, [1, 2, 3, 4]);
+    sys.read_mem(0x80000900, &mut data[..2]).unwrap();
+    assert_eq!(data[..2], [7, 8]);
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn virtio_test() {
+    use crate::devices::plic::Plic;
+    use crate::devices::virtio_rng::{VirtioRng, RngSource};
+    let cfg = ProcessorCfg::from_isa("rv64imac", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    let eirq = sys.processor(0).unwrap().state().eirq().clone();
+    sys.register_device("plic", 0xc000000, 0x4000000, Plic::new(16, vec![Some((0, eirq, 11))])).unwrap();
+    let irq = sys.irq_line("plic", 1).unwrap();
+    sys.register_virtio("rng", 0x10001000, Box::new(VirtioRng::new(RngSource::Seeded(1)).unwrap()), Some(irq)).unwrap();
+    let root = fdt::parse(&sys.compile_fdt().unwrap()).unwrap();
+    let node = root.find("/soc/virtio_mmio@10001000").unwrap();
+    assert_eq!(node.prop("interrupts").unwrap().u32s(), vec![1]);
+    let write = |sys: &System, addr: u64, value: u32| { sys.write_mem(addr, &value.to_le_bytes()).unwrap() };
+    let read = |sys: &System, addr: u64| -> u32 {
+        let mut data = [0; 4];
+        sys.read_mem(addr, &mut data).unwrap();
+        u32::from_le_bytes(data)
+    };
+    assert_eq!(read(&sys, 0x10001008), 4);
+    //one writable buffer of 16 bytes in the queue at 0x80000000
+    write(&sys, 0x80000000, 0x80000800);
+    write(&sys, 0x80000008, 16);
+    write(&sys, 0x8000000c, 2);
+    write(&sys, 0x80000200, 1 << 16);
+    for (reg, value) in [(0x038, 8), (0x080, 0x80000000), (0x090, 0x80000200), (0x0a0, 0x80000400), (0x044, 1), (0x070, 0xf), (0x050, 0)].iter() {
+        write(&sys, 0x10001000 + reg, *value)
+    }
+    assert_eq!(read(&sys, 0x80000400) >> 16, 1);
+    assert_eq!(read(&sys, 0x80000408), 16);
+    assert_eq!(read(&sys, 0x10001060), 1);
+    let mut data = [0; 16];
+    sys.read_mem(0x80000800, &mut data).unwrap();
+    assert_ne!(data, [0; 16]);
+}
+
+#[test]
+fn framebuffer_test() {
+    let cfg = ProcessorCfg::from_isa("rv64imac", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    let fb = sys.register_framebuffer(0x40000000, 4, 2, None, FbFormat::X8R8G8B8).unwrap();
+    assert!(sys.register_framebuffer(0x50000000, 4, 2, None, FbFormat::X8R8G8B8).is_err());
+    //pixels written by harts are scanned out
+    sys.write_mem(0x40000000 + 16, &[0x56, 0x34, 0x12, 0]).unwrap();
+    assert_eq!(&fb.rgb().unwrap()[12..15], &[0x12, 0x34, 0x56]);
+    let root = fdt::parse(&sys.compile_fdt().unwrap()).unwrap();
+    let node = root.find("/chosen/framebuffer@40000000").unwrap();
+    assert_eq!(node.prop("reg").unwrap().u32s(), vec![0, 0x40000000, 0, 32]);
+    assert_eq!(node.prop("stride").unwrap().u32s(), vec![16]);
+    assert!(root.find("/chosen").unwrap().prop("ranges").is_some());
+}
+
+#[test]
+fn clic_test() {
+    let cfg = ProcessorCfg::from_isa("rv64imac_zicsr_smclic", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    sys.register_clic("clic", 0x2800000, 0, 16).unwrap();
+    let insns = |insns: &[u32]| { insns.iter().flat_map(|i| { i.to_le_bytes().to_vec() }).collect::<Vec<u8>>() };
+    //wfi; j .
+    sys.load_image(0x80000000, &insns(&[0x10500073, 0x0000006f])).unwrap();
+    //base of clic mode: csrrsi a0, mnxti, MIE; mret
+    sys.load_image(0x80000100, &insns(&[0x34546573, 0x30200073])).unwrap();
+    //vectored handler: j .
+    sys.load_image(0x80000200, &insns(&[0x0000006f])).unwrap();
+    //mtvt entry of interrupt 5
+    sys.load_image(0x80000828, &0x80000200u64.to_le_bytes()).unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
+    let state = sys.processor(0).unwrap().state();
+    state.set_csr_backdoor(0x305, 0x80000103).unwrap();
+    state.set_csr_backdoor(0x307, 0x80000800).unwrap();
+    state.set_csr_backdoor(0x300, 0x8).unwrap();
+    //nlbits 8, clicintctl is the level. edge triggered interrupts are raised by writing clicintip
+    sys.write_mem(0x2800000, &[8]).unwrap();
+    let raise = |sys: &System, id: u64, shv: bool, ctl: u8| {
+        sys.write_mem(0x2801000 + id * 4, &[0, 1, 0x2 | shv as u8, ctl]).unwrap();
+        sys.write_mem(0x2801000 + id * 4, &[1]).unwrap();
+    };
+    let pc = |sys: &mut System| { *sys.processor(0).unwrap().state().pc() };
+    let csr = |sys: &mut System, id: u32| { sys.processor(0).unwrap().state().csr(id).unwrap() };
+
+    //wfi waits
+    sys.processors()[0].step(1);
+    assert_eq!(pc(&mut sys), 0x80000000);
+
+    //wfi wakes up, and the interrupt with hardware vectoring jumps to its mtvt entry
+    raise(&sys, 5, true, 0x40);
+    sys.processors()[0].step(1);
+    assert_eq!(pc(&mut sys), 0x80000200);
+    assert_eq!(csr(&mut sys, 0x341), 0x80000004);
+    assert_eq!(csr(&mut sys, 0x342), (1 << 63) | (3 << 28) | (1 << 27) | 5);
+    assert_eq!(csr(&mut sys, 0xfb1) >> 24, 0x40);
+    let mut ip = [0u8];
+    sys.read_mem(0x2801000 + 5 * 4, &mut ip).unwrap();
+    assert_eq!(ip[0], 0);
+
+    //lower levels can not preempt the handler, higher ones can
+    sys.processor(0).unwrap().state().set_csr_backdoor(0x300, 0x8).unwrap();
+    raise(&sys, 8, false, 0x20);
+    sys.processors()[0].step(1);
+    assert_eq!(pc(&mut sys), 0x80000200);
+    raise(&sys, 6, false, 0x80);
+    sys.processors()[0].step(1);
+    assert_eq!(pc(&mut sys), 0x80000100);
+    assert_eq!(csr(&mut sys, 0x341), 0x80000200);
+    assert_eq!(csr(&mut sys, 0x342), (1 << 63) | (3 << 28) | (1 << 27) | (0x40 << 16) | 6);
+    assert_eq!(csr(&mut sys, 0xfb1) >> 24, 0x80);
+
+    //mnxti claims interrupt 6 and enables interrupts, 8 is still below the level
+    sys.processors()[0].step(1);
+    assert_eq!(*sys.processor(0).unwrap().state().xreg(10), 0x80000830);
+    assert_eq!(csr(&mut sys, 0x300) & 0x8, 0x8);
+    sys.read_mem(0x2801000 + 6 * 4, &mut ip).unwrap();
+    assert_eq!(ip[0], 0);
+    assert_eq!(pc(&mut sys), 0x80000104);
+
+    //mret restores the level of the preempted handler
+    sys.processors()[0].step(1);
+    assert_eq!(pc(&mut sys), 0x80000200);
+    assert_eq!(csr(&mut sys, 0xfb1) >> 24, 0x40);
+}
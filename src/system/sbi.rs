@@ -0,0 +1,481 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::io::{Read, Write};
+use terminus_spaceport::irq::IrqVec;
+use terminus_spaceport::devices::TERM;
+use terminus_spaceport::EXIT_CTRL;
+use terminus_global::*;
+use crate::devices::clint::{Timer, EventId};
+use crate::processor::{Processor, Privilege};
+use crate::processor::env::{Env, ExitCode, ResetRequest};
+use crate::processor::trap::Exception;
+
+//sbi spec v0.3, error codes
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_FAILED: i64 = -1;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const SBI_SPEC_VERSION: RegT = 3;
+//"term"
+const SBI_IMPL_ID: RegT = 0x7465726d;
+const SBI_IMPL_VERSION: RegT = 1;
+
+const EXT_BASE: RegT = 0x10;
+const EXT_TIME: RegT = 0x54494d45;
+const EXT_IPI: RegT = 0x735049;
+const EXT_RFENCE: RegT = 0x52464e43;
+const EXT_HSM: RegT = 0x48534d;
+const EXT_SRST: RegT = 0x53525354;
+//legacy extensions are 0x0 - 0x8
+const EXT_LEGACY_END: RegT = 0x8;
+
+//events delivered to the target hart when it polls
+const PENDING_IPI: u32 = 1;
+const PENDING_FENCE: u32 = 1 << 1;
+const PENDING_START: u32 = 1 << 2;
+
+//stip is driven by line 2 of eirq
+const STIP_LINE: usize = 2;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum HartStatus {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+}
+
+struct HartState {
+    status: HartStatus,
+    start_addr: RegT,
+    opaque: RegT,
+}
+
+struct SbiHart {
+    eirq: Arc<IrqVec>,
+    timer_event: Mutex<Option<EventId>>,
+    pending: AtomicU32,
+    state: Mutex<HartState>,
+}
+
+//firmware state shared by all harts, harts talk to each other through pending events
+pub struct Sbi {
+    timer: Arc<Timer>,
+    harts: Vec<SbiHart>,
+    exit_code: ExitCode,
+    reset: ResetRequest,
+}
+
+impl Sbi {
+    //eirqs are indexed by hartid, boot_hart is started and the others wait for hart_start.
+    //exit_code is set by system shutdown, and reset is requested by system reboot
+    pub fn new(timer: &Arc<Timer>, eirqs: Vec<Arc<IrqVec>>, boot_hart: usize, exit_code: &ExitCode, reset: &ResetRequest) -> Sbi {
+        Sbi {
+            timer: timer.clone(),
+            harts: eirqs.into_iter().enumerate().map(|(i, eirq)| {
+                SbiHart {
+                    eirq,
+                    timer_event: Mutex::new(None),
+                    pending: AtomicU32::new(0),
+                    state: Mutex::new(HartState {
+                        status: if i == boot_hart { HartStatus::Started } else { HartStatus::Stopped },
+                        start_addr: 0,
+                        opaque: 0,
+                    }),
+                }
+            }).collect(),
+            exit_code: exit_code.clone(),
+            reset: reset.clone(),
+        }
+    }
+
+    //cancel timers of harts, so they do not fire after reboot
+    pub fn stop_timers(&self) {
+        for hart in self.harts.iter() {
+            if let Some(id) = hart.timer_event.lock().unwrap().take() {
+                self.timer.cancel(&id);
+            }
+            hart.eirq.clr_pending(STIP_LINE).unwrap()
+        }
+    }
+
+    //set up a hart reset to the supervisor entry and serve its sbi calls,
+    //the boot hart enters s-mode with a0 = hartid and a1 = dtb_addr, other harts are stopped in m-mode
+    pub fn attach(sbi: &Arc<Sbi>, p: &mut Processor, dtb_addr: RegT) {
+        let hartid = p.state().hartid();
+        let csrs = p.state().icsrs();
+        //delegate all exceptions except ecalls from s-mode and m-mode, and all s-mode interrupts
+        csrs.medeleg_mut().set(0xb1ff);
+        csrs.mideleg_mut().set(0x222);
+        csrs.mie_mut().set(0);
+        csrs.mcounteren_mut().set(0x7);
+        //whole address space is accessible for s-mode and u-mode
+        csrs.pmpaddr0_mut().set(RegT::MAX >> 10);
+        csrs.pmpcfg0_mut().set(0xf);
+        let running = sbi.harts[hartid].state.lock().unwrap().status == HartStatus::Started;
+        if running {
+            p.state_mut().set_privilege(Privilege::S);
+            p.state_mut().set_xreg(10, hartid as RegT);
+            p.state_mut().set_xreg(11, dtb_addr);
+        } else {
+            p.state_mut().set_privilege(Privilege::M);
+        }
+        p.set_env(Box::new(SbiEnv {
+            sbi: sbi.clone(),
+            hartid,
+            running,
+        }))
+    }
+
+    fn set_timer(&self, hartid: usize, time: u64) {
+        let hart = &self.harts[hartid];
+        let mut event = hart.timer_event.lock().unwrap();
+        if let Some(id) = event.take() {
+            self.timer.cancel(&id);
+        }
+        hart.eirq.clr_pending(STIP_LINE).unwrap();
+        if time <= self.timer.time() {
+            hart.eirq.set_pending(STIP_LINE).unwrap();
+        } else if time != u64::MAX {
+            //u64::MAX is used by supervisor to disable timer
+            let eirq = hart.eirq.clone();
+            *event = Some(self.timer.schedule(time, move |_| {
+                eirq.set_pending(STIP_LINE).unwrap()
+            }))
+        }
+    }
+
+    fn send(&self, hartids: &[usize], event: u32) {
+        for hartid in hartids {
+            self.harts[*hartid].pending.fetch_or(event, Ordering::SeqCst);
+        }
+    }
+
+    //harts selected by hart_mask and hart_mask_base, base of all ones selects all harts
+    fn select(&self, mask: RegT, base: RegT, xlen: XLen) -> Result<Vec<usize>, i64> {
+        if base == xlen.mask() {
+            return Ok((0..self.harts.len()).collect());
+        }
+        let mut hartids = vec![];
+        for i in 0..xlen.len() {
+            if (mask >> i) & 1 == 1 {
+                let hartid = base.checked_add(i as RegT).ok_or(SBI_ERR_INVALID_PARAM)? as usize;
+                if hartid >= self.harts.len() {
+                    return Err(SBI_ERR_INVALID_PARAM);
+                }
+                hartids.push(hartid)
+            }
+        }
+        Ok(hartids)
+    }
+
+    fn hart_start(&self, hartid: RegT, start_addr: RegT, opaque: RegT) -> Result<RegT, i64> {
+        let hart = self.harts.get(hartid as usize).ok_or(SBI_ERR_INVALID_PARAM)?;
+        let mut state = hart.state.lock().unwrap();
+        if state.status != HartStatus::Stopped {
+            return Err(SBI_ERR_ALREADY_AVAILABLE);
+        }
+        state.status = HartStatus::StartPending;
+        state.start_addr = start_addr;
+        state.opaque = opaque;
+        hart.pending.fetch_or(PENDING_START, Ordering::SeqCst);
+        Ok(0)
+    }
+
+    fn hart_status(&self, hartid: RegT) -> Result<RegT, i64> {
+        let hart = self.harts.get(hartid as usize).ok_or(SBI_ERR_INVALID_PARAM)?;
+        let status = hart.state.lock().unwrap().status;
+        Ok(status as RegT)
+    }
+
+    fn shutdown(&self, code: u64) {
+        self.exit_code.set(code);
+        EXIT_CTRL.exit("sbi shutdown!").unwrap();
+    }
+}
+
+//sbi calls of a hart, ecalls from s-mode and the time csr are served here instead of m-mode software
+pub struct SbiEnv {
+    sbi: Arc<Sbi>,
+    hartid: usize,
+    running: bool,
+}
+
+impl SbiEnv {
+    fn arg(p: &Processor, i: InsnT) -> RegT {
+        *p.state().xreg(10 + i)
+    }
+
+    fn set_ret(p: &mut Processor, i: InsnT, value: RegT) {
+        let mask = p.state().config().xlen.mask();
+        p.state_mut().set_xreg(10 + i, value & mask)
+    }
+
+    //64-bit argument, split into two registers for rv32
+    fn arg64(p: &Processor, i: InsnT) -> u64 {
+        match p.state().config().xlen {
+            XLen::X64 => Self::arg(p, i),
+            XLen::X32 => Self::arg(p, i) | Self::arg(p, i + 1) << 32,
+        }
+    }
+
+    //legacy extensions pass hart mask by address
+    fn read_hart_mask(p: &Processor, addr: RegT) -> Result<RegT, i64> {
+        if addr == 0 {
+            return Ok(p.state().config().xlen.mask());
+        }
+        match p.state().config().xlen {
+            XLen::X64 => {
+                let mut data: u64 = 0;
+                p.load_store().load_double_word(p.state(), &addr, &mut data, p.mmu()).map_err(|_| { SBI_ERR_INVALID_ADDRESS })?;
+                Ok(data as RegT)
+            }
+            XLen::X32 => {
+                let mut data: u32 = 0;
+                p.load_store().load_word(p.state(), &addr, &mut data, p.mmu()).map_err(|_| { SBI_ERR_INVALID_ADDRESS })?;
+                Ok(data as RegT)
+            }
+        }
+    }
+
+    fn probe(ext: RegT) -> RegT {
+        match ext {
+            EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST => 1,
+            _ if ext <= EXT_LEGACY_END => 1,
+            _ => 0
+        }
+    }
+
+    fn base(&self, p: &Processor, fid: RegT) -> Result<RegT, i64> {
+        let csrs = p.state().icsrs();
+        match fid {
+            0 => Ok(SBI_SPEC_VERSION),
+            1 => Ok(SBI_IMPL_ID),
+            2 => Ok(SBI_IMPL_VERSION),
+            3 => Ok(Self::probe(Self::arg(p, 0))),
+            4 => Ok(csrs.mvendorid().get()),
+            5 => Ok(csrs.marchid().get()),
+            6 => Ok(csrs.mimpid().get()),
+            _ => Err(SBI_ERR_NOT_SUPPORTED)
+        }
+    }
+
+    fn rfence(&self, p: &Processor, fid: RegT) -> Result<RegT, i64> {
+        match fid {
+            //fence.i, sfence.vma, sfence.vma.asid, whole tlb is flushed anyway
+            0..=2 => {
+                let hartids = self.sbi.select(Self::arg(p, 0), Self::arg(p, 1), p.state().config().xlen)?;
+                self.sbi.send(&hartids, PENDING_FENCE);
+                Ok(0)
+            }
+            _ => Err(SBI_ERR_NOT_SUPPORTED)
+        }
+    }
+
+    fn hsm(&mut self, p: &mut Processor, fid: RegT) -> Result<RegT, i64> {
+        match fid {
+            0 => self.sbi.hart_start(Self::arg(p, 0), Self::arg(p, 1), Self::arg(p, 2)),
+            1 => {
+                self.sbi.harts[self.hartid].state.lock().unwrap().status = HartStatus::Stopped;
+                self.running = false;
+                p.state_mut().set_privilege(Privilege::M);
+                Ok(0)
+            }
+            2 => self.sbi.hart_status(Self::arg(p, 0)),
+            //only default retentive suspend, which behaves as wfi
+            3 => if Self::arg(p, 0) & 0xffff_ffff == 0 {
+                Ok(0)
+            } else {
+                Err(SBI_ERR_NOT_SUPPORTED)
+            }
+            _ => Err(SBI_ERR_NOT_SUPPORTED)
+        }
+    }
+
+    fn srst(&self, p: &Processor, fid: RegT) -> Result<RegT, i64> {
+        match (fid, Self::arg(p, 0) & 0xffff_ffff) {
+            //shutdown stops the simulation, reason 1 is system failure. cold and warm reboot reboot the system
+            (0, 0) => {
+                self.sbi.shutdown((Self::arg(p, 1) & 0xffff_ffff == 1) as u64);
+                Ok(0)
+            }
+            (0, 1..=2) => {
+                self.sbi.reset.request();
+                Ok(0)
+            }
+            (0, _) => Err(SBI_ERR_INVALID_PARAM),
+            _ => Err(SBI_ERR_NOT_SUPPORTED)
+        }
+    }
+
+    fn ecall(&mut self, p: &mut Processor) -> Result<RegT, i64> {
+        let ext = *p.state().xreg(17);
+        let fid = *p.state().xreg(16);
+        match ext {
+            EXT_BASE => self.base(p, fid),
+            EXT_TIME if fid == 0 => {
+                self.sbi.set_timer(self.hartid, Self::arg64(p, 0));
+                Ok(0)
+            }
+            EXT_IPI if fid == 0 => {
+                let hartids = self.sbi.select(Self::arg(p, 0), Self::arg(p, 1), p.state().config().xlen)?;
+                self.sbi.send(&hartids, PENDING_IPI);
+                Ok(0)
+            }
+            EXT_RFENCE => self.rfence(p, fid),
+            EXT_HSM => self.hsm(p, fid),
+            EXT_SRST => self.srst(p, fid),
+            _ => Err(SBI_ERR_NOT_SUPPORTED)
+        }
+    }
+
+    //legacy extensions return error or value in a0 only
+    fn legacy_ecall(&mut self, p: &mut Processor) -> RegT {
+        let ext = *p.state().xreg(17);
+        let result = match ext {
+            0 => {
+                self.sbi.set_timer(self.hartid, Self::arg64(p, 0));
+                Ok(0)
+            }
+            1 => {
+                let stdout = TERM.stdout();
+                let mut handle = stdout.lock();
+                handle.write_all(&[Self::arg(p, 0) as u8]).and_then(|_| { handle.flush() }).map(|_| { 0 }).map_err(|_| { SBI_ERR_FAILED })
+            }
+            2 => {
+                let mut data = [0u8; 1];
+                //-1 if no input
+                TERM.stdin().lock().read_exact(&mut data).map(|_| { data[0] as RegT }).map_err(|_| { SBI_ERR_FAILED })
+            }
+            3 => {
+                p.state().icsrs().mip_mut().set_ssip(0);
+                Ok(0)
+            }
+            4..=7 => Self::read_hart_mask(p, Self::arg(p, 0)).and_then(|mask| {
+                let hartids = self.sbi.select(mask, 0, p.state().config().xlen)?;
+                self.sbi.send(&hartids, if ext == 4 { PENDING_IPI } else { PENDING_FENCE });
+                Ok(0)
+            }),
+            _ => {
+                self.sbi.shutdown(0);
+                Ok(0)
+            }
+        };
+        match result {
+            Ok(v) => v,
+            Err(e) => e as RegT
+        }
+    }
+
+    //emulate "csrr rd, time" and "csrr rd, timeh" with mtime
+    fn emulate_time(&self, p: &mut Processor, ir: InsnT) -> bool {
+        let csr = ir >> 20;
+        let rd = (ir >> 7) & 0x1f;
+        let is_csrrs_x0 = ir & 0x7f == 0x73 && (ir >> 12) & 0x7 == 2 && (ir >> 15) & 0x1f == 0;
+        let time = self.sbi.timer.time();
+        let value = match (csr, p.state().config().xlen) {
+            (0xc01, XLen::X64) => time,
+            (0xc01, XLen::X32) => time & 0xffff_ffff,
+            (0xc81, XLen::X32) => time >> 32,
+            _ => return false
+        };
+        if !is_csrrs_x0 || *p.state().privilege() == Privilege::M {
+            return false;
+        }
+        p.state_mut().set_xreg(rd, value);
+        let pc = *p.state().pc();
+        p.state_mut().set_pc(pc + 4);
+        true
+    }
+}
+
+impl Env for SbiEnv {
+    fn poll(&mut self, p: &mut Processor) -> bool {
+        let hart = &self.sbi.harts[self.hartid];
+        if self.running && hart.pending.load(Ordering::Relaxed) == 0 {
+            return true;
+        }
+        let pending = hart.pending.swap(0, Ordering::SeqCst);
+        if pending & PENDING_START != 0 {
+            let mut state = hart.state.lock().unwrap();
+            state.status = HartStatus::Started;
+            p.state_mut().set_pc(state.start_addr);
+            p.state_mut().set_privilege(Privilege::S);
+            p.state_mut().set_xreg(10, self.hartid as RegT);
+            p.state_mut().set_xreg(11, state.opaque);
+            p.state().scsrs().satp_mut().set(0);
+            p.state().icsrs().mie_mut().set(0);
+            self.running = true;
+        }
+        //events to a stopped hart are dropped
+        if self.running {
+            if pending & PENDING_IPI != 0 {
+                p.state().icsrs().mip_mut().set_ssip(1);
+            }
+            if pending & PENDING_FENCE != 0 {
+                p.mmu().flush_tlb();
+                p.fetcher().flush_icache();
+            }
+        }
+        self.running
+    }
+
+    fn trap(&mut self, p: &mut Processor, e: &Exception) -> bool {
+        match e {
+            Exception::SCall => {
+                let ext = *p.state().xreg(17);
+                if ext <= EXT_LEGACY_END {
+                    let ret = self.legacy_ecall(p);
+                    Self::set_ret(p, 0, ret);
+                } else {
+                    let (error, value) = match self.ecall(p) {
+                        Ok(v) => (SBI_SUCCESS, v),
+                        Err(e) => (e, 0)
+                    };
+                    Self::set_ret(p, 0, error as RegT);
+                    Self::set_ret(p, 1, value);
+                }
+                //hart_stop does not return
+                if self.running {
+                    let pc = *p.state().pc();
+                    p.state_mut().set_pc(pc + 4);
+                }
+                true
+            }
+            Exception::IllegalInsn(ir) => self.emulate_time(p, *ir),
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::system::SystemBuilder;
+#[cfg(test)]
+use crate::processor::ProcessorCfg;
+#[cfg(test)]
+use crate::devices::clint::Clint;
+#[cfg(test)]
+use terminus_spaceport::memory::region::GHEAP;
+
+#[test]
+fn sbi_boot_test() {
+    let cfg = ProcessorCfg::from_isa("rv64imacsu", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processors(vec![cfg; 2]).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x10000, 1).unwrap()).unwrap();
+    sys.register_device("clint", 0x02000000, 0x000c0000, Clint::new(sys.timer())).unwrap();
+    //li a7, 0x10; li a6, 0; ecall; csrr a2, time; j .
+    let program = [0x01000893u32, 0x00000813, 0x00000073, 0xc0102673, 0x0000006f].iter().flat_map(|i| { i.to_le_bytes().to_vec() }).collect::<Vec<u8>>();
+    sys.load_image(0x80000000, &program).unwrap();
+    sys.boot_sbi(Some(0x80000000), 0x80008000).unwrap();
+    sys.step(10);
+    let p = sys.processor(0).unwrap();
+    assert_eq!(*p.state().privilege(), Privilege::S);
+    assert_eq!(*p.state().xreg(10), SBI_SUCCESS as RegT);
+    assert_eq!(*p.state().xreg(11), SBI_SPEC_VERSION);
+    assert_eq!(*p.state().pc(), 0x80000010);
+    //hart 1 waits for hart_start
+    assert_eq!(*sys.processor(1).unwrap().state().insns_cnt().borrow(), 0);
+    assert!(sys.exit_code().is_none());
+}
@@ -0,0 +1,367 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::min;
+use terminus_global::*;
+use terminus_spaceport::EXIT_CTRL;
+use crate::devices::bus::Bus;
+use crate::devices::clint::Timer;
+use crate::processor::Processor;
+use crate::processor::env::{Env, ExitCode};
+use crate::processor::trap::Exception;
+use crate::system::hostfs::{self, Fd, FdTable, EBADF, EFAULT, EINVAL, ENOSYS, ERANGE, O_WRONLY, O_RDWR, O_CREAT, O_TRUNC, O_APPEND};
+#[cfg(test)]
+use crate::system::SystemBuilder;
+#[cfg(test)]
+use crate::processor::ProcessorCfg;
+#[cfg(test)]
+use terminus_spaceport::memory::region::GHEAP;
+#[cfg(test)]
+use std::fs;
+
+//riscv semihosting, an ebreak between "slli x0, x0, 0x1f" and "srai x0, x0, 7" requests operation a0
+//with parameter a1, the result is returned in a0. operations follow arm semihosting, fields of parameter
+//blocks are xlen wide.
+const SLLI_X0: u32 = 0x01f01013;
+const EBREAK: u32 = 0x00100073;
+const SRAI_X0: u32 = 0x40705013;
+
+const SYS_OPEN: RegT = 0x01;
+const SYS_CLOSE: RegT = 0x02;
+const SYS_WRITEC: RegT = 0x03;
+const SYS_WRITE0: RegT = 0x04;
+const SYS_WRITE: RegT = 0x05;
+const SYS_READ: RegT = 0x06;
+const SYS_READC: RegT = 0x07;
+const SYS_ISTTY: RegT = 0x09;
+const SYS_SEEK: RegT = 0x0a;
+const SYS_FLEN: RegT = 0x0c;
+const SYS_CLOCK: RegT = 0x10;
+const SYS_TIME: RegT = 0x11;
+const SYS_ERRNO: RegT = 0x13;
+const SYS_GET_CMDLINE: RegT = 0x15;
+const SYS_HEAPINFO: RegT = 0x16;
+const SYS_EXIT: RegT = 0x18;
+const SYS_EXIT_EXTENDED: RegT = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: RegT = 0x20026;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+struct Files {
+    fds: FdTable,
+    //host errno of the last failed operation
+    errno: i64,
+}
+
+//semihosting state shared by all harts
+pub struct Semihosting {
+    bus: Arc<Bus>,
+    timer: Arc<Timer>,
+    exit_code: ExitCode,
+    //host files are confined in root
+    root: PathBuf,
+    cmdline: String,
+    //heap base, heap limit, stack base, stack limit
+    heap_info: [u64; 4],
+    files: Mutex<Files>,
+    exited: AtomicBool,
+}
+
+impl Semihosting {
+    pub fn new(bus: &Arc<Bus>, timer: &Arc<Timer>, exit_code: &ExitCode, root: &str, cmdline: &str, heap_info: [u64; 4]) -> Semihosting {
+        Semihosting {
+            bus: bus.clone(),
+            timer: timer.clone(),
+            exit_code: exit_code.clone(),
+            root: PathBuf::from(root),
+            cmdline: cmdline.to_string(),
+            heap_info,
+            files: Mutex::new(Files { fds: FdTable::new(), errno: 0 }),
+            exited: AtomicBool::new(false),
+        }
+    }
+
+    //serve semihosting requests of the hart, other ebreaks trap as usual
+    pub fn attach(sh: &Arc<Semihosting>, p: &mut Processor) {
+        p.set_env(Box::new(SemihostingEnv(sh.clone())))
+    }
+
+    //guest virtual memory is accessed page by page with the translation of the hart
+    fn for_pages<F: FnMut(u64, usize, usize) -> Result<(), u64>>(p: &Processor, addr: RegT, len: usize, mut f: F) -> Result<(), i64> {
+        let mut offset = 0;
+        while offset < len {
+            let va = addr + offset as RegT;
+            let n = min(len - offset, (PAGE_SIZE - (va & (PAGE_SIZE - 1))) as usize);
+            let pa = p.debug_translate(va).ok_or(EFAULT)?;
+            f(pa, offset, offset + n).map_err(|_| { EFAULT })?;
+            offset += n
+        }
+        Ok(())
+    }
+
+    fn read_mem(&self, p: &Processor, addr: RegT, data: &mut [u8]) -> Result<(), i64> {
+        Self::for_pages(p, addr, data.len(), |pa, start, end| { self.bus.space().read_bytes(&pa, &mut data[start..end]) })
+    }
+
+    fn write_mem(&self, p: &Processor, addr: RegT, data: &[u8]) -> Result<(), i64> {
+        Self::for_pages(p, addr, data.len(), |pa, start, end| { self.bus.space().write_bytes(&pa, &data[start..end]) })
+    }
+
+    fn read_word(&self, p: &Processor, addr: RegT) -> Result<RegT, i64> {
+        let mut bytes = [0u8; 8];
+        self.read_mem(p, addr, &mut bytes[..p.state().config().xlen.size()])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write_words(&self, p: &Processor, addr: RegT, values: &[RegT]) -> Result<(), i64> {
+        let size = p.state().config().xlen.size();
+        self.write_mem(p, addr, &values.iter().flat_map(|v| { v.to_le_bytes()[..size].to_vec() }).collect::<Vec<u8>>())
+    }
+
+    fn field(&self, p: &Processor, param: RegT, i: usize) -> Result<RegT, i64> {
+        self.read_word(p, param + (i * p.state().config().xlen.size()) as RegT)
+    }
+
+    fn read_str(&self, p: &Processor, addr: RegT) -> Result<Vec<u8>, i64> {
+        let mut bytes = vec![];
+        let mut c = [0u8; 1];
+        loop {
+            self.read_mem(p, addr + bytes.len() as RegT, &mut c)?;
+            if c[0] == 0 {
+                return Ok(bytes);
+            }
+            bytes.push(c[0]);
+        }
+    }
+
+    fn is_request(&self, p: &Processor) -> bool {
+        let pc = *p.state().pc();
+        if p.state().ir() != EBREAK || pc < 4 {
+            return false;
+        }
+        let mut insns = [0u8; 12];
+        self.read_mem(p, pc - 4, &mut insns).is_ok() && insns[..4] == SLLI_X0.to_le_bytes() && insns[8..] == SRAI_X0.to_le_bytes()
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, i64> {
+        hostfs::confine(&self.root, Path::new(name))
+    }
+
+    //mode is the index of fopen mode in "r", "rb", "r+", "r+b", "w", "wb", "w+", "w+b", "a", "ab", "a+", "a+b".
+    //":tt" opens stdin for read, stdout for write and stderr for append
+    fn open(&self, p: &Processor, param: RegT) -> Result<RegT, i64> {
+        let (addr, mode, len) = (self.field(p, param, 0)?, self.field(p, param, 1)?, self.field(p, param, 2)?);
+        if mode >= 12 {
+            return Err(EINVAL);
+        }
+        let mut name = vec![0u8; min(len, 4096) as usize];
+        self.read_mem(p, addr, &mut name)?;
+        let name = String::from_utf8(name).map_err(|_| { EINVAL })?;
+        let mut files = self.files.lock().unwrap();
+        if name == ":tt" {
+            return files.fds.dup(mode >> 2, None);
+        }
+        let access = if mode & 0x2 != 0 {
+            O_RDWR
+        } else if mode >> 2 == 0 {
+            0
+        } else {
+            O_WRONLY
+        };
+        let flags = match mode >> 2 {
+            0 => access,
+            1 => access | O_CREAT | O_TRUNC,
+            _ => access | O_CREAT | O_APPEND,
+        };
+        let path = self.path(&name)?;
+        let file = hostfs::open(&path, flags, 0o644)?;
+        Ok(files.fds.alloc(Fd::File(file, path)))
+    }
+
+    fn exit(&self, reason: RegT, subcode: RegT) -> Result<RegT, i64> {
+        let code = if reason == ADP_STOPPED_APPLICATION_EXIT {
+            subcode
+        } else {
+            1
+        };
+        self.exited.store(true, Ordering::SeqCst);
+        self.exit_code.set(code);
+        EXIT_CTRL.exit(&format!("semihosting exit with {}!", code)).unwrap();
+        Ok(0)
+    }
+
+    fn call(&self, p: &Processor, op: RegT, param: RegT) -> Result<RegT, i64> {
+        match op {
+            SYS_OPEN => self.open(p, param),
+            SYS_CLOSE => self.files.lock().unwrap().fds.close(self.field(p, param, 0)?),
+            SYS_WRITEC => {
+                let mut c = [0u8; 1];
+                self.read_mem(p, param, &mut c)?;
+                Fd::Stdout.write(&c)?;
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                Fd::Stdout.write(&self.read_str(p, param)?)?;
+                Ok(0)
+            }
+            //bytes not written
+            SYS_WRITE => {
+                let (fd, addr, len) = (self.field(p, param, 0)?, self.field(p, param, 1)?, self.field(p, param, 2)?);
+                let mut data = vec![0u8; min(len, 0x10_0000) as usize];
+                self.read_mem(p, addr, &mut data)?;
+                let n = self.files.lock().unwrap().fds.get(fd)?.write(&data)?;
+                Ok(len - n as RegT)
+            }
+            //bytes not read
+            SYS_READ => {
+                let (fd, addr, len) = (self.field(p, param, 0)?, self.field(p, param, 1)?, self.field(p, param, 2)?);
+                let mut data = vec![0u8; min(len, 0x10_0000) as usize];
+                let n = self.files.lock().unwrap().fds.get(fd)?.read(&mut data)?;
+                self.write_mem(p, addr, &data[..n])?;
+                Ok(len - n as RegT)
+            }
+            SYS_READC => {
+                let mut c = [0u8; 1];
+                Fd::Stdin.read(&mut c)?;
+                Ok(c[0] as RegT)
+            }
+            SYS_ISTTY => match self.files.lock().unwrap().fds.get(self.field(p, param, 0)?)? {
+                Fd::File(..) => Ok(0),
+                _ => Ok(1)
+            },
+            SYS_SEEK => {
+                let (fd, pos) = (self.field(p, param, 0)?, self.field(p, param, 1)?);
+                self.files.lock().unwrap().fds.get(fd)?.seek(pos, 0).map(|_| { 0 })
+            }
+            SYS_FLEN => {
+                let meta = self.files.lock().unwrap().fds.get(self.field(p, param, 0)?)?.metadata()?;
+                meta.map(|m| { m.len() }).ok_or(EBADF)
+            }
+            //centiseconds since start
+            SYS_CLOCK => Ok((self.timer.time() as u128 * 100 / self.timer.freq() as u128) as RegT),
+            SYS_TIME => Ok(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| { d.as_secs() }).unwrap_or(0)),
+            SYS_ERRNO => Ok(self.files.lock().unwrap().errno as RegT),
+            //the length field is updated to the length of cmdline
+            SYS_GET_CMDLINE => {
+                let (addr, len) = (self.field(p, param, 0)?, self.field(p, param, 1)?);
+                if self.cmdline.len() as RegT >= len {
+                    return Err(ERANGE);
+                }
+                self.write_mem(p, addr, format!("{}\0", self.cmdline).as_bytes())?;
+                self.write_words(p, param + p.state().config().xlen.size() as RegT, &[self.cmdline.len() as RegT])?;
+                Ok(0)
+            }
+            //param points to the address of the result block
+            SYS_HEAPINFO => {
+                let block = self.read_word(p, param)?;
+                self.write_words(p, block, &self.heap_info)?;
+                Ok(0)
+            }
+            //param is the reason itself on rv32
+            SYS_EXIT => match p.state().config().xlen {
+                XLen::X64 => self.exit(self.field(p, param, 0)?, self.field(p, param, 1)?),
+                XLen::X32 => self.exit(param, 0),
+            },
+            SYS_EXIT_EXTENDED => self.exit(self.field(p, param, 0)?, self.field(p, param, 1)?),
+            _ => Err(ENOSYS)
+        }
+    }
+}
+
+struct SemihostingEnv(Arc<Semihosting>);
+
+impl Env for SemihostingEnv {
+    fn poll(&mut self, _: &mut Processor) -> bool {
+        !self.0.exited.load(Ordering::Relaxed)
+    }
+
+    fn trap(&mut self, p: &mut Processor, e: &Exception) -> bool {
+        if let Exception::Breakpoint = e {
+            if !self.0.is_request(p) {
+                return false;
+            }
+            let (op, param) = (*p.state().xreg(10), *p.state().xreg(11));
+            let ret = match self.0.call(p, op, param) {
+                Ok(v) => v,
+                Err(e) => {
+                    self.0.files.lock().unwrap().errno = e;
+                    -1i64 as RegT
+                }
+            };
+            let mask = p.state().config().xlen.mask();
+            let pc = *p.state().pc();
+            p.state_mut().set_xreg(10, ret & mask);
+            p.state_mut().set_pc(pc + 4);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn semihosting_test() {
+    let cfg = ProcessorCfg::from_isa("rv64imac", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    //li a0, SYS_HEAPINFO; auipc a1, 0; addi a1, a1, 0x104; slli x0, x0, 0x1f; ebreak; srai x0, x0, 7;
+    //li a0, SYS_EXIT; auipc a1, 0; addi a1, a1, 0xf4; slli x0, x0, 0x1f; ebreak; srai x0, x0, 7; ebreak
+    let program = [0x01600513u32, 0x00000597, 0x10458593, SLLI_X0, EBREAK, SRAI_X0,
+        0x01800513, 0x00000597, 0x0f458593, SLLI_X0, EBREAK, SRAI_X0, EBREAK];
+    sys.load_image(0x80000000, &program.iter().flat_map(|i| { i.to_le_bytes().to_vec() }).collect::<Vec<u8>>()).unwrap();
+    //heap info pointer, exit block {ADP_Stopped_ApplicationExit, 3}
+    sys.load_image(0x80000108, &0x80000200u64.to_le_bytes()).unwrap();
+    sys.load_image(0x80000110, &[ADP_STOPPED_APPLICATION_EXIT.to_le_bytes(), 3u64.to_le_bytes()].concat()).unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
+    sys.enable_semihosting(".", "test").unwrap();
+    sys.step(20);
+    assert_eq!(sys.exit_code(), Some(3));
+    let mut heap_info = [0u8; 8];
+    sys.read_mem(0x80000200, &mut heap_info).unwrap();
+    assert_eq!(u64::from_le_bytes(heap_info), 0x80000000);
+}
+
+#[test]
+fn semihosting_file_test() {
+    let root = std::env::temp_dir().join(format!("terminus_semihosting_test_{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    std::os::unix::fs::symlink(std::env::temp_dir(), root.join("out")).unwrap();
+    let cfg = ProcessorCfg::from_isa("rv64imac", 1000000000).unwrap();
+    let mut sys = SystemBuilder::new("test").processor(cfg).build().unwrap();
+    sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x1000, 1).unwrap()).unwrap();
+    sys.reset(vec![Some(0x80000000)]).unwrap();
+    let sh = Semihosting::new(sys.bus(), sys.timer(), &ExitCode::default(), root.to_str().unwrap(), "", [0; 4]);
+    let bus = sys.bus().clone();
+    let p = &sys.processors()[0];
+    //names at 0x80000100, data at 0x80000200, parameter block at 0x80000300
+    bus.space().write_bytes(&0x80000100, b"a.txt\0../a.txt\0out/a.txt").unwrap();
+    bus.space().write_bytes(&0x80000200, b"hello").unwrap();
+    let call = |op: RegT, block: &[u64]| {
+        bus.space().write_bytes(&0x80000300, &block.iter().flat_map(|w| { w.to_le_bytes().to_vec() }).collect::<Vec<u8>>()).unwrap();
+        sh.call(p, op, 0x80000300)
+    };
+
+    //"w+b"
+    let fd = call(SYS_OPEN, &[0x80000100, 7, 5]).unwrap();
+    assert_eq!(call(SYS_WRITE, &[fd, 0x80000200, 5]), Ok(0));
+    assert_eq!(call(SYS_FLEN, &[fd]), Ok(5));
+    assert_eq!(call(SYS_SEEK, &[fd, 1]), Ok(0));
+    assert_eq!(call(SYS_READ, &[fd, 0x80000400, 8]), Ok(4));
+    let mut data = [0u8; 4];
+    bus.space().read_bytes(&0x80000400, &mut data).unwrap();
+    assert_eq!(&data, b"ello");
+    assert_eq!(call(SYS_ISTTY, &[fd]), Ok(0));
+    assert_eq!(call(SYS_CLOSE, &[fd]), Ok(0));
+    assert_eq!(call(SYS_READ, &[fd, 0x80000400, 8]), Err(EBADF));
+    assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"hello");
+
+    //"r" of a.txt, then ".." and a symbolic link can not leave root
+    let fd = call(SYS_OPEN, &[0x80000100, 0, 5]).unwrap();
+    assert!(call(SYS_WRITE, &[fd, 0x80000200, 5]).is_err());
+    assert_eq!(call(SYS_OPEN, &[0x80000106, 4, 8]), Err(hostfs::EACCES));
+    assert_eq!(call(SYS_OPEN, &[0x8000010f, 4, 9]), Err(hostfs::EACCES));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -1,5 +1,5 @@
 use terminus::processor::ProcessorCfg;
-use terminus::system::System;
+use terminus::system::SystemBuilder;
 use terminus_global::XLen;
 use terminus_spaceport::memory::region::{GHEAP, U64Access};
 use terminus_spaceport::devices::term_exit;
@@ -11,20 +11,13 @@ use terminus::devices::clint::Clint;
 
 fn riscv_test(xlen: XLen, name: &str, debug: bool, num_cores: usize) -> bool {
     EXIT_CTRL.reset();
-    let configs = vec![ProcessorCfg {
-        xlen,
-        enable_dirty: true,
-        extensions: vec!['m', 'f', 'd', 's', 'u', 'c', 'a'].into_boxed_slice(),
-        freq:1000000000,
-    }; num_cores];
-    let mut sys = System::new(name, Path::new("top_tests/elf").join(Path::new(name)).to_str().expect(&format!("{} not existed!", name)), configs, 10000000);
+    let configs = vec![ProcessorCfg::from_isa(&format!("rv{}imafdcsu", xlen.len()), 1000000000).unwrap(); num_cores];
+    let mut sys = SystemBuilder::new(name).processors(configs).elf_file(Path::new("top_tests/elf").join(Path::new(name)).to_str().expect(&format!("{} not existed!", name))).build().unwrap();
     sys.register_memory("main_memory", 0x80000000, &GHEAP.alloc(0x10000000, 1).expect("main_memory alloc fail!")).unwrap();
     sys.register_device("clint", 0x20000, 0x10000, Clint::new(sys.timer())).unwrap();
     sys.load_elf().unwrap();
-    sys.reset(vec![-1i64 as u64;num_cores]).unwrap();
+    sys.reset(vec![None; num_cores]).unwrap();
 
-    let interval: u64 = 100;
-    let mut interval_cnt: u64 = 0;
     loop {
         if let Ok(msg) = EXIT_CTRL.poll() {
             if debug {
@@ -32,16 +25,12 @@ fn riscv_test(xlen: XLen, name: &str, debug: bool, num_cores: usize) -> bool {
             }
             break;
         }
-        for p in sys.processors() {
-            p.step(1);
-            if debug {
+        sys.step(1);
+        if debug {
+            for p in sys.processors() {
                 println!("{}", p.state().trace())
             }
         }
-        interval_cnt += 1;
-        if interval_cnt % interval == interval - 1 {
-            sys.timer().tick(interval)
-        }
     }
     if debug {
         for p in sys.processors() {